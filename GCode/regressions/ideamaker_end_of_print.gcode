@@ -0,0 +1,42 @@
+;Sliced by ideaMaker 4.2.0.5250, 2021-09-03 09:54:14 UTC-0700
+;Dimension: 205.000 205.000 205.000 0.400
+M140 S60.00
+M104 T0 S215.00
+G21
+G90
+M82
+G28 X0 Y0
+G28 Z0
+G92 E0
+;LAYER:0
+;Z:0.300
+;HEIGHT:0.300
+;PRINTING: cube_a.3mf
+;PRINTING_ID: 0
+G0 F9000 X40.000 Y40.000 Z0.300
+;TYPE:WALL-OUTER
+;WIDTH:0.400
+G1 F1440 X60.000 Y40.000 E1.0000
+G1 X60.000 Y60.000 E2.0000
+G1 X40.000 Y60.000 E3.0000
+G1 X40.000 Y40.000 E4.0000
+;PRINTING: cube_b.3mf
+;PRINTING_ID: 1
+G0 F9000 X90.000 Y90.000
+;TYPE:WALL-OUTER
+;WIDTH:0.400
+G1 F1440 X110.000 Y90.000 E5.0000
+G1 X110.000 Y110.000 E6.0000
+G1 X90.000 Y110.000 E7.0000
+G1 X90.000 Y90.000 E8.0000
+;PRINTING_TIME: 120
+;REMAINING_TIME: 0
+M106 S0
+G0 F300 Z5.300
+G92 E0
+G1 F600 X5.000 Y200.000 E10.0000
+M104 T0 S0
+M140 S0
+M84
+;Print Time: 120
+;Material#1 Used: 0.1
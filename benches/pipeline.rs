@@ -0,0 +1,110 @@
+//! Benchmarks of the G-Code parsers, the hull tracking and the whole pipeline on synthetic
+//! plates in every slicer dialect.
+//!
+//! Run with `cargo bench`, a filter only runs the matching benchmarks, e.g.
+//! `cargo bench -- pipeline/cura`. Each run is compared to the previous one; to compare a change
+//! against a branch, run `cargo bench -- --save-baseline main` on the branch first and
+//! `cargo bench -- --baseline main` with the change.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use preprocess_cancellation::benchmarks::{self, Objects, Options};
+use preprocess_cancellation::parser::parse_gcode;
+use preprocess_cancellation::synthetic::{Dialect, SyntheticGcode};
+use std::time::Duration;
+
+const HULL_MODES: [&str; 3] = ["convex", "concave", "bbox"];
+
+fn parser(c: &mut Criterion) {
+    let gcode = SyntheticGcode::new(Dialect::PrusaSlicer)
+        .objects(4)
+        .layers(5)
+        .to_string();
+    let lines: Vec<&str> = gcode.lines().collect();
+
+    let mut group = c.benchmark_group("parser");
+    group.throughput(Throughput::Bytes(gcode.len() as u64));
+    group.bench_function("parse_gcode", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(parse_gcode(black_box(line)));
+            }
+        })
+    });
+    group.bench_function("parse_move", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(benchmarks::parse_move(black_box(line)));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn hulls(c: &mut Criterion) {
+    // Dense outlines of many objects, as the layers of an object share their points
+    let plate = SyntheticGcode::new(Dialect::PrusaSlicer)
+        .objects(64)
+        .segments(360);
+    let points: Vec<Vec<(f64, f64)>> = (0..plate.objects)
+        .map(|object| {
+            (0..plate.perimeters)
+                .flat_map(|perimeter| plate.perimeter(object, perimeter))
+                .collect()
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("hulls");
+    group.throughput(Throughput::Elements(
+        points.iter().map(Vec::len).sum::<usize>() as u64,
+    ));
+    for mode in HULL_MODES {
+        group.bench_function(format!("track/{mode}"), |b| {
+            b.iter(|| Objects::new(black_box(&points), mode).unwrap())
+        });
+
+        let objects = Objects::new(&points, mode).unwrap();
+        group.bench_function(format!("outlines/{mode}"), |b| {
+            b.iter_batched(|| objects.clone(), Objects::outlines, BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10));
+
+    let default = Options::new(&[]).unwrap();
+    for dialect in Dialect::ALL {
+        for (size, objects, layers) in [("small", 4, 50), ("large", 36, 100)] {
+            let gcode = SyntheticGcode::new(dialect)
+                .objects(objects)
+                .layers(layers)
+                .to_string();
+            group.throughput(Throughput::Bytes(gcode.len() as u64));
+            group.bench_function(format!("{dialect}/{size}"), |b| {
+                b.iter(|| benchmarks::process(gcode.as_bytes(), &default).unwrap())
+            });
+        }
+    }
+
+    // Hull generation dominates with dense outlines and many objects
+    let gcode = SyntheticGcode::new(Dialect::PrusaSlicer)
+        .objects(64)
+        .layers(20)
+        .segments(360)
+        .to_string();
+    group.throughput(Throughput::Bytes(gcode.len() as u64));
+    for mode in HULL_MODES {
+        let options = Options::new(&["--hull-mode", mode]).unwrap();
+        group.bench_function(format!("hulls/{mode}"), |b| {
+            b.iter(|| benchmarks::process(gcode.as_bytes(), &options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parser, hulls, pipeline);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use preprocess_cancellation::layers::LayerFilter;
+
+fuzz_target!(|spec: &str| {
+    if let Ok(filter) = LayerFilter::try_from(spec) {
+        let display = filter.to_string();
+        assert_eq!(LayerFilter::try_from(display.as_str()).as_ref(), Ok(&filter));
+        for layer in 0..64 {
+            filter.resolve(layer).contains(layer);
+        }
+    }
+});
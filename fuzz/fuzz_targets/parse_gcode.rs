@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use preprocess_cancellation::parser::{parse_float, parse_gcode};
+
+fuzz_target!(|line: &str| {
+    let command = parse_gcode(line);
+    for value in command.params.values() {
+        // The fast path has to agree with the standard library on every number
+        let expected = value.parse::<f64>().ok().filter(|value| value.is_finite());
+        assert_eq!(
+            parse_float(value).map(f64::to_bits),
+            expected.map(f64::to_bits)
+        );
+    }
+});
@@ -0,0 +1,120 @@
+//! Read-only analysis of G-Code files.
+//!
+//! The slicer, the objects and their outlines are found the same way as when processing a file,
+//! but nothing is written. Frontends can show the object layout of files that haven't been
+//! processed yet.
+//!
+//! ```no_run
+//! use std::fs::File;
+//!
+//! let analysis = preprocess_cancellation::analysis::analyze(File::open("plate.gcode")?)?;
+//! for object in &analysis.objects {
+//!     println!("{} at {:?}", object.name, object.center);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::layers::LayerFilter;
+use crate::preprocess::{self, ProcessOptions};
+use serde::Serialize;
+use std::io::{Read, Seek};
+
+pub use crate::preprocess::PreprocessError;
+pub use crate::report::{ObjectReport, OverlapReport, Warning};
+
+/// The objects of a G-Code file
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Analysis {
+    /// Slicer that created the file
+    pub slicer: Option<String>,
+    /// The file defines its objects already, they aren't analysed again
+    pub already_processed: bool,
+    /// Layers the outlines were collected from
+    pub layer_filter: Option<LayerFilter>,
+    pub objects: Vec<ObjectReport>,
+    /// Objects whose outlines overlap significantly
+    pub overlaps: Vec<OverlapReport>,
+    /// Problems found with the objects, like outlines without an area
+    pub warnings: Vec<Warning>,
+}
+
+/// Find the slicer and the objects of a file, outlined by the extrusions on all layers
+pub fn analyze(input: impl Read + Seek + Send) -> Result<Analysis, PreprocessError> {
+    analyze_layers(input, LayerFilter::all())
+}
+
+/// Find the slicer and the objects of a file, outlined by the extrusions on the given layers
+pub fn analyze_layers(
+    input: impl Read + Seek + Send,
+    layer_filter: LayerFilter,
+) -> Result<Analysis, PreprocessError> {
+    let options = ProcessOptions {
+        layer_filter,
+        ..Default::default()
+    };
+    let report = preprocess::analyze(input, &options)?;
+
+    Ok(Analysis {
+        slicer: report.slicer,
+        already_processed: report.already_processed,
+        layer_filter: report.layer_filter,
+        objects: report.objects,
+        overlaps: report.overlaps,
+        warnings: report.warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn fixture(name: &str) -> File {
+        File::open(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("GCode")
+                .join(name),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_analyze() {
+        let analysis = analyze(fixture("prusaslicer.gcode")).unwrap();
+        assert_eq!(analysis.slicer.as_deref(), Some("PrusaSlicer"));
+        assert!(!analysis.already_processed);
+        assert!(!analysis.objects.is_empty());
+        assert!(analysis
+            .objects
+            .iter()
+            .all(|object| object.center.is_some() && object.polygon.len() >= 4));
+
+        let first_layer = analyze_layers(fixture("prusaslicer.gcode"), "0".parse().unwrap());
+        assert_eq!(
+            first_layer.unwrap().layer_filter,
+            Some("0".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_analyze_processed() {
+        let mut processed = Vec::new();
+        preprocess::stream(
+            &mut fixture("cura.gcode"),
+            &mut processed,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+
+        let analysis = analyze(Cursor::new(processed)).unwrap();
+        assert!(analysis.already_processed);
+        assert!(analysis.objects.is_empty());
+
+        assert!(matches!(
+            analyze(Cursor::new("G28\nG1 X1 Y1 E1\n")),
+            Err(PreprocessError::UnknownSlicer)
+        ));
+    }
+}
@@ -0,0 +1,67 @@
+//! Entry points for the benchmarks in `benches/`.
+//!
+//! Exposes the parsers, the hull tracking and the pipeline to the benchmarks without making
+//! their types part of the public API, this is not a stable interface.
+
+use crate::cli::Cli;
+use crate::gcode;
+use crate::hulls::{compute_outlines, HullMode, HullOptions, KnownObject};
+use crate::preprocess::{self, PreprocessError, ProcessOptions};
+use clap::{Parser, ValueEnum};
+use std::io::{self, Cursor};
+
+/// Parse a line with the fast path for moves, returning the move number and its X and Y
+pub fn parse_move(line: &str) -> Option<(u32, Option<f64>, Option<f64>)> {
+    gcode::parse_move(line).map(|(number, params)| (number, params.x, params.y))
+}
+
+/// Objects and the extrusion points tracked for their outlines
+#[derive(Clone)]
+pub struct Objects(Vec<KnownObject>);
+
+impl Objects {
+    /// Track the points of each object with a hull mode of the command line, e.g. `concave`
+    pub fn new(points: &[Vec<(f64, f64)>], hull_mode: &str) -> Result<Self, String> {
+        let options = HullOptions {
+            mode: HullMode::from_str(hull_mode, true)?,
+            ..Default::default()
+        };
+        let objects = points
+            .iter()
+            .enumerate()
+            .map(|(object, points)| {
+                let mut known_object = KnownObject::new(&format!("object {object}"), &options);
+                for (x, y) in points {
+                    known_object.hull.add_point(*x, *y);
+                }
+                known_object
+            })
+            .collect();
+        Ok(Self(objects))
+    }
+
+    /// Compute the outlines of all objects, returns the number of their vertices
+    pub fn outlines(self) -> usize {
+        compute_outlines(&self.0);
+        self.0
+            .iter()
+            .map(|known_object| known_object.hull.exterior().0.len())
+            .sum()
+    }
+}
+
+/// Processing options given as command line arguments, e.g. `["--hull-mode", "bbox"]`
+pub struct Options(ProcessOptions);
+
+impl Options {
+    pub fn new(args: &[&str]) -> anyhow::Result<Self> {
+        let cli = Cli::try_parse_from(["preprocess_cancellation", "--stdio"].iter().chain(args))?;
+        Ok(Self(cli.process_options()?))
+    }
+}
+
+/// Process G-Code in memory and discard the output, returns the number of objects found
+pub fn process(input: &[u8], options: &Options) -> Result<usize, PreprocessError> {
+    let report = preprocess::stream(&mut Cursor::new(input), &mut io::sink(), &options.0)?;
+    Ok(report.objects.len())
+}
@@ -0,0 +1,90 @@
+//! Shrink a G-Code file from an issue report into a small fixture reproducing the issue.
+//!
+//! The test command decides whether a candidate still reproduces the issue: it is run by `sh`
+//! with the path of the candidate as `$1` and has to exit successfully if the issue is present,
+//! e.g.
+//!
+//! ```text
+//! minimize_fixture report.gcode -o GCode/regressions/issue_42.gcode \
+//!     --test 'preprocess_cancellation --stdio < "$1" | grep -q "NAME=_"'
+//! ```
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use preprocess_cancellation::minimize::minimize;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Parser)]
+#[clap(about = "Shrink a G-Code file into a small fixture that still reproduces an issue")]
+struct Cli {
+    /// Shell command exiting successfully while the issue reproduces, the candidate file is `$1`
+    #[clap(long, short)]
+    test: String,
+    /// Lines at the start that are always kept, like the comment identifying the slicer
+    #[clap(long, default_value_t = 1)]
+    keep: usize,
+    /// Where to write the minimized fixture
+    #[clap(long, short)]
+    output: PathBuf,
+    /// G-Code file reproducing the issue
+    input: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    let lines = BufReader::new(std::fs::File::open(&args.input)?)
+        .lines()
+        .collect::<io::Result<Vec<String>>>()?;
+    let original = lines.len();
+
+    let workdir = tempfile::tempdir()?;
+    let candidate = workdir.path().join(
+        args.input
+            .file_name()
+            .unwrap_or_else(|| "candidate.gcode".as_ref()),
+    );
+    let mut checks = 0;
+    let mut reproduced = false;
+    let minimized = minimize(lines, args.keep, |lines| {
+        let mut file = io::BufWriter::new(std::fs::File::create(&candidate)?);
+        for line in lines {
+            writeln!(file, "{line}")?;
+        }
+        file.flush()?;
+        drop(file);
+
+        checks += 1;
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&args.test)
+            .arg("sh")
+            .arg(&candidate)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if status.success() {
+            reproduced = true;
+            eprintln!("{:>6} lines reproduce the issue", lines.len());
+        }
+        Ok(status.success())
+    })?;
+
+    if !reproduced {
+        bail!("The test command does not reproduce the issue with the whole input");
+    }
+
+    let mut output = io::BufWriter::new(std::fs::File::create(&args.output)?);
+    for line in &minimized {
+        writeln!(output, "{line}")?;
+    }
+    output.flush()?;
+    eprintln!(
+        "Reduced {original} lines to {} in {checks} checks: {}",
+        minimized.len(),
+        args.output.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! Process a synthetic plate of any size and report the time spent in each phase and the peak
+//! memory used, e.g. to check that `--max-memory` holds on multi-gigabyte files:
+//!
+//! ```text
+//! cargo build --release
+//! target/release/stress_test --size 4 --objects 200 -- --max-memory 64
+//! ```
+//!
+//! Arguments after `--` are passed to `preprocess_cancellation`. The timings are taken from the
+//! report passed to a post-processing hook, so processing needs `sh`. The peak memory is sampled
+//! while the tool runs and only measured on Linux.
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use preprocess_cancellation::synthetic::{Dialect, SyntheticGcode};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How often the memory of the running process is sampled
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Parser)]
+#[clap(about = "Process a large synthetic plate and measure the time and memory it takes")]
+struct Cli {
+    /// Slicer the plate is generated for: prusaslicer, cura, ideamaker or m486
+    #[clap(long, default_value = "prusaslicer")]
+    dialect: Dialect,
+    #[clap(long, default_value_t = 100)]
+    objects: usize,
+    /// Layers of the plate, calculated from --size if not given
+    #[clap(long, conflicts_with = "size")]
+    layers: Option<usize>,
+    /// Approximate size of the plate in GB
+    #[clap(long, value_name = "GB")]
+    size: Option<f64>,
+    /// Perimeters printed per object and layer
+    #[clap(long, default_value_t = 3)]
+    perimeters: usize,
+    /// Extrusion moves per perimeter, the resolution of the plate
+    #[clap(long, default_value_t = 64)]
+    segments: usize,
+    /// Fail if the peak memory of processing exceeds this many MB
+    #[clap(long, value_name = "MB")]
+    max_rss: Option<f64>,
+    /// Keep the generated plate in this file instead of a temporary directory
+    #[clap(long, short)]
+    output: Option<PathBuf>,
+    /// Only generate the plate
+    #[clap(long, requires = "output")]
+    generate_only: bool,
+    /// The preprocess_cancellation executable, by default the one next to this tool
+    #[clap(long)]
+    binary: Option<PathBuf>,
+    /// Arguments passed to preprocess_cancellation
+    #[clap(last = true)]
+    args: Vec<String>,
+}
+
+/// Counts the bytes written, to size the plate without writing it
+#[derive(Default)]
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn generated_size(gcode: &SyntheticGcode) -> Result<u64> {
+    let mut counter = Counter::default();
+    gcode.write(&mut counter)?;
+    Ok(counter.0)
+}
+
+/// The plate with as many layers as needed to reach the requested size
+fn plate(args: &Cli) -> Result<SyntheticGcode> {
+    let gcode = SyntheticGcode::new(args.dialect)
+        .objects(args.objects)
+        .perimeters(args.perimeters)
+        .segments(args.segments);
+    let layers = match (args.layers, args.size) {
+        (Some(layers), _) => layers,
+        (None, Some(size)) => {
+            let fixed = generated_size(&gcode.clone().layers(0))?;
+            let layer = generated_size(&gcode.clone().layers(1))? - fixed;
+            ((size * 1e9 - fixed as f64) / layer as f64).ceil().max(1.0) as usize
+        }
+        (None, None) => gcode.layers,
+    };
+    Ok(gcode.layers(layers))
+}
+
+/// Peak resident memory of a running process in bytes
+fn peak_memory(child: &Child) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", child.id())).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn binary(args: &Cli) -> Result<PathBuf> {
+    if let Some(binary) = &args.binary {
+        return Ok(binary.clone());
+    }
+    let binary = std::env::current_exe()?.with_file_name(format!(
+        "preprocess_cancellation{}",
+        std::env::consts::EXE_SUFFIX
+    ));
+    if !binary.exists() {
+        bail!(
+            "{} not found, build it with cargo build or pass --binary",
+            binary.display()
+        );
+    }
+    Ok(binary)
+}
+
+/// Process the plate, returns the report of the tool and the peak memory if it was measured
+fn process(args: &Cli, plate: &Path, workdir: &Path) -> Result<(Value, Option<u64>)> {
+    let report = workdir.join("report.json");
+    let mut child = Command::new(binary(args)?)
+        .args(&args.args)
+        .arg("--output-dir")
+        .arg(workdir.join("processed"))
+        .arg("--post-hook")
+        .arg(format!(
+            "printf '%s' \"$PREPROCESS_CANCELLATION_REPORT\" > '{}'",
+            report.display()
+        ))
+        .arg(plate)
+        .stdin(Stdio::null())
+        .spawn()?;
+
+    let mut peak = None;
+    let status = loop {
+        // Read before checking for the exit, the process is gone once it has been waited for
+        peak = peak_memory(&child).or(peak);
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    if !status.success() {
+        bail!("preprocess_cancellation exited with {status}");
+    }
+
+    let report = std::fs::read_to_string(&report).context("No report was written")?;
+    Ok((serde_json::from_str(&report)?, peak))
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    let gcode = plate(&args)?;
+
+    let workdir = tempfile::tempdir()?;
+    let path = args
+        .output
+        .clone()
+        .unwrap_or_else(|| workdir.path().join("plate.gcode"));
+
+    let started = Instant::now();
+    let mut writer = BufWriter::new(File::create(&path)?);
+    gcode.write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    let bytes = path.metadata()?.len();
+    eprintln!(
+        "Generated {:.2}GB ({}, {} objects, {} layers) in {:.1}s",
+        bytes as f64 / 1e9,
+        gcode.dialect,
+        gcode.objects,
+        gcode.layers,
+        started.elapsed().as_secs_f64()
+    );
+    if args.generate_only {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let (report, peak) = process(&args, &path, workdir.path())?;
+    let timing = |phase: &str| report["timings"][phase].as_f64().unwrap_or_default();
+    println!(
+        "Processed in {:.1}s ({:.1}MB/s): identification {:.1}s, collection {:.1}s, \
+        hulls {:.1}s, rewrite {:.1}s",
+        started.elapsed().as_secs_f64(),
+        timing("throughput"),
+        timing("identification"),
+        timing("collection"),
+        timing("hulls"),
+        timing("rewrite")
+    );
+
+    match peak {
+        Some(peak) => {
+            let peak = peak as f64 / 1e6;
+            println!("Peak memory {peak:.1}MB");
+            if let Some(max_rss) = args.max_rss.filter(|max_rss| peak > *max_rss) {
+                bail!("The peak memory exceeds {max_rss:.1}MB");
+            }
+        }
+        None if args.max_rss.is_some() => bail!("The peak memory can't be measured here"),
+        None => println!("Peak memory not measured on this platform"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,287 @@
+use crate::layers::LayerFilter;
+use crate::preprocess::ProcessOptions;
+use crate::report::{ObjectReport, OverlapReport, ProcessReport, Warning};
+use crate::splice::Edits;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, rename, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// 64-bit FNV-1a, stable across platforms and compiler versions unlike `DefaultHasher`
+pub(crate) struct Fnv64(pub u64);
+
+impl Fnv64 {
+    pub fn new() -> Self {
+        Self(FNV_OFFSET)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// Everything needed to rewrite a file again without collecting its objects
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CacheEntry {
+    pub slicer: Option<String>,
+    pub layer_filter: Option<LayerFilter>,
+    pub objects: Vec<ObjectReport>,
+    pub overlaps: Vec<OverlapReport>,
+    #[serde(default)]
+    pub warnings: Vec<Warning>,
+    /// Markers with the object definitions already rendered
+    pub edits: Edits,
+}
+
+impl CacheEntry {
+    pub fn new(report: &ProcessReport, edits: Edits) -> Self {
+        Self {
+            slicer: report.slicer.clone(),
+            layer_filter: report.layer_filter.clone(),
+            objects: report.objects.clone(),
+            overlaps: report.overlaps.clone(),
+            // Problems of the output are found again when it is written
+            warnings: report
+                .warnings
+                .iter()
+                .filter(|warning| !matches!(warning, Warning::UnbalancedMarkers { .. }))
+                .cloned()
+                .collect(),
+            edits,
+        }
+    }
+
+    /// Restore the report of the run that created this entry
+    pub fn restore(&mut self, report: &mut ProcessReport) {
+        report.slicer = self.slicer.take();
+        report.layer_filter = self.layer_filter.take();
+        report.objects = std::mem::take(&mut self.objects);
+        report.index_objects();
+        report.overlaps = std::mem::take(&mut self.overlaps);
+        report.warnings = std::mem::take(&mut self.warnings);
+        report.cached = true;
+    }
+}
+
+/// Results of earlier runs, keyed by a hash of the file contents and the options.
+///
+/// Farms re-syncing a library of files only need to hash unchanged files instead of collecting
+/// the extrusions of every object again.
+pub(crate) struct ResultCache {
+    dir: PathBuf,
+}
+
+impl ResultCache {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Hash the whole input together with the options that change the output
+    pub fn key(&self, input: &mut impl BufRead, options: &ProcessOptions) -> io::Result<String> {
+        let mut hash = Fnv64::new();
+        hash.write(fingerprint(options).as_bytes());
+
+        let mut length = 0;
+        loop {
+            let buffer = input.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+            hash.write(buffer);
+            length += buffer.len();
+
+            let consumed = buffer.len();
+            input.consume(consumed);
+        }
+
+        Ok(format!("{:016x}-{length}", hash.0))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    pub fn load(&self, key: &str) -> Option<CacheEntry> {
+        let file = File::open(self.path(key)).ok()?;
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                tracing::warn!("Ignoring invalid cache entry {}: {}", key, err);
+                None
+            }
+        }
+    }
+
+    /// Store an entry, written to a temporary file first so readers never see partial entries
+    pub fn store(&self, key: &str, entry: &CacheEntry) -> io::Result<()> {
+        create_dir_all(&self.dir)?;
+
+        let path = self.path(key);
+        let partial = path.with_extension("json.partial");
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.flush()?;
+        drop(writer);
+
+        rename(partial, path)
+    }
+}
+
+/// The options affecting the output, and the version of the tool
+pub(crate) fn fingerprint(options: &ProcessOptions) -> String {
+    let hull = &options.hull;
+    format!(
+        "{version} layers={layers} mode={mode:?} concavity={concavity} width={width:?} \
+        grid={grid:?} sample={sample:?} budget={budget:?} tools={tools:?} xy_offset={xy_offset:?} transforms={transforms:?} \
+        idex_copy={idex_copy:?} cancel_extruders={cancel_extruders:?} groups=[{groups}] group_macro={group_macro:?} \
+        group_prefix={group_prefix} metadata={metadata} wkt={wkt} \
+        names={names} max_name={max_name:?} brims={brims} supports={supports} wipe_tower={wipe_tower:?} area={area:?} \
+        length={length:?} end_markers={end_markers:?} precedence={precedence:?} normalize={normalize} \
+        header_marker={header_marker} header_text={header_text:?} provenance={provenance} \
+        progress={progress} object_progress={object_progress}",
+        version = env!("CARGO_PKG_VERSION"),
+        layers = options.layer_filter,
+        mode = hull.mode,
+        concavity = hull.concavity,
+        width = hull.line_width,
+        grid = hull.grid,
+        sample = hull.sample_every,
+        budget = hull.budget.limit(),
+        tools = options.tool_offsets,
+        xy_offset = options.xy_offset,
+        transforms = options.transforms,
+        idex_copy = options.idex_copy,
+        cancel_extruders = options.cancel_extruders,
+        groups = options.groups.iter().join(" "),
+        group_macro = options.group_macro,
+        group_prefix = options.group_prefix,
+        metadata = options.object_metadata,
+        wkt = options.wkt,
+        names = options.name_comments,
+        max_name = options.max_name_length,
+        brims = options.assign_brims,
+        supports = options.assign_supports,
+        wipe_tower = options.wipe_tower,
+        area = options.min_object_area,
+        length = options.min_extrusion_length,
+        end_markers = options.end_markers,
+        precedence = options.marker_precedence,
+        normalize = options.normalize,
+        header_marker = options.header_marker,
+        header_text = options.header_text,
+        provenance = options.provenance.is_some(),
+        progress = options.progress,
+        object_progress = options.object_progress,
+    )
+}
+
+/// Short hash of the [`fingerprint`] of the options, recorded in the header by `--provenance`
+pub(crate) fn options_hash(options: &ProcessOptions) -> String {
+    let mut hash = Fnv64::new();
+    hash.write(fingerprint(options).as_bytes());
+    format!("{:016x}", hash.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hulls::{HullOptions, PointBudget};
+    use crate::splice::Marker;
+
+    #[test]
+    fn test_fnv64() {
+        // Reference values of the FNV-1a test suite
+        let mut hash = Fnv64::new();
+        assert_eq!(hash.0, 0xcbf29ce484222325);
+        hash.write(b"a");
+        assert_eq!(hash.0, 0xaf63dc4c8601ec8c);
+        let mut hash = Fnv64::new();
+        hash.write(b"foobar");
+        assert_eq!(hash.0, 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ResultCache::new(dir.path());
+        let options = ProcessOptions::default();
+
+        let key = cache.key(&mut "G28\nG1 X1".as_bytes(), &options).unwrap();
+        assert_eq!(
+            key,
+            cache.key(&mut "G28\nG1 X1".as_bytes(), &options).unwrap()
+        );
+        assert!(key.ends_with("-9"));
+        assert_ne!(
+            key,
+            cache.key(&mut "G28\nG1 X2".as_bytes(), &options).unwrap()
+        );
+
+        let wkt = ProcessOptions {
+            wkt: true,
+            ..Default::default()
+        };
+        assert_ne!(key, cache.key(&mut "G28\nG1 X1".as_bytes(), &wkt).unwrap());
+
+        // The budget can replace outlines with bounding boxes
+        let budget = ProcessOptions {
+            hull: HullOptions {
+                budget: PointBudget::from_megabytes(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_ne!(
+            key,
+            cache.key(&mut "G28\nG1 X1".as_bytes(), &budget).unwrap()
+        );
+        assert_ne!(options_hash(&options), options_hash(&budget));
+        // Only the limit counts, not the points used
+        assert_eq!(
+            options_hash(&budget),
+            options_hash(&ProcessOptions {
+                hull: HullOptions {
+                    budget: budget.hull.budget.fresh(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ResultCache::new(&dir.path().join("cache"));
+        assert!(cache.load("missing").is_none());
+
+        let mut edits = Edits::default();
+        edits.track(0, "G28");
+        edits.after(1, Marker::Text("; marker\n".into()));
+        let report = ProcessReport {
+            slicer: Some("Cura".into()),
+            ..Default::default()
+        };
+        cache
+            .store("key", &CacheEntry::new(&report, edits))
+            .unwrap();
+
+        let mut entry = cache.load("key").unwrap();
+        let mut restored = ProcessReport::default();
+        entry.restore(&mut restored);
+        assert_eq!(restored.slicer.as_deref(), Some("Cura"));
+        assert!(restored.cached);
+
+        std::fs::write(cache.path("broken"), "{").unwrap();
+        assert!(cache.load("broken").is_none());
+    }
+}
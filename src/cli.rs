@@ -0,0 +1,1116 @@
+//! The command line tool, the `preprocess_cancellation` binary only runs [`main`].
+
+use crate::container::{Container, ContainerError, DEFAULT_PLATE_TEMPLATE};
+use crate::daemon::Queue;
+use crate::exit::{ExitStatus, FailedFile, ValidationFailed};
+use crate::gcode::Provenance;
+use crate::groups::ObjectGroup;
+use crate::hulls::{HullMode, HullOptions, PointBudget};
+use crate::layers::LayerFilter;
+use crate::motion::{self, ToolOffset, Toolhead};
+use crate::orphans::WipeTowerMode;
+use crate::output::{OutputOptions, OutputTemplate, SuffixPlacement};
+use crate::paths::{NativeFileSystem, Retry};
+use crate::preprocess::{
+    MarkerPrecedence, PreprocessError, ProcessOptions, RefreshMode, DEFAULT_BUFFER_SIZE,
+    DEFAULT_SCAN_WINDOW,
+};
+use crate::remote::{RemoteFile, UploadTarget};
+use crate::report::{BatchEntry, ProcessReport};
+#[cfg(feature = "otlp")]
+use crate::telemetry;
+use crate::transform::{Affine, Transform};
+use crate::{
+    compare, container, coverage, daemon, diff, extract, gcode, hooks, hulls, klipper, output,
+    paths, preprocess, report,
+};
+use anyhow::Result;
+use clap::{ArgAction, ColorChoice, Parser, ValueHint};
+use itertools::Itertools;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{stdin, stdout, BufReader, Read, Seek};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{field, Level, Span};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+
+/// Preprocess G-Code files to inject support for Klipper's EXCLUDE_OBJECT feature.
+///
+/// Current supported slicers:{n}
+///   * Cura{n}
+///   * Slic3r beta{n}
+///   * PrusaSlicer{n}
+///   * Superslicer{n}
+///   * Orcaslicer{n}
+///   * Ideamaker{n}
+///   * GCode with Marlin M486 tags
+#[derive(clap::Parser, Debug)]
+#[clap(
+    author,
+    about,
+    version,
+    name = "Preprocess Cancellation",
+    color = ColorChoice::Auto,
+    after_long_help = ExitStatus::help()
+)]
+pub(crate) struct Cli {
+    /// Verbose mode (-v, -vv, -vvv, etc.)
+    #[clap(short, long, action=ArgAction::Count)]
+    verbose: u8,
+    /// Add a suffix to the G-code output. Without this the file will be rewritten in place.
+    #[clap(short = 'o', long, value_parser = output::parse_suffix)]
+    pub output_suffix: Option<String>,
+    /// Where the suffix goes in the file name
+    #[clap(long, value_enum, default_value_t = SuffixPlacement::default())]
+    pub suffix_placement: SuffixPlacement,
+    /// Move the untouched originals into this directory once they are processed
+    #[clap(long, value_hint=ValueHint::DirPath)]
+    pub archive_dir: Option<PathBuf>,
+    /// G-Code output directory
+    #[clap(short='O', long, value_hint=ValueHint::DirPath)]
+    pub output_dir: Option<PathBuf>,
+    /// Output path template, relative to the output directory
+    ///
+    /// Supported placeholders: {stem}, {ext}, {name}, {slicer}, {objects}, {date} and {plate}.
+    /// The plates of 3MF containers are extracted as {stem}_plate{plate}.gcode by default.
+    #[clap(long, value_name = "TEMPLATE")]
+    pub output_template: Option<OutputTemplate>,
+    /// Use shapely to generate a hull polygon for objects
+    ///
+    /// This is a no-op and only exists for compatibility with the Python version
+    #[clap(long, hide=true, group="processing", conflicts_with="disable_shapely", action=ArgAction::SetTrue)]
+    enable_shapely: bool,
+    /// Disable using shapely for low memory systems
+    ///
+    /// This is a no-op and only exists for compatibility with the Python version
+    #[clap(long, hide=true, group="processing", conflicts_with="enable_shapely", action=ArgAction::SetTrue)]
+    disable_shapely: bool,
+    /// Layers to collect shape points from.
+    ///
+    /// '*' will collect all layers
+    /// '*/n' to collect every nth layer
+    /// 'n-m' to collect layers from n to m
+    /// 'n+k/s' to collect every sth layer starting at layer n+k
+    /// 'odd' or 'even' to collect every other layer
+    /// 'first' to collect only the first layer of each object
+    /// '-n' to collect the nth layer counted from the end ('-1' is the last layer)
+    /// '~n' to collect the last n layers
+    /// '%a-b' to collect layers from a to b percent of the print
+    #[clap(
+        short = 'l',
+        long,
+        group = "processing",
+        default_value = "*",
+        value_name = "LAYERS",
+        conflicts_with = "fast"
+    )]
+    pub layers: String,
+    /// Use only the first layer for point collection
+    #[clap(long, group="processing", conflicts_with="layers", action=ArgAction::SetTrue)]
+    pub fast: bool,
+    /// Copy processed files to a remote host using scp
+    ///
+    /// Accepts sftp://[user@]host[:port]/path URLs or scp style [user@]host:path destinations.
+    #[clap(long, value_name = "DESTINATION")]
+    pub upload: Option<UploadTarget>,
+    /// Algorithm used to generate the object outline polygons
+    #[clap(long, value_enum, default_value_t = HullMode::Convex)]
+    pub hull_mode: HullMode,
+    /// Concavity of concave hulls, lower values follow the object outline more closely
+    #[clap(long, default_value_t = 2.0, value_name = "CONCAVITY")]
+    pub hull_concavity: f64,
+    /// Extrusion line width in mm, object polygons are grown by half of it
+    ///
+    /// Detected from the slicer settings in the G-Code file if not given, use 0 to disable.
+    #[clap(long, value_name = "MM")]
+    pub extrusion_width: Option<f64>,
+    /// Snap extrusion points to a grid of this size, e.g. 0.5
+    ///
+    /// Reduces memory usage and speeds up hull generation for high resolution prints.
+    #[clap(long, value_name = "MM")]
+    pub grid: Option<f64>,
+    /// Only use every nth extrusion move of an object for its polygon
+    ///
+    /// Speeds up processing of very dense G-Code with little effect on the polygons.
+    #[clap(long, value_name = "N")]
+    pub sample_every: Option<NonZeroUsize>,
+    /// Limit the memory used to store extrusion points to about this many megabytes
+    ///
+    /// Once exceeded, the points of an object are collapsed into its hull, or only its
+    /// bounding box is kept. Prevents running out of memory on hosts like a Raspberry Pi.
+    #[clap(long, value_name = "MB")]
+    pub max_memory: Option<NonZeroUsize>,
+    /// Size of the buffer used to read G-Code files in KiB
+    #[clap(long, default_value_t = NonZeroUsize::new(DEFAULT_BUFFER_SIZE / 1024).unwrap(), value_name = "KB")]
+    pub read_buffer: NonZeroUsize,
+    /// Size of the buffer used to write processed files in KiB
+    ///
+    /// Larger buffers reduce the number of writes, which helps on network filesystems.
+    #[clap(long, default_value_t = NonZeroUsize::new(DEFAULT_BUFFER_SIZE / 1024).unwrap(), value_name = "KB")]
+    pub write_buffer: NonZeroUsize,
+    /// Attempts to open or replace a file while another program has it open
+    ///
+    /// Slicers, uploads and virus scanners briefly lock the files they work on, mostly on
+    /// Windows.
+    #[clap(long, value_name = "N", default_value_t = paths::DEFAULT_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..=paths::MAX_ATTEMPTS as i64))]
+    pub retry_attempts: u32,
+    /// Milliseconds to wait before the first retry, doubled for every further attempt up to 5
+    /// seconds
+    #[clap(long, value_name = "MS", default_value_t = paths::DEFAULT_RETRY_DELAY.as_millis() as u64)]
+    pub retry_delay: u64,
+    /// Wait until a file didn't change for this many seconds before processing it
+    ///
+    /// For files still being written by the slicer or an upload, e.g. in the queue of
+    /// --daemon. Files still changing after 60 checks fail.
+    #[clap(long, value_name = "SECONDS")]
+    pub wait_stable: Option<f64>,
+    /// Cache the results by file contents in this directory
+    ///
+    /// Unchanged files are rewritten from the cache without collecting their objects again,
+    /// which speeds up repeated runs over the same files.
+    #[clap(long, value_hint=ValueHint::DirPath, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// Keep the analysis of each file next to it until the file is written
+    ///
+    /// Processing a file again after an interruption, e.g. of a huge file on slow storage,
+    /// continues with writing it instead of analysing it again.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub resume: bool,
+    /// Process files again that were already processed by this tool
+    ///
+    /// `outdated` only processes files again that were processed by a different version, or
+    /// with different options recorded by `--provenance`.
+    #[clap(long, value_enum, default_value_t = RefreshMode::Never)]
+    pub refresh: RefreshMode,
+    /// Only scan this many KiB at the start and end of a file to identify the slicer
+    ///
+    /// The whole file is scanned if the slicer isn't found there, 0 always scans the whole file.
+    #[clap(long, default_value_t = DEFAULT_SCAN_WINDOW / 1024, value_name = "KB")]
+    pub scan_window: usize,
+    /// XY offset added to moves of a tool on multi-extruder machines, e.g. 1=-0.25,0.1
+    ///
+    /// Can be given multiple times, once for each tool.
+    #[clap(long, value_name = "TOOL=X,Y")]
+    pub tool_offset: Vec<ToolOffset>,
+    /// Slicer coordinates of the printer's origin, subtracted from the object outlines
+    ///
+    /// E.g. 100,100 for a delta printer centered at 0,0 that was sliced for a 200x200 bed with
+    /// its origin in a corner.
+    #[clap(long, value_name = "X,Y", value_parser = motion::parse_xy, allow_hyphen_values = true)]
+    pub bed_origin: Option<(f64, f64)>,
+    /// XY offset added to the object outlines, e.g. of the carriage printing the file
+    #[clap(long, value_name = "X,Y", value_parser = motion::parse_xy, allow_hyphen_values = true)]
+    pub xy_offset: Option<(f64, f64)>,
+    /// Transform the object outlines like the firmware transforms the moves, e.g. mirror-x=150
+    ///
+    /// One of mirror-x=X, mirror-y=Y, translate=X,Y or rotate=DEG[@X,Y] rotating counterclockwise
+    /// around X,Y. Can be given multiple times, the transforms are applied in order after the
+    /// offsets.
+    #[clap(long, value_name = "TRANSFORM", allow_hyphen_values = true)]
+    pub transform: Vec<Transform>,
+    /// Place the second print of each object in IDEX copy or mirror mode, e.g. mirror-x=150
+    ///
+    /// Takes the same transforms as --transform, applied to the outline of each object. Files
+    /// enabling copy mode with M605 S2 X<offset> are placed by the offset if not given.
+    #[clap(long, value_name = "TRANSFORM", allow_hyphen_values = true)]
+    pub idex_copy: Vec<Transform>,
+    /// Exclude every object printed only with this tool before the print starts, e.g. T1
+    ///
+    /// Can be given multiple times. Objects also printed with other tools are still printed.
+    #[clap(long, value_name = "TOOL", value_parser = motion::parse_tool)]
+    pub cancel_extruder: Vec<u32>,
+    /// Group related objects, e.g. wheels=wheel*,rim?.stl for all copies of the wheel and rims
+    ///
+    /// The patterns match the names in the slicer or the sanitized names, * matches any
+    /// characters and ? a single one. Can be given multiple times, objects belong to the first
+    /// matching group. The groups are listed in a comment after the object definitions.
+    #[clap(long, value_name = "NAME=PATTERN")]
+    pub group: Vec<ObjectGroup>,
+    /// Call this macro with the objects of each group, e.g. DEFINE_OBJECT_GROUP
+    ///
+    /// Called like "DEFINE_OBJECT_GROUP GROUP=wheels OBJECTS=wheel_1,wheel_2" after the object
+    /// definitions.
+    #[clap(long, value_name = "MACRO", requires = "group")]
+    pub group_macro: Option<String>,
+    /// Prefix the names of grouped objects with the name of their group
+    #[clap(long, requires = "group", action=ArgAction::SetTrue)]
+    pub group_prefix: bool,
+    /// Comment marking the end of the print in ideaMaker files, e.g. ";END_OF_PRINT"
+    ///
+    /// Can be given multiple times. ";REMAINING_TIME: 0" is always recognized.
+    #[clap(long, value_name = "COMMENT")]
+    pub end_marker: Vec<String>,
+    /// Fail instead of warning when the outlines of objects overlap
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub strict: bool,
+    /// Markers used for files marking their objects with both comments and M486 commands
+    ///
+    /// With comments, the M486 commands are commented out.
+    #[clap(long, value_enum, default_value_t = MarkerPrecedence::default())]
+    pub marker_precedence: MarkerPrecedence,
+    /// Comment out object commands conflicting with the markers used, e.g. from start G-Code
+    ///
+    /// Exclude object commands are commented out when a file doesn't both define and start its
+    /// objects, as are M486 commands when the slicer comments take precedence over them.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub normalize: bool,
+    /// Warn instead of failing when an object in the output is not started and ended properly
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub lenient_verification: bool,
+    /// Fail for files with warnings, e.g. overlapping objects or renamed objects
+    ///
+    /// The warnings are listed in the JSON report of every file, files with warnings are not
+    /// written when this is given.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub warnings_as_errors: bool,
+    /// Fail for files without any objects instead of copying them unchanged
+    ///
+    /// The tool exits with status 3 when no objects were found.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub require_objects: bool,
+    /// Fail for files that were processed already instead of copying them unchanged
+    ///
+    /// The tool exits with status 7 for them, files processed again with --refresh and files that
+    /// only define objects without starting them don't fail.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub fail_if_processed: bool,
+    /// Add the start and end markers to files that define objects without starting them
+    ///
+    /// By default such files are copied unchanged with a warning.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub complete_partial: bool,
+    /// Add a comment with the Z range and layer count before each object definition
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub object_metadata: bool,
+    /// Add a comment with the outline of each object in Well-Known-Text format
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub wkt: bool,
+    /// Add a comment mapping the name of each object in the slicer to its sanitized name
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub name_comments: bool,
+    /// Leave out the comment marking processed files at the start of the header
+    ///
+    /// For frontends reading metadata from the first comments, processed files are marked by a
+    /// comment after their last line instead.
+    #[clap(long, action=ArgAction::SetTrue, conflicts_with = "header_text")]
+    pub no_header_marker: bool,
+    /// Start the header with this comment instead of the marker
+    #[clap(long, value_name = "TEXT", value_parser = gcode::parse_header_text)]
+    pub header_text: Option<String>,
+    /// Add a `; object NAME layer K/N` comment after each object start
+    ///
+    /// Timelapse triggers and macros can tell from it which object is printed and how far along
+    /// it is.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub object_progress: bool,
+    /// Add M73 progress commands after the elapsed time comments of Cura files
+    ///
+    /// Hosts otherwise show the position in the file as the progress, which the object
+    /// definitions at the start of the file throw off. Files reporting their progress already
+    /// are left as they are.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub progress: bool,
+    /// Record the slicer, the layer filter, the hull mode and hashes of the input and options
+    ///
+    /// With `--refresh outdated` files processed with different options are processed again.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub provenance: bool,
+    /// Shorten longer object names, ending them with a hash of the full name to keep them unique
+    ///
+    /// Some frontends fail to show very long names, e.g. from Cura meshes with deep paths.
+    #[clap(long, value_name = "CHARS", value_parser = clap::value_parser!(u16).range(hulls::MIN_NAME_LENGTH as i64..))]
+    pub max_name_length: Option<u16>,
+    /// Assign skirt and brim extrusions to the nearest object, so they are cancelled with it
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub assign_brims: bool,
+    /// Assign support printed outside of objects to the object it is inside of
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub assign_supports: bool,
+    /// How the wipe tower of multi-material prints is handled
+    #[clap(long, value_enum, default_value_t = WipeTowerMode::Keep)]
+    pub wipe_tower: WipeTowerMode,
+    /// Don't define objects with an outline smaller than this, e.g. purge lines
+    #[clap(long, value_name = "MM2")]
+    pub min_object_area: Option<f64>,
+    /// Don't define objects with a total extrusion length shorter than this
+    #[clap(long, value_name = "MM")]
+    pub min_extrusion_length: Option<f64>,
+    /// Command to run after each successfully processed file
+    ///
+    /// The command is executed by the system shell. The environment variables
+    /// PREPROCESS_CANCELLATION_INPUT, PREPROCESS_CANCELLATION_OUTPUT,
+    /// PREPROCESS_CANCELLATION_OBJECTS, PREPROCESS_CANCELLATION_OBJECT_COUNT and
+    /// PREPROCESS_CANCELLATION_REPORT (JSON) describe the processed file.
+    #[clap(long, value_name = "CMD")]
+    pub post_hook: Option<String>,
+    /// Export tracing spans of each file and its phases to an OpenTelemetry collector
+    ///
+    /// The spans are sent over OTLP/HTTP to URL/v1/traces, e.g. http://localhost:4318, and carry
+    /// the time spent on each file and phase and whether processing failed.
+    #[cfg(feature = "otlp")]
+    #[clap(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+    /// Process the files dropped into QUEUE/incoming until stopped, e.g. as a systemd service
+    ///
+    /// The processed files are written to QUEUE/processed unless an output directory is given,
+    /// the originals of files that fail are moved to QUEUE/failed. SIGTERM and SIGINT stop the
+    /// service once the file being processed is finished. Readiness is reported to systemd with
+    /// sd_notify.
+    #[clap(long, value_name = "QUEUE", value_hint=ValueHint::DirPath, conflicts_with="gcode")]
+    pub daemon: Option<PathBuf>,
+    /// Seconds between scans of the queue directory
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 2.0,
+        requires = "daemon"
+    )]
+    pub poll_interval: f64,
+    /// Read G-Code from stdin and write the processed result to stdout
+    ///
+    /// Intended for use from a Cura post-processing script.
+    #[clap(long, conflicts_with_all=["output_suffix", "output_dir", "output_template", "archive_dir", "daemon", "gcode"], action=ArgAction::SetTrue)]
+    pub stdio: bool,
+    /// Check processed files the way Klipper reads them instead of processing them
+    ///
+    /// Reports exclude object commands Klipper would reject or misinterpret, and fails if any
+    /// would be rejected. The moves are simulated as well, the check fails if an object extrudes
+    /// outside of its POLYGON.
+    #[clap(long, conflicts_with_all=["stdio", "daemon", "upload", "post_hook"], action=ArgAction::SetTrue)]
+    pub verify: bool,
+    /// Print a unified diff of the changes instead of writing the processed files
+    ///
+    /// Lets the inserted and modified lines be reviewed before a file is sent to a printer.
+    #[clap(long, conflicts_with_all=["stdio", "daemon", "verify", "upload", "post_hook"], action=ArgAction::SetTrue)]
+    pub diff: bool,
+    /// Process each file twice and fail if the second pass changes anything
+    ///
+    /// Processing a processed file again has to leave it byte for byte unchanged, otherwise the
+    /// differences are printed as a unified diff. Nothing is written.
+    #[clap(long, conflicts_with_all=["stdio", "daemon", "verify", "diff", "upload", "post_hook"], action=ArgAction::SetTrue)]
+    pub check_idempotent: bool,
+    /// Print only the G-Code of one object, as if every other object was cancelled
+    ///
+    /// Moves of the other objects are skipped, the rest of the file is kept. Useful to check the
+    /// boundaries of an object or to reprint a single failed part.
+    #[clap(long, value_name = "NAME", conflicts_with_all=["stdio", "daemon", "verify", "diff", "upload", "post_hook"])]
+    pub extract_object: Option<String>,
+    /// Compare the results with the Python version of preprocess_cancellation
+    ///
+    /// The command is run through the system shell with the path of a copy of each file
+    /// appended, e.g. "python3 preprocess_cancellation.py". Lists where the defined objects and
+    /// the positions of their markers differ and fails if any file differs.
+    #[clap(long, value_name = "CMD", conflicts_with_all=["stdio", "daemon", "verify", "diff", "extract_object", "upload", "post_hook"])]
+    pub compare_python: Option<String>,
+    /// Print a table with the slicer, objects, added lines, duration and status of every file
+    #[clap(long, conflicts_with_all=["stdio", "daemon", "verify", "diff", "extract_object"], action=ArgAction::SetTrue)]
+    pub report: bool,
+    /// Print a JSON object with the code, file, line and message of the error when the run fails
+    ///
+    /// Printed as the last line on stderr, after the log.
+    #[clap(long, action=ArgAction::SetTrue)]
+    pub json_errors: bool,
+    /// G-code input files
+    ///
+    /// http(s):// and s3:// URLs are downloaded using curl or the aws CLI. Without an
+    /// output directory the processed file is uploaded back to the same location.
+    #[clap(value_hint=ValueHint::FilePath, num_args=1.., required_unless_present_any=["stdio", "daemon"])]
+    pub gcode: Vec<PathBuf>,
+}
+
+impl Cli {
+    pub(crate) fn process_options(&self) -> Result<ProcessOptions, PreprocessError> {
+        let layers = if self.fast { "first" } else { &self.layers };
+        let layer_filter = layers.parse::<LayerFilter>().map_err(|err| {
+            tracing::error!("{}", err);
+            PreprocessError::InvalidLayerFilter
+        })?;
+
+        Ok(ProcessOptions {
+            layer_filter,
+            hull: HullOptions {
+                mode: self.hull_mode,
+                concavity: self.hull_concavity,
+                line_width: self.extrusion_width,
+                grid: self.grid,
+                sample_every: self.sample_every,
+                budget: self
+                    .max_memory
+                    .map(|megabytes| PointBudget::from_megabytes(megabytes.get()))
+                    .unwrap_or_default(),
+            },
+            tool_offsets: self.tool_offset.clone(),
+            xy_offset: self.outline_offset(),
+            transforms: self.transform.clone(),
+            idex_copy: self.idex_copy.clone(),
+            cancel_extruders: self.cancel_extruder.clone(),
+            groups: self.group.clone(),
+            group_macro: self.group_macro.clone(),
+            group_prefix: self.group_prefix,
+            strict: self.strict,
+            object_metadata: self.object_metadata,
+            wkt: self.wkt,
+            name_comments: self.name_comments,
+            header_marker: !self.no_header_marker,
+            header_text: self.header_text.clone(),
+            provenance: self.provenance.then(Provenance::default),
+            max_name_length: self.max_name_length.map(usize::from),
+            assign_brims: self.assign_brims,
+            assign_supports: self.assign_supports,
+            wipe_tower: self.wipe_tower,
+            min_object_area: self.min_object_area,
+            min_extrusion_length: self.min_extrusion_length,
+            read_buffer: self.read_buffer.get() * 1024,
+            write_buffer: self.write_buffer.get() * 1024,
+            retry: self.retry(),
+            cache_dir: self.cache_dir.clone(),
+            resume: self.resume,
+            refresh: self.refresh,
+            scan_window: self.scan_window.saturating_mul(1024),
+            end_markers: self.end_marker.clone(),
+            lenient_verification: self.lenient_verification,
+            warnings_as_errors: self.warnings_as_errors,
+            marker_precedence: self.marker_precedence,
+            normalize: self.normalize,
+            object_progress: self.object_progress,
+            progress: self.progress,
+            analyze_only: false,
+            strip_m486: false,
+            idex: None,
+            require_objects: self.require_objects,
+            fail_if_processed: self.fail_if_processed,
+            complete_partial: self.complete_partial,
+        })
+    }
+
+    fn retry(&self) -> Retry {
+        Retry {
+            attempts: self.retry_attempts,
+            delay: Duration::from_millis(self.retry_delay),
+        }
+    }
+
+    /// Offset of the outlines from the slicer's to the printer's coordinates
+    fn outline_offset(&self) -> (f64, f64) {
+        let (x, y) = self.xy_offset.unwrap_or_default();
+        let (origin_x, origin_y) = self.bed_origin.unwrap_or_default();
+        (x - origin_x, y - origin_y)
+    }
+
+    fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            suffix: self.output_suffix.clone(),
+            suffix_placement: self.suffix_placement,
+            dir: self
+                .output_dir
+                .clone()
+                .or_else(|| self.daemon.as_deref().map(|dir| Queue::new(dir).processed)),
+            template: self.output_template.clone(),
+            plate: None,
+        }
+    }
+}
+
+fn setup_logging(args: &Cli) -> Result<()> {
+    let log_level = match args.verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+
+    // Logging, stdout is reserved for G-Code output in --stdio mode
+    let logging = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(LevelFilter::from_level(log_level));
+    let registry = tracing_subscriber::registry().with(logging);
+
+    // The spans of files and phases are exported whatever the verbosity of the log
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = &args.otlp_endpoint {
+        let spans = telemetry::otlp_layer(endpoint)?.with_filter(LevelFilter::INFO);
+        registry.with(spans).init();
+        return Ok(());
+    }
+
+    registry.init();
+    Ok(())
+}
+
+/// Wait for a local file to be written completely, if asked to
+fn wait_until_stable(args: &Cli, filename: &Path) -> Result<()> {
+    if let Some(seconds) = args.wait_stable {
+        let settle = Duration::try_from_secs_f64(seconds)?;
+        paths::wait_until_stable(&NativeFileSystem, filename, settle)?;
+    }
+    Ok(())
+}
+
+fn process_local(
+    args: &Cli,
+    options: &ProcessOptions,
+    filename: &Path,
+    slic3r_output_name: &Option<PathBuf>,
+) -> Result<ProcessReport> {
+    wait_until_stable(args, filename)?;
+    let report = match slic3r_output_name {
+        // PrusaSlicer expects the temporary file to be rewritten in place,
+        // renames are communicated through a separate file.
+        Some(output_name) => {
+            let report = preprocess::file(filename, &OutputOptions::default(), options)?;
+            if let Some(suffix) = &args.output_suffix {
+                preprocess::slic3r_rename_output(
+                    filename,
+                    output_name,
+                    suffix,
+                    args.suffix_placement,
+                )?;
+            }
+            report
+        }
+        None => match &args.archive_dir {
+            Some(archive_dir) => {
+                preprocess::file_archived(filename, archive_dir, &args.output_options(), options)?
+            }
+            None => preprocess::file(filename, &args.output_options(), options)?,
+        },
+    };
+
+    if let Some(target) = &args.upload {
+        target.upload(&report.output)?;
+    }
+
+    Ok(report)
+}
+
+/// Extract the plates of a 3MF container and process each of them as a G-Code file of its own,
+/// next to the container unless an output directory has been given.
+fn process_container(
+    args: &Cli,
+    options: &ProcessOptions,
+    filename: &Path,
+) -> Result<Vec<(ProcessReport, Duration)>> {
+    wait_until_stable(args, filename)?;
+    let mut container = Container::open(filename)?;
+    let plates = container.plates()?;
+    if plates.is_empty() {
+        return Err(ContainerError::NoPlates(filename.to_string_lossy().to_string()).into());
+    }
+
+    let workdir = tempfile::tempdir()?;
+    let local = workdir.path().join(container::gcode_name(filename));
+    let mut output = args.output_options();
+    if output.template.is_none() {
+        output.template = Some(OutputTemplate::from_str(DEFAULT_PLATE_TEMPLATE)?);
+    }
+    if output.dir.is_none() {
+        output.dir = filename.parent().map(Path::to_path_buf);
+    }
+
+    let mut reports = Vec::new();
+    for plate in plates {
+        let started = Instant::now();
+        tracing::info!("Extracting plate {}", plate.number);
+        container.extract(&plate, &local)?;
+
+        output.plate = Some(plate.number);
+        let mut report = preprocess::file(&local, &output, options)?;
+        report.input = filename.to_path_buf();
+        if let Some(target) = &args.upload {
+            target.upload(&report.output)?;
+        }
+        reports.push((report, started.elapsed()));
+    }
+
+    Ok(reports)
+}
+
+/// Download a remote file, process it and either store it in the output directory or
+/// upload the result back next to the original, unless an upload target has been given.
+fn process_remote(
+    args: &Cli,
+    options: &ProcessOptions,
+    remote: &RemoteFile,
+) -> Result<ProcessReport> {
+    let workdir = tempfile::tempdir()?;
+    let local = workdir.path().join(remote.file_name()?);
+    remote.fetch(&local)?;
+
+    let mut report = preprocess::file(&local, &args.output_options(), options)?;
+    report.input = PathBuf::from(remote.url());
+
+    if let Some(target) = &args.upload {
+        target.upload(&report.output)?;
+    } else if args.output_dir.is_none() {
+        let name = report
+            .output
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let destination = remote.with_file_name(&name)?;
+        destination.upload(&report.output)?;
+        report.output = PathBuf::from(destination.url());
+    }
+
+    Ok(report)
+}
+
+/// Check already processed files without changing them
+fn verify_files(files: &[PathBuf], toolhead: &Toolhead) -> Result<()> {
+    let mut failed = 0;
+    let mut uncovered_files = 0;
+    for filename in files {
+        let file = File::open(filename)?;
+        let verification = klipper::verify(BufReader::new(file))?;
+        let uncovered =
+            coverage::check_coverage(BufReader::new(File::open(filename)?), toolhead.clone())?;
+
+        for issue in &verification.issues {
+            println!("{}: {}", filename.to_string_lossy(), issue);
+        }
+        for entry in &uncovered {
+            println!("{}: {}", filename.to_string_lossy(), entry);
+        }
+        println!(
+            "{}: {} objects, {} errors, {} warnings, {} objects outside of their outline",
+            filename.to_string_lossy(),
+            verification.objects.len(),
+            verification.errors(),
+            verification.issues.len() - verification.errors(),
+            uncovered.len()
+        );
+
+        if verification.errors() > 0 {
+            failed += 1;
+        }
+        if !uncovered.is_empty() {
+            uncovered_files += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(
+            ValidationFailed(format!("Klipper would reject commands in {failed} files")).into(),
+        );
+    }
+    if uncovered_files > 0 {
+        return Err(ValidationFailed(format!(
+            "Objects extrude outside of their outline in {uncovered_files} files"
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Print the changes processing would make to local files without writing them
+fn diff_files(files: &[PathBuf], options: &ProcessOptions) -> Result<()> {
+    let mut stdout = stdout().lock();
+    for filename in files {
+        if RemoteFile::parse(filename).is_some() {
+            anyhow::bail!("--diff only supports local files");
+        }
+
+        let mut processed = tempfile::tempfile()?;
+        preprocess::stream(&mut File::open(filename)?, &mut processed, options)?;
+        processed.rewind()?;
+
+        let name = filename.to_string_lossy();
+        let changes = diff::unified_diff(
+            BufReader::new(File::open(filename)?),
+            BufReader::new(processed),
+            (&name, &format!("{name} (processed)")),
+            &mut stdout,
+        )?;
+        tracing::info!("{name}: {changes} lines added or removed");
+    }
+
+    Ok(())
+}
+
+/// Process local files twice and print the changes of the second pass, which should be none
+fn check_idempotent(files: &[PathBuf], options: &ProcessOptions) -> Result<()> {
+    let mut stdout = stdout().lock();
+    let mut failed = 0;
+    for filename in files {
+        if RemoteFile::parse(filename).is_some() {
+            anyhow::bail!("--check-idempotent only supports local files");
+        }
+
+        let mut first = tempfile::tempfile()?;
+        preprocess::stream(&mut File::open(filename)?, &mut first, options)?;
+        first.rewind()?;
+        let mut second = tempfile::tempfile()?;
+        preprocess::stream(&mut first, &mut second, options)?;
+        first.rewind()?;
+        second.rewind()?;
+
+        let name = filename.to_string_lossy();
+        let changes = diff::unified_diff(
+            BufReader::new(&first),
+            BufReader::new(&second),
+            (
+                &format!("{name} (processed)"),
+                &format!("{name} (processed twice)"),
+            ),
+            &mut stdout,
+        )?;
+        // The diff ignores line endings, the files have to be identical
+        if changes > 0 || !same_content(first, second)? {
+            tracing::error!("{name}: processing again changed {changes} lines");
+            failed += 1;
+        } else {
+            tracing::info!("{name}: processing again left the file unchanged");
+        }
+    }
+
+    if failed > 0 {
+        return Err(ValidationFailed(format!("Processing again changed {failed} files")).into());
+    }
+    Ok(())
+}
+
+fn same_content(mut first: File, mut second: File) -> std::io::Result<bool> {
+    let (mut a, mut b) = (Vec::new(), Vec::new());
+    first.rewind()?;
+    first.read_to_end(&mut a)?;
+    second.rewind()?;
+    second.read_to_end(&mut b)?;
+    Ok(a == b)
+}
+
+/// Process local files with both versions and print where their results differ
+fn compare_files(files: &[PathBuf], cmd: &str, options: &ProcessOptions) -> Result<()> {
+    let mut differing = 0;
+    for filename in files {
+        if RemoteFile::parse(filename).is_some() {
+            anyhow::bail!("--compare-python only supports local files");
+        }
+
+        let mut processed = tempfile::tempfile()?;
+        preprocess::stream(&mut File::open(filename)?, &mut processed, options)?;
+        processed.rewind()?;
+        let ours = compare::semantics(
+            BufReader::new(File::open(filename)?),
+            BufReader::new(processed),
+        )?;
+        let theirs = compare::semantics(
+            BufReader::new(File::open(filename)?),
+            BufReader::new(compare::run_python(cmd, filename)?),
+        )?;
+
+        let name = filename.to_string_lossy();
+        let differences = compare::compare(&ours, &theirs);
+        if differences.is_empty() {
+            tracing::info!("{name}: same objects and markers as the Python version");
+            continue;
+        }
+        differing += 1;
+        for difference in differences {
+            println!("{name}: {difference}");
+        }
+    }
+
+    if differing > 0 {
+        return Err(ValidationFailed(format!(
+            "{differing} of {} files differ from the Python version",
+            files.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Print the G-Code of a single object of a local file, processing the file first if needed
+fn extract_file(files: &[PathBuf], name: &str, options: &ProcessOptions) -> Result<()> {
+    let [filename] = files else {
+        anyhow::bail!("--extract-object takes a single file");
+    };
+    if RemoteFile::parse(filename).is_some() {
+        anyhow::bail!("--extract-object only supports local files");
+    }
+
+    let mut processed = tempfile::tempfile()?;
+    preprocess::stream(&mut File::open(filename)?, &mut processed, options)?;
+    processed.rewind()?;
+
+    let verification = klipper::verify(BufReader::new(&processed))?;
+    if !verification.objects.contains_key(&name.to_uppercase()) {
+        let names = verification.objects.keys().sorted().join(", ");
+        anyhow::bail!("Object {name} not found, the file defines {names}");
+    }
+    processed.rewind()?;
+
+    let starts = extract::extract_object(BufReader::new(processed), name, &mut stdout().lock())?;
+    tracing::info!("Extracted {starts} parts of {name}");
+
+    Ok(())
+}
+
+/// Machine-readable description of the error a run failed with
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    code: &'static str,
+    file: Option<PathBuf>,
+    line: Option<usize>,
+    message: String,
+}
+
+impl From<&anyhow::Error> for ErrorReport {
+    fn from(e: &anyhow::Error) -> Self {
+        let error = e.downcast_ref::<PreprocessError>();
+        let file = e
+            .downcast_ref::<FailedFile>()
+            .map(|failed| failed.file.clone());
+        // The failed file is the outermost context, it is reported on its own
+        let message = e
+            .chain()
+            .skip(usize::from(file.is_some()))
+            .map(ToString::to_string)
+            .join(": ");
+        Self {
+            code: error.map_or("error", PreprocessError::code),
+            file,
+            line: error.and_then(PreprocessError::line),
+            message,
+        }
+    }
+}
+
+/// Run the command line tool
+pub fn main() -> ExitCode {
+    let args = Cli::parse();
+    let result = run(&args);
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown();
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            if args.json_errors {
+                let report = ErrorReport::from(&e);
+                eprintln!("{}", serde_json::to_string(&report).unwrap_or_default());
+            }
+            ExitStatus::from_error(&e).into()
+        }
+    }
+}
+
+/// Process a file of the daemon's queue, the output is written to the processed directory
+fn process_queued(
+    args: &Cli,
+    options: &ProcessOptions,
+    filename: &Path,
+) -> Result<Vec<ProcessReport>> {
+    let reports = match container::is_container(filename) {
+        true => process_container(args, options, filename)?
+            .into_iter()
+            .map(|(report, _duration)| report)
+            .collect(),
+        false => vec![process_local(args, options, filename, &None)?],
+    };
+
+    if let Some(cmd) = &args.post_hook {
+        for report in &reports {
+            hooks::post_process(cmd, report)?;
+        }
+    }
+    Ok(reports)
+}
+
+/// Span covering the processing of a file, the phases are traced as spans within it
+fn file_span(file: &str) -> Span {
+    tracing::info_span!(
+        "file",
+        file,
+        slicer = field::Empty,
+        objects = field::Empty,
+        error = field::Empty,
+        otel.status_code = field::Empty,
+    )
+}
+
+fn record_objects<'a>(span: &Span, reports: impl IntoIterator<Item = &'a ProcessReport>) {
+    let mut objects = 0;
+    for report in reports {
+        if let Some(slicer) = &report.slicer {
+            span.record("slicer", slicer.as_str());
+        }
+        objects += report.objects.len();
+    }
+    span.record("objects", objects);
+}
+
+fn record_error(span: &Span, error: &impl std::fmt::Display) {
+    span.record("error", error.to_string());
+    span.record("otel.status_code", "ERROR");
+}
+
+/// Name of a processed file in the batch summary, plates are listed by their number
+fn batch_label(filename: &Path, report: &ProcessReport) -> String {
+    match report.plate {
+        Some(plate) => format!("{} plate {}", filename.to_string_lossy(), plate),
+        None => filename.to_string_lossy().to_string(),
+    }
+}
+
+fn run(args: &Cli) -> Result<()> {
+    setup_logging(args)?;
+
+    if args.verify {
+        let toolhead = Toolhead::new(&args.tool_offset)
+            .with_xy_offset(args.outline_offset())
+            .with_transform(Affine::new(&args.transform));
+        return verify_files(&args.gcode, &toolhead);
+    }
+
+    let options = args.process_options()?;
+
+    if args.diff {
+        return diff_files(&args.gcode, &options);
+    }
+    if args.check_idempotent {
+        return check_idempotent(&args.gcode, &options);
+    }
+    if let Some(name) = &args.extract_object {
+        return extract_file(&args.gcode, name, &options);
+    }
+
+    if let Some(cmd) = &args.compare_python {
+        return compare_files(&args.gcode, cmd, &options);
+    }
+
+    if args.stdio {
+        let span = file_span("stdin");
+        let _entered = span.enter();
+        let report = preprocess::stream(&mut stdin().lock(), &mut stdout().lock(), &options)
+            .inspect(|report| record_objects(&span, [report]))
+            .inspect_err(|e| {
+                record_error(&span, e);
+                tracing::error!("Error processing G-Code from stdin: {}", e)
+            })?;
+
+        if let Some(cmd) = &args.post_hook {
+            hooks::post_process(cmd, &report)?;
+        }
+
+        return Ok(());
+    }
+
+    if let Some(dir) = &args.daemon {
+        let stop = daemon::shutdown_flag()?;
+        let interval = Duration::try_from_secs_f64(args.poll_interval)?;
+        let queue = Queue {
+            retry: options.retry,
+            ..Queue::new(dir)
+        };
+        daemon::run(&queue, interval, &stop, |filename| {
+            let span = file_span(&filename.to_string_lossy());
+            let _entered = span.enter();
+            process_queued(args, &options, filename)
+                .inspect(|reports| record_objects(&span, reports))
+                .inspect_err(|e| record_error(&span, e))
+                .map(|_reports| ())
+        })?;
+        return Ok(());
+    }
+
+    let slic3r_output_name = preprocess::slic3r_output_name();
+    if slic3r_output_name.is_some() && args.output_dir.is_some() {
+        tracing::warn!("Running as a PrusaSlicer post-processing script, ignoring --output-dir");
+    }
+    if slic3r_output_name.is_some() && args.archive_dir.is_some() {
+        tracing::warn!("Running as a PrusaSlicer post-processing script, ignoring --archive-dir");
+    }
+
+    let mut summary = Vec::new();
+    for (processed, filename) in args.gcode.iter().enumerate() {
+        tracing::debug!("Processing GCode file: {}", filename.to_string_lossy());
+
+        let span = file_span(&filename.to_string_lossy());
+        let entered = span.enter();
+        let started = Instant::now();
+        let result = match RemoteFile::parse(filename) {
+            Some(remote) => process_remote(args, &options, &remote)
+                .map(|report| vec![(report, started.elapsed())]),
+            None if container::is_container(filename) => {
+                process_container(args, &options, filename)
+            }
+            None => process_local(args, &options, filename, &slic3r_output_name)
+                .map(|report| vec![(report, started.elapsed())]),
+        };
+        match &result {
+            Ok(reports) => record_objects(&span, reports.iter().map(|(report, _)| report)),
+            Err(e) => record_error(&span, e),
+        }
+        drop(entered);
+
+        match result {
+            Ok(reports) => {
+                tracing::info!("Successfully processed {}", filename.to_string_lossy());
+
+                for (report, duration) in reports {
+                    if let Some(cmd) = &args.post_hook {
+                        if let Err(e) = hooks::post_process(cmd, &report) {
+                            tracing::error!(
+                                "Error running post-processing hook for {}: {}",
+                                &filename.to_string_lossy(),
+                                e
+                            );
+                            if args.report {
+                                summary.push(BatchEntry {
+                                    file: batch_label(filename, &report),
+                                    result: Err(e.to_string()),
+                                    duration,
+                                });
+                                print!("{}", report::summary_table(&summary));
+                            }
+                            return Err(anyhow::Error::new(e).context(FailedFile {
+                                file: filename.clone(),
+                                processed,
+                            }));
+                        }
+                    }
+                    if args.report {
+                        summary.push(BatchEntry {
+                            file: batch_label(filename, &report),
+                            result: Ok(report),
+                            duration,
+                        });
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Error processing file {}: {}",
+                    &filename.to_string_lossy(),
+                    e
+                );
+                if args.report {
+                    summary.push(BatchEntry {
+                        file: filename.to_string_lossy().to_string(),
+                        result: Err(e.to_string()),
+                        duration: started.elapsed(),
+                    });
+                    print!("{}", report::summary_table(&summary));
+                }
+                return Err(e.context(FailedFile {
+                    file: filename.clone(),
+                    processed,
+                }));
+            }
+        }
+    }
+
+    if args.report {
+        print!("{}", report::summary_table(&summary));
+    }
+
+    Ok(())
+}
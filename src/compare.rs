@@ -0,0 +1,351 @@
+use crate::hooks::shell;
+use crate::klipper::{self, split_command, ObjectDefinition};
+use crate::lines::LineReader;
+use itertools::{EitherOrBoth, Itertools};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Seek};
+use std::path::Path;
+
+/// Differences of centers and outlines in mm that are still the same object, both versions
+/// round coordinates differently
+pub(crate) const TOLERANCE: f64 = 0.05;
+/// Marker differences listed per file, the rest are only counted
+const MAX_MARKER_DIFFERENCES: usize = 10;
+
+/// An object starting or ending before a command of the original file
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Marker {
+    /// Commands of the original file before the marker, comments and blank lines don't count
+    pub position: usize,
+    pub start: bool,
+    pub name: String,
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.start { "START" } else { "END" };
+        write!(f, "{kind} {} after command {}", self.name, self.position)
+    }
+}
+
+/// What Klipper sees of a processed file: the defined objects and where each starts and ends
+#[derive(Debug, Default)]
+pub(crate) struct Semantics {
+    pub objects: HashMap<String, ObjectDefinition>,
+    pub markers: Vec<Marker>,
+}
+
+/// Read the objects and markers of a processed file.
+///
+/// Lines of the original file are matched in order, the processed file may comment them out.
+/// Markers are located by the commands of the original file before them, so versions that place
+/// them before or after a comment are treated the same.
+pub(crate) fn semantics(
+    original: impl BufRead,
+    mut processed: impl BufRead + Seek,
+) -> io::Result<Semantics> {
+    let objects = klipper::verify(&mut processed)?.objects;
+    processed.rewind()?;
+
+    let mut original = LineReader::untruncated(original);
+    let mut next = original.next_line()?.map(|(_, line)| line.to_string());
+    let mut position = 0;
+    let mut markers = Vec::new();
+
+    let mut lines = LineReader::untruncated(processed);
+    while let Some((_, line)) = lines.next_line()? {
+        if let Some(expected) = &next {
+            let command = expected.trim();
+            let commented = !command.is_empty()
+                && line.trim_start().starts_with(';')
+                && line.ends_with(command);
+            if line == expected || commented {
+                if split_command(expected).is_some() {
+                    position += 1;
+                }
+                next = original.next_line()?.map(|(_, line)| line.to_string());
+                continue;
+            }
+        }
+
+        let Some((command, args)) = split_command(line) else {
+            continue;
+        };
+        let start = match command.to_uppercase().as_str() {
+            "EXCLUDE_OBJECT_START" => true,
+            "EXCLUDE_OBJECT_END" => false,
+            _ => continue,
+        };
+        let name = klipper::extended_params(args)
+            .ok()
+            .and_then(|mut params| params.remove("NAME"))
+            .unwrap_or_default()
+            .to_uppercase();
+        markers.push(Marker {
+            position,
+            start,
+            name,
+        });
+    }
+
+    Ok(Semantics { objects, markers })
+}
+
+/// Extents of an outline as min x, min y, max x, max y
+fn extents(polygon: &[Vec<f64>]) -> Option<[f64; 4]> {
+    let (xs, ys): (Vec<f64>, Vec<f64>) = polygon.iter().map(|point| (point[0], point[1])).unzip();
+    let min = |values: &[f64]| values.iter().copied().reduce(f64::min);
+    let max = |values: &[f64]| values.iter().copied().reduce(f64::max);
+    Some([min(&xs)?, min(&ys)?, max(&xs)?, max(&ys)?])
+}
+
+fn differs(ours: &[f64], theirs: &[f64]) -> bool {
+    ours.len() != theirs.len()
+        || ours
+            .iter()
+            .zip(theirs)
+            .any(|(ours, theirs)| (ours - theirs).abs() > TOLERANCE)
+}
+
+fn format_values(values: &[f64]) -> String {
+    values.iter().map(|value| format!("{value:.3}")).join(",")
+}
+
+/// Describe how the result of the Python implementation differs from ours, empty if both
+/// define the same objects and start and end them at the same commands
+pub(crate) fn compare(ours: &Semantics, theirs: &Semantics) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    for name in ours
+        .objects
+        .keys()
+        .chain(theirs.objects.keys())
+        .unique()
+        .sorted()
+    {
+        let (ours, theirs) = match (ours.objects.get(name), theirs.objects.get(name)) {
+            (Some(ours), Some(theirs)) => (ours, theirs),
+            (Some(_), None) => {
+                differences.push(format!("{name} is only defined by this version"));
+                continue;
+            }
+            (None, _) => {
+                differences.push(format!("{name} is only defined by the Python version"));
+                continue;
+            }
+        };
+
+        if let (Some(center), Some(python)) = (&ours.center, &theirs.center) {
+            if differs(center, python) {
+                differences.push(format!(
+                    "{name}: CENTER={} instead of {}",
+                    format_values(center),
+                    format_values(python)
+                ));
+            }
+        }
+        let outline = ours.polygon.as_deref().and_then(extents);
+        let python = theirs.polygon.as_deref().and_then(extents);
+        if let (Some(outline), Some(python)) = (outline, python) {
+            if differs(&outline, &python) {
+                differences.push(format!(
+                    "{name}: outline spans {} instead of {}",
+                    format_values(&outline),
+                    format_values(&python)
+                ));
+            }
+        }
+    }
+
+    let markers: Vec<String> = ours
+        .markers
+        .iter()
+        .zip_longest(&theirs.markers)
+        .filter_map(|markers| match markers {
+            EitherOrBoth::Both(ours, theirs) if ours == theirs => None,
+            EitherOrBoth::Both(ours, theirs) => Some(format!("{ours} instead of {theirs}")),
+            EitherOrBoth::Left(ours) => Some(format!("{ours} is only written by this version")),
+            EitherOrBoth::Right(theirs) => {
+                Some(format!("{theirs} is only written by the Python version"))
+            }
+        })
+        .collect();
+    let hidden = markers.len().saturating_sub(MAX_MARKER_DIFFERENCES);
+    differences.extend(markers.into_iter().take(MAX_MARKER_DIFFERENCES));
+    if hidden > 0 {
+        differences.push(format!("{hidden} more markers differ"));
+    }
+
+    differences
+}
+
+/// Process a copy of the file with the Python implementation, which modifies it in place.
+///
+/// The path of the copy is appended to the command, e.g. `python3 preprocess_cancellation.py`.
+pub(crate) fn run_python(cmd: &str, filename: &Path) -> anyhow::Result<File> {
+    let dir = tempfile::tempdir()?;
+    let copy = dir
+        .path()
+        .join(filename.file_name().unwrap_or("input.gcode".as_ref()));
+    fs::copy(filename, &copy)?;
+
+    let output = shell(&format!("{cmd} \"{}\"", copy.display()))
+        .output()
+        .map_err(|err| anyhow::anyhow!("Error running the Python version: {err}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "The Python version exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // Keep the file open after the directory is removed, where the platform allows it
+    let mut processed = tempfile::tempfile()?;
+    io::copy(&mut File::open(&copy)?, &mut processed)?;
+    processed.rewind()?;
+    Ok(processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocess::{self, ProcessOptions};
+    use crate::snapshots::{fixtures, fixtures_dir};
+    use std::io::{BufReader, Cursor};
+
+    const ORIGINAL: &str = "; generated by PrusaSlicer\nG28\n; printing object a\nG1 X1 E1\n\
+        ; stop printing object a\nG1 Z1\n";
+
+    fn read(processed: &str) -> Semantics {
+        semantics(ORIGINAL.as_bytes(), Cursor::new(processed)).unwrap()
+    }
+
+    #[test]
+    fn test_semantics() {
+        let semantics = read(
+            "; generated by PrusaSlicer\n\nEXCLUDE_OBJECT_DEFINE NAME=a CENTER=1,0 \
+            POLYGON=[[0,0],[2,0],[2,1]]\nG28\n; printing object a\nEXCLUDE_OBJECT_START NAME=a\n\
+            G1 X1 E1\nEXCLUDE_OBJECT_END NAME=a\n; stop printing object a\nG1 Z1\n",
+        );
+        assert_eq!(
+            semantics.markers,
+            [
+                Marker {
+                    position: 1,
+                    start: true,
+                    name: "A".into()
+                },
+                Marker {
+                    position: 2,
+                    start: false,
+                    name: "A".into()
+                }
+            ]
+        );
+        assert_eq!(semantics.objects["A"].center, Some(vec![1.0, 0.0]));
+    }
+
+    #[test]
+    fn test_compare() {
+        let ours = read(
+            "EXCLUDE_OBJECT_DEFINE NAME=a CENTER=1,0 POLYGON=[[0,0],[2,0],[2,1]]\n\
+            EXCLUDE_OBJECT_DEFINE NAME=b CENTER=5,5\n{ORIGINAL}"
+                .replace("{ORIGINAL}", ORIGINAL)
+                .replace(
+                    "; printing object a\n",
+                    "EXCLUDE_OBJECT_START NAME=a\n; printing object a\n",
+                )
+                .replace(
+                    "; stop printing object a\n",
+                    "EXCLUDE_OBJECT_END NAME=a\n; stop printing object a\n",
+                )
+                .as_str(),
+        );
+        // The markers after the comments are at the same commands
+        let python = read(
+            "EXCLUDE_OBJECT_DEFINE NAME=A CENTER=1.01,0 POLYGON=[[0,0],[2,0],[2,1.5]]\n{ORIGINAL}"
+                .replace("{ORIGINAL}", ORIGINAL)
+                .replace(
+                    "; printing object a\n",
+                    "; printing object a\nEXCLUDE_OBJECT_START NAME=A\n",
+                )
+                .replace(
+                    "; stop printing object a\n",
+                    "; stop printing object a\nEXCLUDE_OBJECT_END NAME=A\n",
+                )
+                .as_str(),
+        );
+
+        assert_eq!(
+            compare(&ours, &python),
+            [
+                "A: outline spans 0.000,0.000,2.000,1.000 instead of 0.000,0.000,2.000,1.500",
+                "B is only defined by this version"
+            ]
+        );
+        assert!(compare(&python, &python).is_empty());
+    }
+
+    #[test]
+    fn test_compare_markers() {
+        let ours = read(
+            &ORIGINAL
+                .replace("G28\n", "G28\nEXCLUDE_OBJECT_START NAME=a\n")
+                .replace("G1 Z1\n", "EXCLUDE_OBJECT_END NAME=a\nG1 Z1\n"),
+        );
+        let python = read(
+            &ORIGINAL
+                .replace("G28\n", "G28\nEXCLUDE_OBJECT_START NAME=a\n")
+                .replace("G1 Z1\n", "G1 Z1\nEXCLUDE_OBJECT_END NAME=a\n"),
+        );
+        assert_eq!(
+            compare(&ours, &python),
+            ["END A after command 2 instead of END A after command 3"]
+        );
+        assert_eq!(
+            compare(&ours, &read(ORIGINAL)),
+            [
+                "A is only defined by this version",
+                "START A after command 1 is only written by this version",
+                "END A after command 2 is only written by this version"
+            ]
+        );
+    }
+
+    /// Only runs with the command of the Python version in `PREPROCESS_CANCELLATION_PYTHON`
+    #[test]
+    fn test_fixtures_match_python() {
+        let Ok(cmd) = std::env::var("PREPROCESS_CANCELLATION_PYTHON") else {
+            return;
+        };
+
+        let mut differences = Vec::new();
+        for fixture in fixtures(&fixtures_dir()) {
+            let mut processed = tempfile::tempfile().unwrap();
+            let result = preprocess::stream(
+                &mut File::open(&fixture).unwrap(),
+                &mut processed,
+                &ProcessOptions::default(),
+            );
+            if result.is_err() {
+                continue;
+            }
+            processed.rewind().unwrap();
+
+            let original = || BufReader::new(File::open(&fixture).unwrap());
+            let ours = semantics(original(), BufReader::new(processed)).unwrap();
+            let python = run_python(&cmd, &fixture).unwrap();
+            let theirs = semantics(original(), BufReader::new(python)).unwrap();
+            differences.extend(
+                compare(&ours, &theirs)
+                    .into_iter()
+                    .map(|difference| format!("{}: {difference}", fixture.display())),
+            );
+        }
+
+        assert!(differences.is_empty(), "{}", differences.join("\n"));
+    }
+}
@@ -0,0 +1,473 @@
+//! Klipper's handling of the exclude object commands, as an oracle for the generated lines.
+//!
+//! Follows `klippy/gcode.py` and `klippy/extras/exclude_object.py` of Klipper v0.12.0 statement
+//! by statement, including what Klipper accepts silently but handles differently than intended,
+//! e.g. objects started without a definition. [`crate::klipper`] checks the output of the tool
+//! and is more forgiving. When Klipper changes how it reads these commands, this module is the
+//! one place to update and the tests below show which generated lines no longer conform.
+
+use crate::preprocess::{self, ProcessOptions};
+use crate::snapshots::{fixtures, fixtures_dir};
+use crate::synthetic::{Dialect, SyntheticGcode};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+
+/// `GCodeDispatch.args_r`, splits a line into the letters and values of its parameters
+static ARGS_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Z_]+|[A-Z*/]").unwrap());
+/// `GCodeDispatch.extended_r`, the arguments of an extended command
+static EXTENDED_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^\s*(?:N[0-9]+\s*)?(?P<cmd>[a-zA-Z_][a-zA-Z0-9_]+)(?:\s+|$)(?P<args>[^#*;]*?)\s*(?:[#*;].*)?$",
+    )
+    .unwrap()
+});
+
+/// The state of Klipper's `exclude_object` module after running a file
+#[derive(Debug, Default)]
+pub(crate) struct Klipper {
+    /// Definitions sorted by name, as reported in the printer status
+    pub objects: Vec<Map<String, Value>>,
+    pub current_object: Option<String>,
+    /// Messages shown in the console, numbered by line starting at 1
+    pub responses: Vec<(usize, String)>,
+    /// Commands that stop the print
+    pub errors: Vec<(usize, String)>,
+    /// Objects started without being defined first
+    pub implicit: Vec<String>,
+    started: bool,
+}
+
+impl Klipper {
+    /// Run every line of a file
+    pub fn run(gcode: &str) -> Self {
+        let mut klipper = Self::default();
+        for (number, line) in gcode.lines().enumerate() {
+            let line_no = number + 1;
+            if let Err(err) = klipper.line(line_no, line) {
+                klipper.errors.push((line_no, err));
+            }
+        }
+        klipper
+    }
+
+    /// `GCodeDispatch._process_commands` for a single line
+    fn line(&mut self, line_no: usize, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        let command = command_name(line);
+        if !matches!(
+            command.as_str(),
+            "EXCLUDE_OBJECT_DEFINE" | "EXCLUDE_OBJECT_START" | "EXCLUDE_OBJECT_END"
+        ) {
+            return Ok(());
+        }
+
+        let params = extended_params(line)?;
+        let name = params.get("NAME").map(|name| name.to_uppercase());
+        match command.as_str() {
+            "EXCLUDE_OBJECT_DEFINE" => self.define(line_no, params),
+            "EXCLUDE_OBJECT_START" => {
+                let name = name.ok_or(format!("Error on '{line}': missing NAME"))?;
+                if !self.objects.iter().any(|object| object["name"] == name) {
+                    self.implicit.push(name.clone());
+                    self.add_object_definition(Map::from_iter([(
+                        "name".into(),
+                        name.clone().into(),
+                    )]));
+                }
+                self.current_object = Some(name);
+                self.started = true;
+                Ok(())
+            }
+            _ => {
+                if self.current_object.is_none() && self.started {
+                    self.respond(
+                        line_no,
+                        "EXCLUDE_OBJECT_END called, but no object is currently active".into(),
+                    );
+                    return Ok(());
+                }
+                if let Some(name) = name.filter(|name| Some(name) != self.current_object.as_ref()) {
+                    let current = self.current_object.as_deref().unwrap_or("None");
+                    self.respond(
+                        line_no,
+                        format!(
+                            "EXCLUDE_OBJECT_END NAME={name} does not match the current object NAME={current}"
+                        ),
+                    );
+                }
+                self.current_object = None;
+                Ok(())
+            }
+        }
+    }
+
+    fn define(
+        &mut self,
+        line_no: usize,
+        mut params: HashMap<String, String>,
+    ) -> Result<(), String> {
+        if params.get("RESET").is_some_and(|reset| !reset.is_empty()) {
+            *self = Self {
+                responses: std::mem::take(&mut self.responses),
+                errors: std::mem::take(&mut self.errors),
+                ..Self::default()
+            };
+            return Ok(());
+        }
+        let name = params.remove("NAME").unwrap_or_default().to_uppercase();
+        if name.is_empty() {
+            // Lists the known objects instead
+            self.respond(line_no, format!("Known objects: {:?}", self.objects));
+            return Ok(());
+        }
+
+        let center = params.remove("CENTER");
+        let polygon = params.remove("POLYGON");
+        let mut object: Map<String, Value> = params
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect();
+        object.insert("name".into(), name.into());
+        // Python's json module also reads NaN and Infinity, which the web interfaces can't draw
+        if let Some(center) = center {
+            let center = serde_json::from_str(&format!("[{center}]"))
+                .map_err(|err| format!("Internal error on command: CENTER: {err}"))?;
+            object.insert("center".into(), center);
+        }
+        if let Some(polygon) = polygon {
+            let polygon = serde_json::from_str(&polygon)
+                .map_err(|err| format!("Internal error on command: POLYGON: {err}"))?;
+            object.insert("polygon".into(), polygon);
+        }
+        self.add_object_definition(object);
+        Ok(())
+    }
+
+    /// Duplicate names are kept, Klipper doesn't check for them
+    fn add_object_definition(&mut self, object: Map<String, Value>) {
+        self.objects.push(object);
+        self.objects
+            .sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    }
+
+    fn respond(&mut self, line_no: usize, message: String) {
+        self.responses.push((line_no, message));
+    }
+}
+
+/// The command a line is dispatched to, parameters split off with `args_r` like Klipper does
+/// before it knows whether the command is extended
+fn command_name(line: &str) -> String {
+    let line = line.split(';').next().unwrap_or_default().to_uppercase();
+    let matches: Vec<_> = ARGS_RE.find_iter(&line).collect();
+    // The text between the nth and the next parameter letters
+    let value = |n: usize| {
+        let end = matches.get(n + 1).map_or(line.len(), |next| next.start());
+        line[matches[n].end()..end].trim().to_string()
+    };
+    match matches.first().map(|first| first.as_str()) {
+        None => String::new(),
+        Some("N") if matches.len() >= 2 => format!("{}{}", matches[1].as_str(), value(1)),
+        Some("N") => String::new(),
+        Some(command) => format!("{command}{}", value(0)),
+    }
+}
+
+/// `GCodeDispatch._get_extended_params`
+fn extended_params(line: &str) -> Result<HashMap<String, String>, String> {
+    let malformed = || format!("Malformed command '{line}'");
+    let args = EXTENDED_RE
+        .captures(line)
+        .and_then(|captures| captures.name("args"))
+        .ok_or_else(malformed)?;
+    shlex_split(args.as_str())
+        .ok_or_else(malformed)?
+        .into_iter()
+        .map(|word| {
+            let (key, value) = word.split_once('=').ok_or_else(malformed)?;
+            Ok((key.to_uppercase(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Python's `shlex.split` in POSIX mode: backslashes only escape quotes and backslashes inside
+/// double quotes, `None` for unbalanced quotes
+fn shlex_split(input: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\r' | '\n' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\') => word.push(c),
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    Some(words)
+}
+
+/// A list of two numbers the web interfaces can draw
+fn is_point(value: &Value) -> bool {
+    value
+        .as_array()
+        .is_some_and(|values| values.len() == 2 && values.iter().all(Value::is_number))
+}
+
+/// Everything Klipper does differently from what the generated lines intend: commands it
+/// rejects or answers with a message, objects it defines implicitly or twice, definitions the
+/// web interfaces can't draw and objects still printing at the end
+pub(crate) fn violations(gcode: &str) -> Vec<String> {
+    let klipper = Klipper::run(gcode);
+    let mut violations: Vec<String> = klipper
+        .errors
+        .iter()
+        .chain(&klipper.responses)
+        .map(|(line, message)| format!("line {line}: {message}"))
+        .collect();
+    violations.extend(
+        klipper
+            .implicit
+            .iter()
+            .map(|name| format!("{name} is started without being defined")),
+    );
+
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    for object in &klipper.objects {
+        let name = object["name"].as_str().unwrap_or_default();
+        *names.entry(name).or_default() += 1;
+        if object.get("center").is_some_and(|center| !is_point(center)) {
+            violations.push(format!("CENTER of {name} is not a point"));
+        }
+        let polygon = object.get("polygon").map(|polygon| {
+            polygon
+                .as_array()
+                .is_some_and(|points| points.len() >= 3 && points.iter().all(is_point))
+        });
+        if polygon == Some(false) {
+            violations.push(format!(
+                "POLYGON of {name} is not a list of 3 or more points"
+            ));
+        }
+    }
+    violations.extend(
+        names
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, count)| format!("{name} is defined {count} times")),
+    );
+    if let Some(current) = klipper.current_object {
+        violations.push(format!("{current} is still printing at the end"));
+    }
+
+    violations
+}
+
+/// Option sets changing what is written next to the definitions and how objects are named
+fn option_sets() -> Vec<(&'static str, ProcessOptions)> {
+    vec![
+        ("default", ProcessOptions::default()),
+        (
+            "comments",
+            ProcessOptions {
+                wkt: true,
+                object_metadata: true,
+                name_comments: true,
+                header_text: Some("; cancel objects".into()),
+                provenance: Some(Default::default()),
+                progress: true,
+                object_progress: true,
+                max_name_length: Some(16),
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+/// Process the file and check the output against the oracle, `None` if processing failed
+fn check(gcode: &[u8], options: &ProcessOptions) -> Option<Vec<String>> {
+    let mut output = Vec::new();
+    let report = preprocess::stream(&mut &gcode[..], &mut output, options).ok()?;
+    let output = String::from_utf8_lossy(&output);
+
+    let mut violations = violations(&output);
+    let klipper = Klipper::run(&output);
+    for object in &report.objects {
+        let name = object.name.to_uppercase();
+        if !klipper
+            .objects
+            .iter()
+            .any(|defined| defined["name"] == name)
+        {
+            violations.push(format!("{} is read as a different name", object.name));
+        }
+    }
+    Some(violations)
+}
+
+#[test]
+fn test_command_name() {
+    assert_eq!(
+        command_name("EXCLUDE_OBJECT_START NAME=a"),
+        "EXCLUDE_OBJECT_START"
+    );
+    assert_eq!(
+        command_name("  n12 exclude_object_end"),
+        "EXCLUDE_OBJECT_END"
+    );
+    assert_eq!(command_name("; EXCLUDE_OBJECT_START NAME=a"), "");
+    assert_eq!(command_name("G1 X1 Y2"), "G1");
+    assert_eq!(command_name("M486 S1"), "M486");
+}
+
+#[test]
+fn test_extended_params() {
+    let params = extended_params(r#"EXCLUDE_OBJECT_DEFINE name='a b' X="1\$" ; c"#).unwrap();
+    assert_eq!(params["NAME"], "a b");
+    // Unlike a shell, Python keeps the backslash
+    assert_eq!(params["X"], r"1\$");
+
+    // Comments end the arguments, even inside of quotes
+    assert!(extended_params("EXCLUDE_OBJECT_START NAME='a;b'").is_err());
+    assert_eq!(
+        extended_params("EXCLUDE_OBJECT_START NAME=a#b").unwrap()["NAME"],
+        "a"
+    );
+    assert!(extended_params("EXCLUDE_OBJECT_START NAME").is_err());
+}
+
+#[test]
+fn test_klipper() {
+    let klipper = Klipper::run(
+        "EXCLUDE_OBJECT_DEFINE NAME=b CENTER=1,2 POLYGON=[[0,0],[1,0],[1,1]] EXTRA=x\n\
+        EXCLUDE_OBJECT_DEFINE NAME=a\n\
+        EXCLUDE_OBJECT_START NAME=c\n\
+        EXCLUDE_OBJECT_END NAME=a\n\
+        EXCLUDE_OBJECT_END\n\
+        EXCLUDE_OBJECT_START\n\
+        EXCLUDE_OBJECT_DEFINE NAME=d POLYGON=[[0,0]\n\
+        EXCLUDE_OBJECT_DEFINE\n",
+    );
+
+    let names: Vec<&str> = klipper
+        .objects
+        .iter()
+        .map(|object| object["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["A", "B", "C"]);
+    assert_eq!(klipper.objects[1]["center"], serde_json::json!([1, 2]));
+    assert_eq!(klipper.objects[1]["EXTRA"], "x");
+    assert_eq!(klipper.implicit, ["C"]);
+    assert_eq!(
+        klipper
+            .responses
+            .iter()
+            .map(|(line, _)| *line)
+            .collect::<Vec<_>>(),
+        [4, 5, 8]
+    );
+    assert_eq!(
+        klipper
+            .errors
+            .iter()
+            .map(|(line, _)| *line)
+            .collect::<Vec<_>>(),
+        [6, 7]
+    );
+
+    let klipper = Klipper::run("EXCLUDE_OBJECT_DEFINE NAME=a\nEXCLUDE_OBJECT_DEFINE RESET=1\n");
+    assert!(klipper.objects.is_empty());
+}
+
+#[test]
+fn test_violations() {
+    assert_eq!(
+        violations(
+            "EXCLUDE_OBJECT_DEFINE NAME=a CENTER=1 POLYGON=[[0,0],[1,1]]\n\
+            EXCLUDE_OBJECT_DEFINE NAME=A\n\
+            EXCLUDE_OBJECT_START NAME=b\n"
+        ),
+        [
+            "B is started without being defined",
+            "CENTER of A is not a point",
+            "POLYGON of A is not a list of 3 or more points",
+            "A is defined 2 times",
+            "B is still printing at the end",
+        ]
+    );
+    assert!(violations(
+        "EXCLUDE_OBJECT_DEFINE NAME=a CENTER=1,1 POLYGON=[[0,0],[1,0],[1,1]]\n\
+        EXCLUDE_OBJECT_START NAME=a\nG1 X1 E1\nEXCLUDE_OBJECT_END NAME=a\n"
+    )
+    .is_empty());
+}
+
+/// The processed fixtures conform with every set of options
+#[test]
+fn test_fixtures_conform() {
+    let mut problems = Vec::new();
+    for fixture in fixtures(&fixtures_dir()) {
+        let mut gcode = Vec::new();
+        std::io::Read::read_to_end(&mut File::open(&fixture).unwrap(), &mut gcode).unwrap();
+        for (options_name, options) in option_sets() {
+            for violation in check(&gcode, &options).unwrap_or_default() {
+                problems.push(format!(
+                    "{} ({options_name}): {violation}",
+                    fixture.display()
+                ));
+            }
+        }
+    }
+
+    assert!(problems.is_empty(), "{}", problems.join("\n"));
+}
+
+/// Synthetic plates of every slicer conform
+#[test]
+fn test_synthetic_conform() {
+    for dialect in Dialect::ALL {
+        let mut gcode = Vec::new();
+        SyntheticGcode::new(dialect)
+            .objects(6)
+            .layers(3)
+            .write(&mut gcode)
+            .unwrap();
+        for (options_name, options) in option_sets() {
+            let violations = check(&gcode, &options).expect("synthetic plates are processed");
+            assert!(
+                violations.is_empty(),
+                "{dialect:?} ({options_name}): {violations:?}"
+            );
+        }
+    }
+}
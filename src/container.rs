@@ -0,0 +1,173 @@
+//! 3MF containers holding sliced plates, as exported by OrcaSlicer and Bambu Studio.
+//!
+//! Hosts running Klipper can't read the G-Code inside such archives, so each plate is extracted
+//! and processed as a G-Code file of its own.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use zip::ZipArchive;
+
+/// Output template of the plates when none is given
+pub(crate) const DEFAULT_PLATE_TEMPLATE: &str = "{stem}_plate{plate}.gcode";
+
+#[derive(Debug, Error)]
+pub enum ContainerError {
+    #[error("Error reading container {0}")]
+    Read(String),
+    #[error("No sliced plates found in {0}")]
+    NoPlates(String),
+}
+
+/// Whether the file is a 3MF or ZIP container rather than plain G-Code
+pub(crate) fn is_container(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("3mf") || ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Name of the G-Code file in a container, `plate.gcode.3mf` and `plate.3mf` both hold
+/// `plate.gcode`
+pub(crate) fn gcode_name(path: &Path) -> PathBuf {
+    let name = PathBuf::from(path.file_name().unwrap_or_default()).with_extension("");
+    match name.extension() {
+        Some(_) => name,
+        None => name.with_extension("gcode"),
+    }
+}
+
+/// A sliced plate within a container
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Plate {
+    /// Number of the plate in the slicer, starting at 1
+    pub number: usize,
+    /// Index of the entry in the archive
+    index: usize,
+}
+
+pub(crate) struct Container<R> {
+    archive: ZipArchive<R>,
+}
+
+impl Container<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, ContainerError> {
+        let error = || ContainerError::Read(path.to_string_lossy().to_string());
+        let file = File::open(path).map_err(|_err| error())?;
+        Self::new(BufReader::new(file)).map_err(|_err| error())
+    }
+}
+
+impl<R: Read + Seek> Container<R> {
+    pub fn new(reader: R) -> io::Result<Self> {
+        Ok(Self {
+            archive: ZipArchive::new(reader)?,
+        })
+    }
+
+    /// The G-Code entries, numbered by their `plate_N.gcode` names or in archive order
+    pub fn plates(&mut self) -> io::Result<Vec<Plate>> {
+        let mut plates = Vec::new();
+        for index in 0..self.archive.len() {
+            let entry = self.archive.by_index_raw(index)?;
+            if !entry.is_file() || !entry.name().to_ascii_lowercase().ends_with(".gcode") {
+                continue;
+            }
+            let number = Path::new(entry.name())
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.strip_prefix("plate_"))
+                .and_then(|number| number.parse().ok())
+                .unwrap_or(plates.len() + 1);
+            plates.push(Plate { number, index });
+        }
+
+        plates.sort_by_key(|plate| (plate.number, plate.index));
+        Ok(plates)
+    }
+
+    /// Write the G-Code of a plate to a file
+    pub fn extract(&mut self, plate: &Plate, dest: &Path) -> io::Result<()> {
+        let mut entry = self.archive.by_index(plate.index)?;
+        let mut writer = BufWriter::new(File::create(dest)?);
+        io::copy(&mut entry, &mut writer)?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// A container with the given entries, compressed like the slicers do
+    fn archive(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_is_container() {
+        assert!(is_container(Path::new("plate.gcode.3mf")));
+        assert!(is_container(Path::new("dir/PLATE.3MF")));
+        assert!(is_container(Path::new("plates.zip")));
+        assert!(!is_container(Path::new("plate.gcode")));
+        assert!(!is_container(Path::new("3mf")));
+    }
+
+    #[test]
+    fn test_gcode_name() {
+        assert_eq!(
+            gcode_name(Path::new("dir/cube.gcode.3mf")),
+            Path::new("cube.gcode")
+        );
+        assert_eq!(gcode_name(Path::new("cube.3mf")), Path::new("cube.gcode"));
+        assert_eq!(gcode_name(Path::new("cube.zip")), Path::new("cube.gcode"));
+    }
+
+    #[test]
+    fn test_plates() {
+        let data = archive(&[
+            ("3D/3dmodel.model", "<model/>"),
+            ("Metadata/plate_2.gcode", "G1 X2\n"),
+            ("Metadata/plate_2.gcode.md5", "0"),
+            ("Metadata/plate_10.gcode", "G1 X10\n"),
+            ("Metadata/plate_1.gcode", "G1 X1\n"),
+        ]);
+        let mut container = Container::new(Cursor::new(data)).unwrap();
+        let plates = container.plates().unwrap();
+        assert_eq!(
+            plates.iter().map(|plate| plate.number).collect::<Vec<_>>(),
+            [1, 2, 10]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("plate.gcode");
+        container.extract(&plates[2], &dest).unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "G1 X10\n");
+
+        // Plates without numbers in their names are numbered in archive order
+        let data = archive(&[("a.gcode", "G1 X1\n"), ("b.GCODE", "G1 X2\n")]);
+        let plates = Container::new(Cursor::new(data)).unwrap().plates().unwrap();
+        assert_eq!(
+            plates.iter().map(|plate| plate.number).collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn test_not_a_container() {
+        assert!(Container::new(Cursor::new(b"G28\n".to_vec())).is_err());
+        assert!(matches!(
+            Container::open(Path::new("missing.3mf")),
+            Err(ContainerError::Read(_))
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::klipper::{extended_params, split_command};
+use crate::lines::LineReader;
+use crate::motion::Toolhead;
+use geo::{BoundingRect, Contains, Coord, EuclideanDistance, Point, Polygon, Rect};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead};
+
+/// Distance in mm extrusions may be outside of their outline, the outlines are simplified and
+/// coordinates are rounded
+pub(crate) const TOLERANCE: f64 = 0.1;
+
+/// An outline and its bounding box, to skip the polygon test for points far outside
+struct Outline {
+    polygon: Polygon,
+    bounds: Rect,
+}
+
+impl Outline {
+    fn parse(polygon: &str) -> Option<Self> {
+        let points: Vec<[f64; 2]> = serde_json::from_str(polygon).ok()?;
+        let polygon = Polygon::new(
+            points.into_iter().map(|[x, y]| Coord { x, y }).collect(),
+            vec![],
+        );
+        let bounds = polygon.bounding_rect()?;
+        Some(Self { polygon, bounds })
+    }
+
+    /// How far the point is outside of the outline, 0 inside of it
+    fn distance(&self, point: (f64, f64)) -> f64 {
+        let point = Point::new(point.0, point.1);
+        let min = self.bounds.min();
+        let max = self.bounds.max();
+        let outside_bounds = (min.x - point.x())
+            .max(point.x() - max.x)
+            .max(min.y - point.y())
+            .max(point.y() - max.y);
+        if outside_bounds > TOLERANCE {
+            return self.polygon.euclidean_distance(&point);
+        }
+
+        match self.polygon.contains(&point) {
+            true => 0.0,
+            false => self.polygon.euclidean_distance(&point),
+        }
+    }
+}
+
+/// Extrusions of an object outside of its outline
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Uncovered {
+    pub object: String,
+    /// Line of the first extrusion outside, starting at 1
+    pub line: usize,
+    pub point: (f64, f64),
+    /// Extruded points outside of the outline
+    pub points: usize,
+    /// Largest distance of a point from the outline in mm
+    pub distance: f64,
+}
+
+impl fmt::Display for Uncovered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}: {} extrusions of {} are outside of its POLYGON by up to {:.3}mm, first at {:.3},{:.3}",
+            self.line, self.points, self.object, self.distance, self.point.0, self.point.1
+        )
+    }
+}
+
+/// Simulate the moves of a processed file and find the objects that extrude outside of the
+/// POLYGON they are defined with.
+///
+/// Arcs are interpolated and positioning modes, offsets and tools are followed the same way as
+/// while collecting the outlines. Objects without a polygon aren't checked. Returns one entry per
+/// object, in the order they were first found outside.
+pub(crate) fn check_coverage(
+    input: impl BufRead,
+    mut toolhead: Toolhead,
+) -> io::Result<Vec<Uncovered>> {
+    let mut outlines: HashMap<String, Outline> = HashMap::new();
+    let mut current: Option<String> = None;
+    let mut uncovered: Vec<Uncovered> = Vec::new();
+
+    let mut lines = LineReader::untruncated(input);
+    while let Some((line_no, line)) = lines.next_line()? {
+        if let Some((command, args)) = split_command(line) {
+            let name = || {
+                extended_params(args)
+                    .ok()
+                    .and_then(|mut params| params.remove("NAME"))
+                    .map(|name| name.to_uppercase())
+            };
+            match command.to_uppercase().as_str() {
+                "EXCLUDE_OBJECT_DEFINE" => {
+                    let polygon = extended_params(args)
+                        .ok()
+                        .and_then(|mut params| params.remove("POLYGON"));
+                    if let Some((name, outline)) =
+                        name().zip(polygon.as_deref().and_then(Outline::parse))
+                    {
+                        outlines.insert(name, outline);
+                    }
+                    continue;
+                }
+                "EXCLUDE_OBJECT_START" => {
+                    current = name();
+                    continue;
+                }
+                "EXCLUDE_OBJECT_END" => {
+                    current = None;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        let start = toolhead.reported_position();
+        let motion = toolhead.apply_line(line);
+        let Some((object, outline)) = current
+            .as_ref()
+            .and_then(|name| outlines.get(name).map(|outline| (name, outline)))
+        else {
+            continue;
+        };
+        if !motion.extruding {
+            continue;
+        }
+
+        // The extrusion starts where the previous move ended
+        for point in start.into_iter().chain(motion.points) {
+            let distance = outline.distance(point);
+            if distance <= TOLERANCE {
+                continue;
+            }
+
+            match uncovered.iter_mut().find(|entry| entry.object == *object) {
+                Some(entry) => {
+                    entry.points += 1;
+                    entry.distance = entry.distance.max(distance);
+                }
+                None => uncovered.push(Uncovered {
+                    object: object.clone(),
+                    line: line_no + 1,
+                    point,
+                    points: 1,
+                    distance,
+                }),
+            }
+        }
+    }
+
+    Ok(uncovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocess::{self, ProcessOptions};
+    use crate::snapshots::{fixtures, fixtures_dir};
+    use std::fs::File;
+    use std::io::{BufReader, Cursor, Seek};
+
+    const GCODE: &str =
+        "EXCLUDE_OBJECT_DEFINE NAME=a POLYGON=[[0,0],[10,0],[10,10],[0,10],[0,0]]\n\
+        G90\nM83\nG0 X1 Y1\n\
+        EXCLUDE_OBJECT_START NAME=a\n\
+        G1 X9 Y1 E1\n\
+        G1 X9.05 Y9 E1\n\
+        G2 X9 Y11 I0 J1 E1\n\
+        G1 X9 Y9 E-1\n\
+        G0 X20 Y20\n\
+        EXCLUDE_OBJECT_END NAME=a\n\
+        G1 X30 Y30 E1\n";
+
+    #[test]
+    fn test_coverage() {
+        let uncovered = check_coverage(GCODE.as_bytes(), Toolhead::default()).unwrap();
+        assert_eq!(uncovered.len(), 1);
+        let entry = &uncovered[0];
+        assert_eq!((entry.object.as_str(), entry.line), ("A", 8));
+        // The arc leaves the outline at the top and ends 1mm above it
+        assert!((entry.distance - 1.0).abs() < 1e-6, "{entry:?}");
+        assert!(entry.points > 1);
+    }
+
+    #[test]
+    fn test_relative_positioning() {
+        let gcode = "EXCLUDE_OBJECT_DEFINE NAME=a POLYGON=[[0,0],[10,0],[10,10],[0,10],[0,0]]\n\
+            G0 X5 Y5\nG91\nEXCLUDE_OBJECT_START NAME=a\nG1 X4 E1\nG1 X2 E1\n\
+            EXCLUDE_OBJECT_END NAME=a\n";
+        let uncovered = check_coverage(gcode.as_bytes(), Toolhead::default()).unwrap();
+        assert_eq!(uncovered.len(), 1);
+        assert_eq!(uncovered[0].point, (11.0, 5.0));
+    }
+
+    /// Every outline of the processed fixtures covers the extrusions of its object
+    #[test]
+    fn test_fixtures_covered() {
+        let mut problems = Vec::new();
+        for fixture in fixtures(&fixtures_dir()) {
+            let mut processed = Cursor::new(Vec::new());
+            let result = preprocess::stream(
+                &mut File::open(&fixture).unwrap(),
+                &mut processed,
+                &ProcessOptions::default(),
+            );
+            if result.is_err() {
+                continue;
+            }
+            processed.rewind().unwrap();
+
+            for entry in check_coverage(BufReader::new(processed), Toolhead::default()).unwrap() {
+                problems.push(format!("{}: {entry}", fixture.display()));
+            }
+        }
+
+        assert!(problems.is_empty(), "{}", problems.join("\n"));
+    }
+}
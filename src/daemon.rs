@@ -0,0 +1,274 @@
+//! Queue directory processed as a service, e.g. by systemd on the printer host.
+//!
+//! Files dropped into `incoming/` are processed into `processed/` and removed from the queue,
+//! the originals of files that fail are moved to `failed/` next to a `.error` file with the
+//! reason. Files are only picked up once their size and modification time stopped changing, so
+//! uploads still being written are left alone.
+
+use crate::paths::{replace_file, NativeFileSystem, Retry};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a shutdown is checked for while waiting for the next scan
+const STOP_CHECK: Duration = Duration::from_millis(100);
+
+/// The directories of a queue
+pub(crate) struct Queue {
+    pub incoming: PathBuf,
+    pub processed: PathBuf,
+    pub failed: PathBuf,
+    /// Attempts to move a failed file aside while another program has it open
+    pub retry: Retry,
+}
+
+impl Queue {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            incoming: dir.join("incoming"),
+            processed: dir.join("processed"),
+            failed: dir.join("failed"),
+            retry: Retry::default(),
+        }
+    }
+
+    fn create(&self) -> io::Result<()> {
+        for dir in [&self.incoming, &self.processed, &self.failed] {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Move a file that failed to the failed directory and store the reason next to it
+    fn fail(&self, path: &Path, error: &str) -> io::Result<()> {
+        let dest = self.failed.join(path.file_name().unwrap_or_default());
+        replace_file(&NativeFileSystem, path, &dest, &self.retry)?;
+
+        let mut reason = dest.into_os_string();
+        reason.push(".error");
+        fs::write(reason, format!("{error}\n"))
+    }
+}
+
+/// Files in the incoming directory, by their size and modification time when last seen
+#[derive(Default)]
+struct Scan {
+    seen: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+}
+
+impl Scan {
+    /// Files that didn't change since the previous scan, hidden files are skipped as uploads are
+    /// often written to a hidden temporary file first
+    fn ready(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut seen = HashMap::new();
+        let mut ready = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            // Files can be removed while scanning
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let path = entry.path();
+            let state = (metadata.len(), metadata.modified().ok());
+            if self.seen.get(&path) == Some(&state) {
+                ready.push(path.clone());
+            }
+            seen.insert(path, state);
+        }
+
+        self.seen = seen;
+        ready.sort();
+        Ok(ready)
+    }
+}
+
+/// Flag set once the service is asked to stop by SIGTERM or SIGINT. A second signal exits
+/// immediately.
+pub(crate) fn shutdown_flag() -> io::Result<Arc<AtomicBool>> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))?;
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+    Ok(stop)
+}
+
+/// Send a state to the service manager, if it is waiting for one, see sd_notify(3)
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        match socket_path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &address)
+            }
+            _ => socket.send_to(state.as_bytes(), &socket_path),
+        }
+    });
+    if let Err(err) = result {
+        tracing::warn!("Could not notify the service manager: {}", err);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+/// Wait for the next scan, returns early once the service is stopped
+fn wait(interval: Duration, stop: &AtomicBool) {
+    let deadline = Instant::now() + interval;
+    while !stop.load(Ordering::Relaxed) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        std::thread::sleep(left.min(STOP_CHECK));
+    }
+}
+
+/// Process the files of the queue until `stop` is set, the file being processed is finished
+/// first. `process` writes the output of a file, its original is removed from the queue once
+/// it succeeded.
+pub(crate) fn run<E: Display>(
+    queue: &Queue,
+    interval: Duration,
+    stop: &AtomicBool,
+    mut process: impl FnMut(&Path) -> Result<(), E>,
+) -> io::Result<()> {
+    queue.create()?;
+    tracing::info!("Processing files dropped into {}", queue.incoming.display());
+    notify("READY=1");
+    let watchdog = std::env::var_os("WATCHDOG_USEC").is_some();
+
+    let mut scan = Scan::default();
+    let (mut processed, mut failed) = (0, 0);
+    while !stop.load(Ordering::Relaxed) {
+        for path in scan.ready(&queue.incoming)? {
+            match process(&path) {
+                Ok(()) => {
+                    processed += 1;
+                    // Already gone if the original was archived
+                    match fs::remove_file(&path) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                            tracing::warn!("Could not remove {}: {}", path.display(), err);
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    failed += 1;
+                    tracing::error!("Error processing file {}: {}", path.display(), e);
+                    if let Err(err) = queue.fail(&path, &e.to_string()) {
+                        tracing::error!("Could not move {} aside: {}", path.display(), err);
+                    }
+                }
+            }
+            notify(&format!("STATUS={processed} processed, {failed} failed"));
+
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+
+        if watchdog {
+            notify("WATCHDOG=1");
+        }
+        wait(interval, stop);
+    }
+
+    notify("STOPPING=1");
+    tracing::info!("Stopped after processing {processed} files, {failed} failed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_waits_for_complete_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("plate.gcode");
+        fs::write(&file, "G28\n").unwrap();
+        fs::write(dir.path().join(".upload.gcode"), "G28\n").unwrap();
+        fs::create_dir(dir.path().join("plates")).unwrap();
+
+        let mut scan = Scan::default();
+        assert!(scan.ready(dir.path()).unwrap().is_empty());
+        assert_eq!(scan.ready(dir.path()).unwrap(), std::slice::from_ref(&file));
+
+        // Still being written
+        fs::write(&file, "G28\nG1 X1\n").unwrap();
+        assert!(scan.ready(dir.path()).unwrap().is_empty());
+        assert_eq!(scan.ready(dir.path()).unwrap(), std::slice::from_ref(&file));
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = Queue::new(dir.path());
+        queue.create().unwrap();
+        for name in ["good.gcode", "bad.gcode"] {
+            fs::write(queue.incoming.join(name), "G28\n").unwrap();
+        }
+
+        let stop = AtomicBool::new(false);
+        let mut seen = Vec::new();
+        run(&queue, Duration::ZERO, &stop, |path| {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            seen.push(name.clone());
+            // The file being processed is finished, the others stay in the queue
+            stop.store(true, Ordering::Relaxed);
+            match name.as_str() {
+                "good.gcode" => {
+                    fs::copy(path, queue.processed.join(&name)).unwrap();
+                    Ok(())
+                }
+                _ => Err("No objects"),
+            }
+        })
+        .unwrap();
+
+        assert_eq!(seen, ["bad.gcode"]);
+        assert!(queue.failed.join("bad.gcode").exists());
+        assert_eq!(
+            fs::read_to_string(queue.failed.join("bad.gcode.error")).unwrap(),
+            "No objects\n"
+        );
+        assert!(queue.incoming.join("good.gcode").exists());
+
+        stop.store(false, Ordering::Relaxed);
+        let mut calls = 0;
+        run(&queue, Duration::ZERO, &stop, |path| {
+            calls += 1;
+            stop.store(true, Ordering::Relaxed);
+            fs::copy(path, queue.processed.join(path.file_name().unwrap())).map(|_| ())
+        })
+        .unwrap();
+
+        assert_eq!(calls, 1);
+        assert!(queue.processed.join("good.gcode").exists());
+        assert_eq!(fs::read_dir(&queue.incoming).unwrap().count(), 0);
+    }
+}
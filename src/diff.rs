@@ -0,0 +1,264 @@
+use crate::lines::LineReader;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+
+/// Unchanged lines shown around each change
+const CONTEXT: usize = 3;
+/// Lines searched ahead for the next matching line, longer than the header of most files
+const LOOKAHEAD: usize = 4096;
+
+/// Lines read ahead of the current position of one side of the diff
+struct Lines<R> {
+    reader: LineReader<R>,
+    ahead: VecDeque<String>,
+    done: bool,
+}
+
+impl<R: BufRead> Lines<R> {
+    fn new(input: R) -> Self {
+        Self {
+            reader: LineReader::untruncated(input),
+            ahead: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn fill(&mut self, count: usize) -> io::Result<()> {
+        while self.ahead.len() < count && !self.done {
+            match self.reader.next_line()? {
+                Some((_, line)) => self.ahead.push_back(line.to_string()),
+                None => self.done = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&mut self, matches: impl Fn(&str) -> bool) -> io::Result<Option<usize>> {
+        self.fill(LOOKAHEAD)?;
+        Ok(self.ahead.iter().position(|candidate| matches(candidate)))
+    }
+
+    fn pop(&mut self) -> String {
+        self.ahead.pop_front().unwrap_or_default()
+    }
+}
+
+/// Collects changed lines with their context into hunks and writes them in unified diff format
+struct Hunks<'a, W: Write> {
+    output: &'a mut W,
+    /// File names, written before the first hunk
+    header: Option<(String, String)>,
+    /// Next line of the original and the modified file, starting at 1
+    old_line: usize,
+    new_line: usize,
+    /// Unchanged lines before the next change
+    before: VecDeque<String>,
+    hunk: Vec<(char, String)>,
+    start: (usize, usize),
+    /// Unchanged lines at the end of the current hunk
+    trailing: usize,
+    changes: usize,
+}
+
+impl<'a, W: Write> Hunks<'a, W> {
+    fn equal(&mut self, line: String) -> io::Result<()> {
+        self.old_line += 1;
+        self.new_line += 1;
+        if self.hunk.is_empty() {
+            self.before.push_back(line);
+            if self.before.len() > CONTEXT {
+                self.before.pop_front();
+            }
+            return Ok(());
+        }
+
+        self.hunk.push((' ', line));
+        self.trailing += 1;
+        if self.trailing > 2 * CONTEXT {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn change(&mut self, kind: char, line: String) {
+        if self.hunk.is_empty() {
+            self.start = (
+                self.old_line - self.before.len(),
+                self.new_line - self.before.len(),
+            );
+            self.hunk
+                .extend(self.before.drain(..).map(|line| (' ', line)));
+        }
+        match kind {
+            '-' => self.old_line += 1,
+            _ => self.new_line += 1,
+        }
+        self.hunk.push((kind, line));
+        self.trailing = 0;
+        self.changes += 1;
+    }
+
+    /// Write the current hunk, unchanged lines beyond the context start the next one
+    fn flush(&mut self) -> io::Result<()> {
+        if self.hunk.is_empty() {
+            return Ok(());
+        }
+
+        let excess = self.trailing.saturating_sub(CONTEXT);
+        let tail = self.hunk.split_off(self.hunk.len() - excess);
+        self.before.extend(
+            tail.into_iter()
+                .map(|(_, line)| line)
+                .skip(excess.saturating_sub(CONTEXT)),
+        );
+        self.trailing = 0;
+
+        if let Some((original, modified)) = self.header.take() {
+            writeln!(self.output, "--- {original}")?;
+            writeln!(self.output, "+++ {modified}")?;
+        }
+
+        let old_count = self.hunk.iter().filter(|(kind, _)| *kind != '+').count();
+        let new_count = self.hunk.iter().filter(|(kind, _)| *kind != '-').count();
+        // Empty ranges start at the line before them
+        let old_start = self.start.0 - usize::from(old_count == 0);
+        let new_start = self.start.1 - usize::from(new_count == 0);
+        writeln!(
+            self.output,
+            "@@ -{old_start},{old_count} +{new_start},{new_count} @@"
+        )?;
+        for (kind, line) in self.hunk.drain(..) {
+            writeln!(self.output, "{kind}{line}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write the differences between the original and the processed file as a unified diff and
+/// return the number of lines added and removed.
+///
+/// The processed file mostly has lines inserted, so the next matching line is searched within
+/// a window instead of computing a minimal diff of the whole file.
+pub(crate) fn unified_diff(
+    original: impl BufRead,
+    modified: impl BufRead,
+    names: (&str, &str),
+    output: &mut impl Write,
+) -> io::Result<usize> {
+    let mut old = Lines::new(original);
+    let mut new = Lines::new(modified);
+    let mut hunks = Hunks {
+        output,
+        header: Some((names.0.to_string(), names.1.to_string())),
+        old_line: 1,
+        new_line: 1,
+        before: VecDeque::new(),
+        hunk: Vec::new(),
+        start: (1, 1),
+        trailing: 0,
+        changes: 0,
+    };
+
+    loop {
+        old.fill(1)?;
+        new.fill(1)?;
+        let (a, b) = match (old.ahead.front(), new.ahead.front()) {
+            (None, None) => break,
+            (Some(_), None) => {
+                hunks.change('-', old.pop());
+                continue;
+            }
+            (None, Some(_)) => {
+                hunks.change('+', new.pop());
+                continue;
+            }
+            (Some(a), Some(b)) if a == b => {
+                new.pop();
+                hunks.equal(old.pop())?;
+                continue;
+            }
+            (Some(a), Some(b)) => (a.clone(), b.clone()),
+        };
+
+        // Commented out commands replace the original line
+        let command = a.trim();
+        let replaces = |line: &str| {
+            !command.is_empty() && line.trim_start().starts_with(';') && line.ends_with(command)
+        };
+        if !replaces(&b) {
+            if let Some(inserted) = new.position(|line| line == a || replaces(line))? {
+                for _ in 0..inserted {
+                    hunks.change('+', new.pop());
+                }
+                continue;
+            }
+            if let Some(removed) = old.position(|line| line == b)? {
+                for _ in 0..removed {
+                    hunks.change('-', old.pop());
+                }
+                continue;
+            }
+        }
+
+        hunks.change('-', old.pop());
+        hunks.change('+', new.pop());
+    }
+
+    hunks.trailing = hunks.trailing.min(CONTEXT);
+    hunks.flush()?;
+    Ok(hunks.changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(original: &str, modified: &str) -> (String, usize) {
+        let mut output = Vec::new();
+        let changes = unified_diff(
+            original.as_bytes(),
+            modified.as_bytes(),
+            ("a.gcode", "b.gcode"),
+            &mut output,
+        )
+        .unwrap();
+        (String::from_utf8(output).unwrap(), changes)
+    }
+
+    #[test]
+    fn test_unchanged() {
+        assert_eq!(diff("G28\nG1 X1\n", "G28\nG1 X1\n"), (String::new(), 0));
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let original = "; header\nG28\nG1 X1\nG1 X2\nG1 X3\nG1 X4\nG1 X5\nG1 X6\nG1 X7\nG1 X8\n\
+            M486 S1\nG1 X9\n";
+        let modified = "; header\n\n\nEXCLUDE_OBJECT_DEFINE NAME=a\nG28\nG1 X1\nG1 X2\nG1 X3\n\
+            G1 X4\nG1 X5\nG1 X6\nG1 X7\nG1 X8\n; M486 S1\nEXCLUDE_OBJECT_START NAME=a\nG1 X9\n\
+            EXCLUDE_OBJECT_END NAME=a\n";
+
+        let (output, changes) = diff(original, modified);
+        assert_eq!(changes, 7);
+        assert_eq!(
+            output,
+            "--- a.gcode\n+++ b.gcode\n\
+            @@ -1,4 +1,7 @@\n \
+            ; header\n+\n+\n+EXCLUDE_OBJECT_DEFINE NAME=a\n G28\n G1 X1\n G1 X2\n\
+            @@ -8,5 +11,7 @@\n \
+            G1 X6\n G1 X7\n G1 X8\n-M486 S1\n+; M486 S1\n+EXCLUDE_OBJECT_START NAME=a\n \
+            G1 X9\n+EXCLUDE_OBJECT_END NAME=a\n"
+        );
+    }
+
+    #[test]
+    fn test_removed_lines() {
+        let (output, changes) = diff("G28\nEXCLUDE_OBJECT_START NAME=a\nG1 X1\n", "G28\nG1 X1\n");
+        assert_eq!(changes, 1);
+        assert_eq!(
+            output,
+            "--- a.gcode\n+++ b.gcode\n@@ -1,3 +1,2 @@\n G28\n-EXCLUDE_OBJECT_START NAME=a\n G1 X1\n"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Bytes looked at to recognize UTF-16 without a byte order mark
+const SNIFF_LENGTH: usize = 512;
+
+/// Encodings of the input other than plain UTF-8
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Encoding {
+    /// UTF-8 starting with a byte order mark
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Utf8Bom => "UTF-8 with byte order mark",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+        })
+    }
+}
+
+/// Whether a file starting with these bytes is binary data rather than text, like a binary
+/// G-Code file or an image selected by mistake. Checked after ruling out UTF-16.
+pub(crate) fn is_binary(start: &[u8]) -> bool {
+    let start = &start[..start.len().min(SNIFF_LENGTH * 16)];
+    let control = start
+        .iter()
+        .filter(|byte| byte.is_ascii_control() && !b"\t\n\r\x0c".contains(byte))
+        .count();
+
+    start.contains(&0) || control * 10 > start.len()
+}
+
+impl Encoding {
+    /// The encoding of a file starting with these bytes, None for UTF-8 and ASCII.
+    ///
+    /// Files without a byte order mark are taken as UTF-16 when most of their characters have a
+    /// zero byte in the same place, which G-Code being mostly ASCII always has.
+    pub fn detect(start: &[u8]) -> Option<Encoding> {
+        if start.starts_with(b"\xEF\xBB\xBF") {
+            return Some(Encoding::Utf8Bom);
+        }
+        if start.starts_with(b"\xFF\xFE") {
+            return Some(Encoding::Utf16Le);
+        }
+        if start.starts_with(b"\xFE\xFF") {
+            return Some(Encoding::Utf16Be);
+        }
+
+        let start = &start[..start.len().min(SNIFF_LENGTH) & !1];
+        if start.len() < 4 {
+            return None;
+        }
+        let units = start.len() / 2;
+        let zeros = |offset: usize| {
+            start
+                .iter()
+                .skip(offset)
+                .step_by(2)
+                .filter(|byte| **byte == 0)
+                .count()
+        };
+        let (even, odd) = (zeros(0), zeros(1));
+        if odd * 4 >= units * 3 && even * 8 <= units {
+            Some(Encoding::Utf16Le)
+        } else if even * 4 >= units * 3 && odd * 8 <= units {
+            Some(Encoding::Utf16Be)
+        } else {
+            None
+        }
+    }
+
+    /// Copy the input to the output as UTF-8 without a byte order mark.
+    ///
+    /// Unpaired surrogates are replaced by U+FFFD, a lone byte at the end of the input is dropped.
+    pub fn transcode(self, mut input: impl Read, output: &mut impl Write) -> io::Result<()> {
+        if self == Encoding::Utf8Bom {
+            input.read_exact(&mut [0; 3])?;
+            io::copy(&mut input, output)?;
+            return Ok(());
+        }
+
+        let mut buffer = vec![0; 64 * 1024];
+        let mut pending = 0;
+        let mut text = String::new();
+        let mut first = true;
+
+        loop {
+            let read = input.read(&mut buffer[pending..])?;
+            let end = pending + read;
+            let mut units: Vec<u16> = buffer[..end]
+                .chunks_exact(2)
+                .map(|pair| match self {
+                    Encoding::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+                    _ => u16::from_le_bytes([pair[0], pair[1]]),
+                })
+                .collect();
+            // A high surrogate at the end of the buffer is decoded with the next read
+            let mut carry = end % 2;
+            if read > 0
+                && units
+                    .last()
+                    .is_some_and(|unit| (0xD800..0xDC00).contains(unit))
+            {
+                units.pop();
+                carry += 2;
+            }
+
+            text.clear();
+            text.extend(
+                char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+            );
+            let mut decoded = text.as_str();
+            if first && !decoded.is_empty() {
+                first = false;
+                decoded = decoded.strip_prefix('\u{FEFF}').unwrap_or(decoded);
+            }
+            output.write_all(decoded.as_bytes())?;
+
+            if read == 0 {
+                return Ok(());
+            }
+            buffer.copy_within(end - carry..end, 0);
+            pending = carry;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, big_endian: bool, bom: bool) -> Vec<u8> {
+        let units = bom.then_some(0xFEFF).into_iter().chain(text.encode_utf16());
+        units
+            .flat_map(|unit| match big_endian {
+                true => unit.to_be_bytes(),
+                false => unit.to_le_bytes(),
+            })
+            .collect()
+    }
+
+    /// Reads one byte at a time
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let length = buf.len().min(self.0.len()).min(1);
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        let gcode = "; generated by PrusaSlicer 2.6.0\nG28\nG1 X10 Y10\n";
+        assert_eq!(Encoding::detect(gcode.as_bytes()), None);
+        assert_eq!(Encoding::detect(b""), None);
+        assert_eq!(
+            Encoding::detect(format!("\u{FEFF}{gcode}").as_bytes()),
+            Some(Encoding::Utf8Bom)
+        );
+        for bom in [true, false] {
+            assert_eq!(
+                Encoding::detect(&utf16(gcode, false, bom)),
+                Some(Encoding::Utf16Le)
+            );
+            assert_eq!(
+                Encoding::detect(&utf16(gcode, true, bom)),
+                Some(Encoding::Utf16Be)
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_binary() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary(
+            b"; generated by PrusaSlicer\r\nG28\n\x0c\tG1 X1 ; \xC3\xA9\n"
+        ));
+        assert!(is_binary(b"GCDE\x01\x00\x00\x00"));
+        assert!(is_binary(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"));
+        assert!(is_binary(b"G28\x01\x02\x03"));
+    }
+
+    #[test]
+    fn test_transcode() {
+        let gcode = "; printing object Würfel 🧊\nG1 X10 Y10 ; 温度\n";
+        for (encoding, bytes) in [
+            (Encoding::Utf8Bom, format!("\u{FEFF}{gcode}").into_bytes()),
+            (Encoding::Utf16Le, utf16(gcode, false, true)),
+            (Encoding::Utf16Be, utf16(gcode, true, true)),
+            (Encoding::Utf16Le, utf16(gcode, false, false)),
+        ] {
+            // Single bytes split code units and surrogate pairs between reads
+            let mut output = Vec::new();
+            encoding
+                .transcode(ByteReader(bytes.as_slice()), &mut output)
+                .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), gcode, "{encoding}");
+        }
+
+        let mut output = Vec::new();
+        Encoding::Utf16Le
+            .transcode(&[0x3B, 0x00, 0x00, 0xD8, 0x0A, 0x00][..], &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), ";\u{FFFD}\n");
+    }
+}
@@ -0,0 +1,204 @@
+//! Exit status of the command line tool.
+//!
+//! Each kind of failure exits with a status of its own, so scripts and hooks can react to it
+//! without parsing the log. The table of statuses is printed at the end of `--help`.
+
+use crate::hooks::HookError;
+use crate::preprocess::PreprocessError;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use thiserror::Error;
+
+/// A check of `--verify`, `--check-idempotent` or `--compare-python` found problems
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub(crate) struct ValidationFailed(pub String);
+
+/// The file a batch failed on and the number of files processed before it
+#[derive(Debug, Error)]
+#[error("Error processing file {}", .file.to_string_lossy())]
+pub(crate) struct FailedFile {
+    pub file: PathBuf,
+    pub processed: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ExitStatus {
+    Success = 0,
+    Failure = 1,
+    Usage = 2,
+    NoObjects = 3,
+    UnknownSlicer = 4,
+    Io = 5,
+    InvalidFilter = 6,
+    /// Files processed already are copied unchanged and only fail with `--fail-if-processed`,
+    /// archiving a file whose original is in the archive directory always fails
+    AlreadyProcessed = 7,
+    Validation = 8,
+    PartialBatch = 9,
+    HookFailed = 10,
+}
+
+impl ExitStatus {
+    pub const ALL: [Self; 11] = [
+        Self::Success,
+        Self::Failure,
+        Self::Usage,
+        Self::NoObjects,
+        Self::UnknownSlicer,
+        Self::Io,
+        Self::InvalidFilter,
+        Self::AlreadyProcessed,
+        Self::Validation,
+        Self::PartialBatch,
+        Self::HookFailed,
+    ];
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Success => "All files were processed or checked",
+            Self::Failure => "Any other error",
+            Self::Usage => "Invalid command line arguments",
+            Self::NoObjects => "A file has no objects and --require-objects is given",
+            Self::UnknownSlicer => "The slicer of a file could not be identified",
+            Self::Io => "A file could not be read or written",
+            Self::InvalidFilter => "The --layers filter is invalid",
+            Self::AlreadyProcessed => {
+                "A file was processed already with --fail-if-processed, or is archived already"
+            }
+            Self::Validation => "A check failed, or the output would be invalid",
+            Self::PartialBatch => "A file failed after earlier files of the batch were processed",
+            Self::HookFailed => "The --post-hook failed for a processed file",
+        }
+    }
+
+    /// The status of a run that failed with the error
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        // Earlier files were written already, which matters more to scripts than the cause
+        if e.downcast_ref::<FailedFile>()
+            .is_some_and(|failed| failed.processed > 0)
+        {
+            return Self::PartialBatch;
+        }
+        if e.downcast_ref::<ValidationFailed>().is_some() {
+            return Self::Validation;
+        }
+        if e.downcast_ref::<HookError>().is_some() {
+            return Self::HookFailed;
+        }
+        if let Some(error) = e.downcast_ref::<PreprocessError>() {
+            return Self::from(error);
+        }
+        match e.downcast_ref::<std::io::Error>() {
+            Some(_) => Self::Io,
+            None => Self::Failure,
+        }
+    }
+
+    /// Table of the statuses for the help output
+    pub fn help() -> String {
+        let mut help = String::from("Exit status:\n");
+        for status in Self::ALL {
+            let _ = writeln!(help, "  {:>2}  {}", status.code(), status.description());
+        }
+        help
+    }
+}
+
+impl From<&PreprocessError> for ExitStatus {
+    fn from(error: &PreprocessError) -> Self {
+        match error {
+            PreprocessError::NoObjects(_) => Self::NoObjects,
+            PreprocessError::UnknownSlicer | PreprocessError::BinaryInput => Self::UnknownSlicer,
+            PreprocessError::IoError(_)
+            | PreprocessError::RewindError
+            | PreprocessError::ReadError
+            | PreprocessError::WriteError
+            | PreprocessError::CreateOutputDirectory
+            | PreprocessError::CreateArchiveDirectory
+            | PreprocessError::TempFile
+            | PreprocessError::FlushTempFile
+            | PreprocessError::ReadLineError(..) => Self::Io,
+            PreprocessError::InvalidLayerFilter => Self::InvalidFilter,
+            PreprocessError::AlreadyProcessed | PreprocessError::Archived(_) => {
+                Self::AlreadyProcessed
+            }
+            PreprocessError::MalformedLine(..)
+            | PreprocessError::UnbalancedMarkers(_)
+            | PreprocessError::OverlappingObjects(..)
+            | PreprocessError::Warnings(_) => Self::Validation,
+            PreprocessError::Other => Self::Failure,
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_from_error() {
+        let status = |e: anyhow::Error| ExitStatus::from_error(&e);
+
+        assert_eq!(
+            status(PreprocessError::NoObjects("Cura".into()).into()),
+            ExitStatus::NoObjects
+        );
+        assert_eq!(
+            status(PreprocessError::UnknownSlicer.into()),
+            ExitStatus::UnknownSlicer
+        );
+        assert_eq!(
+            status(PreprocessError::AlreadyProcessed.into()),
+            ExitStatus::AlreadyProcessed
+        );
+        assert_eq!(
+            status(PreprocessError::InvalidLayerFilter.into()),
+            ExitStatus::InvalidFilter
+        );
+        assert_eq!(status(std::io::Error::other("busy").into()), ExitStatus::Io);
+        assert_eq!(
+            status(ValidationFailed("1 file changed".into()).into()),
+            ExitStatus::Validation
+        );
+        assert_eq!(
+            status(HookError::Failed("exit status: 1".into()).into()),
+            ExitStatus::HookFailed
+        );
+        assert_eq!(status(anyhow::anyhow!("failed")), ExitStatus::Failure);
+
+        // The cause is found behind the failed file, unless files were processed before it
+        let failed = |processed| {
+            Err::<(), _>(PreprocessError::Archived("raw/a.gcode".into()))
+                .context(FailedFile {
+                    file: "a.gcode".into(),
+                    processed,
+                })
+                .unwrap_err()
+        };
+        assert_eq!(status(failed(0)), ExitStatus::AlreadyProcessed);
+        assert_eq!(status(failed(2)), ExitStatus::PartialBatch);
+    }
+
+    #[test]
+    fn test_help() {
+        let help = ExitStatus::help();
+        assert_eq!(help.lines().count(), ExitStatus::ALL.len() + 1);
+        assert!(help.contains("   3  A file has no objects"), "{help}");
+        for (code, status) in ExitStatus::ALL.iter().enumerate() {
+            assert_eq!(usize::from(status.code()), code);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::klipper::{extended_params, split_command};
+use crate::lines::LineReader;
+use crate::motion::Toolhead;
+use std::io::{self, BufRead, Write};
+
+/// An exclude object command and the upper-cased object it names
+enum ObjectCommand {
+    Define(Option<String>),
+    Start(Option<String>),
+    End,
+}
+
+impl ObjectCommand {
+    fn parse(command: &str, args: &str) -> Option<Self> {
+        let name = || {
+            extended_params(args)
+                .ok()
+                .and_then(|mut params| params.remove("NAME"))
+                .map(|name| name.to_uppercase())
+        };
+
+        match command.to_uppercase().as_str() {
+            "EXCLUDE_OBJECT_DEFINE" => Some(Self::Define(name())),
+            "EXCLUDE_OBJECT_START" => Some(Self::Start(name())),
+            "EXCLUDE_OBJECT_END" => Some(Self::End),
+            _ => None,
+        }
+    }
+}
+
+/// G0-G3, the commands moving the toolhead
+fn is_move(command: &str) -> bool {
+    command
+        .strip_prefix(['G', 'g'])
+        .and_then(|number| number.parse::<u32>().ok())
+        .is_some_and(|number| number <= 3)
+}
+
+/// Copy the G-Code printing a single object, as if every other object was excluded in Klipper.
+///
+/// Lines outside of objects are kept, like the start G-Code, layer changes and skirts. Moves of
+/// other objects are skipped, their other commands like temperature or fan changes are kept.
+/// Before the next move after skipped objects the extruder position is restored and the toolhead
+/// moves to where the objects left it, so the rest of the file continues from the same state.
+///
+/// Returns how often the object was started, usually once per layer.
+pub(crate) fn extract_object(
+    input: impl BufRead,
+    name: &str,
+    output: &mut impl Write,
+) -> io::Result<usize> {
+    let name = name.to_uppercase();
+    let mut lines = LineReader::untruncated(input);
+    let mut toolhead = Toolhead::default();
+    let mut feedrate: Option<String> = None;
+    // The object being skipped
+    let mut skipping: Option<String> = None;
+    // Skipped objects moved the toolhead since the last move written
+    let mut moved = false;
+    let mut starts = 0;
+
+    while let Some((_, line)) = lines.next_line()? {
+        let command = split_command(line);
+        match command.and_then(|(command, args)| ObjectCommand::parse(command, args)) {
+            Some(ObjectCommand::Define(Some(object))) if object != name => continue,
+            Some(ObjectCommand::Start(Some(object))) if object != name => {
+                skipping = Some(object);
+                continue;
+            }
+            Some(ObjectCommand::Start(_)) => {
+                skipping = None;
+                starts += 1;
+            }
+            Some(ObjectCommand::End) if skipping.is_some() => {
+                skipping = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let moving = command.is_some_and(|(command, _)| is_move(command));
+        if moving && skipping.is_none() && std::mem::take(&mut moved) {
+            resume(&toolhead, feedrate.as_deref(), output)?;
+        }
+
+        toolhead.apply_line(line);
+        match command {
+            Some((_, args)) if moving => {
+                if let Some(value) = args
+                    .split_whitespace()
+                    .find_map(|word| word.strip_prefix(['F', 'f']))
+                {
+                    feedrate = Some(value.to_string());
+                }
+                if skipping.is_some() {
+                    moved = true;
+                    continue;
+                }
+            }
+            // Comments of skipped objects are dropped along with their moves
+            None if skipping.is_some() => continue,
+            _ => {}
+        }
+
+        output.write_all(line.as_bytes())?;
+        output.write_all(b"\n")?;
+    }
+
+    Ok(starts)
+}
+
+/// Restore the state skipped objects left behind: the extruder position with absolute
+/// extrusion, and the position of the toolhead with the last feedrate
+fn resume(toolhead: &Toolhead, feedrate: Option<&str>, output: &mut impl Write) -> io::Result<()> {
+    writeln!(output, "; resume after skipped objects")?;
+    if let Some(e) = toolhead.absolute_e() {
+        writeln!(output, "G92 E{e:.5}")?;
+    }
+
+    let (x, y, z) = toolhead.logical_position();
+    let axes: String = [("X", x), ("Y", y), ("Z", z)]
+        .into_iter()
+        .filter_map(|(axis, value)| value.map(|value| format!(" {axis}{value:.3}")))
+        .collect();
+    if axes.is_empty() {
+        return Ok(());
+    }
+    let feedrate = feedrate.map(|f| format!(" F{f}")).unwrap_or_default();
+
+    if toolhead.is_relative() {
+        writeln!(output, "G90\nG1{axes}{feedrate}\nG91")
+    } else {
+        writeln!(output, "G1{axes}{feedrate}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(gcode: &str, name: &str) -> (String, usize) {
+        let mut output = Vec::new();
+        let starts = extract_object(gcode.as_bytes(), name, &mut output).unwrap();
+        (String::from_utf8(output).unwrap(), starts)
+    }
+
+    const GCODE: &str = "EXCLUDE_OBJECT_DEFINE NAME=a CENTER=15,10\n\
+        EXCLUDE_OBJECT_DEFINE NAME=b CENTER=35,30\n\
+        G28\n\
+        M82\n\
+        G1 Z0.2 F600\n\
+        EXCLUDE_OBJECT_START NAME=a\n\
+        ; perimeter\n\
+        G1 X10 Y10 F3000\n\
+        G1 X20 Y10 E1\n\
+        M106 S255\n\
+        EXCLUDE_OBJECT_END NAME=a\n\
+        EXCLUDE_OBJECT_START NAME=b\n\
+        G1 X30 Y30\n\
+        G1 X40 Y30 E2 F1200\n\
+        EXCLUDE_OBJECT_END NAME=b\n\
+        G1 Z0.4\n\
+        EXCLUDE_OBJECT_START NAME=a\n\
+        G1 X20 Y12 E3\n\
+        EXCLUDE_OBJECT_END NAME=a\n";
+
+    #[test]
+    fn test_extract_object() {
+        assert_eq!(
+            extract(GCODE, "b"),
+            (
+                "EXCLUDE_OBJECT_DEFINE NAME=b CENTER=35,30\n\
+                G28\n\
+                M82\n\
+                G1 Z0.2 F600\n\
+                M106 S255\n\
+                EXCLUDE_OBJECT_START NAME=b\n\
+                ; resume after skipped objects\n\
+                G92 E1.00000\n\
+                G1 X20.000 Y10.000 Z0.200 F3000\n\
+                G1 X30 Y30\n\
+                G1 X40 Y30 E2 F1200\n\
+                EXCLUDE_OBJECT_END NAME=b\n\
+                G1 Z0.4\n"
+                    .to_string(),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn test_extract_relative_extrusion() {
+        let (output, starts) = extract(&GCODE.replace("M82", "M83"), "B");
+        assert_eq!(starts, 1);
+        assert!(!output.contains("G92"));
+        assert!(
+            output.contains("; resume after skipped objects\nG1 X20.000 Y10.000 Z0.200 F3000\n")
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use crate::lines::LineReader;
+use std::io::{self, BufRead, Write};
+
+/// Checksum of a line sent to the printer, the XOR of all bytes before the `*`
+pub(crate) fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+/// Split a line of the form `N<line> <command>*<checksum>` into the line number and command
+pub(crate) fn unframe(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim();
+    let rest = line.strip_prefix(['N', 'n'])?;
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let number = rest[..digits].parse().ok()?;
+
+    let (command, checksum) = rest[digits..].rsplit_once('*')?;
+    if checksum.is_empty() || !checksum.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((number, command.trim()))
+}
+
+/// The number of the first command, if the file is numbered and checksummed for streaming
+/// over a serial connection
+pub(crate) fn first_line_number(input: &mut impl BufRead) -> io::Result<Option<usize>> {
+    let mut lines = LineReader::new(input);
+    while let Some((_, line)) = lines.next_line()? {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        return Ok(unframe(line).map(|(number, _)| number));
+    }
+
+    Ok(None)
+}
+
+/// Copy numbered lines to the output without their line numbers and checksums
+pub(crate) fn strip_framing(input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut lines = LineReader::untruncated(input);
+    while let Some((_, line)) = lines.next_line()? {
+        let line = unframe(line).map_or(line, |(_, command)| command);
+        output.write_all(line.as_bytes())?;
+        output.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Numbers every command written and adds its checksum.
+///
+/// Comments and blank lines are written unchanged. `M110 N<line>` resets the line number the
+/// printer expects next, so the numbering continues from there.
+pub(crate) struct FramingWriter<W: Write> {
+    inner: W,
+    next: usize,
+    line: Vec<u8>,
+    framed: Vec<u8>,
+}
+
+impl<W: Write> FramingWriter<W> {
+    pub fn new(inner: W, first: usize) -> Self {
+        Self {
+            inner,
+            next: first,
+            line: Vec::new(),
+            framed: Vec::new(),
+        }
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        let content = self.line.trim_ascii();
+        if content.is_empty() || content.starts_with(b";") {
+            self.inner.write_all(&self.line)?;
+            self.inner.write_all(b"\n")?;
+            self.line.clear();
+            return Ok(());
+        }
+
+        let number = reset_line_number(content).unwrap_or(self.next);
+        self.next = number + 1;
+
+        self.framed.clear();
+        write!(self.framed, "N{number} ")?;
+        self.framed.extend_from_slice(content);
+        let checksum = checksum(&self.framed);
+        writeln!(self.framed, "*{checksum}")?;
+        self.inner.write_all(&self.framed)?;
+        self.line.clear();
+
+        Ok(())
+    }
+
+    /// Write a last line without line break and flush the output
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            self.write_line()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// The line number set by a `M110 N<line>` command
+fn reset_line_number(content: &[u8]) -> Option<usize> {
+    let content = std::str::from_utf8(content).ok()?;
+    let mut words = content.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("M110") {
+        return None;
+    }
+    words.find_map(|word| word.strip_prefix(['N', 'n'])?.parse().ok())
+}
+
+impl<W: Write> Write for FramingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|byte| *byte == b'\n') {
+            self.line.extend_from_slice(&rest[..end]);
+            self.write_line()?;
+            rest = &rest[end + 1..];
+        }
+        self.line.extend_from_slice(rest);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unframe() {
+        assert_eq!(unframe("N0 M110 N0*125"), Some((0, "M110 N0")));
+        assert_eq!(unframe("N12 G1 X1.5 E0.2*33"), Some((12, "G1 X1.5 E0.2")));
+        assert_eq!(unframe("G1 X1"), None);
+        assert_eq!(unframe("N12 G1 X1"), None);
+        assert_eq!(unframe("NAME=a*b"), None);
+        assert_eq!(checksum(b"N0 M110 N0"), 125);
+    }
+
+    #[test]
+    fn test_first_line_number() {
+        let framed = "; comment\n\nN5 G28*20\nN6 G1 X1*97\n";
+        assert_eq!(first_line_number(&mut framed.as_bytes()).unwrap(), Some(5));
+        let plain = "; N1 G28*18\nG28\n";
+        assert_eq!(first_line_number(&mut plain.as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_framing_round_trip() {
+        let mut output = Vec::new();
+        let mut writer = FramingWriter::new(&mut output, 1);
+        writer
+            .write_all(b"; header\nG28\n\nEXCLUDE_OBJECT_START NAME=a\nM110 N10\nG1 X1")
+            .unwrap();
+        writer.finish().unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "; header");
+        assert_eq!(lines[1], format!("N1 G28*{}", checksum(b"N1 G28")));
+        assert_eq!(lines[2], "");
+        assert!(lines[3].starts_with("N2 EXCLUDE_OBJECT_START NAME=a*"));
+        assert!(lines[4].starts_with("N10 M110 N10*"));
+        assert!(lines[5].starts_with("N11 G1 X1*"));
+
+        let mut stripped = Vec::new();
+        strip_framing(output.as_bytes(), &mut stripped).unwrap();
+        assert_eq!(
+            String::from_utf8(stripped).unwrap(),
+            "; header\nG28\n\nEXCLUDE_OBJECT_START NAME=a\nM110 N10\nG1 X1\n"
+        );
+    }
+}
@@ -1,75 +1,386 @@
-use crate::hulls::KnownObject;
+use crate::cache::{options_hash, Fnv64};
+use crate::groups::objects_by_group;
+use crate::hulls::{HullMode, KnownObject};
+use crate::lines::LineReader;
+pub(crate) use crate::parser::{parse_float, parse_gcode, Command};
+use crate::preprocess::ProcessOptions;
 use clap::__derive_refs::once_cell;
+use clap::ValueEnum;
 use generator::{done, Generator, Gn};
 use geo::{HasDimensions, Point};
+use itertools::Itertools;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead};
+
+const HEADER_MARKER_PREFIX: &str =
+    "; Pre-Processed for Cancel-Object support by preprocess_cancellation";
+const PROVENANCE_PREFIX: &str = "; provenance ";
+/// Marks the progress commands added after elapsed time comments
+const PROGRESS_SUFFIX: &str = " ; progress";
+/// Marks processed files at their end when the header marker is disabled or replaced
+const TRAILER_PREFIX: &str = "; Cancel-Object support added by preprocess_cancellation";
 
 static HEADER_MARKER: Lazy<String> = Lazy::new(|| {
     let version =
         option_env!("CARGO_PKG_VERSION").map_or("".to_string(), |version| format!(" v{version}"));
 
-    format!("; Pre-Processed for Cancel-Object support by preprocess_cancellation{version}\n")
+    format!("{HEADER_MARKER_PREFIX}{version}\n")
 });
 
+static TRAILER: Lazy<String> = Lazy::new(|| format!("{TRAILER_PREFIX} v{}\n", tool_version()));
+
+/// Version of this tool, as written into the header marker
+pub(crate) fn tool_version() -> &'static str {
+    option_env!("CARGO_PKG_VERSION").unwrap_or_default()
+}
+
+/// The version of the tool that wrote a header marker line, empty for markers without one
+pub(crate) fn header_marker_version(line: &str) -> Option<&str> {
+    line.strip_prefix(HEADER_MARKER_PREFIX)
+        .map(|version| version.trim().trim_start_matches('v'))
+}
+
+/// The version of the tool that wrote a trailer line
+pub(crate) fn trailer_version(line: &str) -> Option<&str> {
+    line.strip_prefix(TRAILER_PREFIX)
+        .map(|version| version.trim().trim_start_matches('v'))
+}
+
+/// The line written after the last line of the file, in place of the header marker
+pub(crate) fn trailer(options: &ProcessOptions) -> Option<&'static str> {
+    (!options.header_marker || options.header_text.is_some()).then(|| TRAILER.as_str())
+}
+
+/// Check that the text fits on a single line and make it a comment
+pub(crate) fn parse_header_text(value: &str) -> Result<String, String> {
+    if value.trim().is_empty() || value.chars().any(char::is_control) {
+        return Err("the header text must be a single line".into());
+    }
+
+    Ok(match value.starts_with(';') {
+        true => value.to_string(),
+        false => format!("; {value}"),
+    })
+}
+
+/// The number of objects announced by the header
+pub(crate) fn known_objects_count(line: &str) -> Option<usize> {
+    line.strip_prefix("; ")?
+        .strip_suffix(" known objects")?
+        .parse()
+        .ok()
+}
+
+/// What produced the object outlines of a file, recorded in the header so prints can be audited
+/// and processing again notices changed options
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Provenance {
+    pub slicer: String,
+    /// Hash of the input lines without the markers of earlier runs, so it doesn't change by
+    /// processing the file again with its line breaks converted
+    pub input: String,
+    /// Hash of the options affecting the output, see [`options_hash`]
+    pub options: String,
+}
+
+impl Provenance {
+    /// Hash the rest of the input and the options
+    pub fn new(
+        slicer: &str,
+        input: &mut impl BufRead,
+        options: &ProcessOptions,
+    ) -> io::Result<Self> {
+        let mut hash = Fnv64::new();
+        let mut lines = LineReader::untruncated(input);
+        while let Some((_, line)) = lines.next_line()? {
+            hash.write(line.as_bytes());
+            hash.write(b"\n");
+        }
+
+        Ok(Self {
+            slicer: slicer.to_string(),
+            input: format!("{:016x}", hash.0),
+            options: options_hash(options),
+        })
+    }
+}
+
+/// The options hash of a provenance comment
+pub(crate) fn provenance_options(line: &str) -> Option<&str> {
+    line.strip_prefix(PROVENANCE_PREFIX)?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("options="))
+}
+
+/// Whether a line is a provenance comment
+pub(crate) fn is_provenance(line: &str) -> bool {
+    line.starts_with(PROVENANCE_PREFIX)
+}
+
+/// M73 command reporting the progress at the elapsed time of a print taking `total` seconds
+pub(crate) fn progress_command(elapsed: f64, total: f64) -> String {
+    let percent = (elapsed / total * 100.0).round().clamp(0.0, 100.0) as u32;
+    let remaining = ((total - elapsed).max(0.0) / 60.0).ceil() as u64;
+    format!("M73 P{percent} R{remaining}{PROGRESS_SUFFIX}\n")
+}
+
+/// Whether a line is a progress command added by [`progress_command`]
+pub(crate) fn is_progress(line: &str) -> bool {
+    line.starts_with("M73 ") && line.ends_with(PROGRESS_SUFFIX)
+}
+
+/// Whether a line starts with a command or comment, ignoring case, indentation and the
+/// whitespace after the `;` of comments
+pub(crate) fn starts_with_marker(line: &str, marker: &str) -> bool {
+    strip_marker(line, marker).is_some()
+}
+
+/// The rest of a line after a command or comment, matched like [`starts_with_marker`]
+pub(crate) fn strip_marker<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    let line = line.trim_start();
+    let (line, marker) = match (line.strip_prefix(';'), marker.strip_prefix(';')) {
+        (Some(line), Some(marker)) => (line.trim_start(), marker.trim_start()),
+        (None, Some(_)) => return None,
+        _ => (line, marker),
+    };
+
+    line.get(..marker.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(marker))
+        .map(|_| &line[marker.len()..])
+}
+
+/// Round to the same precision used for CENTER coordinates
+fn round_coord(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
 fn dump_coords(point: &Point) -> String {
     format!("{x:0.3},{y:0.3}", x = point.x(), y = point.y())
 }
 
-pub(crate) struct Command<'a> {
-    #[allow(dead_code)]
-    pub command: Option<&'a str>,
-    pub params: HashMap<&'a str, &'a str>,
+/// Parameters of a G0-G3 move
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct MoveParams {
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+    pub e: Option<f64>,
+    pub i: Option<f64>,
+    pub j: Option<f64>,
+    pub r: Option<f64>,
 }
 
-pub(crate) fn parse_gcode(line: &str) -> Command {
-    // Drop the comment
-    let line = match line.split_once(';') {
-        None => line.trim(),
-        Some((line, _comment)) => line.trim(),
-    };
+impl MoveParams {
+    pub fn from_command(command: &Command) -> Self {
+        let param = |name| command.params.get(name).and_then(|v| parse_float(v));
+        Self {
+            x: param("X"),
+            y: param("Y"),
+            z: param("Z"),
+            e: param("E"),
+            i: param("I"),
+            j: param("J"),
+            r: param("R"),
+        }
+    }
+
+    fn slot(&mut self, name: u8) -> Option<&mut Option<f64>> {
+        match name {
+            b'X' => Some(&mut self.x),
+            b'Y' => Some(&mut self.y),
+            b'Z' => Some(&mut self.z),
+            b'E' => Some(&mut self.e),
+            b'I' => Some(&mut self.i),
+            b'J' => Some(&mut self.j),
+            b'R' => Some(&mut self.r),
+            _ => None,
+        }
+    }
+}
 
+/// Fast path for the moves making up most of a G-Code file.
+///
+/// Scans a `G0`-`G3` line for its parameters directly instead of collecting them into the
+/// `HashMap` of [`parse_gcode`]. Returns the move number and its parameters, `None` for all
+/// other commands and for lines the fast path can't handle, which need [`parse_gcode`].
+pub(crate) fn parse_move(line: &str) -> Option<(u32, MoveParams)> {
+    let line = line.split_once(';').map_or(line, |(line, _comment)| line);
     let mut parts = line.split_whitespace();
-    let command = parts.next();
 
-    let mut parsed = HashMap::new();
+    let command = parts.next()?;
+    let number = command.strip_prefix(['G', 'g'])?.parse::<u32>().ok()?;
+    if number > 3 {
+        return None;
+    }
 
+    let mut params = MoveParams::default();
     for param in parts {
-        if param.contains('=') {
-            param
-                .split_once('=')
-                .map(|(key, value)| parsed.insert(key, value));
-        } else {
-            parsed.insert(&param[0..1], &param[1..]);
+        let name = *param.as_bytes().first()?;
+        if !name.is_ascii() || param.contains('=') {
+            return None;
+        }
+        if let Some(slot) = params.slot(name) {
+            *slot = parse_float(&param[1..]);
         }
     }
 
-    Command {
-        command,
-        params: parsed,
-    }
+    Some((number, params))
 }
 
-pub(crate) fn exclude_object_header(
-    known_objects: &HashMap<String, KnownObject>,
-) -> Generator<'_, (), String> {
+pub(crate) fn exclude_object_header<'a>(
+    known_objects: &'a HashMap<String, KnownObject>,
+    options: &'a ProcessOptions,
+) -> Generator<'a, (), String> {
     Gn::new_scoped(move |mut s| {
+        // Sorted by name, so the same plate always gets the same header
+        let objects: Vec<&KnownObject> = known_objects
+            .values()
+            .sorted_by(|a, b| a.name.cmp(&b.name))
+            .collect();
+
         s.yield_with("\n\n".into());
-        s.yield_with(HEADER_MARKER.to_string());
+        match &options.header_text {
+            Some(text) => s.yield_with(format!("{text}\n")),
+            None if options.header_marker => s.yield_with(HEADER_MARKER.to_string()),
+            None => {}
+        }
         s.yield_with(format!(
             "; {count} known objects\n",
             count = known_objects.len()
         ));
+        if let Some(provenance) = &options.provenance {
+            s.yield_with(provenance_comment(provenance, options));
+        }
+
+        if options.name_comments {
+            for known_object in &objects {
+                s.yield_with(format!(
+                    "; object '{original}' -> NAME={name}\n",
+                    original = known_object.original_name,
+                    name = known_object.name
+                ));
+            }
+        }
+
+        // The tools are only listed for plates printed with more than one
+        let extruders = objects_by_extruder(known_objects.values());
+        let multi_tool = extruders.len() > 1;
+        if options.object_metadata && multi_tool {
+            for (extruder, names) in &extruders {
+                s.yield_with(format!(
+                    "; extruder {extruder} OBJECTS={names}\n",
+                    names = names.join(",")
+                ));
+            }
+        }
+
+        for known_object in &objects {
+            if options.object_metadata {
+                s.yield_from(object_metadata(known_object, multi_tool));
+            }
+            s.yield_from(exclude_object_define(known_object, options.wkt));
+        }
 
-        for known_object in known_objects.values() {
-            s.yield_from(exclude_object_define(known_object));
+        for (group, names) in objects_by_group(known_objects.values()) {
+            let names = names.join(",");
+            s.yield_with(format!("; group NAME={group} OBJECTS={names}\n"));
+            if let Some(group_macro) = &options.group_macro {
+                s.yield_with(format!("{group_macro} GROUP={group} OBJECTS={names}\n"));
+            }
         }
 
+        if !options.cancel_extruders.is_empty() {
+            let cancelled = known_objects
+                .values()
+                .filter(|known_object| known_object.printed_only_with(&options.cancel_extruders))
+                .map(|known_object| &known_object.name)
+                .sorted();
+            for name in cancelled {
+                s.yield_with(format!("EXCLUDE_OBJECT NAME={name}\n"));
+            }
+        }
+
+        done!()
+    })
+}
+
+/// Comment with the settings the outlines were generated with, the slicer and the hashes
+fn provenance_comment(provenance: &Provenance, options: &ProcessOptions) -> String {
+    let hull = &options.hull;
+    let mode = hull.mode.to_possible_value().map_or_else(
+        || format!("{:?}", hull.mode),
+        |value| value.get_name().to_string(),
+    );
+    let concavity = match hull.mode {
+        HullMode::Concave => format!(" concavity={}", hull.concavity),
+        _ => String::new(),
+    };
+
+    format!(
+        "{PROVENANCE_PREFIX}slicer={slicer} layers={layers} hull={mode}{concavity} \
+        input={input} options={options}\n",
+        slicer = provenance.slicer,
+        layers = options.layer_filter,
+        input = provenance.input,
+        options = provenance.options,
+    )
+}
+
+/// Comment with the Z range and layer count of an object, for frontends and macros
+/// Sorted names of the objects printed by each tool
+fn objects_by_extruder<'a>(
+    known_objects: impl Iterator<Item = &'a KnownObject>,
+) -> BTreeMap<u32, Vec<&'a str>> {
+    let mut extruders: BTreeMap<u32, Vec<&str>> = BTreeMap::new();
+    for known_object in known_objects {
+        for extruder in &known_object.extruders {
+            extruders
+                .entry(*extruder)
+                .or_default()
+                .push(&known_object.name);
+        }
+    }
+    extruders
+        .values_mut()
+        .for_each(|names| names.sort_unstable());
+    extruders
+}
+
+fn object_metadata(known_object: &KnownObject, extruders: bool) -> Generator<'_, (), String> {
+    Gn::new_scoped(move |mut s| {
+        s.yield_with(format!("; object NAME={name}", name = known_object.name));
+        if let Some((min, max)) = known_object.z_range {
+            s.yield_with(format!(
+                " Z_MIN={min} Z_MAX={max}",
+                min = round_coord(min),
+                max = round_coord(max)
+            ));
+        }
+        s.yield_with(format!(" LAYERS={}", known_object.layers()));
+        if extruders {
+            s.yield_with(format!(
+                " EXTRUDERS={}",
+                known_object.extruders.iter().join(",")
+            ));
+        }
+        s.yield_with("\n".to_string());
+
         done!()
     })
 }
 
-fn exclude_object_define(known_object: &KnownObject) -> Generator<'_, (), String> {
+/// Polygon in Well-Known-Text format
+fn to_wkt(points: &[(f64, f64)]) -> String {
+    if points.is_empty() {
+        return "POLYGON EMPTY".to_string();
+    }
+
+    let coords: Vec<String> = points.iter().map(|(x, y)| format!("{x} {y}")).collect();
+    format!("POLYGON (({}))", coords.join(", "))
+}
+
+fn exclude_object_define(known_object: &KnownObject, wkt: bool) -> Generator<'_, (), String> {
     Gn::new_scoped(move |mut s| {
         s.yield_with(format!(
             "EXCLUDE_OBJECT_DEFINE NAME={name}",
@@ -79,9 +390,12 @@ fn exclude_object_define(known_object: &KnownObject) -> Generator<'_, (), String
             s.yield_with(format!(" CENTER={center}", center = dump_coords(&center)));
         }
 
-        let polygon = known_object.hull.exterior();
+        let polygon = known_object.exterior();
+        let points: Vec<(f64, f64)> = polygon
+            .iter()
+            .map(|p| (round_coord(p.x()), round_coord(p.y())))
+            .collect();
         if !polygon.is_empty() {
-            let points: Vec<(f64, f64)> = polygon.iter().map(|p| (p.x(), p.y())).collect();
             if let Ok(coords) = serde_json::to_string(&points) {
                 s.yield_with(format!(" POLYGON={coords}", coords = coords));
             }
@@ -89,6 +403,14 @@ fn exclude_object_define(known_object: &KnownObject) -> Generator<'_, (), String
 
         s.yield_with("\n".to_string());
 
+        if wkt {
+            s.yield_with(format!(
+                "; wkt NAME={name} {wkt}\n",
+                name = known_object.name,
+                wkt = to_wkt(&points)
+            ));
+        }
+
         done!()
     })
 }
@@ -100,9 +422,233 @@ pub(crate) fn exclude_object_start(name: &str) -> Generator<'_, (), String> {
     })
 }
 
+/// Comment after the start of an object, counting the layers of the object
+pub(crate) fn object_progress_comment(name: &str, layer: usize, layers: usize) -> String {
+    format!("; object {name} layer {layer}/{layers}\n")
+}
+
+/// Whether a line is a comment added by [`object_progress_comment`]
+pub(crate) fn is_object_progress(line: &str) -> bool {
+    line.strip_prefix("; object ")
+        .and_then(|rest| rest.rsplit_once(" layer "))
+        .and_then(|(_, count)| count.split_once('/'))
+        .is_some_and(|(layer, layers)| {
+            layer.parse::<usize>().is_ok() && layers.parse::<usize>().is_ok()
+        })
+}
+
 pub(crate) fn exclude_object_end(name: &str) -> Generator<'_, (), String> {
     Gn::new_scoped(move |mut s| {
         s.yield_with(format!("EXCLUDE_OBJECT_END NAME={name}\n"));
         done!()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_move() {
+        for line in [
+            "G1 X10.5 Y-3.25 E0.0125",
+            "g0 x1 Y2",
+            "G01 X1 Y2 F1500 ; comment with X5",
+            "G2 X10 Y10 I5 J0 E1.5",
+            "G3 X1 Y1 R-2.5",
+            "G1 X1 X2",
+            "G1 Xfoo Y1",
+            "G1 E-0.8 F2100",
+            "G1 X.5 Y-.25 E1.2e-05",
+            "G1 X+1.5E1 Y1e-3",
+            "G1",
+        ] {
+            let command = parse_gcode(line);
+            let (number, params) = parse_move(line).unwrap();
+            assert_eq!(
+                number,
+                command.command.unwrap()[1..].parse::<u32>().unwrap(),
+                "{line}"
+            );
+            assert_eq!(params, MoveParams::from_command(&command), "{line}");
+        }
+
+        for line in [
+            "G28", "G92 E0", "M83", "T1", "G1.5 X1", "", "; G1 X1", "G1 X=1",
+        ] {
+            assert_eq!(parse_move(line), None, "{line}");
+        }
+
+        // Lines the fast path leaves to `parse_gcode` must not panic there either
+        let command = parse_gcode("G1 X1 \u{e9}5");
+        assert_eq!(command.params.get("\u{e9}"), Some(&"5"));
+        assert_eq!(MoveParams::from_command(&command).x, Some(1.0));
+    }
+
+    #[test]
+    fn test_starts_with_marker() {
+        assert!(starts_with_marker(
+            "EXCLUDE_OBJECT_DEFINE NAME=a",
+            "EXCLUDE_OBJECT_DEFINE"
+        ));
+        assert!(starts_with_marker(
+            "  exclude_object_define NAME=a",
+            "EXCLUDE_OBJECT_DEFINE"
+        ));
+        assert!(starts_with_marker("m486 S1", "M486"));
+        assert!(starts_with_marker(
+            "\t;  Generated by PRUSASLICER 2.6.0",
+            "; generated by PrusaSlicer"
+        ));
+        assert!(starts_with_marker(
+            ";generated by PrusaSlicer",
+            "; generated by PrusaSlicer"
+        ));
+
+        assert!(!starts_with_marker(
+            "; EXCLUDE_OBJECT_DEFINE",
+            "EXCLUDE_OBJECT_DEFINE"
+        ));
+        assert!(!starts_with_marker("M486", "; M486"));
+        assert!(!starts_with_marker("M48", "M486"));
+    }
+
+    #[test]
+    fn test_strip_marker() {
+        assert_eq!(
+            strip_marker("  ;Printing Object cube id:0", "; printing object "),
+            Some("cube id:0")
+        );
+        assert_eq!(strip_marker(";mesh:part.stl", ";MESH:"), Some("part.stl"));
+        assert_eq!(strip_marker("\t;  LAYER:3", ";LAYER:"), Some("3"));
+        assert_eq!(strip_marker("m486 S1", "M486"), Some(" S1"));
+        assert_eq!(strip_marker(";MES", ";MESH:"), None);
+        assert_eq!(strip_marker(";M\u{e9}SH:a", ";MESH:"), None);
+        assert_eq!(strip_marker("MESH:a", ";MESH:"), None);
+    }
+
+    #[test]
+    fn test_header_marker_version() {
+        assert_eq!(
+            header_marker_version(HEADER_MARKER.trim_end()),
+            Some(tool_version())
+        );
+        assert_eq!(
+            header_marker_version(
+                "; Pre-Processed for Cancel-Object support by preprocess_cancellation v0.2.1"
+            ),
+            Some("0.2.1")
+        );
+        assert_eq!(
+            header_marker_version(
+                "; Pre-Processed for Cancel-Object support by preprocess_cancellation"
+            ),
+            Some("")
+        );
+        assert_eq!(header_marker_version("; generated by PrusaSlicer"), None);
+
+        assert_eq!(trailer_version(TRAILER.trim_end()), Some(tool_version()));
+        assert_eq!(trailer_version(HEADER_MARKER.trim_end()), None);
+    }
+
+    #[test]
+    fn test_parse_header_text() {
+        assert_eq!(parse_header_text("; objects").unwrap(), "; objects");
+        assert_eq!(parse_header_text("objects").unwrap(), "; objects");
+        assert!(parse_header_text(" ").is_err());
+        assert!(parse_header_text("a\nG28").is_err());
+    }
+
+    #[test]
+    fn test_provenance_comment() {
+        let provenance =
+            Provenance::new("Cura", &mut "G28\n".as_bytes(), &Default::default()).unwrap();
+        let crlf = Provenance::new("Cura", &mut "G28\r\n".as_bytes(), &Default::default()).unwrap();
+        assert_eq!(provenance, crlf);
+        assert_eq!(provenance.input.len(), 16);
+        let options = ProcessOptions {
+            layer_filter: "1-3".parse().unwrap(),
+            hull: crate::hulls::HullOptions {
+                mode: HullMode::Concave,
+                concavity: 1.5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let comment = provenance_comment(&provenance, &options);
+        assert_eq!(
+            comment,
+            format!(
+                "; provenance slicer=Cura layers=1-3 hull=concave concavity=1.5 input={} \
+                options={}\n",
+                provenance.input, provenance.options
+            )
+        );
+        assert!(is_provenance(&comment));
+        assert_eq!(
+            provenance_options(comment.trim_end()),
+            Some(provenance.options.as_str())
+        );
+        assert_eq!(provenance_options("; 2 known objects"), None);
+    }
+
+    #[test]
+    fn test_object_progress_comment() {
+        let comment = object_progress_comment("cube_stl", 3, 25);
+        assert_eq!(comment, "; object cube_stl layer 3/25\n");
+        assert!(is_object_progress(comment.trim_end()));
+        assert!(!is_object_progress("; object NAME=cube_stl Z_MIN=0.2"));
+        assert!(!is_object_progress("; object cube layer one/2"));
+    }
+
+    #[test]
+    fn test_progress_command() {
+        assert_eq!(progress_command(0.0, 600.0), "M73 P0 R10 ; progress\n");
+        assert_eq!(progress_command(150.0, 600.0), "M73 P25 R8 ; progress\n");
+        assert_eq!(progress_command(620.0, 600.0), "M73 P100 R0 ; progress\n");
+        assert!(is_progress("M73 P25 R8 ; progress"));
+        assert!(!is_progress("M73 P25 R8"));
+    }
+
+    #[test]
+    fn test_exclude_object_header_order() {
+        let options = ProcessOptions {
+            name_comments: true,
+            ..Default::default()
+        };
+        let header = |names: &[&str]| -> String {
+            let known_objects: HashMap<String, KnownObject> = names
+                .iter()
+                .map(|name| {
+                    let mut known_object = KnownObject::new(name, &options.hull);
+                    known_object.hull.add_point(1.0, 1.0);
+                    (name.to_string(), known_object)
+                })
+                .collect();
+            exclude_object_header(&known_objects, &options).collect()
+        };
+
+        let names: Vec<String> = (0..20).map(|i| format!("part {i}")).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let reversed: Vec<&str> = names.iter().rev().copied().collect();
+        let output = header(&names);
+        assert_eq!(output, header(&reversed));
+
+        let defined: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("EXCLUDE_OBJECT_DEFINE NAME="))
+            .collect();
+        assert_eq!(defined.len(), 20);
+        assert!(defined.windows(2).all(|pair| pair[0] < pair[1]), "{output}");
+    }
+
+    #[test]
+    fn test_to_wkt() {
+        assert_eq!(to_wkt(&[]), "POLYGON EMPTY");
+        assert_eq!(
+            to_wkt(&[(0.0, 0.0), (1.5, 0.0), (1.5, 2.0), (0.0, 0.0)]),
+            "POLYGON ((0 0, 1.5 0, 1.5 2, 0 0))"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Groups of related objects, e.g. all copies of one part of an assembly.
+//!
+//! Objects are assigned to the first group with a matching pattern. The groups are listed in a
+//! comment manifest after the object definitions, and optionally passed to a macro, so a single
+//! macro can cancel all objects of a group.
+
+use crate::hulls::KnownObject;
+use regex::{Regex, RegexBuilder};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Error)]
+pub enum GroupError {
+    #[error("Invalid group {0}, expected NAME=PATTERN[,PATTERN...]")]
+    Invalid(String),
+}
+
+/// A named group and the patterns of the objects it contains
+#[derive(Clone, Debug)]
+pub(crate) struct ObjectGroup {
+    pub name: String,
+    patterns: Vec<String>,
+    regex: Regex,
+}
+
+impl fmt::Display for ObjectGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.patterns.join(","))
+    }
+}
+
+impl FromStr for ObjectGroup {
+    type Err = GroupError;
+
+    /// Parse `NAME=PATTERN[,PATTERN...]`, `*` and `?` in the patterns match any characters or a
+    /// single one
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || GroupError::Invalid(value.to_string());
+
+        let (name, patterns) = value.split_once('=').ok_or_else(invalid)?;
+        let name = KnownObject::clean_id(name, None);
+        let patterns: Vec<String> = patterns
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(String::from)
+            .collect();
+        if name.is_empty() || patterns.is_empty() {
+            return Err(invalid());
+        }
+
+        let alternatives: Vec<String> = patterns
+            .iter()
+            .map(|pattern| {
+                regex::escape(pattern)
+                    .replace(r"\*", ".*")
+                    .replace(r"\?", ".")
+            })
+            .collect();
+        let regex = RegexBuilder::new(&format!("^(?:{})$", alternatives.join("|")))
+            .case_insensitive(true)
+            .build()
+            .map_err(|_err| invalid())?;
+
+        Ok(Self {
+            name,
+            patterns,
+            regex,
+        })
+    }
+}
+
+impl ObjectGroup {
+    /// Whether the name of the object in the slicer or its sanitized name match a pattern
+    fn matches(&self, known_object: &KnownObject) -> bool {
+        self.regex.is_match(&known_object.name) || self.regex.is_match(&known_object.original_name)
+    }
+}
+
+/// Assign the objects to the first matching group, optionally prefixing their names with the
+/// name of the group so frontends list the objects of a group together
+pub(crate) fn assign_groups(
+    known_objects: &mut HashMap<String, KnownObject>,
+    groups: &[ObjectGroup],
+    prefix: bool,
+) {
+    for known_object in known_objects.values_mut() {
+        known_object.group = groups
+            .iter()
+            .find(|group| group.matches(known_object))
+            .map(|group| group.name.clone());
+        if let (Some(group), true) = (&known_object.group, prefix) {
+            known_object.name = format!("{group}_{}", known_object.name);
+        }
+    }
+
+    for group in groups {
+        if !known_objects
+            .values()
+            .any(|known_object| known_object.group.as_ref() == Some(&group.name))
+        {
+            tracing::warn!("No objects match the group {}", group);
+        }
+    }
+}
+
+/// Sorted names of the objects of each group
+pub(crate) fn objects_by_group<'a>(
+    known_objects: impl Iterator<Item = &'a KnownObject>,
+) -> BTreeMap<&'a str, Vec<&'a str>> {
+    let mut groups: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for known_object in known_objects {
+        if let Some(group) = &known_object.group {
+            groups.entry(group).or_default().push(&known_object.name);
+        }
+    }
+    groups.values_mut().for_each(|names| names.sort_unstable());
+    groups
+}
+
+/// Whether a line of the header lists a group, as a comment or a macro call
+pub(crate) fn is_group_line(line: &str) -> bool {
+    line.starts_with("; group NAME=") || (line.contains(" GROUP=") && line.contains(" OBJECTS="))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hulls::HullOptions;
+
+    fn objects(names: &[&str]) -> HashMap<String, KnownObject> {
+        names
+            .iter()
+            .map(|name| {
+                let known_object = KnownObject::new(name, &HullOptions::default());
+                (name.to_string(), known_object)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let group: ObjectGroup = "Wheels=wheel*, Hub?.stl".parse().unwrap();
+        assert_eq!(group.name, "Wheels");
+        assert_eq!(group.to_string(), "Wheels=wheel*,Hub?.stl");
+        for invalid in ["wheels", "=wheel*", "wheels=", "wheels= , "] {
+            assert!(invalid.parse::<ObjectGroup>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_assign_groups() {
+        let mut known_objects = objects(&[
+            "wheel.stl id:0 copy 0",
+            "Wheel.stl id:0 copy 1",
+            "hub1.stl",
+            "hub12.stl",
+            "frame",
+        ]);
+        let groups = [
+            "wheels=wheel.stl*".parse().unwrap(),
+            "hubs=hub?.stl,frame".parse().unwrap(),
+            "wheels_again=wheel*".parse().unwrap(),
+        ];
+        assign_groups(&mut known_objects, &groups, false);
+
+        let by_group = objects_by_group(known_objects.values());
+        assert_eq!(
+            by_group,
+            BTreeMap::from([
+                ("hubs", vec!["frame", "hub1_stl"]),
+                (
+                    "wheels",
+                    vec!["Wheel_stl_id_0_copy_1", "wheel_stl_id_0_copy_0"]
+                ),
+            ])
+        );
+        assert_eq!(known_objects["hub12.stl"].group, None);
+
+        // Sanitized names match as well, and the names can be prefixed with the group
+        let mut known_objects = objects(&["hub1.stl"]);
+        assign_groups(
+            &mut known_objects,
+            &["hubs=hub1_stl".parse().unwrap()],
+            true,
+        );
+        assert_eq!(known_objects["hub1.stl"].name, "hubs_hub1_stl");
+    }
+
+    #[test]
+    fn test_is_group_line() {
+        assert!(is_group_line("; group NAME=wheels OBJECTS=a,b"));
+        assert!(is_group_line("DEFINE_GROUP GROUP=wheels OBJECTS=a,b"));
+        assert!(!is_group_line("; group of wheels"));
+        assert!(!is_group_line("G1 X1"));
+    }
+}
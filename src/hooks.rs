@@ -0,0 +1,101 @@
+use crate::report::ProcessReport;
+use std::process::Command;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("Error executing post-processing hook: {0}")]
+    Spawn(String),
+    #[error("Post-processing hook exited with {0}")]
+    Failed(String),
+}
+
+/// Run a command through the platform shell
+pub(crate) fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", cmd]);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.args(["-c", cmd]);
+        command
+    }
+}
+
+/// Run a user supplied command after a file has been processed successfully.
+///
+/// The command is run through the platform shell with the following environment variables set:
+///
+/// * `PREPROCESS_CANCELLATION_INPUT` - path of the original G-Code file
+/// * `PREPROCESS_CANCELLATION_OUTPUT` - path of the processed G-Code file
+/// * `PREPROCESS_CANCELLATION_OBJECTS` - comma separated list of object names
+/// * `PREPROCESS_CANCELLATION_OBJECT_COUNT` - number of known objects
+/// * `PREPROCESS_CANCELLATION_REPORT` - JSON document describing the processing result
+pub(crate) fn post_process(cmd: &str, report: &ProcessReport) -> Result<(), HookError> {
+    tracing::debug!("Running post-processing hook: {}", cmd);
+
+    let status = shell(cmd)
+        .env("PREPROCESS_CANCELLATION_INPUT", &report.input)
+        .env("PREPROCESS_CANCELLATION_OUTPUT", &report.output)
+        .env(
+            "PREPROCESS_CANCELLATION_OBJECTS",
+            report.object_names().join(","),
+        )
+        .env(
+            "PREPROCESS_CANCELLATION_OBJECT_COUNT",
+            report.objects.len().to_string(),
+        )
+        .env("PREPROCESS_CANCELLATION_REPORT", report.to_json())
+        .status()
+        .map_err(|err| HookError::Spawn(err.to_string()))?;
+
+    if !status.success() {
+        return Err(HookError::Failed(status.to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::report::ObjectReport;
+    use std::path::PathBuf;
+
+    fn report() -> ProcessReport {
+        ProcessReport {
+            input: PathBuf::from("in.gcode"),
+            output: PathBuf::from("out.gcode"),
+            objects: vec![
+                ObjectReport {
+                    name: "cube".into(),
+                    ..Default::default()
+                },
+                ObjectReport {
+                    name: "cylinder".into(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_post_hook_environment() {
+        let cmd = r#"test "$PREPROCESS_CANCELLATION_INPUT" = "in.gcode" \
+            && test "$PREPROCESS_CANCELLATION_OUTPUT" = "out.gcode" \
+            && test "$PREPROCESS_CANCELLATION_OBJECTS" = "cube,cylinder" \
+            && test "$PREPROCESS_CANCELLATION_OBJECT_COUNT" = "2""#;
+
+        assert!(post_process(cmd, &report()).is_ok());
+    }
+
+    #[test]
+    fn test_post_hook_failure() {
+        assert!(matches!(
+            post_process("exit 3", &report()),
+            Err(HookError::Failed(_))
+        ));
+    }
+}
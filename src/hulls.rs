@@ -1,12 +1,38 @@
+use crate::cache::Fnv64;
+use crate::layers::LayerFilter;
+use crate::transform::Affine;
 use dashmap::DashSet;
-use geo::{ConvexHull, MultiPoint, Point, Simplify};
+use geo::{
+    Area, ConcaveHull, ConvexHull, Coord, EuclideanDistance, LineString, MultiPoint, Point,
+    Polygon, Rect, Simplify, Winding,
+};
 use itertools::{Itertools, MinMaxResult};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use ordered_float::OrderedFloat;
 use regex::Regex;
+use std::collections::BTreeSet;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 static CLEAN_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\W+"#).unwrap());
 
+/// Hex digits of the hash added to truncated object names
+const NAME_HASH_DIGITS: usize = 8;
+/// Shortest name length that leaves room for a part of the name besides the hash
+pub(crate) const MIN_NAME_LENGTH: usize = 16;
+
+/// Maximum difference in mm between copies sharing an outline
+const COPY_TOLERANCE: f64 = 0.05;
+/// Number of points checked against the outline of the original of a copy
+const COPY_SAMPLES: usize = 64;
+
+/// Minimum padding around the bounding box used in place of a degenerate hull
+const DEGENERATE_PADDING: f64 = 0.5;
+
+/// Approximate memory used by a stored point, including the overhead of the hash set
+const BYTES_PER_POINT: usize = 40;
+
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 struct DecimalPoint {
     x: OrderedFloat<f64>,
@@ -22,70 +48,630 @@ impl DecimalPoint {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum HullMode {
+    /// Convex hull around all extrusion points
+    #[default]
+    Convex,
+    /// Concave hull hugging the outline of L- or ring-shaped objects more closely
+    Concave,
+    /// Axis aligned bounding box, only the extents are kept in memory
+    Bbox,
+}
+
+/// Limit on the number of points stored by all hull trackers of a file.
+///
+/// Clones share the count, so every tracker created from the same options draws from the
+/// same budget. Trackers release their points when they are dropped.
 #[derive(Clone, Debug, Default)]
+pub(crate) struct PointBudget {
+    limit: Option<usize>,
+    used: Arc<AtomicUsize>,
+}
+
+impl PointBudget {
+    pub fn from_megabytes(megabytes: usize) -> Self {
+        Self {
+            limit: Some(megabytes.saturating_mul(1024 * 1024) / BYTES_PER_POINT),
+            ..Default::default()
+        }
+    }
+
+    /// A budget with the same limit and no points used, for the next file
+    pub fn fresh(&self) -> Self {
+        Self {
+            limit: self.limit,
+            ..Default::default()
+        }
+    }
+
+    fn add(&self, points: usize) {
+        self.used.fetch_add(points, Ordering::Relaxed);
+    }
+
+    fn release(&self, points: usize) {
+        self.used.fetch_sub(points, Ordering::Relaxed);
+    }
+
+    /// Maximum number of points, `None` without a limit
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Points stored by all trackers
+    #[cfg(test)]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn exceeded(&self) -> bool {
+        self.limit
+            .is_some_and(|limit| self.used.load(Ordering::Relaxed) > limit)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct HullOptions {
+    pub mode: HullMode,
+    /// Relative measure of concavity for concave hulls, lower values produce tighter outlines
+    pub concavity: f64,
+    /// Extrusion line width, the hull is grown by half of it to cover the physical object
+    pub line_width: Option<f64>,
+    /// Points are snapped to a grid of this size to collapse nearly identical points
+    pub grid: Option<f64>,
+    /// Only every nth extrusion move contributes points
+    pub sample_every: Option<NonZeroUsize>,
+    /// Points stored by all trackers, exceeding it collapses points or falls back to bounds
+    pub budget: PointBudget,
+}
+
+impl Default for HullOptions {
+    fn default() -> Self {
+        Self {
+            mode: HullMode::default(),
+            concavity: 2.0,
+            line_width: None,
+            grid: None,
+            sample_every: None,
+            budget: PointBudget::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum PointStorage {
+    /// Every distinct extrusion point
+    Points(DashSet<DecimalPoint>),
+    /// Only the extents of all points seen so far
+    Bounds(Option<Rect>),
+}
+
+impl Default for PointStorage {
+    fn default() -> Self {
+        Self::Points(DashSet::default())
+    }
+}
+
+#[derive(Debug, Default)]
 pub(crate) struct HullTracker {
-    points: DashSet<DecimalPoint>,
+    points: PointStorage,
+    options: HullOptions,
+    /// Number of extrusion moves seen, for sampling
+    moves: usize,
+    /// Outline computed after all points have been collected
+    outline: OnceCell<(MultiPoint, bool)>,
 }
 
 impl HullTracker {
-    pub fn add_point(&self, x: f64, y: f64) {
-        self.points.insert(DecimalPoint::new(x, y));
+    pub fn new(options: &HullOptions) -> Self {
+        let points = match options.mode {
+            HullMode::Bbox => PointStorage::Bounds(None),
+            HullMode::Convex | HullMode::Concave => PointStorage::default(),
+        };
+
+        Self {
+            points,
+            options: options.clone(),
+            moves: 0,
+            outline: OnceCell::new(),
+        }
     }
 
-    pub fn center(&self) -> Option<Point> {
-        let x = match self.points.iter().map(|p| p.x).minmax() {
+    /// Number of points held against the budget
+    pub fn stored(&self) -> usize {
+        match &self.points {
+            PointStorage::Points(points) => points.len(),
+            PointStorage::Bounds(_) => 0,
+        }
+    }
+
+    /// Add the points of an extrusion move, skipping moves between samples
+    pub fn add_move(&mut self, points: &[(f64, f64)]) {
+        let sampled = self
+            .options
+            .sample_every
+            .is_none_or(|every| self.moves.is_multiple_of(every.get()));
+        self.moves += 1;
+
+        if sampled {
+            for (x, y) in points {
+                self.add_point(*x, *y);
+            }
+        }
+    }
+
+    pub fn add_point(&mut self, x: f64, y: f64) {
+        let (x, y) = match self.options.grid {
+            Some(grid) if grid > 0.0 => ((x / grid).round() * grid, (y / grid).round() * grid),
+            _ => (x, y),
+        };
+        self.outline.take();
+
+        match &mut self.points {
+            PointStorage::Points(points) => {
+                if points.insert(DecimalPoint::new(x, y)) {
+                    self.options.budget.add(1);
+                    if self.options.budget.exceeded() {
+                        self.shrink();
+                    }
+                }
+            }
+            PointStorage::Bounds(bounds) => {
+                let point = Coord { x, y };
+                *bounds = Some(match bounds {
+                    None => Rect::new(point, point),
+                    Some(rect) => Rect::new(
+                        Coord {
+                            x: rect.min().x.min(x),
+                            y: rect.min().y.min(y),
+                        },
+                        Coord {
+                            x: rect.max().x.max(x),
+                            y: rect.max().y.max(y),
+                        },
+                    ),
+                });
+            }
+        }
+    }
+
+    /// Free memory once the point budget is exceeded.
+    ///
+    /// The points are collapsed into the vertices of their hull, which doesn't change convex
+    /// hulls. When that doesn't free at least half of them, only the bounds are kept from now on.
+    fn shrink(&mut self) {
+        let PointStorage::Points(points) = &self.points else {
+            return;
+        };
+
+        let stored = points.len();
+        let hull = match self.options.mode {
+            HullMode::Concave => self.as_multipoint().concave_hull(self.options.concavity),
+            HullMode::Convex | HullMode::Bbox => self.as_multipoint().convex_hull(),
+        };
+        let vertices: DashSet<DecimalPoint> = hull
+            .exterior()
+            .points()
+            .map(|p| DecimalPoint::new(p.x(), p.y()))
+            .collect();
+
+        if vertices.len() * 2 <= stored {
+            tracing::debug!("Collapsed {} points into {}", stored, vertices.len());
+            self.options.budget.release(stored - vertices.len());
+            self.points = PointStorage::Points(vertices);
+        } else {
+            tracing::info!("Memory budget exceeded, keeping only the bounding box of an object");
+            self.options.budget.release(stored);
+            self.points = PointStorage::Bounds(self.bounds());
+        }
+    }
+
+    /// Add all points of another tracker
+    pub fn merge(&mut self, other: &HullTracker) {
+        match &other.points {
+            PointStorage::Points(points) => {
+                for point in points.iter() {
+                    self.add_point(point.x.into_inner(), point.y.into_inner());
+                }
+            }
+            PointStorage::Bounds(bounds) => {
+                if let Some(rect) = bounds {
+                    self.add_point(rect.min().x, rect.min().y);
+                    self.add_point(rect.max().x, rect.max().y);
+                }
+            }
+        }
+    }
+
+    pub fn bounds(&self) -> Option<Rect> {
+        let points = match &self.points {
+            PointStorage::Bounds(bounds) => return *bounds,
+            PointStorage::Points(points) => points,
+        };
+
+        let (min_x, max_x) = match points.iter().map(|p| p.x).minmax() {
             MinMaxResult::NoElements => return None,
-            MinMaxResult::OneElement(value) => value,
-            MinMaxResult::MinMax(min_x, max_x) => (min_x + max_x) / 2.0,
+            MinMaxResult::OneElement(value) => (value, value),
+            MinMaxResult::MinMax(min_x, max_x) => (min_x, max_x),
         };
-        let y = match self.points.iter().map(|p| p.y).minmax() {
+        let (min_y, max_y) = match points.iter().map(|p| p.y).minmax() {
             MinMaxResult::NoElements => return None,
-            MinMaxResult::OneElement(value) => value,
-            MinMaxResult::MinMax(min_y, max_y) => (min_y + max_y) / 2.0,
+            MinMaxResult::OneElement(value) => (value, value),
+            MinMaxResult::MinMax(min_y, max_y) => (min_y, max_y),
         };
 
-        Some(Point::new(x.into(), y.into()))
+        Some(Rect::new(
+            Coord {
+                x: min_x.into_inner(),
+                y: min_y.into_inner(),
+            },
+            Coord {
+                x: max_x.into_inner(),
+                y: max_y.into_inner(),
+            },
+        ))
+    }
+
+    pub fn center(&self) -> Option<Point> {
+        self.bounds().map(|bounds| bounds.center().into())
     }
 
     fn as_multipoint(&self) -> MultiPoint {
-        MultiPoint::new(
-            self.points
-                .iter()
-                .map(|p| Point::new(p.x.into(), p.y.into()))
-                .collect::<Vec<Point>>(),
-        )
+        match &self.points {
+            PointStorage::Points(points) => MultiPoint::new(
+                points
+                    .iter()
+                    .map(|p| Point::new(p.x.into(), p.y.into()))
+                    .collect::<Vec<Point>>(),
+            ),
+            PointStorage::Bounds(bounds) => bounds
+                .map(|rect| rect.to_polygon().exterior().points().collect())
+                .unwrap_or_else(|| MultiPoint::new(vec![])),
+        }
     }
+
     pub fn exterior(&self) -> MultiPoint {
-        self.as_multipoint()
-            .convex_hull()
-            .simplify(&0.02)
-            .exterior()
-            .points()
-            .collect()
+        self.outline().0
+    }
+
+    /// The outline of all points and whether the hull was degenerate.
+    ///
+    /// Collinear points or a single point don't enclose an area, a padded bounding box is
+    /// returned for those instead.
+    pub fn outline(&self) -> (MultiPoint, bool) {
+        self.outline.get_or_init(|| self.compute_outline()).clone()
+    }
+
+    /// Compute and cache the outline without cloning it
+    fn prepare_outline(&self) {
+        self.outline.get_or_init(|| self.compute_outline());
+    }
+
+    /// Use the outline of an identical copy, moved to the position of this one.
+    ///
+    /// A sample of the collected points is checked against the moved outline, copies that are
+    /// rotated or otherwise differ keep computing their own outline and `false` is returned.
+    pub fn reuse_outline(&mut self, original: &(MultiPoint, bool), original_bounds: Rect) -> bool {
+        let Some(bounds) = self.bounds() else {
+            return false;
+        };
+        if (bounds.width() - original_bounds.width()).abs() > COPY_TOLERANCE
+            || (bounds.height() - original_bounds.height()).abs() > COPY_TOLERANCE
+        {
+            return false;
+        }
+
+        let offset = bounds.center() - original_bounds.center();
+        let (exterior, degenerate) = original;
+        let exterior: MultiPoint = exterior
+            .iter()
+            .map(|p| Point::new(p.x() + offset.x, p.y() + offset.y))
+            .collect();
+
+        if let PointStorage::Points(points) = &self.points {
+            let polygon = Polygon::new(exterior.iter().map(|p| p.0).collect(), vec![]);
+            // The same sample in every run, a set iterates in a random order
+            let sample = points
+                .iter()
+                .map(|p| {
+                    let mut hash = Fnv64::new();
+                    hash.write(&p.x.to_le_bytes());
+                    hash.write(&p.y.to_le_bytes());
+                    (hash.0, p.x, p.y)
+                })
+                .k_smallest(COPY_SAMPLES);
+            let fits = sample.into_iter().all(|(_, x, y)| {
+                let point = Point::new(x.into_inner(), y.into_inner());
+                point.euclidean_distance(&polygon) <= COPY_TOLERANCE
+            });
+            if !fits {
+                return false;
+            }
+        }
+
+        self.outline = OnceCell::with_value((exterior, *degenerate));
+        true
+    }
+
+    /// A tracker holding the points and outline of this one transformed, e.g. mirrored
+    pub fn transformed(&self, transform: &Affine) -> Self {
+        let (exterior, degenerate) = self.outline();
+        let mut exterior: LineString = exterior
+            .iter()
+            .map(|p| Coord::from(transform.apply((p.x(), p.y()))))
+            .collect();
+        // Mirroring reverses the winding
+        exterior.make_ccw_winding();
+
+        let mut tracker = Self::new(&self.options);
+        for point in self.as_multipoint().iter() {
+            let (x, y) = transform.apply((point.x(), point.y()));
+            tracker.add_point(x, y);
+        }
+        tracker.outline = OnceCell::with_value((exterior.points().collect(), degenerate));
+        tracker
+    }
+
+    fn compute_outline(&self) -> (MultiPoint, bool) {
+        let hull = match self.options.mode {
+            HullMode::Convex | HullMode::Bbox => self.as_multipoint().convex_hull(),
+            HullMode::Concave => self.as_multipoint().concave_hull(self.options.concavity),
+        };
+
+        let hull = hull.simplify(&0.02);
+        let half_width = self.options.line_width.unwrap_or_default() / 2.0;
+
+        if hull.exterior().0.len() < 4 || hull.unsigned_area() <= f64::EPSILON {
+            let Some(bounds) = self.bounds() else {
+                return (MultiPoint::new(vec![]), false);
+            };
+
+            let padding = half_width.max(DEGENERATE_PADDING);
+            let padded = Rect::new(
+                Coord {
+                    x: bounds.min().x - padding,
+                    y: bounds.min().y - padding,
+                },
+                Coord {
+                    x: bounds.max().x + padding,
+                    y: bounds.max().y + padding,
+                },
+            );
+            let mut exterior = padded.to_polygon().exterior().clone();
+            exterior.make_ccw_winding();
+
+            return (exterior.points().collect(), true);
+        }
+
+        let hull = if half_width > 0.0 {
+            grow(hull, half_width)
+        } else {
+            hull
+        };
+
+        (hull.exterior().points().collect(), false)
+    }
+}
+
+impl Clone for HullTracker {
+    fn clone(&self) -> Self {
+        // The copy holds its points as well
+        self.options.budget.add(self.stored());
+        Self {
+            points: self.points.clone(),
+            options: self.options.clone(),
+            moves: self.moves,
+            outline: self.outline.clone(),
+        }
+    }
+}
+
+impl Drop for HullTracker {
+    fn drop(&mut self) {
+        self.options.budget.release(self.stored());
+    }
+}
+
+/// Compute the outlines of all objects up front, spread over the available cores.
+///
+/// Outlines are cached by their trackers, so the header doesn't compute one hull after another
+/// on plates with many objects. Threads take the next object as they finish, large objects
+/// don't hold up the small ones.
+pub(crate) fn compute_outlines<'a>(objects: impl IntoIterator<Item = &'a KnownObject>) {
+    let objects: Vec<&KnownObject> = objects.into_iter().collect();
+    let threads = std::thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(objects.len());
+    if threads <= 1 {
+        objects.iter().for_each(|ko| ko.hull.prepare_outline());
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                while let Some(ko) = objects.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    ko.hull.prepare_outline();
+                }
+            });
+        }
+    });
+}
+
+/// Offset the exterior of a polygon outwards by the given distance.
+///
+/// Vertices are moved along the bisector of the adjacent edge normals, sharp corners are
+/// limited to twice the distance to avoid long spikes.
+fn grow(polygon: Polygon, distance: f64) -> Polygon {
+    let mut exterior = polygon.exterior().clone();
+    exterior.make_ccw_winding();
+
+    // The ring is closed, the last coordinate repeats the first one
+    let coords = &exterior.0[..exterior.0.len().saturating_sub(1)];
+    if coords.len() < 3 {
+        return polygon;
+    }
+
+    let normal = |from: Coord, to: Coord| -> Option<Coord> {
+        let (dx, dy) = (to.x - from.x, to.y - from.y);
+        let length = dx.hypot(dy);
+        (length > f64::EPSILON).then(|| Coord {
+            x: dy / length,
+            y: -dx / length,
+        })
+    };
+
+    let mut grown: Vec<Coord> = Vec::with_capacity(coords.len() + 1);
+    for (i, &current) in coords.iter().enumerate() {
+        let previous = coords[(i + coords.len() - 1) % coords.len()];
+        let next = coords[(i + 1) % coords.len()];
+
+        let (Some(n1), Some(n2)) = (normal(previous, current), normal(current, next)) else {
+            grown.push(current);
+            continue;
+        };
+
+        let bisector = Coord {
+            x: n1.x + n2.x,
+            y: n1.y + n2.y,
+        };
+        let length = bisector.x.hypot(bisector.y);
+        let offset = if length > f64::EPSILON {
+            let direction = Coord {
+                x: bisector.x / length,
+                y: bisector.y / length,
+            };
+            let miter = (distance / (direction.x * n1.x + direction.y * n1.y)).min(2.0 * distance);
+            Coord {
+                x: direction.x * miter,
+                y: direction.y * miter,
+            }
+        } else {
+            Coord {
+                x: n1.x * distance,
+                y: n1.y * distance,
+            }
+        };
+
+        grown.push(Coord {
+            x: current.x + offset.x,
+            y: current.y + offset.y,
+        });
     }
+
+    Polygon::new(LineString::new(grown), vec![])
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct KnownObject {
     pub(crate) name: String,
+    /// Name of the object in the slicer, before sanitizing it
+    pub(crate) original_name: String,
     pub(crate) hull: HullTracker,
     pub(crate) layer: isize,
+    /// Tools used to extrude this object
+    pub(crate) extruders: BTreeSet<u32>,
+    /// Lowest and highest Z of extrusions within this object
+    pub(crate) z_range: Option<(f64, f64)>,
+    /// Total length of all extrusion moves within this object
+    pub(crate) extrusion_length: f64,
+    /// Layer of the first extrusion within this object
+    pub(crate) first_layer: Option<usize>,
+    /// Group of related objects this object belongs to
+    pub(crate) group: Option<String>,
 }
 
 impl KnownObject {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str, hull_options: &HullOptions) -> Self {
         Self {
-            name: Self::clean_id(name),
+            name: Self::clean_id(name, None),
+            original_name: name.to_string(),
+            hull: HullTracker::new(hull_options),
             ..Default::default()
         }
     }
 
-    fn clean_id(name: &str) -> String {
+    pub fn add_z(&mut self, z: f64) {
+        self.z_range = Some(match self.z_range {
+            None => (z, z),
+            Some((min, max)) => (min.min(z), max.max(z)),
+        });
+    }
+
+    /// Merge the extrusions of another part of the same object, optionally skipping its points
+    pub fn merge(&mut self, other: &KnownObject, with_points: bool) {
+        if with_points {
+            self.hull.merge(&other.hull);
+        }
+        if let Some((min, max)) = other.z_range {
+            self.add_z(min);
+            self.add_z(max);
+        }
+        self.extruders.extend(other.extruders.iter().copied());
+        self.extrusion_length += other.extrusion_length;
+    }
+
+    /// Whether points extruded on the given layer are collected for the outline
+    pub fn collects_layer(&mut self, layer: usize, layer_filter: &LayerFilter) -> bool {
+        let first_layer = *self.first_layer.get_or_insert(layer);
+        if layer_filter.is_per_object() {
+            layer_filter.contains(layer.saturating_sub(first_layer))
+        } else {
+            layer_filter.contains(layer)
+        }
+    }
+
+    /// Area covered by the outline of the object in mm²
+    pub fn area(&self) -> f64 {
+        Polygon::new(self.hull.exterior().into_iter().collect(), vec![]).unsigned_area()
+    }
+
+    /// Whether all extrusions of the object were made with the given tools
+    pub fn printed_only_with(&self, tools: &[u32]) -> bool {
+        !self.extruders.is_empty() && self.extruders.iter().all(|tool| tools.contains(tool))
+    }
+
+    /// Number of layers the object has been seen on
+    pub fn layers(&self) -> usize {
+        (self.layer + 1).max(0) as usize
+    }
+
+    /// The outline of the object, warns about degenerate hulls
+    pub fn exterior(&self) -> MultiPoint {
+        let (exterior, degenerate) = self.hull.outline();
+        if degenerate {
+            tracing::warn!(
+                "Object {} has no area, using a padded bounding box instead",
+                self.name
+            );
+        }
+
+        exterior
+    }
+
+    /// An ASCII name usable in G-Code commands.
+    ///
+    /// Names longer than `max_length` are cut short and end with a hash of the whole name, so
+    /// names sharing a long prefix stay unique and the same name is always shortened the same way.
+    pub(crate) fn clean_id(name: &str, max_length: Option<usize>) -> String {
         let ascii_name = any_ascii::any_ascii(name);
-        CLEAN_RE
-            .replace_all(&ascii_name, "_")
-            .trim_matches('_')
-            .into()
+        let name = CLEAN_RE.replace_all(&ascii_name, "_");
+        let name = name.trim_matches('_');
+        match max_length {
+            Some(max_length) if name.len() > max_length => {
+                let mut hash = Fnv64::new();
+                hash.write(name.as_bytes());
+                let prefix = &name[..max_length.max(MIN_NAME_LENGTH) - NAME_HASH_DIGITS - 1];
+                format!(
+                    "{}_{:0width$x}",
+                    prefix.trim_end_matches('_'),
+                    hash.0 as u32,
+                    width = NAME_HASH_DIGITS
+                )
+            }
+            _ => name.into(),
+        }
     }
 }
 
@@ -93,8 +679,14 @@ impl Default for KnownObject {
     fn default() -> Self {
         Self {
             name: "".to_string(),
+            original_name: "".to_string(),
             hull: HullTracker::default(),
             layer: -1,
+            extruders: BTreeSet::new(),
+            z_range: None,
+            extrusion_length: 0.0,
+            first_layer: None,
+            group: None,
         }
     }
 }
@@ -102,10 +694,11 @@ impl Default for KnownObject {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use geo::Contains;
 
     #[test]
     fn test_hulls_simple() {
-        let ht = HullTracker::default();
+        let mut ht = HullTracker::default();
         ht.add_point(0.0, 0.0);
         ht.add_point(0.0, 1.0);
         ht.add_point(1.0, 1.0);
@@ -126,7 +719,7 @@ mod tests {
 
     #[test]
     fn test_hulls_rhombus() {
-        let ht = HullTracker::default();
+        let mut ht = HullTracker::default();
         ht.add_point(0.0, 5.0);
         ht.add_point(5.0, 10.0);
         ht.add_point(10.0, 5.0);
@@ -147,7 +740,7 @@ mod tests {
 
     #[test]
     fn test_hulls_circle() {
-        let ht = HullTracker::default();
+        let mut ht = HullTracker::default();
         ht.add_point(0.0, 5.0);
         ht.add_point(5.0, 10.0);
         ht.add_point(10.0, 5.0);
@@ -171,9 +764,327 @@ mod tests {
         assert_eq!(ht.center(), Some(Point::new(5.0, 5.0)));
     }
 
+    #[test]
+    fn test_hulls_concave() {
+        let options = HullOptions {
+            mode: HullMode::Concave,
+            concavity: 1.0,
+            ..Default::default()
+        };
+        let mut concave = HullTracker::new(&options);
+        let mut convex = HullTracker::default();
+
+        // L-shaped object, the empty area between the arms should not be covered by a concave hull
+        for i in 0..=20 {
+            let i = i as f64 / 2.0;
+            for (x, y) in [
+                (i, 0.0),
+                (0.0, i),
+                (i, 2.0),
+                (2.0, i),
+                (10.0, i / 5.0),
+                (i / 5.0, 10.0),
+            ] {
+                concave.add_point(x, y);
+                convex.add_point(x, y);
+            }
+        }
+
+        let concave = Polygon::new(concave.exterior().into_iter().collect(), vec![]);
+        let convex = Polygon::new(convex.exterior().into_iter().collect(), vec![]);
+
+        assert!(concave.unsigned_area() < convex.unsigned_area());
+        assert!(convex.contains(&Point::new(5.0, 5.0)));
+        assert!(!concave.contains(&Point::new(5.0, 5.0)));
+    }
+
+    #[test]
+    fn test_hulls_bbox() {
+        let options = HullOptions {
+            mode: HullMode::Bbox,
+            ..Default::default()
+        };
+        let mut ht = HullTracker::new(&options);
+        assert_eq!(ht.center(), None);
+        assert!(ht.exterior().0.is_empty());
+
+        ht.add_point(0.0, 5.0);
+        ht.add_point(5.0, 10.0);
+        ht.add_point(10.0, 5.0);
+        ht.add_point(5.0, 0.0);
+
+        assert!(matches!(ht.points, PointStorage::Bounds(Some(_))));
+        assert_eq!(
+            ht.exterior(),
+            MultiPoint::new(vec![
+                Point::new(10.0, 0.0),
+                Point::new(10.0, 10.0),
+                Point::new(0.0, 10.0),
+                Point::new(0.0, 0.0),
+                Point::new(10.0, 0.0)
+            ])
+        );
+        assert_eq!(ht.center(), Some(Point::new(5.0, 5.0)));
+    }
+
+    #[test]
+    fn test_hulls_degenerate() {
+        let mut ht = HullTracker::default();
+        assert_eq!(ht.outline(), (MultiPoint::new(vec![]), false));
+
+        ht.add_point(5.0, 5.0);
+        let (exterior, degenerate) = ht.outline();
+        assert!(degenerate);
+        assert_eq!(
+            exterior,
+            MultiPoint::new(vec![
+                Point::new(4.5, 4.5),
+                Point::new(5.5, 4.5),
+                Point::new(5.5, 5.5),
+                Point::new(4.5, 5.5),
+                Point::new(4.5, 4.5)
+            ])
+        );
+
+        // Single wall calibration line
+        for x in 0..=10 {
+            ht.add_point(x as f64, 5.0);
+        }
+        let (exterior, degenerate) = ht.outline();
+        assert!(degenerate);
+        let polygon = Polygon::new(exterior.into_iter().collect(), vec![]);
+        assert_eq!(polygon.unsigned_area(), 11.0);
+    }
+
+    #[test]
+    fn test_hulls_reuse_outline() {
+        let shape = [
+            (0.0, 0.0),
+            (10.0, 0.0),
+            (10.0, 4.0),
+            (2.0, 10.0),
+            (0.0, 10.0),
+        ];
+        let mut original = HullTracker::default();
+        let mut copy = HullTracker::default();
+        let mut rotated = HullTracker::default();
+        for (x, y) in shape {
+            original.add_point(x, y);
+            copy.add_point(x + 50.0, y + 20.0);
+            rotated.add_point(10.0 - x + 50.0, 10.0 - y);
+        }
+
+        let outline = original.outline();
+        let bounds = original.bounds().unwrap();
+        assert!(copy.reuse_outline(&outline, bounds));
+        assert_eq!(
+            copy.exterior(),
+            outline
+                .0
+                .iter()
+                .map(|p| Point::new(p.x() + 50.0, p.y() + 20.0))
+                .collect::<MultiPoint>()
+        );
+
+        assert!(!rotated.reuse_outline(&outline, bounds));
+
+        // Adding points invalidates the outline
+        copy.add_point(100.0, 100.0);
+        assert_eq!(copy.bounds().unwrap().max(), Coord { x: 100.0, y: 100.0 });
+        assert!(copy
+            .exterior()
+            .iter()
+            .any(|p| *p == Point::new(100.0, 100.0)));
+    }
+
+    #[test]
+    fn test_hulls_grid() {
+        let options = HullOptions {
+            grid: Some(0.5),
+            ..Default::default()
+        };
+        let mut ht = HullTracker::new(&options);
+        for i in 0..=1000 {
+            let offset = i as f64 / 10_000.0;
+            ht.add_point(offset, offset);
+            ht.add_point(10.0 - offset, offset);
+            ht.add_point(10.0 - offset, 10.0 - offset);
+            ht.add_point(offset, 10.0 - offset);
+        }
+
+        let PointStorage::Points(points) = &ht.points else {
+            panic!("Expected points storage");
+        };
+        assert!(points.len() <= 4 * 2);
+        assert_eq!(
+            ht.bounds().map(|b| (b.width(), b.height())),
+            Some((10.0, 10.0))
+        );
+    }
+
+    #[test]
+    fn test_hulls_line_width() {
+        for mode in [HullMode::Convex, HullMode::Concave, HullMode::Bbox] {
+            let options = HullOptions {
+                mode,
+                line_width: Some(0.4),
+                ..Default::default()
+            };
+            let mut ht = HullTracker::new(&options);
+            ht.add_point(0.0, 0.0);
+            ht.add_point(0.0, 10.0);
+            ht.add_point(10.0, 10.0);
+            ht.add_point(10.0, 0.0);
+
+            let mut exterior: Vec<(f64, f64)> = ht
+                .exterior()
+                .iter()
+                .map(|p| {
+                    (
+                        (p.x() * 1000.0).round() / 1000.0,
+                        (p.y() * 1000.0).round() / 1000.0,
+                    )
+                })
+                .collect();
+            exterior.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            exterior.dedup();
+
+            assert_eq!(
+                exterior,
+                vec![(-0.2, -0.2), (-0.2, 10.2), (10.2, -0.2), (10.2, 10.2)],
+                "{mode:?}"
+            );
+            assert_eq!(ht.center(), Some(Point::new(5.0, 5.0)));
+        }
+    }
+
     #[test]
     fn test_unicode_object_names() {
-        let known_object = KnownObject::new("Dé id:0 copy 0");
+        let known_object = KnownObject::new("Dé id:0 copy 0", &HullOptions::default());
         assert_eq!(known_object.name, "De_id_0_copy_0")
     }
+
+    #[test]
+    fn test_clean_id_max_length() {
+        let name = "C:\\Users\\me\\Documents\\very long project name\\bracket left.stl";
+        assert_eq!(
+            KnownObject::clean_id(name, None),
+            "C_Users_me_Documents_very_long_project_name_bracket_left_stl"
+        );
+        let truncated = KnownObject::clean_id(name, Some(32));
+        assert_eq!(truncated, "C_Users_me_Documents_ve_1734dc2a");
+        assert_eq!(KnownObject::clean_id(name, Some(32)), truncated);
+        assert_ne!(
+            KnownObject::clean_id(&name.replace("left", "right"), Some(32)),
+            truncated
+        );
+
+        // The separator isn't doubled when the name is cut after an underscore
+        assert_eq!(KnownObject::clean_id(name, Some(20)).len(), 19);
+        assert_eq!(KnownObject::clean_id("short name", Some(16)), "short_name");
+    }
+
+    #[test]
+    fn test_collects_first_layer_per_object() {
+        let filter = LayerFilter::first_layer();
+        let mut ko = KnownObject::new("second_object", &HullOptions::default());
+
+        // Objects printed after each other start on a later layer of the print
+        assert!(ko.collects_layer(40, &filter));
+        assert!(ko.collects_layer(40, &filter));
+        assert!(!ko.collects_layer(41, &filter));
+
+        let mut ko = KnownObject::new("first_object", &HullOptions::default());
+        assert!(!ko.collects_layer(40, &LayerFilter::try_from("0").unwrap()));
+    }
+
+    #[test]
+    fn test_point_budget() {
+        let budget = PointBudget {
+            limit: Some(50),
+            ..Default::default()
+        };
+        let options = HullOptions {
+            budget: budget.clone(),
+            ..Default::default()
+        };
+        let mut limited = HullTracker::new(&options);
+        let mut unlimited = HullTracker::default();
+
+        // A filled square collapses into its corners without changing the hull
+        for x in 0..20 {
+            for y in 0..20 {
+                limited.add_point(x as f64, y as f64);
+                unlimited.add_point(x as f64, y as f64);
+            }
+        }
+        assert!(budget.used() <= 50);
+        assert!(matches!(limited.points, PointStorage::Points(_)));
+        assert_eq!(limited.exterior(), unlimited.exterior());
+
+        // Points on a circle can't be collapsed, the tracker falls back to the bounds
+        let mut circle = HullTracker::new(&options);
+        for i in 0..100 {
+            let angle = i as f64 / 100.0 * std::f64::consts::TAU;
+            circle.add_point(10.0 * angle.cos(), 10.0 * angle.sin());
+        }
+        assert!(matches!(circle.points, PointStorage::Bounds(Some(_))));
+        assert!(budget.used() <= 50);
+
+        // Clones hold their points as well, dropped trackers release them
+        let used = budget.used();
+        let copy = limited.clone();
+        assert_eq!(budget.used(), used + copy.stored());
+        drop(copy);
+        drop(limited);
+        drop(circle);
+        assert_eq!(budget.used(), 0);
+
+        let fresh = budget.fresh();
+        assert_eq!(fresh.limit, Some(50));
+        assert_eq!(fresh.used.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_compute_outlines() {
+        let objects: Vec<KnownObject> = (0..16)
+            .map(|i| {
+                let mut ko = KnownObject::new(&format!("object {i}"), &HullOptions::default());
+                let offset = i as f64 * 20.0;
+                for (x, y) in [
+                    (0.0, 0.0),
+                    (10.0, 0.0),
+                    (10.0, 10.0),
+                    (0.0, 10.0),
+                    (5.0, 5.0),
+                ] {
+                    ko.hull.add_point(x + offset, y);
+                }
+                ko
+            })
+            .collect();
+
+        compute_outlines(&objects);
+        for (i, ko) in objects.iter().enumerate() {
+            assert!(ko.hull.outline.get().is_some());
+            let serial = ko.hull.compute_outline();
+            assert_eq!(ko.hull.outline(), serial, "object {i}");
+        }
+    }
+
+    #[test]
+    fn test_sample_every() {
+        let mut ht = HullTracker::new(&HullOptions {
+            sample_every: NonZeroUsize::new(3),
+            ..Default::default()
+        });
+        for x in 0..9 {
+            ht.add_move(&[(x as f64, 0.0), (x as f64, 1.0)]);
+        }
+
+        let bounds = ht.bounds().unwrap();
+        assert_eq!(bounds.min().x, 0.0);
+        assert_eq!(bounds.max().x, 6.0);
+        assert_eq!(bounds.max().y, 1.0);
+    }
 }
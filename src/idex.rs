@@ -0,0 +1,171 @@
+//! Copy and mirror modes of IDEX printers.
+//!
+//! In these modes both carriages print the same moves, so every object prints twice. The second
+//! print of each object is defined as an object of its own, placed by `--idex-copy` or the offset
+//! of the mode command, so the object map shows both.
+
+use crate::gcode::parse_gcode;
+use crate::hulls::KnownObject;
+use crate::transform::{Affine, Transform};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Mode enabled by `M605` or Klipper's `SET_DUAL_CARRIAGE`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum IdexMode {
+    /// Both carriages move the same way, `M605 S2` gives their distance
+    Copy { offset: Option<f64> },
+    /// The second carriage moves mirrored along X
+    Mirror,
+}
+
+impl fmt::Display for IdexMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Copy { .. } => write!(f, "copy"),
+            Self::Mirror => write!(f, "mirror"),
+        }
+    }
+}
+
+impl IdexMode {
+    /// The mode enabled by a command, other modes and other commands return `None`
+    pub fn from_line(line: &str) -> Option<Self> {
+        let command = parse_gcode(line);
+        let param = |name: &str| {
+            command
+                .params
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| *value)
+        };
+
+        match command.command? {
+            name if name.eq_ignore_ascii_case("M605") => match param("S")?.trim() {
+                "2" => Some(Self::Copy {
+                    offset: param("X").and_then(|x| x.parse().ok()),
+                }),
+                "3" => Some(Self::Mirror),
+                _ => None,
+            },
+            name if name.eq_ignore_ascii_case("SET_DUAL_CARRIAGE") => {
+                match param("MODE")?.to_ascii_uppercase().as_str() {
+                    "COPY" => Some(Self::Copy { offset: None }),
+                    "MIRROR" => Some(Self::Mirror),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Transform from each object to its second print, `None` if it isn't known
+    pub fn placement(&self, idex_copy: &[Transform]) -> Option<Affine> {
+        if !idex_copy.is_empty() {
+            return Some(Affine::new(idex_copy));
+        }
+        match self {
+            Self::Copy { offset: Some(x) } => Some(Affine::new(&[Transform::Translate(*x, 0.0)])),
+            _ => None,
+        }
+    }
+}
+
+/// Define the second print of every object, named after the object with a `_copy` suffix.
+///
+/// Returns the keys of the added objects.
+pub(crate) fn add_copies(
+    known_objects: &mut HashMap<String, KnownObject>,
+    placement: &Affine,
+) -> HashSet<String> {
+    let copies: Vec<(String, KnownObject)> = known_objects
+        .iter()
+        .map(|(key, known_object)| {
+            let copy = KnownObject {
+                name: format!("{}_copy", known_object.name),
+                original_name: format!("{} (copy)", known_object.original_name),
+                hull: known_object.hull.transformed(placement),
+                ..known_object.clone()
+            };
+            (format!("{key} (copy)"), copy)
+        })
+        .collect();
+    let keys = copies.iter().map(|(key, _)| key.clone()).collect();
+    known_objects.extend(copies);
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hulls::HullOptions;
+
+    #[test]
+    fn test_from_line() {
+        assert_eq!(
+            IdexMode::from_line("M605 S2 X150 R0 ; duplicate"),
+            Some(IdexMode::Copy {
+                offset: Some(150.0)
+            })
+        );
+        assert_eq!(
+            IdexMode::from_line("m605 s2"),
+            Some(IdexMode::Copy { offset: None })
+        );
+        assert_eq!(IdexMode::from_line("M605 S3"), Some(IdexMode::Mirror));
+        assert_eq!(IdexMode::from_line("M605 S1"), None);
+        assert_eq!(
+            IdexMode::from_line("SET_DUAL_CARRIAGE CARRIAGE=1 MODE=COPY"),
+            Some(IdexMode::Copy { offset: None })
+        );
+        assert_eq!(
+            IdexMode::from_line("set_dual_carriage carriage=1 mode=mirror"),
+            Some(IdexMode::Mirror)
+        );
+        assert_eq!(
+            IdexMode::from_line("SET_DUAL_CARRIAGE CARRIAGE=0 MODE=PRIMARY"),
+            None
+        );
+        assert_eq!(IdexMode::from_line("; M605 S2"), None);
+    }
+
+    #[test]
+    fn test_placement() {
+        let copy = IdexMode::Copy {
+            offset: Some(150.0),
+        };
+        assert_eq!(
+            copy.placement(&[]).unwrap().apply((10.0, 5.0)),
+            (160.0, 5.0)
+        );
+        // Given transforms take precedence over the offset of the command
+        let mirror_x = ["mirror-x=150".parse().unwrap()];
+        assert_eq!(
+            copy.placement(&mirror_x).unwrap().apply((10.0, 5.0)),
+            (290.0, 5.0)
+        );
+        assert!(IdexMode::Mirror.placement(&[]).is_none());
+        assert!(IdexMode::Copy { offset: None }.placement(&[]).is_none());
+    }
+
+    #[test]
+    fn test_add_copies() {
+        let mut known_object = KnownObject::new("cube.stl", &HullOptions::default());
+        for (x, y) in [(0.0, 0.0), (10.0, 0.0), (10.0, 20.0), (0.0, 20.0)] {
+            known_object.hull.add_point(x, y);
+        }
+        let mut known_objects = HashMap::from([("cube.stl".to_string(), known_object)]);
+
+        let placement = Affine::new(&["mirror-x=150".parse().unwrap()]);
+        let copies = add_copies(&mut known_objects, &placement);
+        assert_eq!(copies, HashSet::from(["cube.stl (copy)".to_string()]));
+        assert_eq!(known_objects.len(), 2);
+        let copy = &known_objects["cube.stl (copy)"];
+        assert_eq!(copy.name, "cube_stl_copy");
+        assert_eq!(copy.original_name, "cube.stl (copy)");
+        let bounds = copy.hull.bounds().unwrap();
+        assert_eq!((bounds.min().x, bounds.max().x), (290.0, 300.0));
+        assert_eq!((bounds.min().y, bounds.max().y), (0.0, 20.0));
+        assert!(!copy.hull.outline().1);
+    }
+}
@@ -0,0 +1,410 @@
+//! Reads processed files the way Klipper's `exclude_object` module does.
+//!
+//! Independent of the code writing the markers, so that mistakes in it are not repeated when
+//! checking its output. Follows Klipper's G-Code parser for extended commands: comments start at
+//! `;`, `#` or `*`, parameters are split like a shell command line and names are upper-cased.
+
+use crate::lines::LineReader;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Severity {
+    /// Klipper rejects the command and stops the print
+    Error,
+    /// Klipper accepts the command, but excluding objects won't work as expected
+    Warning,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Issue {
+    /// Line of the file, starting at 1
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "line {}: {}: {}", self.line, severity, self.message)
+    }
+}
+
+/// An object as Klipper stores it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ObjectDefinition {
+    pub center: Option<Vec<f64>>,
+    pub polygon: Option<Vec<Vec<f64>>>,
+    /// Defined by an `EXCLUDE_OBJECT_START` instead of `EXCLUDE_OBJECT_DEFINE`
+    pub implicit: bool,
+}
+
+/// The outcome of reading a whole file
+#[derive(Debug, Default)]
+pub(crate) struct Verification {
+    pub objects: HashMap<String, ObjectDefinition>,
+    pub issues: Vec<Issue>,
+}
+
+impl Verification {
+    pub fn errors(&self) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == Severity::Error)
+            .count()
+    }
+}
+
+/// Read a file and report every exclude object command Klipper would reject or misinterpret
+pub(crate) fn verify(input: impl BufRead) -> io::Result<Verification> {
+    let mut state = State::default();
+    let mut lines = LineReader::new(input);
+    while let Some((line_no, line)) = lines.next_line()? {
+        state.line = line_no + 1;
+        state.command(line);
+    }
+
+    if let Some(current) = state.current.take() {
+        state.warning(format!(
+            "Object {current} is still printing at the end of the file"
+        ));
+    }
+
+    Ok(Verification {
+        objects: state.objects,
+        issues: state.issues,
+    })
+}
+
+#[derive(Default)]
+struct State {
+    line: usize,
+    objects: HashMap<String, ObjectDefinition>,
+    current: Option<String>,
+    issues: Vec<Issue>,
+}
+
+impl State {
+    fn issue(&mut self, severity: Severity, message: String) {
+        self.issues.push(Issue {
+            line: self.line,
+            severity,
+            message,
+        });
+    }
+
+    fn error(&mut self, message: String) {
+        self.issue(Severity::Error, message);
+    }
+
+    fn warning(&mut self, message: String) {
+        self.issue(Severity::Warning, message);
+    }
+
+    fn command(&mut self, line: &str) {
+        let Some((command, args)) = split_command(line) else {
+            return;
+        };
+        let command = command.to_uppercase();
+        if !command.starts_with("EXCLUDE_OBJECT_") {
+            return;
+        }
+
+        let params = match extended_params(args) {
+            Ok(params) => params,
+            Err(err) => return self.error(format!("Malformed command '{}': {err}", line.trim())),
+        };
+
+        match command.as_str() {
+            "EXCLUDE_OBJECT_DEFINE" => self.define(&params),
+            "EXCLUDE_OBJECT_START" => self.start(&params),
+            "EXCLUDE_OBJECT_END" => self.end(&params),
+            _ => {}
+        }
+    }
+
+    fn define(&mut self, params: &HashMap<String, String>) {
+        let Some(name) = params.get("NAME").map(|name| name.to_uppercase()) else {
+            return self.error("EXCLUDE_OBJECT_DEFINE without a NAME".into());
+        };
+
+        let mut definition = ObjectDefinition::default();
+        if let Some(center) = params.get("CENTER").filter(|center| !center.is_empty()) {
+            match serde_json::from_str::<Value>(&format!("[{center}]")) {
+                Ok(value) => match coordinates(&value) {
+                    Some(center) => definition.center = Some(center),
+                    None => self.warning(format!("CENTER of {name} is not a point: {center}")),
+                },
+                Err(err) => return self.error(format!("Invalid CENTER of {name}: {err}")),
+            }
+        }
+        if let Some(polygon) = params.get("POLYGON").filter(|polygon| !polygon.is_empty()) {
+            match serde_json::from_str::<Value>(polygon) {
+                Ok(value) => {
+                    let points = value
+                        .as_array()
+                        .and_then(|points| points.iter().map(coordinates).collect());
+                    match points {
+                        Some(points) => definition.polygon = Some(points),
+                        None => self.warning(format!("POLYGON of {name} is not a list of points")),
+                    }
+                }
+                Err(err) => return self.error(format!("Invalid POLYGON of {name}: {err}")),
+            }
+        }
+
+        if self.objects.insert(name.clone(), definition).is_some() {
+            self.warning(format!("Object {name} is defined again"));
+        }
+    }
+
+    fn start(&mut self, params: &HashMap<String, String>) {
+        let Some(name) = params.get("NAME").map(|name| name.to_uppercase()) else {
+            return self.error("EXCLUDE_OBJECT_START without a NAME".into());
+        };
+
+        if !self.objects.contains_key(&name) {
+            self.warning(format!("Object {name} is started without being defined"));
+            let definition = ObjectDefinition {
+                implicit: true,
+                ..Default::default()
+            };
+            self.objects.insert(name.clone(), definition);
+        }
+        if let Some(current) = self.current.take() {
+            self.warning(format!("Object {name} started while {current} is printing"));
+        }
+        self.current = Some(name);
+    }
+
+    fn end(&mut self, params: &HashMap<String, String>) {
+        let name = params.get("NAME").map(|name| name.to_uppercase());
+        match (self.current.take(), name) {
+            (None, _) => self.warning("EXCLUDE_OBJECT_END without a printing object".into()),
+            (Some(current), Some(name)) if current != name => self.warning(format!(
+                "EXCLUDE_OBJECT_END NAME={name} does not match the printing object {current}"
+            )),
+            _ => {}
+        }
+    }
+}
+
+/// A list of numbers, as used for points in definitions
+fn coordinates(value: &Value) -> Option<Vec<f64>> {
+    value
+        .as_array()
+        .filter(|values| values.len() == 2)?
+        .iter()
+        .map(Value::as_f64)
+        .collect()
+}
+
+/// The command and its arguments without the comment, `None` for lines without a command
+pub(crate) fn split_command(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    // Line numbers are skipped
+    let line = match line.strip_prefix(['N', 'n']) {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => rest
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .trim_start(),
+        _ => line,
+    };
+
+    let end = line.find(['#', '*', ';']).unwrap_or(line.len());
+    let line = line[..end].trim_end();
+    let command_end = line.find(char::is_whitespace).unwrap_or(line.len());
+    let command = &line[..command_end];
+    let valid = command.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && command.len() > 1
+        && command
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    valid.then(|| (command, line[command_end..].trim_start()))
+}
+
+/// Parameters of an extended command, split like Python's `shlex.split`
+pub(crate) fn extended_params(args: &str) -> Result<HashMap<String, String>, String> {
+    shell_words(args)?
+        .into_iter()
+        .map(|word| match word.split_once('=') {
+            Some((key, value)) => Ok((key.to_uppercase(), value.to_string())),
+            None => Err(format!("parameter '{word}' without a value")),
+        })
+        .collect()
+}
+
+fn shell_words(input: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("no closing quotation".into()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("no closing quotation".into()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("no closing quotation".into()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("no escaped character".into()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issues(gcode: &str) -> Vec<String> {
+        verify(gcode.as_bytes())
+            .unwrap()
+            .issues
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(
+            split_command("N12 exclude_object_start NAME=a ; comment"),
+            Some(("exclude_object_start", "NAME=a"))
+        );
+        assert_eq!(
+            split_command("EXCLUDE_OBJECT_END NAME=a#b"),
+            Some(("EXCLUDE_OBJECT_END", "NAME=a"))
+        );
+        assert_eq!(split_command("; EXCLUDE_OBJECT_START NAME=a"), None);
+        assert_eq!(split_command(""), None);
+    }
+
+    #[test]
+    fn test_extended_params() {
+        let params = extended_params(r#"NAME='part 1' center="1,2" X=a\ b"#).unwrap();
+        assert_eq!(params["NAME"], "part 1");
+        assert_eq!(params["CENTER"], "1,2");
+        assert_eq!(params["X"], "a b");
+
+        assert!(extended_params("NAME='part").is_err());
+        assert!(extended_params("NAME=a b").is_err());
+    }
+
+    #[test]
+    fn test_valid_file() {
+        let verification = verify(
+            "EXCLUDE_OBJECT_DEFINE NAME=cube CENTER=10,10 POLYGON=[[0,0],[20,0],[20,20]]\n\
+            exclude_object_start name=Cube\n\
+            G1 X1 E1\n\
+            EXCLUDE_OBJECT_END NAME=cube\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(verification.issues, vec![]);
+        let cube = &verification.objects["CUBE"];
+        assert_eq!(cube.center, Some(vec![10.0, 10.0]));
+        assert_eq!(cube.polygon.as_ref().map(Vec::len), Some(3));
+    }
+
+    #[test]
+    fn test_processed_fixtures() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("GCode");
+        for filename in [
+            "cura.gcode",
+            "ideamaker.gcode",
+            "m486.gcode",
+            "superslicer.gcode",
+        ] {
+            let mut input = std::fs::File::open(path.join(filename)).unwrap();
+            let mut output = Vec::new();
+            let report = crate::preprocess::stream(
+                &mut input,
+                &mut output,
+                &crate::preprocess::ProcessOptions::default(),
+            )
+            .unwrap();
+
+            let verification = verify(output.as_slice()).unwrap();
+            assert_eq!(verification.issues, vec![], "{filename}");
+            assert_eq!(
+                verification.objects.len(),
+                report.objects.len(),
+                "{filename}"
+            );
+            assert!(verification
+                .objects
+                .values()
+                .all(|object| !object.implicit && object.polygon.is_some()));
+        }
+    }
+
+    #[test]
+    fn test_rejected_commands() {
+        assert_eq!(
+            issues(
+                "EXCLUDE_OBJECT_DEFINE CENTER=1,1\n\
+                EXCLUDE_OBJECT_DEFINE NAME=a POLYGON=[[0,0],[1,1]\n\
+                EXCLUDE_OBJECT_DEFINE NAME=b CENTER=1,x\n\
+                EXCLUDE_OBJECT_DEFINE NAME=c POLYGON=[1,2]\n\
+                EXCLUDE_OBJECT_DEFINE NAME=c\n\
+                EXCLUDE_OBJECT_START NAME='d\n\
+                EXCLUDE_OBJECT_START NAME=d\n\
+                EXCLUDE_OBJECT_START NAME=c\n\
+                EXCLUDE_OBJECT_END NAME=d\n\
+                EXCLUDE_OBJECT_END\n\
+                EXCLUDE_OBJECT_START NAME=c\n"
+            ),
+            vec![
+                "line 1: error: EXCLUDE_OBJECT_DEFINE without a NAME",
+                "line 2: error: Invalid POLYGON of A: EOF while parsing a list at line 1 column 12",
+                "line 3: error: Invalid CENTER of B: expected value at line 1 column 4",
+                "line 4: warning: POLYGON of C is not a list of points",
+                "line 5: warning: Object C is defined again",
+                "line 6: error: Malformed command 'EXCLUDE_OBJECT_START NAME='d': \
+                no closing quotation",
+                "line 7: warning: Object D is started without being defined",
+                "line 8: warning: Object C started while D is printing",
+                "line 9: warning: EXCLUDE_OBJECT_END NAME=D does not match the printing object C",
+                "line 10: warning: EXCLUDE_OBJECT_END without a printing object",
+                "line 11: warning: Object C is still printing at the end of the file",
+            ]
+        );
+    }
+}
@@ -1,16 +1,34 @@
+//! Layer filters select the layers whose extrusions make up the outline of an object.
+//!
+//! Filters are written as a comma separated list of layer specs, see [`LayerFilter`].
+
+use crate::gcode::starts_with_marker;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 use thiserror::Error;
 
-#[derive(Clone, Debug, Error)]
-pub(crate) enum FilterParserError {
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum FilterParserError {
     #[error("The start value {0} could not be parsed")]
     StartValue(String),
     #[error("The stop value {0} could not be parsed")]
     StopValue(String),
     #[error("The given step size of {0} could not be parsed")]
     StepSize(String),
+    #[error("The offset {0} could not be parsed")]
+    Offset(String),
+    #[error("The step size of {0} must be larger than zero")]
+    ZeroStep(String),
+    #[error("The start layer {0} is after the stop layer {1}")]
+    StartAfterStop(usize, usize),
+    #[error("The layer count {0} could not be parsed")]
+    LayerCount(String),
+    #[error("The percentage {0} could not be parsed")]
+    Percentage(String),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 struct LayerRange {
     start: usize,
     stop: usize,
@@ -19,7 +37,8 @@ struct LayerRange {
 
 impl LayerRange {
     pub fn contains(&self, value: usize) -> bool {
-        (self.start <= value && value <= self.stop) && ((value - self.start) % self.step == 0)
+        (self.start <= value && value <= self.stop)
+            && (value - self.start).is_multiple_of(self.step)
     }
 }
 
@@ -33,62 +52,225 @@ impl Default for LayerRange {
     }
 }
 
-#[derive(Clone, Debug)]
-pub(crate) struct LayerFilter {
+/// Layers given relative to the end of the print, resolved once the number of layers is known
+#[derive(Clone, Debug, PartialEq)]
+enum RelativeRange {
+    /// `-n`, the n-th layer counted from the end (`-1` is the last layer)
+    FromEnd(usize),
+    /// `~n`, the last n layers
+    Last(usize),
+    /// `%a-b`, the share of the print from a to b percent
+    Percent(f64, f64),
+}
+
+impl RelativeRange {
+    fn resolve(&self, layer_count: usize) -> Option<LayerRange> {
+        let (start, stop) = match *self {
+            Self::FromEnd(n) => {
+                let layer = layer_count.checked_sub(n)?;
+                (layer, layer)
+            }
+            Self::Last(n) => (layer_count.saturating_sub(n), layer_count.checked_sub(1)?),
+            Self::Percent(from, to) => {
+                let start = (layer_count as f64 * from / 100.0).floor() as usize;
+                let stop = ((layer_count as f64 * to / 100.0).ceil() as usize).checked_sub(1)?;
+                (start, stop)
+            }
+        };
+
+        (start <= stop).then_some(LayerRange {
+            start,
+            stop,
+            step: 1,
+        })
+    }
+
+    fn parse(filter: &str) -> Result<Option<Self>, FilterParserError> {
+        if let Some(count) = filter.strip_prefix('~') {
+            return count
+                .parse()
+                .map(|count| Some(Self::Last(count)))
+                .map_err(|_err| FilterParserError::LayerCount(count.into()));
+        }
+
+        if let Some(percent) = filter.strip_prefix('%') {
+            let parse = |value: &str, default: f64| {
+                if value.is_empty() {
+                    return Ok(default);
+                }
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|value| (0.0..=100.0).contains(value))
+                    .ok_or_else(|| FilterParserError::Percentage(value.into()))
+            };
+            let (from, to) = percent.split_once('-').unwrap_or((percent, percent));
+            let (from, to) = (parse(from, 0.0)?, parse(to, 100.0)?);
+            if from > to {
+                return Err(FilterParserError::Percentage(percent.into()));
+            }
+            return Ok(Some(Self::Percent(from, to)));
+        }
+
+        // Negative numbers count from the end, ranges from the first layer are written as `0-5`
+        if let Some(layer) = filter.strip_prefix('-') {
+            if let Ok(layer @ 1..) = layer.parse::<usize>() {
+                return Ok(Some(Self::FromEnd(layer)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// A set of layers, e.g. `*/5`, `0-10,odd` or `~5`.
+///
+/// * `*` every layer, `n` a single layer, `n-m` or `n-` ranges of layers
+/// * `/s` after a range every sth layer, `+k` before the step shifts the first layer by k
+/// * `odd` and `even` every other layer
+/// * `-n` the nth layer counted from the end, `~n` the last n layers, `%a-b` a to b percent
+/// * `first` the first layer of each object
+///
+/// Filters are parsed with [`FromStr`], formatted back into a spec with [`fmt::Display`] and
+/// (de)serialized as that spec.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LayerFilter {
     ranges: Vec<LayerRange>,
+    relative: Vec<RelativeRange>,
+    /// Layers are counted from the first layer of each object instead of the start of the print
+    per_object: bool,
 }
 
 impl LayerFilter {
-    pub fn contains(&self, value: usize) -> bool {
-        self.ranges.iter().any(|range| range.contains(value))
+    /// Filter matching every layer
+    pub fn all() -> Self {
+        Self {
+            ranges: vec![LayerRange::default()],
+            relative: Vec::new(),
+            per_object: false,
+        }
     }
 
-    fn parse_filter_string(filters: &str) -> Result<LayerRange, FilterParserError> {
-        if let Ok(layer) = filters.parse::<usize>() {
-            return Ok(LayerRange {
-                start: layer,
-                stop: layer,
+    /// Filter matching only the first layer of each object
+    pub fn first_layer() -> Self {
+        Self {
+            ranges: vec![LayerRange {
+                start: 0,
+                stop: 0,
                 step: 1,
-            });
+            }],
+            relative: Vec::new(),
+            per_object: true,
         }
+    }
 
-        if filters == "*" {
-            return Ok(LayerRange::default());
-        }
+    /// Whether layers are counted from the first layer of each object
+    pub fn is_per_object(&self) -> bool {
+        self.per_object
+    }
+
+    /// Whether the filter has layers relative to the end of the print
+    pub fn is_relative(&self) -> bool {
+        !self.relative.is_empty()
+    }
 
-        let mut filters = filters;
-        let mut start: usize = 0;
-        let mut stop: usize = 1;
-        let mut step: usize = 1;
+    /// Turn relative layers into absolute ones for a print with the given number of layers
+    pub fn resolve(&self, layer_count: usize) -> Self {
+        let ranges = self
+            .ranges
+            .iter()
+            .cloned()
+            .chain(self.relative.iter().filter_map(|r| r.resolve(layer_count)))
+            .collect();
 
-        if filters.starts_with('*') {
-            start = 0;
-            stop = usize::MAX;
+        Self {
+            ranges,
+            relative: Vec::new(),
+            per_object: self.per_object,
         }
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.ranges.iter().any(|range| range.contains(value))
+    }
 
-        if filters.contains('/') {
-            if let Some((left, right)) = filters.split_once('/') {
-                filters = left;
-                step = right
-                    .parse()
-                    .map_err(|_err| FilterParserError::StepSize(right.into()))?;
+    fn parse_filter_string(filter: &str) -> Result<LayerRange, FilterParserError> {
+        match filter {
+            "odd" => {
+                return Ok(LayerRange {
+                    start: 1,
+                    stop: usize::MAX,
+                    step: 2,
+                })
             }
+            "even" => {
+                return Ok(LayerRange {
+                    start: 0,
+                    stop: usize::MAX,
+                    step: 2,
+                })
+            }
+            _ => {}
+        }
+
+        let (range, step) = match filter.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<usize>()
+                    .map_err(|_err| FilterParserError::StepSize(step.into()))?,
+            ),
+            None => (filter, 1),
+        };
+        if step == 0 {
+            return Err(FilterParserError::ZeroStep(filter.into()));
         }
 
-        if filters.contains('-') {
-            if let Some((left, right)) = filters.split_once('-') {
-                let left = if left.is_empty() { "0" } else { left };
-                start = left
-                    .parse()
-                    .map_err(|_err| FilterParserError::StartValue(left.into()))?;
-                stop = if right.is_empty() {
-                    usize::MAX
-                } else {
-                    right
-                        .parse::<usize>()
-                        .map_err(|_err| FilterParserError::StopValue(right.into()))?
-                };
+        let (range, offset) = match range.split_once('+') {
+            Some((range, offset)) => (
+                range,
+                offset
+                    .parse::<usize>()
+                    .map_err(|_err| FilterParserError::Offset(offset.into()))?,
+            ),
+            None => (range, 0),
+        };
+
+        let parse_start = |value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|_err| FilterParserError::StartValue(value.into()))
+        };
+
+        let (start, stop) = if range == "*" {
+            (0, usize::MAX)
+        } else if let Some((left, right)) = range.split_once('-') {
+            let start = if left.is_empty() {
+                0
+            } else {
+                parse_start(left)?
+            };
+            let stop = if right.is_empty() {
+                usize::MAX
+            } else {
+                right
+                    .parse::<usize>()
+                    .map_err(|_err| FilterParserError::StopValue(right.into()))?
+            };
+            (start, stop)
+        } else {
+            // A single layer, or with a step or offset every nth layer starting at that layer
+            let layer = parse_start(range)?;
+            if range.len() == filter.len() {
+                (layer, layer)
+            } else {
+                (layer, usize::MAX)
             }
+        };
+
+        let start = start.saturating_add(offset);
+        if start > stop {
+            return Err(FilterParserError::StartAfterStop(start, stop));
         }
 
         Ok(LayerRange { start, stop, step })
@@ -99,12 +281,122 @@ impl TryFrom<&str> for LayerFilter {
     type Error = FilterParserError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let ranges: Vec<LayerRange> = value
-            .split(',')
-            .map(Self::parse_filter_string)
-            .collect::<Result<Vec<_>, _>>()?;
+        if value == "first" {
+            return Ok(Self::first_layer());
+        }
+
+        let mut ranges = Vec::new();
+        let mut relative = Vec::new();
+
+        for filter in value.split(',') {
+            match RelativeRange::parse(filter)? {
+                Some(range) => relative.push(range),
+                None => ranges.push(Self::parse_filter_string(filter)?),
+            }
+        }
+
+        Ok(Self {
+            ranges,
+            relative,
+            per_object: false,
+        })
+    }
+}
+
+impl FromStr for LayerFilter {
+    type Err = FilterParserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
 
-        Ok(Self { ranges })
+impl TryFrom<String> for LayerFilter {
+    type Error = FilterParserError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_str())
+    }
+}
+
+impl From<LayerFilter> for String {
+    fn from(value: LayerFilter) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for LayerRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.start, self.stop) {
+            (start, stop) if start == stop && self.step == 1 => return write!(f, "{start}"),
+            (0, usize::MAX) => write!(f, "*")?,
+            (start, usize::MAX) => write!(f, "{start}-")?,
+            (start, stop) => write!(f, "{start}-{stop}")?,
+        }
+        if self.step != 1 {
+            write!(f, "/{}", self.step)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for RelativeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FromEnd(layer) => write!(f, "-{layer}"),
+            Self::Last(count) => write!(f, "~{count}"),
+            Self::Percent(from, to) => write!(f, "%{from}-{to}"),
+        }
+    }
+}
+
+impl fmt::Display for LayerFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.per_object {
+            return write!(f, "first");
+        }
+
+        let specs: Vec<String> = self
+            .ranges
+            .iter()
+            .map(ToString::to_string)
+            .chain(self.relative.iter().map(ToString::to_string))
+            .collect();
+        write!(f, "{}", specs.join(","))
+    }
+}
+
+/// Global layer index derived from the layer change comments of the slicers.
+///
+/// Cura and ideaMaker emit `;LAYER:n`, the Slic3r family `;LAYER_CHANGE` and ideaMaker, PrusaSlicer
+/// and its forks add a `;Z:` comment. Consecutive markers without a command in between belong to
+/// the same layer change, so every slicer counts each layer exactly once.
+#[derive(Clone, Debug, Default)]
+pub struct LayerTracker {
+    layer: Option<usize>,
+    pending: bool,
+}
+
+impl LayerTracker {
+    /// Index of the current layer, `None` before the first layer change comment
+    pub fn layer(&self) -> Option<usize> {
+        self.layer
+    }
+
+    pub fn track(&mut self, line: &str) {
+        let line = line.trim_start();
+        if starts_with_marker(line, ";LAYER:")
+            || starts_with_marker(line, ";LAYER_CHANGE")
+            || starts_with_marker(line, ";Z:")
+        {
+            if !self.pending {
+                self.layer = Some(self.layer.map_or(0, |layer| layer + 1));
+                self.pending = true;
+            }
+        } else if !line.is_empty() && !line.starts_with(';') {
+            self.pending = false;
+        }
     }
 }
 
@@ -162,7 +454,7 @@ mod tests {
 
     #[test]
     fn test_layer_filter_range_unbounded_stop() {
-        let result = LayerFilter::try_from("-5").unwrap();
+        let result = LayerFilter::try_from("0-5").unwrap();
         assert!(result.contains(1));
         assert!(result.contains(2));
         assert!(result.contains(3));
@@ -211,4 +503,228 @@ mod tests {
         assert!(!result.contains(9));
         assert!(!result.contains(11));
     }
+
+    #[test]
+    fn test_layer_filter_from_end() {
+        let filter = LayerFilter::try_from("-1").unwrap();
+        assert!(filter.is_relative());
+
+        let result = filter.resolve(10);
+        assert!(result.contains(9));
+        assert!(!result.contains(8));
+        assert!(!result.contains(0));
+
+        assert!(!LayerFilter::try_from("-11")
+            .unwrap()
+            .resolve(10)
+            .contains(0));
+    }
+
+    #[test]
+    fn test_layer_filter_last_layers() {
+        let result = LayerFilter::try_from("~5").unwrap().resolve(10);
+        assert!(!result.contains(4));
+        assert!(result.contains(5));
+        assert!(result.contains(9));
+
+        assert!(LayerFilter::try_from("~20")
+            .unwrap()
+            .resolve(10)
+            .contains(0));
+    }
+
+    #[test]
+    fn test_layer_filter_percentage() {
+        let result = LayerFilter::try_from("%0-25").unwrap().resolve(100);
+        assert!(result.contains(0));
+        assert!(result.contains(24));
+        assert!(!result.contains(25));
+
+        let result = LayerFilter::try_from("0,%50-").unwrap().resolve(10);
+        assert!(result.contains(0));
+        assert!(!result.contains(4));
+        assert!(result.contains(5));
+        assert!(result.contains(9));
+
+        assert!(LayerFilter::try_from("%0-101").is_err());
+        assert!(LayerFilter::try_from("~x").is_err());
+    }
+
+    #[test]
+    fn test_layer_filter_odd_even() {
+        let odd = LayerFilter::try_from("odd").unwrap();
+        let even = LayerFilter::try_from("even").unwrap();
+        for layer in 0..10 {
+            assert_eq!(odd.contains(layer), layer % 2 == 1);
+            assert_eq!(even.contains(layer), layer % 2 == 0);
+        }
+    }
+
+    #[test]
+    fn test_layer_filter_step_offset() {
+        let result = LayerFilter::try_from("*+1/3").unwrap();
+        assert!(!result.contains(0));
+        assert!(result.contains(1));
+        assert!(!result.contains(3));
+        assert!(result.contains(4));
+
+        let result = LayerFilter::try_from("10+2/5").unwrap();
+        assert!(!result.contains(10));
+        assert!(result.contains(12));
+        assert!(result.contains(17));
+        assert!(!result.contains(7));
+    }
+
+    #[test]
+    fn test_layer_filter_validation() {
+        assert!(matches!(
+            LayerFilter::try_from("*/0"),
+            Err(FilterParserError::ZeroStep(_))
+        ));
+        assert!(matches!(
+            LayerFilter::try_from("5-1"),
+            Err(FilterParserError::StartAfterStop(5, 1))
+        ));
+        assert!(matches!(
+            LayerFilter::try_from("1-3+5"),
+            Err(FilterParserError::StartAfterStop(6, 3))
+        ));
+        assert!(LayerFilter::try_from("*+x/2").is_err());
+        assert!(LayerFilter::try_from("second").is_err());
+        assert!(LayerFilter::try_from("%50-25").is_err());
+    }
+
+    fn track_all(lines: &[&str]) -> Vec<Option<usize>> {
+        let mut tracker = LayerTracker::default();
+        lines
+            .iter()
+            .map(|line| {
+                tracker.track(line);
+                tracker.layer()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_layer_tracker_slic3r() {
+        let layers = track_all(&[
+            "G28",
+            ";LAYER_CHANGE",
+            ";Z:0.2",
+            ";HEIGHT:0.2",
+            "G1 X1 Y1 E1",
+            ";LAYER_CHANGE",
+            ";Z:0.4",
+            "G1 X2 Y2 E2",
+        ]);
+        assert_eq!(
+            layers,
+            vec![
+                None,
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layer_tracker_mixed_case() {
+        let layers = track_all(&[
+            "G28",
+            "  ;layer_change",
+            ";z:0.2",
+            "G1 X1 Y1 E1",
+            "\t; Layer:1",
+            "G1 X2 Y2 E2",
+            ";LAYER_CHANGE",
+            "G1 X3 Y3 E3",
+        ]);
+        assert_eq!(
+            layers,
+            vec![
+                None,
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layer_tracker_repeated_layer_numbers() {
+        // ideaMaker numbers raft layers negative and repeats the number for each raft layer
+        let layers = track_all(&[
+            ";LAYER:-2",
+            ";Z:0.500",
+            "G1 X1 Y1 E1",
+            ";LAYER:-2",
+            ";Z:1.000",
+            "G1 X2 Y2 E2",
+            ";LAYER:0",
+            "G1 X3 Y3 E3",
+        ]);
+        assert_eq!(
+            layers,
+            vec![
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layer_filter_display_round_trip() {
+        for spec in [
+            "*",
+            "1",
+            "*/2",
+            "1-5",
+            "5-",
+            "0-5",
+            "1-10/2",
+            "1,3-5,6-10/2",
+            "-1",
+            "~5",
+            "%0-25",
+            "first",
+        ] {
+            let filter: LayerFilter = spec.parse().unwrap();
+            assert_eq!(filter.to_string(), spec);
+        }
+
+        for (spec, display) in [
+            ("odd", "1-/2"),
+            ("even", "*/2"),
+            ("*+1/3", "1-/3"),
+            ("%50-", "%50-100"),
+        ] {
+            let filter: LayerFilter = spec.parse().unwrap();
+            assert_eq!(filter.to_string(), display);
+            assert_eq!(display.parse::<LayerFilter>().unwrap(), filter);
+        }
+    }
+
+    #[test]
+    fn test_layer_filter_serde() {
+        let filter: LayerFilter = "0-10/2,~3".parse().unwrap();
+        let json = serde_json::to_string(&filter).unwrap();
+        assert_eq!(json, r#""0-10/2,~3""#);
+        assert_eq!(serde_json::from_str::<LayerFilter>(&json).unwrap(), filter);
+        assert!(serde_json::from_str::<LayerFilter>(r#""*/0""#).is_err());
+    }
 }
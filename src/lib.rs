@@ -0,0 +1,52 @@
+//! Building blocks of the `preprocess_cancellation` G-Code preprocessor that are useful outside
+//! of the command line tool.
+
+pub mod analysis;
+#[doc(hidden)]
+pub mod benchmarks;
+mod cache;
+#[doc(hidden)]
+pub mod cli;
+mod compare;
+#[cfg(test)]
+mod conformance;
+mod container;
+mod coverage;
+mod daemon;
+mod diff;
+mod encoding;
+mod exit;
+mod extract;
+mod framing;
+mod gcode;
+mod groups;
+mod hooks;
+mod hulls;
+mod idex;
+mod klipper;
+pub mod layers;
+mod lines;
+pub mod minimize;
+mod motion;
+mod orphans;
+mod output;
+pub mod parser;
+mod paths;
+mod preprocess;
+#[cfg(test)]
+mod properties;
+mod remote;
+mod report;
+mod resume;
+mod slicers;
+#[cfg(test)]
+mod snapshots;
+mod splice;
+pub mod synthetic;
+#[cfg(feature = "otlp")]
+mod telemetry;
+mod transform;
+mod types;
+#[cfg(feature = "vectored-write")]
+mod vectored;
+mod verify;
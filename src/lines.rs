@@ -0,0 +1,121 @@
+use std::io::{self, BufRead, Read};
+
+/// Lines are cut off after this many bytes when they are only looked at. Commands and markers
+/// are at the start of a line, the rest of a huge line of thumbnail data or binary garbage is
+/// skipped instead of being copied into the buffer.
+pub(crate) const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Reads lines into a reusable buffer instead of allocating a new `String` for every line
+pub(crate) struct LineReader<R> {
+    input: R,
+    buffer: Vec<u8>,
+    line_no: usize,
+    max_length: u64,
+}
+
+impl<R: BufRead> LineReader<R> {
+    /// Lines longer than [`MAX_LINE_LENGTH`] are cut off
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            buffer: Vec::with_capacity(256),
+            line_no: 0,
+            max_length: MAX_LINE_LENGTH as u64,
+        }
+    }
+
+    /// Returns lines of any length, for copying them to the output
+    pub fn untruncated(input: R) -> Self {
+        Self {
+            max_length: u64::MAX,
+            ..Self::new(input)
+        }
+    }
+
+    /// Index of the line read next
+    pub fn line_no(&self) -> usize {
+        self.line_no
+    }
+
+    /// The next line and its index without the line break, `None` at the end of the input
+    pub fn next_line(&mut self) -> io::Result<Option<(usize, &str)>> {
+        self.buffer.clear();
+        let read = (&mut self.input)
+            .take(self.max_length)
+            .read_until(b'\n', &mut self.buffer)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let truncated = read as u64 == self.max_length
+            && !self.buffer.ends_with(b"\n")
+            && self.input.skip_until(b'\n')? > 0;
+
+        let line_no = self.line_no;
+        self.line_no += 1;
+
+        let mut line = self.buffer.as_slice();
+        if let Some(stripped) = line.strip_suffix(b"\n") {
+            line = stripped.strip_suffix(b"\r").unwrap_or(stripped);
+        }
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => line,
+            // Cut off in the middle of a character
+            Err(err) if truncated && err.error_len().is_none() => {
+                std::str::from_utf8(&line[..err.valid_up_to()]).unwrap_or_default()
+            }
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+
+        Ok(Some((line_no, line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_reader() {
+        let mut reader = LineReader::new("G28\r\n; comment\n\nG1 X1".as_bytes());
+        let mut lines = Vec::new();
+        while let Some((line_no, line)) = reader.next_line().unwrap() {
+            lines.push((line_no, line.to_string()));
+        }
+
+        assert_eq!(
+            lines,
+            vec![
+                (0, "G28".to_string()),
+                (1, "; comment".to_string()),
+                (2, "".to_string()),
+                (3, "G1 X1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_reader_long_lines() {
+        let long = format!("; {}é{}", "x".repeat(MAX_LINE_LENGTH - 3), "y".repeat(1000));
+        let gcode = format!("G28\n{long}\nG1 X1\n");
+
+        let mut reader = LineReader::new(gcode.as_bytes());
+        assert_eq!(reader.next_line().unwrap(), Some((0, "G28")));
+        let (line_no, line) = reader.next_line().unwrap().unwrap();
+        assert_eq!(line_no, 1);
+        assert_eq!(line.len(), MAX_LINE_LENGTH - 1);
+        assert!(long.starts_with(line));
+        assert_eq!(reader.next_line().unwrap(), Some((2, "G1 X1")));
+        assert_eq!(reader.next_line().unwrap(), None);
+
+        let mut reader = LineReader::untruncated(gcode.as_bytes());
+        reader.next_line().unwrap();
+        assert_eq!(reader.next_line().unwrap(), Some((1, long.as_str())));
+    }
+
+    #[test]
+    fn test_line_reader_invalid_utf8() {
+        let mut reader = LineReader::new(&b"G28\n\xff\xfe\n"[..]);
+        assert!(reader.next_line().unwrap().is_some());
+        assert!(reader.next_line().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Shrinking real-world G-Code into small fixtures that still reproduce an issue.
+//!
+//! Implements delta debugging over the lines of a file: chunks of lines are removed as long as
+//! the remaining lines still reproduce the issue, with the chunks getting smaller until no single
+//! line can be removed anymore.
+
+use std::io;
+
+/// Remove as many lines as possible while `reproduces` holds for the remaining lines.
+///
+/// The first `keep` lines are never removed, e.g. the comment identifying the slicer. The issue
+/// has to reproduce with all lines, otherwise they are returned unchanged.
+pub fn minimize<F>(lines: Vec<String>, keep: usize, mut reproduces: F) -> io::Result<Vec<String>>
+where
+    F: FnMut(&[String]) -> io::Result<bool>,
+{
+    let keep = keep.min(lines.len());
+    let (head, mut rest) = {
+        let mut lines = lines;
+        let rest = lines.split_off(keep);
+        (lines, rest)
+    };
+
+    let mut candidate = Vec::with_capacity(head.len() + rest.len());
+    let mut check = |rest: &[String]| {
+        candidate.clear();
+        candidate.extend_from_slice(&head);
+        candidate.extend_from_slice(rest);
+        reproduces(&candidate)
+    };
+
+    if !check(&rest)? {
+        return Ok([head.as_slice(), rest.as_slice()].concat());
+    }
+
+    let mut chunks = 2;
+    while !rest.is_empty() {
+        let size = rest.len().div_ceil(chunks);
+        let mut reduced = false;
+
+        let mut start = 0;
+        while start < rest.len() {
+            let end = (start + size).min(rest.len());
+            let complement = [&rest[..start], &rest[end..]].concat();
+            if check(&complement)? {
+                rest = complement;
+                reduced = true;
+            } else {
+                start = end;
+            }
+        }
+
+        if reduced {
+            chunks = chunks.saturating_sub(1).max(2);
+        } else if size == 1 {
+            break;
+        } else {
+            chunks = (chunks * 2).min(rest.len());
+        }
+    }
+
+    Ok([head.as_slice(), rest.as_slice()].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_minimize() {
+        let gcode = lines(
+            "; generated by PrusaSlicer\nG28\nG1 Z0.2\n; printing object a\nG1 X1 E1\n\
+            G1 X2 E2\n; stop printing object a\nG1 Z0.4\n; printing object b\nG1 X3 E3 ; bad\n\
+            ; stop printing object b\nM84\n",
+        );
+
+        let mut checks = 0;
+        let minimized = minimize(gcode, 1, |lines| {
+            checks += 1;
+            Ok(lines.iter().any(|line| line.ends_with("; bad"))
+                && lines.iter().any(|line| line == "; printing object b"))
+        })
+        .unwrap();
+
+        assert_eq!(
+            minimized,
+            lines("; generated by PrusaSlicer\n; printing object b\nG1 X3 E3 ; bad\n")
+        );
+        assert!(checks < 40, "{checks}");
+    }
+
+    #[test]
+    fn test_minimize_not_reproducing() {
+        let gcode = lines("G28\nG1 X1\n");
+        assert_eq!(minimize(gcode.clone(), 0, |_| Ok(false)).unwrap(), gcode);
+    }
+}
@@ -0,0 +1,747 @@
+use crate::gcode::{parse_float, parse_gcode, parse_move, Command, MoveParams};
+use crate::preprocess::ProcessOptions;
+use crate::transform::Affine;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Error)]
+pub enum ToolOffsetError {
+    #[error("Invalid tool offset {0}, expected TOOL=X,Y")]
+    Invalid(String),
+    #[error("Invalid position {0}, expected X,Y")]
+    InvalidPosition(String),
+    #[error("Invalid tool {0}, expected T<n> or <n>")]
+    InvalidTool(String),
+}
+
+/// Maximum angle covered by a single interpolated arc segment
+const ARC_SEGMENT_ANGLE: f64 = PI / 36.0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum MoveKind {
+    Linear,
+    Clockwise,
+    CounterClockwise,
+}
+
+impl MoveKind {
+    fn from_command(command: &str) -> Option<Self> {
+        let (letter, number) = command.split_at(1);
+        if !letter.eq_ignore_ascii_case("g") {
+            return None;
+        }
+
+        Self::from_number(number.parse::<u32>().ok()?)
+    }
+
+    fn from_number(number: u32) -> Option<Self> {
+        match number {
+            0 | 1 => Some(Self::Linear),
+            2 => Some(Self::Clockwise),
+            3 => Some(Self::CounterClockwise),
+            _ => None,
+        }
+    }
+}
+
+/// XY offset of a tool on multi-extruder machines, e.g. `1=-0.25,0.1` for T1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ToolOffset {
+    pub tool: u32,
+    pub x: f64,
+    pub y: f64,
+}
+
+impl FromStr for ToolOffset {
+    type Err = ToolOffsetError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || ToolOffsetError::Invalid(value.to_string());
+
+        let (tool, offset) = value.split_once('=').ok_or_else(invalid)?;
+        let (x, y) = offset.split_once(',').ok_or_else(invalid)?;
+        let tool = tool.trim().trim_start_matches(['T', 't']);
+
+        Ok(Self {
+            tool: tool.parse().map_err(|_err| invalid())?,
+            x: x.trim().parse().map_err(|_err| invalid())?,
+            y: y.trim().parse().map_err(|_err| invalid())?,
+        })
+    }
+}
+
+/// Parse a position or offset given as X,Y
+pub(crate) fn parse_xy(value: &str) -> Result<(f64, f64), ToolOffsetError> {
+    let invalid = || ToolOffsetError::InvalidPosition(value.to_string());
+
+    let (x, y) = value.split_once(',').ok_or_else(invalid)?;
+    Ok((
+        x.trim().parse().map_err(|_err| invalid())?,
+        y.trim().parse().map_err(|_err| invalid())?,
+    ))
+}
+
+/// Parse a tool given as T1 or 1
+pub(crate) fn parse_tool(value: &str) -> Result<u32, ToolOffsetError> {
+    value
+        .trim()
+        .trim_start_matches(['T', 't'])
+        .parse()
+        .map_err(|_err| ToolOffsetError::InvalidTool(value.to_string()))
+}
+
+/// Number of selectable workspaces, G54 through G59
+const WORKSPACES: usize = 6;
+
+/// Tracks the toolhead position while reading G-Code moves
+///
+/// The position is kept in bed coordinates, G92 and workspace offsets are applied to the
+/// coordinates of each move.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Toolhead {
+    x: Option<f64>,
+    y: Option<f64>,
+    z: Option<f64>,
+    e: f64,
+    /// Offset set by G92
+    offset: (f64, f64),
+    /// Z offset set by G92
+    z_offset: f64,
+    /// Workspace offsets set by G10 L2/L20
+    workspaces: [(f64, f64); WORKSPACES],
+    /// Active workspace selected by G54-G59
+    workspace: usize,
+    /// G91 relative positioning
+    relative: bool,
+    /// M83 relative extrusion
+    relative_extrusion: bool,
+    /// Active tool selected by T<n>
+    tool: u32,
+    /// Configured XY offsets by tool, applied to the reported points
+    tool_offsets: HashMap<u32, (f64, f64)>,
+    /// XY offset applied to the reported points of all tools
+    xy_offset: (f64, f64),
+    /// Transform applied to the reported points after the offsets
+    transform: Affine,
+}
+
+/// A single move of the toolhead
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Move {
+    /// XY points visited along the way, excluding the start position
+    pub points: Vec<(f64, f64)>,
+    /// Filament is pushed out during the move
+    pub extruding: bool,
+    /// Length of the move in the XY plane
+    pub length: f64,
+}
+
+fn param(command: &Command, name: &str) -> Option<f64> {
+    command.params.get(name).and_then(|v| parse_float(v))
+}
+
+impl Toolhead {
+    pub fn new(tool_offsets: &[ToolOffset]) -> Self {
+        Self {
+            tool_offsets: tool_offsets
+                .iter()
+                .map(|offset| (offset.tool, (offset.x, offset.y)))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Report the points moved by the offset, e.g. to the coordinates of a printer whose origin
+    /// differs from the slicer's
+    pub fn with_xy_offset(mut self, xy_offset: (f64, f64)) -> Self {
+        self.xy_offset = xy_offset;
+        self
+    }
+
+    /// Report the points transformed, e.g. mirrored like the firmware of an IDEX printer does
+    pub fn with_transform(mut self, transform: Affine) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// The toolhead with the offsets and transforms of the options
+    pub fn from_options(options: &ProcessOptions) -> Self {
+        Self::new(&options.tool_offsets)
+            .with_xy_offset(options.xy_offset)
+            .with_transform(Affine::new(&options.transforms))
+    }
+
+    pub fn tool(&self) -> u32 {
+        self.tool
+    }
+
+    pub fn position(&self) -> Option<(f64, f64)> {
+        self.x.zip(self.y)
+    }
+
+    /// The position as reported in the points of moves, with the offsets and transform applied
+    pub fn reported_position(&self) -> Option<(f64, f64)> {
+        let (dx, dy) = self.point_offset();
+        self.position()
+            .map(|(x, y)| self.transform.apply((x + dx, y + dy)))
+    }
+
+    /// Offset of the reported points, of the active tool and of all tools
+    fn point_offset(&self) -> (f64, f64) {
+        self.tool_offsets
+            .get(&self.tool)
+            .map_or(self.xy_offset, |offset| {
+                (offset.0 + self.xy_offset.0, offset.1 + self.xy_offset.1)
+            })
+    }
+
+    pub fn z(&self) -> Option<f64> {
+        self.z
+    }
+
+    /// Logical coordinates of the position, the values a move back to it would use
+    pub fn logical_position(&self) -> (Option<f64>, Option<f64>, Option<f64>) {
+        let origin = self.origin();
+        (
+            self.x.map(|x| x - origin.0),
+            self.y.map(|y| y - origin.1),
+            self.z.map(|z| z - self.z_offset),
+        )
+    }
+
+    /// Position of the extruder, `None` with relative extrusion
+    pub fn absolute_e(&self) -> Option<f64> {
+        (!self.relative_e()).then_some(self.e)
+    }
+
+    /// G91 relative positioning is active
+    pub fn is_relative(&self) -> bool {
+        self.relative
+    }
+
+    fn relative_e(&self) -> bool {
+        self.relative || self.relative_extrusion
+    }
+
+    /// Bed coordinates of the logical origin
+    fn origin(&self) -> (f64, f64) {
+        let workspace = self.workspaces[self.workspace];
+        (workspace.0 + self.offset.0, workspace.1 + self.offset.1)
+    }
+
+    /// G92: Set the current logical position without moving
+    fn set_position(&mut self, command: &Command) {
+        if let Some(e) = param(command, "E") {
+            self.e = e;
+        }
+
+        if let Some(z) = param(command, "Z") {
+            match self.z {
+                Some(current) => self.z_offset = current - z,
+                None => self.z = Some(z + self.z_offset),
+            }
+        }
+
+        let workspace = self.workspaces[self.workspace];
+        if let Some(x) = param(command, "X") {
+            match self.x {
+                Some(current) => self.offset.0 = current - x - workspace.0,
+                // Without a known position the logical and bed coordinates are assumed to match
+                None => self.x = Some(x + self.origin().0),
+            }
+        }
+        if let Some(y) = param(command, "Y") {
+            match self.y {
+                Some(current) => self.offset.1 = current - y - workspace.1,
+                None => self.y = Some(y + self.origin().1),
+            }
+        }
+    }
+
+    /// G10 L2/L20: Set the offset of a workspace
+    fn set_workspace(&mut self, command: &Command) {
+        let index = match param(command, "P") {
+            Some(p) if p >= 1.0 && p <= WORKSPACES as f64 => p as usize - 1,
+            Some(_) => return,
+            None => self.workspace,
+        };
+
+        match param(command, "L").map(|l| l as u32) {
+            // Offset given directly
+            Some(2) => {
+                if let Some(x) = param(command, "X") {
+                    self.workspaces[index].0 = x;
+                }
+                if let Some(y) = param(command, "Y") {
+                    self.workspaces[index].1 = y;
+                }
+            }
+            // Offset chosen so the current position has the given coordinates
+            Some(20) => {
+                if let Some((x, current)) = param(command, "X").zip(self.x) {
+                    self.workspaces[index].0 = current - x - self.offset.0;
+                }
+                if let Some((y, current)) = param(command, "Y").zip(self.y) {
+                    self.workspaces[index].1 = current - y - self.offset.1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply a command to the toolhead state and return the resulting move, if any.
+    ///
+    /// Linear moves yield their end point, arcs are interpolated. Nothing is returned while
+    /// the position is not fully known yet.
+    pub fn apply(&mut self, command: &Command) -> Move {
+        let Some(name) = command.command else {
+            return Move::default();
+        };
+
+        match name.to_uppercase().as_str() {
+            "G90" => self.relative = false,
+            "G91" => self.relative = true,
+            "M82" => self.relative_extrusion = false,
+            "M83" => self.relative_extrusion = true,
+            "G92" => self.set_position(command),
+            "G92.1" => {
+                self.offset = (0.0, 0.0);
+                self.z_offset = 0.0;
+            }
+            "G10" => self.set_workspace(command),
+            "G54" | "G55" | "G56" | "G57" | "G58" | "G59" => {
+                self.workspace = name[1..].parse::<usize>().unwrap_or(54) - 54;
+            }
+            tool if tool.starts_with('T') => {
+                if let Ok(tool) = tool[1..].parse::<u32>() {
+                    self.tool = tool;
+                }
+            }
+            _ => {
+                if let Some(kind) = MoveKind::from_command(name) {
+                    return self.travel(&MoveParams::from_command(command), kind);
+                }
+            }
+        }
+
+        Move::default()
+    }
+
+    /// Apply a line of G-Code, moves take a fast path that doesn't build a [`Command`]
+    pub fn apply_line(&mut self, line: &str) -> Move {
+        match parse_move(line) {
+            Some((number, params)) => match MoveKind::from_number(number) {
+                Some(kind) => self.travel(&params, kind),
+                None => Move::default(),
+            },
+            None => self.apply(&parse_gcode(line)),
+        }
+    }
+
+    fn travel(&mut self, params: &MoveParams, kind: MoveKind) -> Move {
+        let extruding = match params.e {
+            None => false,
+            Some(e) if self.relative_e() => e > 0.0,
+            Some(e) => {
+                let extruded = e > self.e;
+                self.e = e;
+                extruded
+            }
+        };
+
+        if let Some(z) = params.z {
+            self.z = if self.relative {
+                self.z.map(|current| current + z)
+            } else {
+                Some(z + self.z_offset)
+            };
+        }
+
+        let (x, y) = (params.x, params.y);
+        if x.is_none() && y.is_none() {
+            return Move::default();
+        }
+
+        let start = self.position();
+        if self.relative {
+            self.x = self.x.zip(x.or(Some(0.0))).map(|(x, dx)| x + dx);
+            self.y = self.y.zip(y.or(Some(0.0))).map(|(y, dy)| y + dy);
+        } else {
+            let origin = self.origin();
+            self.x = x.map(|x| x + origin.0).or(self.x);
+            self.y = y.map(|y| y + origin.1).or(self.y);
+        }
+
+        let Some(end) = self.position() else {
+            return Move::default();
+        };
+
+        let mut points = match (kind, start) {
+            (MoveKind::Linear, _) | (_, None) => vec![end],
+            (direction, Some(start)) => arc(params, direction, start, end),
+        };
+
+        let length = start.map_or(0.0, |start| {
+            std::iter::once(start)
+                .chain(points.iter().copied())
+                .tuple_windows()
+                .map(|(a, b)| (b.0 - a.0).hypot(b.1 - a.1))
+                .sum()
+        });
+
+        let (dx, dy) = self.point_offset();
+        if dx != 0.0 || dy != 0.0 {
+            for point in points.iter_mut() {
+                *point = (point.0 + dx, point.1 + dy);
+            }
+        }
+        if !self.transform.is_identity() {
+            for point in points.iter_mut() {
+                *point = self.transform.apply(*point);
+            }
+        }
+
+        Move {
+            points,
+            extruding,
+            length,
+        }
+    }
+}
+
+fn arc(
+    params: &MoveParams,
+    direction: MoveKind,
+    start: (f64, f64),
+    end: (f64, f64),
+) -> Vec<(f64, f64)> {
+    let clockwise = direction == MoveKind::Clockwise;
+
+    let center = match (params.i, params.j, params.r) {
+        (None, None, Some(radius)) => radius_center(start, end, radius, clockwise),
+        (i, j, _) => Some((start.0 + i.unwrap_or(0.0), start.1 + j.unwrap_or(0.0))),
+    };
+    let Some(center) = center else {
+        return vec![end];
+    };
+
+    let radius = (start.0 - center.0).hypot(start.1 - center.1);
+    let start_angle = (start.1 - center.1).atan2(start.0 - center.0);
+    let end_angle = (end.1 - center.1).atan2(end.0 - center.0);
+
+    let mut sweep = end_angle - start_angle;
+    if clockwise && sweep >= 0.0 {
+        sweep -= 2.0 * PI;
+    } else if !clockwise && sweep <= 0.0 {
+        sweep += 2.0 * PI;
+    }
+
+    let segments = (sweep.abs() / ARC_SEGMENT_ANGLE).ceil().max(1.0) as usize;
+    let mut points: Vec<(f64, f64)> = (1..segments)
+        .map(|i| {
+            let angle = start_angle + sweep * i as f64 / segments as f64;
+            (
+                center.0 + radius * angle.cos(),
+                center.1 + radius * angle.sin(),
+            )
+        })
+        .collect();
+    points.push(end);
+
+    points
+}
+
+/// Center of an arc given in radius format, negative radii select the longer arc
+fn radius_center(
+    start: (f64, f64),
+    end: (f64, f64),
+    radius: f64,
+    clockwise: bool,
+) -> Option<(f64, f64)> {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let distance = dx.hypot(dy);
+    if distance <= f64::EPSILON || distance > 2.0 * radius.abs() + 1e-6 {
+        return None;
+    }
+
+    let height = (radius.powi(2) - (distance / 2.0).powi(2)).max(0.0).sqrt();
+    let midpoint = (start.0 + dx / 2.0, start.1 + dy / 2.0);
+    // Unit normal pointing to the left of the direction of travel
+    let normal = (-dy / distance, dx / distance);
+
+    let left = clockwise == (radius < 0.0);
+    let side = if left { 1.0 } else { -1.0 };
+
+    Some((
+        midpoint.0 + side * height * normal.0,
+        midpoint.1 + side * height * normal.1,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(toolhead: &mut Toolhead, line: &str) -> Vec<(f64, f64)> {
+        toolhead.apply(&parse_gcode(line)).points
+    }
+
+    fn extruding(toolhead: &mut Toolhead, line: &str) -> bool {
+        toolhead.apply(&parse_gcode(line)).extruding
+    }
+
+    fn assert_on_circle(points: &[(f64, f64)], center: (f64, f64), radius: f64) {
+        for (x, y) in points {
+            let distance = (x - center.0).hypot(y - center.1);
+            assert!(
+                (distance - radius).abs() < 1e-6,
+                "({x}, {y}) is not on the arc"
+            );
+        }
+    }
+
+    #[test]
+    fn test_linear_moves() {
+        let mut toolhead = Toolhead::default();
+        assert!(apply(&mut toolhead, "G1 X10 E1").is_empty());
+        assert_eq!(apply(&mut toolhead, "G1 Y5 E1"), vec![(10.0, 5.0)]);
+        assert_eq!(apply(&mut toolhead, "G0 X20"), vec![(20.0, 5.0)]);
+        assert!(apply(&mut toolhead, "G1 E-1").is_empty());
+        assert!(apply(&mut toolhead, "M104 S200").is_empty());
+        assert_eq!(toolhead.position(), Some((20.0, 5.0)));
+        assert_eq!(toolhead.apply(&parse_gcode("G1 X23 Y9 E2")).length, 5.0);
+    }
+
+    #[test]
+    fn test_arc_center_format() {
+        let mut toolhead = Toolhead::default();
+        apply(&mut toolhead, "G0 X10 Y0");
+
+        // Half circle counter-clockwise around the origin, passing through (0, 10)
+        let points = apply(&mut toolhead, "G3 X-10 Y0 I-10 J0 E1");
+        assert_on_circle(&points, (0.0, 0.0), 10.0);
+        assert!(points.iter().all(|(_, y)| *y >= -1e-9));
+        assert!(points.iter().any(|(_, y)| (*y - 10.0).abs() < 1e-9));
+        assert_eq!(points.last(), Some(&(-10.0, 0.0)));
+
+        // Back clockwise, again through (0, 10)
+        let points = apply(&mut toolhead, "G2 X10 Y0 I10 J0 E1");
+        assert_on_circle(&points, (0.0, 0.0), 10.0);
+        assert!(points.iter().any(|(_, y)| (*y - 10.0).abs() < 1e-9));
+
+        // Full circle
+        let points = apply(&mut toolhead, "G2 X10 Y0 I-10 J0 E1");
+        assert_on_circle(&points, (0.0, 0.0), 10.0);
+        assert!(points.iter().any(|(x, _)| (*x + 10.0).abs() < 1e-9));
+        assert!(points.len() > 36);
+    }
+
+    #[test]
+    fn test_arc_radius_format() {
+        let mut toolhead = Toolhead::default();
+        apply(&mut toolhead, "G0 X0 Y0");
+
+        // Quarter circle clockwise, center at (10, 0)
+        let points = apply(&mut toolhead, "G2 X10 Y10 R10 E1");
+        assert_on_circle(&points, (10.0, 0.0), 10.0);
+
+        // Same end points counter-clockwise, center at (0, 10)
+        let mut toolhead = Toolhead::default();
+        apply(&mut toolhead, "G0 X0 Y0");
+        let points = apply(&mut toolhead, "G3 X10 Y10 R10 E1");
+        assert_on_circle(&points, (0.0, 10.0), 10.0);
+    }
+
+    #[test]
+    fn test_relative_positioning() {
+        let mut toolhead = Toolhead::default();
+        apply(&mut toolhead, "G91");
+        assert!(apply(&mut toolhead, "G1 X10 Y10").is_empty());
+
+        apply(&mut toolhead, "G90");
+        apply(&mut toolhead, "G1 X10 Y10");
+        apply(&mut toolhead, "G91");
+        assert_eq!(apply(&mut toolhead, "G1 X5 E1"), vec![(15.0, 10.0)]);
+        assert_eq!(apply(&mut toolhead, "G1 X-5 Y-5 E1"), vec![(10.0, 5.0)]);
+        apply(&mut toolhead, "G90");
+        assert_eq!(apply(&mut toolhead, "G1 X5 E1"), vec![(5.0, 5.0)]);
+    }
+
+    #[test]
+    fn test_extrusion_modes() {
+        let mut toolhead = Toolhead::default();
+        apply(&mut toolhead, "G1 X0 Y0");
+
+        // Absolute extrusion
+        apply(&mut toolhead, "M82");
+        assert!(extruding(&mut toolhead, "G1 X1 Y1 E1"));
+        assert!(extruding(&mut toolhead, "G1 X2 Y2 E2"));
+        assert!(!extruding(&mut toolhead, "G1 X3 Y3 E1.5"));
+        assert!(!extruding(&mut toolhead, "G1 X4 Y4"));
+        apply(&mut toolhead, "G92 E0");
+        assert!(extruding(&mut toolhead, "G1 X5 Y5 E0.5"));
+
+        // Relative extrusion
+        apply(&mut toolhead, "M83");
+        assert!(extruding(&mut toolhead, "G1 X1 Y1 E0.5"));
+        assert!(extruding(&mut toolhead, "G1 X2 Y2 E0.5"));
+        assert!(!extruding(&mut toolhead, "G1 X3 Y3 E-0.8"));
+        assert!(!extruding(&mut toolhead, "G1 X4 Y4 E0"));
+
+        // G91 implies relative extrusion
+        apply(&mut toolhead, "M82");
+        apply(&mut toolhead, "G91");
+        assert!(extruding(&mut toolhead, "G1 X1 E0.5"));
+        assert!(extruding(&mut toolhead, "G1 X1 E0.5"));
+    }
+
+    #[test]
+    fn test_position_offsets() {
+        let mut toolhead = Toolhead::default();
+        apply(&mut toolhead, "G1 X10 Y10");
+
+        // Current position becomes the logical origin
+        apply(&mut toolhead, "G92 X0 Y0");
+        assert_eq!(toolhead.position(), Some((10.0, 10.0)));
+        assert_eq!(apply(&mut toolhead, "G1 X5 Y5 E1"), vec![(15.0, 15.0)]);
+
+        // E only resets leave the XY offset alone
+        apply(&mut toolhead, "G92 E0");
+        assert_eq!(apply(&mut toolhead, "G1 X0 Y0 E1"), vec![(10.0, 10.0)]);
+
+        apply(&mut toolhead, "G92.1");
+        assert_eq!(apply(&mut toolhead, "G1 X5 Y5 E2"), vec![(5.0, 5.0)]);
+    }
+
+    #[test]
+    fn test_workspace_offsets() {
+        let mut toolhead = Toolhead::default();
+        apply(&mut toolhead, "G10 L2 P2 X100 Y50");
+        assert_eq!(apply(&mut toolhead, "G1 X1 Y1"), vec![(1.0, 1.0)]);
+
+        apply(&mut toolhead, "G55");
+        assert_eq!(apply(&mut toolhead, "G1 X1 Y1"), vec![(101.0, 51.0)]);
+
+        // Make the current position (101, 51) the origin of G56
+        apply(&mut toolhead, "G10 L20 P3 X0 Y0");
+        apply(&mut toolhead, "G56");
+        assert_eq!(apply(&mut toolhead, "G1 X2 Y2"), vec![(103.0, 53.0)]);
+
+        apply(&mut toolhead, "G54");
+        assert_eq!(apply(&mut toolhead, "G1 X2 Y2"), vec![(2.0, 2.0)]);
+    }
+
+    #[test]
+    fn test_tool_offsets() {
+        let offsets = [ToolOffset::from_str("T1=-10,0.5").unwrap()];
+        assert_eq!(
+            offsets[0],
+            ToolOffset {
+                tool: 1,
+                x: -10.0,
+                y: 0.5
+            }
+        );
+        assert!(ToolOffset::from_str("1=10").is_err());
+        assert!(ToolOffset::from_str("a=1,1").is_err());
+
+        assert_eq!(parse_tool("T1").unwrap(), 1);
+        assert_eq!(parse_tool("2").unwrap(), 2);
+        assert!(parse_tool("E1").is_err());
+
+        let mut toolhead = Toolhead::new(&offsets);
+        assert_eq!(apply(&mut toolhead, "G1 X20 Y20 E1"), vec![(20.0, 20.0)]);
+        apply(&mut toolhead, "T1");
+        assert_eq!(toolhead.tool(), 1);
+        assert_eq!(apply(&mut toolhead, "G1 X20 Y20 E1"), vec![(10.0, 20.5)]);
+        apply(&mut toolhead, "T0");
+        assert_eq!(apply(&mut toolhead, "G1 X20 Y20 E1"), vec![(20.0, 20.0)]);
+    }
+
+    #[test]
+    fn test_xy_offset() {
+        assert_eq!(parse_xy("-100, 50.5").unwrap(), (-100.0, 50.5));
+        assert!(parse_xy("100").is_err());
+        assert!(parse_xy("a,1").is_err());
+
+        let offsets = [ToolOffset::from_str("T1=-10,0").unwrap()];
+        let mut toolhead = Toolhead::new(&offsets).with_xy_offset((-100.0, -100.0));
+        assert_eq!(apply(&mut toolhead, "G1 X20 Y20 E1"), vec![(-80.0, -80.0)]);
+        apply(&mut toolhead, "T1");
+        assert_eq!(apply(&mut toolhead, "G1 X20 Y20 E1"), vec![(-90.0, -80.0)]);
+        assert_eq!(toolhead.reported_position(), Some((-90.0, -80.0)));
+        // The logical position is unaffected
+        assert_eq!(toolhead.logical_position(), (Some(20.0), Some(20.0), None));
+    }
+
+    #[test]
+    fn test_z_position() {
+        let mut toolhead = Toolhead::default();
+        assert_eq!(toolhead.z(), None);
+        apply(&mut toolhead, "G1 Z0.2 F3000");
+        assert_eq!(toolhead.z(), Some(0.2));
+        apply(&mut toolhead, "G91");
+        apply(&mut toolhead, "G1 Z0.2");
+        apply(&mut toolhead, "G90");
+        assert!((toolhead.z().unwrap() - 0.4).abs() < 1e-9);
+        apply(&mut toolhead, "G92 Z0");
+        apply(&mut toolhead, "G1 Z1");
+        assert!((toolhead.z().unwrap() - 1.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_apply_line() {
+        let lines = [
+            "G28",
+            "G1 Z0.2 F3000",
+            "G1 X10 Y10",
+            "G1 X20 Y10 E1.5",
+            "G2 X30 Y10 I5 J0 E2",
+            "G3 X20 Y10 R5 E2.5",
+            "G91",
+            "G1 X1 Y-1 E0.1",
+            "G90",
+            "M83",
+            "G92 X0 Y0",
+            "g1 X5 Y5 E0.2 ; comment",
+            "T1",
+            "G1 E-0.8",
+            "G1 X.5 Y-.5 E1.2e-05",
+            "G1 X5E-1 Y+2.5 E.1",
+        ];
+
+        let mut fast = Toolhead::default();
+        let mut generic = Toolhead::default();
+        for line in lines {
+            assert_eq!(
+                fast.apply_line(line),
+                generic.apply(&parse_gcode(line)),
+                "{line}"
+            );
+        }
+        assert_eq!(fast.position(), generic.position());
+        assert_eq!(fast.tool(), generic.tool());
+    }
+
+    #[test]
+    fn test_odd_number_formats() {
+        let mut toolhead = Toolhead::default();
+        toolhead.apply_line("M83");
+        toolhead.apply_line("G1 X1 Y1");
+
+        let toolhead_move = toolhead.apply_line("G1 X.5 Y-.5 E1.2e-05");
+        assert!(toolhead_move.extruding);
+        assert_eq!(toolhead_move.points, vec![(0.5, -0.5)]);
+
+        let toolhead_move = toolhead.apply(&parse_gcode("G1 X5e-1 Y+1.5E0 E.1"));
+        assert!(toolhead_move.extruding);
+        assert_eq!(toolhead_move.points, vec![(0.5, 1.5)]);
+
+        // Infinite or NaN coordinates are ignored instead of ending up in the outlines
+        let toolhead_move = toolhead.apply_line("G1 Xinf Y2 E1");
+        assert_eq!(toolhead_move.points, vec![(0.5, 2.0)]);
+    }
+}
@@ -0,0 +1,457 @@
+use crate::hulls::{HullOptions, KnownObject};
+use crate::motion::Move;
+use crate::preprocess::ProcessOptions;
+use crate::splice::{Marker, Position};
+use geo::{BoundingRect, Centroid, Contains, EuclideanDistance, MultiPoint, Point, Polygon};
+use ordered_float::OrderedFloat;
+use std::collections::HashMap;
+
+/// Name of the pseudo-object defined for unlabeled wipe towers
+pub(crate) const WIPE_TOWER_NAME: &str = "WIPE_TOWER";
+
+/// How purge/wipe towers of multi-material prints are handled
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum WipeTowerMode {
+    /// Leave the wipe tower as sliced, labeled or not
+    #[default]
+    Keep,
+    /// Define unlabeled wipe towers as their own object
+    Object,
+    /// Don't define wipe towers labeled as objects, they belong to no object
+    Exclude,
+}
+
+/// Slicers labeling the wipe tower as an object use names like `wipe_tower` or `Prime tower`
+pub(crate) fn is_wipe_tower(name: &str) -> bool {
+    let name = name.to_lowercase().replace(['_', '-', ' '], "");
+    name.contains("wipetower") || name.contains("primetower")
+}
+
+/// Extrusions printed outside of any object marker
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum OrphanKind {
+    /// Skirt and brim, assigned to the nearest object
+    SkirtBrim,
+    /// Support material, assigned to the object it is inside of
+    Support,
+    /// Extrusions without a type following an object, handled like support
+    Unmarked,
+    /// Purge/wipe tower of multi-material prints
+    WipeTower,
+}
+
+impl OrphanKind {
+    /// Kind of extrusion announced by a `;TYPE:` or `; FEATURE:` comment
+    pub fn from_type_comment(line: &str) -> Option<Option<Self>> {
+        let kind = line
+            .strip_prefix(";TYPE:")
+            .or_else(|| line.strip_prefix("; FEATURE:"))?
+            .trim()
+            .to_lowercase();
+        if is_wipe_tower(&kind) {
+            Some(Some(Self::WipeTower))
+        } else if kind.contains("skirt") || kind.contains("brim") {
+            Some(Some(Self::SkirtBrim))
+        } else if kind.contains("support") {
+            Some(Some(Self::Support))
+        } else {
+            Some(None)
+        }
+    }
+}
+
+/// Continuous extrusion path outside of any object
+#[derive(Clone, Debug)]
+struct OrphanPath {
+    kind: OrphanKind,
+    first_line: usize,
+    last_line: usize,
+    points: Vec<Point>,
+}
+
+/// Collects extrusion paths outside of objects, so they can be assigned to an object
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OrphanTracker {
+    brims: bool,
+    supports: bool,
+    wipe_tower: WipeTowerMode,
+    kind: Option<OrphanKind>,
+    current: Option<OrphanPath>,
+    paths: Vec<OrphanPath>,
+}
+
+impl OrphanTracker {
+    pub fn new(options: &ProcessOptions) -> Self {
+        Self {
+            brims: options.assign_brims,
+            supports: options.assign_supports,
+            wipe_tower: options.wipe_tower,
+            ..Default::default()
+        }
+    }
+
+    fn enabled(&self, kind: OrphanKind) -> bool {
+        match kind {
+            OrphanKind::SkirtBrim => self.brims,
+            OrphanKind::Support | OrphanKind::Unmarked => self.supports,
+            OrphanKind::WipeTower => self.wipe_tower == WipeTowerMode::Object,
+        }
+    }
+
+    fn finish_path(&mut self) {
+        if let Some(path) = self.current.take() {
+            self.paths.push(path);
+        }
+    }
+
+    /// Track a single line, `line_no` is the zero based index of the line in the file
+    pub fn track(&mut self, line_no: usize, line: &str, in_object: bool, toolhead_move: &Move) {
+        if let Some(kind) = OrphanKind::from_type_comment(line) {
+            self.finish_path();
+            self.kind = kind;
+            return;
+        }
+
+        if in_object {
+            self.finish_path();
+            self.kind = Some(OrphanKind::Unmarked);
+            return;
+        }
+
+        let Some(kind) = self.kind.filter(|kind| self.enabled(*kind)) else {
+            return;
+        };
+
+        if toolhead_move.points.is_empty() {
+            return;
+        }
+
+        if !toolhead_move.extruding {
+            // Travel moves separate paths, the wipe tower is handled as a single block
+            if kind != OrphanKind::WipeTower {
+                self.finish_path();
+            }
+            return;
+        }
+
+        let path = self.current.get_or_insert_with(|| OrphanPath {
+            kind,
+            first_line: line_no,
+            last_line: line_no,
+            points: Vec::new(),
+        });
+        path.last_line = line_no;
+        path.points
+            .extend(toolhead_move.points.iter().map(|(x, y)| Point::new(*x, *y)));
+    }
+
+    /// Pseudo-object covering all unlabeled wipe tower extrusions
+    pub fn wipe_tower(&self, hull_options: &HullOptions) -> Option<KnownObject> {
+        let mut paths = self
+            .paths
+            .iter()
+            .chain(self.current.iter())
+            .filter(|path| path.kind == OrphanKind::WipeTower)
+            .peekable();
+        paths.peek()?;
+
+        let mut wipe_tower = KnownObject::new(WIPE_TOWER_NAME, hull_options);
+        for path in paths {
+            wipe_tower.layer += 1;
+            for point in &path.points {
+                wipe_tower.hull.add_point(point.x(), point.y());
+            }
+        }
+
+        Some(wipe_tower)
+    }
+
+    /// Assign the collected paths to the objects they belong to
+    pub fn assign<'a>(
+        mut self,
+        known_objects: impl IntoIterator<Item = &'a KnownObject>,
+    ) -> OrphanAssignments {
+        self.finish_path();
+
+        let outlines: Vec<(&str, Polygon, Option<Point>)> = known_objects
+            .into_iter()
+            .map(|ko| {
+                let exterior = ko.hull.exterior();
+                (
+                    ko.name.as_str(),
+                    Polygon::new(exterior.into_iter().collect(), vec![]),
+                    ko.hull.center(),
+                )
+            })
+            .filter(|(_, polygon, _)| !polygon.exterior().0.is_empty())
+            .collect();
+
+        let mut assignments = OrphanAssignments::default();
+        for path in self.paths {
+            let points = MultiPoint::new(path.points);
+            let (Some(centroid), Some(bounds)) = (points.centroid(), points.bounding_rect()) else {
+                continue;
+            };
+
+            // Skirts surrounding several objects are shared and stay unassigned
+            let enclosed = outlines
+                .iter()
+                .filter(|(_, _, center)| center.is_some_and(|c| bounds.contains(&c)))
+                .count();
+            if enclosed > 1 && path.kind == OrphanKind::SkirtBrim {
+                continue;
+            }
+
+            let owner = match path.kind {
+                OrphanKind::WipeTower => Some(WIPE_TOWER_NAME.to_string()),
+                OrphanKind::SkirtBrim => outlines
+                    .iter()
+                    .min_by_key(|(_, polygon, center)| {
+                        (
+                            OrderedFloat(centroid.euclidean_distance(polygon)),
+                            OrderedFloat(
+                                center.map_or(f64::MAX, |c| centroid.euclidean_distance(&c)),
+                            ),
+                        )
+                    })
+                    .map(|(name, _, _)| name.to_string()),
+                OrphanKind::Support | OrphanKind::Unmarked => outlines
+                    .iter()
+                    .filter(|(_, polygon, _)| polygon.contains(&centroid))
+                    .min_by_key(|(_, _, center)| {
+                        OrderedFloat(center.map_or(f64::MAX, |c| centroid.euclidean_distance(&c)))
+                    })
+                    .map(|(name, _, _)| name.to_string()),
+            };
+
+            if let Some(owner) = owner {
+                tracing::debug!(
+                    "Assigning lines {}-{} to object {}",
+                    path.first_line + 1,
+                    path.last_line + 1,
+                    owner
+                );
+                assignments.starts.insert(path.first_line, owner.clone());
+                assignments.ends.insert(path.last_line, owner);
+            }
+        }
+
+        assignments
+    }
+}
+
+/// Object markers to add around extrusions outside of objects, by line index
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OrphanAssignments {
+    starts: HashMap<usize, String>,
+    ends: HashMap<usize, String>,
+}
+
+impl OrphanAssignments {
+    /// Object to start before the given line
+    #[cfg(test)]
+    pub fn start(&self, line_no: usize) -> Option<&str> {
+        self.starts.get(&line_no).map(String::as_str)
+    }
+
+    /// Object to end after the given line
+    #[cfg(test)]
+    pub fn end(&self, line_no: usize) -> Option<&str> {
+        self.ends.get(&line_no).map(String::as_str)
+    }
+
+    /// Start and end markers of all assigned paths
+    pub fn into_markers(self) -> impl Iterator<Item = (usize, Position, Marker)> {
+        let starts = self
+            .starts
+            .into_iter()
+            .map(|(line_no, name)| (line_no, Position::Before, Marker::Start(name)));
+        let ends = self
+            .ends
+            .into_iter()
+            .map(|(line_no, name)| (line_no, Position::After, Marker::End(name)));
+        starts.chain(ends)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcode::parse_gcode;
+    use crate::hulls::HullOptions;
+    use crate::motion::Toolhead;
+
+    fn brims() -> ProcessOptions {
+        ProcessOptions {
+            assign_brims: true,
+            ..Default::default()
+        }
+    }
+
+    fn track(tracker: &mut OrphanTracker, gcode: &[(&str, bool)]) {
+        let mut toolhead = Toolhead::default();
+        for (line_no, (line, in_object)) in gcode.iter().enumerate() {
+            let toolhead_move = toolhead.apply(&parse_gcode(line));
+            tracker.track(line_no, line, *in_object, &toolhead_move);
+        }
+    }
+
+    fn square(name: &str, x: f64, y: f64) -> KnownObject {
+        let mut known_object = KnownObject::new(name, &HullOptions::default());
+        for (dx, dy) in [(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)] {
+            known_object.hull.add_point(x + dx, y + dy);
+        }
+        known_object
+    }
+
+    #[test]
+    fn test_assign_brims() {
+        let gcode = [
+            ";TYPE:Skirt/Brim",
+            "G1 X-1 Y-1",
+            "G1 X11 Y-1 E1",
+            "G1 X11 Y11 E2",
+            "G1 X-1 Y11 E3",
+            "G1 X-1 Y-1 E4",
+            "G1 X49 Y-1",
+            "G1 X61 Y-1 E5",
+            "G1 X61 Y11 E6",
+            "G1 X49 Y11 E7",
+            "G1 X49 Y-1 E8",
+            ";TYPE:Perimeter",
+            "G1 X0 Y0 E9",
+        ];
+
+        let mut tracker = OrphanTracker::new(&brims());
+        let mut toolhead = Toolhead::default();
+        for (line_no, line) in gcode.iter().enumerate() {
+            let toolhead_move = toolhead.apply(&parse_gcode(line));
+            tracker.track(line_no, line, false, &toolhead_move);
+        }
+
+        let objects = [square("a", 0.0, 0.0), square("b", 50.0, 0.0)];
+        let assignments = tracker.assign(objects.iter());
+        assert_eq!(assignments.start(2), Some("a"));
+        assert_eq!(assignments.end(5), Some("a"));
+        assert_eq!(assignments.start(7), Some("b"));
+        assert_eq!(assignments.end(10), Some("b"));
+        assert_eq!(assignments.start(12), None);
+    }
+
+    #[test]
+    fn test_shared_skirt() {
+        let gcode = [
+            ";TYPE:Skirt",
+            "G1 X-5 Y-5",
+            "G1 X65 Y-5 E1",
+            "G1 X65 Y15 E2",
+            "G1 X-5 Y15 E3",
+            "G1 X-5 Y-5 E4",
+        ];
+
+        let mut tracker = OrphanTracker::new(&brims());
+        let mut toolhead = Toolhead::default();
+        for (line_no, line) in gcode.iter().enumerate() {
+            let toolhead_move = toolhead.apply(&parse_gcode(line));
+            tracker.track(line_no, line, false, &toolhead_move);
+        }
+
+        let objects = [square("a", 0.0, 0.0), square("b", 50.0, 0.0)];
+        let assignments = tracker.assign(objects.iter());
+        assert_eq!(assignments.start(2), None);
+    }
+
+    #[test]
+    fn test_assign_brims_disabled() {
+        let mut tracker = OrphanTracker::new(&ProcessOptions::default());
+        let mut toolhead = Toolhead::default();
+        for (line_no, line) in [";TYPE:SKIRT", "G1 X0 Y0", "G1 X10 Y0 E1"]
+            .iter()
+            .enumerate()
+        {
+            let toolhead_move = toolhead.apply(&parse_gcode(line));
+            tracker.track(line_no, line, false, &toolhead_move);
+        }
+
+        let objects = [square("a", 0.0, 0.0)];
+        let assignments = tracker.assign(objects.iter());
+        assert_eq!(assignments.start(2), None);
+    }
+
+    #[test]
+    fn test_assign_supports() {
+        let gcode = [
+            ("G1 X1 Y1", true),
+            ("G1 X9 Y9 E1", true),
+            // Unmarked extrusion after the object inside of a
+            ("G1 X2 Y2", false),
+            ("G1 X8 Y2 E2", false),
+            ("G1 X8 Y8 E3", false),
+            (";TYPE:Support material", false),
+            ("G1 X52 Y2", false),
+            ("G1 X58 Y2 E4", false),
+            ("G1 X58 Y8 E5", false),
+            // Support outside of all objects
+            ("G1 X30 Y2", false),
+            ("G1 X32 Y2 E6", false),
+            ("G1 X32 Y8 E7", false),
+        ];
+
+        let options = ProcessOptions {
+            assign_supports: true,
+            ..Default::default()
+        };
+        let mut tracker = OrphanTracker::new(&options);
+        track(&mut tracker, &gcode);
+
+        let objects = [square("a", 0.0, 0.0), square("b", 50.0, 0.0)];
+        let assignments = tracker.assign(objects.iter());
+        assert_eq!(assignments.start(3), Some("a"));
+        assert_eq!(assignments.end(4), Some("a"));
+        assert_eq!(assignments.start(7), Some("b"));
+        assert_eq!(assignments.end(8), Some("b"));
+        assert_eq!(assignments.start(10), None);
+
+        // Brims only
+        let mut tracker = OrphanTracker::new(&brims());
+        track(&mut tracker, &gcode);
+        assert_eq!(tracker.assign(objects.iter()).start(7), None);
+    }
+
+    #[test]
+    fn test_wipe_tower() {
+        assert!(is_wipe_tower("wipe_tower"));
+        assert!(is_wipe_tower("Prime tower"));
+        assert!(is_wipe_tower("PRIME-TOWER"));
+        assert!(!is_wipe_tower("tower"));
+
+        let gcode = [
+            (";TYPE:Wipe tower", false),
+            ("G1 X100 Y100", false),
+            ("G1 X110 Y100 E1", false),
+            ("G1 X110 Y110", false),
+            ("G1 X100 Y110 E2", false),
+            (";TYPE:Perimeter", true),
+            ("G1 X0 Y0 E3", true),
+        ];
+
+        let options = ProcessOptions {
+            wipe_tower: WipeTowerMode::Object,
+            ..Default::default()
+        };
+        let mut tracker = OrphanTracker::new(&options);
+        track(&mut tracker, &gcode);
+
+        let wipe_tower = tracker.wipe_tower(&options.hull).unwrap();
+        assert_eq!(wipe_tower.name, WIPE_TOWER_NAME);
+        assert_eq!(wipe_tower.hull.center(), Some(Point::new(105.0, 105.0)));
+
+        let assignments = tracker.assign([&wipe_tower]);
+        assert_eq!(assignments.start(2), Some(WIPE_TOWER_NAME));
+        assert_eq!(assignments.end(4), Some(WIPE_TOWER_NAME));
+
+        let mut tracker = OrphanTracker::new(&ProcessOptions::default());
+        track(&mut tracker, &gcode);
+        assert!(tracker.wipe_tower(&options.hull).is_none());
+    }
+}
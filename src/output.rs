@@ -0,0 +1,356 @@
+use crate::paths::{portable_file_name, portable_path};
+use crate::report::ProcessReport;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+#[derive(Clone, Debug, Error)]
+pub enum TemplateError {
+    #[error("Unknown placeholder {{{0}}} in output template")]
+    UnknownPlaceholder(String),
+    #[error("Unterminated placeholder in output template")]
+    Unterminated,
+    #[error("Output suffix {0:?} contains characters not allowed in file names")]
+    InvalidSuffix(String),
+}
+
+/// Characters not allowed in file names on Windows, which includes the path separators
+pub(crate) const RESERVED_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Where the output suffix is placed in the file name
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum SuffixPlacement {
+    /// Before the extension, `plate.gcode` becomes `plate.SUFFIX.gcode`
+    #[default]
+    Extension,
+    /// After the file stem, `plate.gcode` becomes `plate_SUFFIX.gcode`
+    Stem,
+    /// Instead of the extension, `plate.gcode` becomes `plate.SUFFIX`
+    Replace,
+}
+
+const PLACEHOLDERS: [&str; 7] = ["stem", "ext", "name", "slicer", "objects", "date", "plate"];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder(String),
+}
+
+/// Output path template like `{slicer}/{date}/{stem}.gcode`, evaluated for each file
+///
+/// Supported placeholders:
+///
+/// * `{stem}` - input file name without extension
+/// * `{ext}` - input file extension
+/// * `{name}` - input file name
+/// * `{slicer}` - the detected slicer
+/// * `{objects}` - number of detected objects
+/// * `{date}` - current date (UTC) as YYYY-MM-DD
+/// * `{plate}` - number of the plate, for files extracted from 3MF containers
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct OutputTemplate {
+    segments: Vec<Segment>,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = TemplateError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut rest = value;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+
+            let end = rest[start..].find('}').ok_or(TemplateError::Unterminated)? + start;
+            let placeholder = &rest[start + 1..end];
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return Err(TemplateError::UnknownPlaceholder(placeholder.to_string()));
+            }
+
+            segments.push(Segment::Placeholder(placeholder.to_string()));
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+impl OutputTemplate {
+    fn value(placeholder: &str, src: &Path, report: &ProcessReport) -> String {
+        let lossy = |value: Option<&OsStr>| value.unwrap_or_default().to_string_lossy().to_string();
+
+        match placeholder {
+            "stem" => lossy(src.file_stem()),
+            "ext" => lossy(src.extension()),
+            "name" => lossy(src.file_name()),
+            "slicer" => report
+                .slicer
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string()),
+            "objects" => report.objects.len().to_string(),
+            "date" => today(),
+            "plate" => report
+                .plate
+                .map(|plate| plate.to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    pub fn render(&self, src: &Path, report: &ProcessReport) -> PathBuf {
+        let rendered: String = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Placeholder(placeholder) => Self::value(placeholder, src, report),
+            })
+            .collect();
+
+        PathBuf::from(rendered)
+    }
+}
+
+/// Current date in UTC formatted as YYYY-MM-DD
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or_default();
+
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Convert days since the unix epoch into a (year, month, day) tuple
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Check that a suffix can be part of a file name on every platform
+pub(crate) fn parse_suffix(value: &str) -> Result<String, TemplateError> {
+    if value.is_empty() || value.contains(RESERVED_CHARS) || value.chars().any(char::is_control) {
+        return Err(TemplateError::InvalidSuffix(value.to_string()));
+    }
+
+    Ok(value.to_string())
+}
+
+/// Add the suffix to the file name of the path, the directories are left untouched
+pub(crate) fn with_suffix(path: &Path, suffix: &str, placement: SuffixPlacement) -> PathBuf {
+    let Some(stem) = path.file_stem() else {
+        return path.to_path_buf();
+    };
+
+    let mut name = stem.to_owned();
+    match (placement, path.extension()) {
+        (SuffixPlacement::Extension, Some(extension)) => {
+            name.push(".");
+            name.push(suffix);
+            name.push(".");
+            name.push(extension);
+        }
+        (SuffixPlacement::Stem, extension) => {
+            name.push("_");
+            name.push(suffix);
+            if let Some(extension) = extension {
+                name.push(".");
+                name.push(extension);
+            }
+        }
+        (SuffixPlacement::Extension | SuffixPlacement::Replace, _) => {
+            name.push(".");
+            name.push(suffix);
+        }
+    }
+
+    path.with_file_name(name)
+}
+
+/// Where and under which name processed files are written
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OutputOptions {
+    /// Suffix added to the file name
+    pub suffix: Option<String>,
+    pub suffix_placement: SuffixPlacement,
+    /// Output directory, defaults to the directory of the input file
+    pub dir: Option<PathBuf>,
+    /// Template for the output path, relative to the output directory
+    pub template: Option<OutputTemplate>,
+    /// Number of the plate of the 3MF container the file was extracted from
+    pub plate: Option<usize>,
+}
+
+impl OutputOptions {
+    pub fn destination(&self, src: &Path, report: &ProcessReport) -> Option<PathBuf> {
+        let mut dest_path = match (&self.dir, &self.template) {
+            (None, None) => src.to_path_buf(),
+            (Some(dir), None) => dir.join(src.file_name()?),
+            (dir, Some(template)) => {
+                let base = match dir {
+                    Some(dir) => dir.as_path(),
+                    None => src.parent()?,
+                };
+                base.join(portable_path(&template.render(src, report)))
+            }
+        };
+
+        if let Some(suffix) = &self.suffix {
+            dest_path = with_suffix(&dest_path, suffix, self.suffix_placement);
+        }
+        // Files processed in place keep their name
+        if dest_path != src {
+            if let Some(name) = dest_path.file_name() {
+                dest_path.set_file_name(portable_file_name(name));
+            }
+        }
+
+        Some(dest_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_suffix() {
+        for (placement, path, expected) in [
+            (
+                SuffixPlacement::Extension,
+                "dir/file.gcode",
+                "dir/file.processed.gcode",
+            ),
+            (SuffixPlacement::Extension, "dir/file", "dir/file.processed"),
+            (
+                SuffixPlacement::Stem,
+                "dir/file.gcode",
+                "dir/file_processed.gcode",
+            ),
+            (SuffixPlacement::Stem, "dir/file", "dir/file_processed"),
+            (
+                SuffixPlacement::Stem,
+                "dir.v2/file",
+                "dir.v2/file_processed",
+            ),
+            (
+                SuffixPlacement::Replace,
+                "dir/file.gcode",
+                "dir/file.processed",
+            ),
+            (SuffixPlacement::Replace, "dir/file", "dir/file.processed"),
+            (SuffixPlacement::Stem, "dir/.gcode", "dir/.gcode_processed"),
+        ] {
+            assert_eq!(
+                with_suffix(Path::new(path), "processed", placement),
+                PathBuf::from(expected),
+                "{placement:?} {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_suffix() {
+        assert_eq!(parse_suffix("v2-processed").unwrap(), "v2-processed");
+        for suffix in ["", "a/b", "a\\b", "c:d", "what?", "tab\t"] {
+            assert!(
+                matches!(parse_suffix(suffix), Err(TemplateError::InvalidSuffix(_))),
+                "{suffix:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_output_template() {
+        let template = OutputTemplate::from_str("{slicer}/{objects}/{stem}_done.{ext}").unwrap();
+        let report = ProcessReport {
+            slicer: Some("PrusaSlicer".into()),
+            ..Default::default()
+        };
+        let options = OutputOptions {
+            dir: Some(PathBuf::from("out")),
+            template: Some(template),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            options.destination(Path::new("in/plate.gcode"), &report),
+            Some(PathBuf::from("out/PrusaSlicer/0/plate_done.gcode"))
+        );
+
+        let template = OutputTemplate::from_str("{stem}_plate{plate}.{ext}").unwrap();
+        let report = ProcessReport {
+            plate: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.render(Path::new("in/cube.gcode"), &report),
+            PathBuf::from("cube_plate2.gcode")
+        );
+
+        assert!(matches!(
+            OutputTemplate::from_str("{stem}/{unknown}"),
+            Err(TemplateError::UnknownPlaceholder(_))
+        ));
+        assert!(matches!(
+            OutputTemplate::from_str("{stem"),
+            Err(TemplateError::Unterminated)
+        ));
+    }
+
+    #[test]
+    fn test_portable_destination() {
+        let report = ProcessReport {
+            slicer: Some("AUX".into()),
+            ..Default::default()
+        };
+        let options = OutputOptions {
+            template: Some(OutputTemplate::from_str("{slicer}/{stem}.").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.destination(Path::new("in/plate.gcode"), &report),
+            Some(PathBuf::from("in/_AUX/plate"))
+        );
+
+        let options = OutputOptions {
+            suffix: Some("processed".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.destination(Path::new("in/con"), &report),
+            Some(PathBuf::from("in/_con.processed"))
+        );
+        assert_eq!(
+            OutputOptions::default().destination(Path::new("in/con"), &report),
+            Some(PathBuf::from("in/con"))
+        );
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+}
@@ -0,0 +1,163 @@
+//! Parsing single lines of G-Code.
+//!
+//! Kept free of any processing state so the parsers can be fuzzed on their own.
+
+use std::collections::HashMap;
+
+/// A command and its parameters, `X10` and `NAME=a` both become a key and a value
+pub struct Command<'a> {
+    pub command: Option<&'a str>,
+    pub params: HashMap<&'a str, &'a str>,
+}
+
+/// Split a line into its command and parameters, ignoring the comment
+pub fn parse_gcode(line: &str) -> Command<'_> {
+    // Drop the comment
+    let line = match line.split_once(';') {
+        None => line.trim(),
+        Some((line, _comment)) => line.trim(),
+    };
+
+    let mut parts = line.split_whitespace();
+    let command = parts.next();
+
+    let mut parsed = HashMap::new();
+
+    for param in parts {
+        if param.contains('=') {
+            param
+                .split_once('=')
+                .map(|(key, value)| parsed.insert(key, value));
+        } else {
+            // Split after the first character, which isn't necessarily a single byte
+            let name_length = param.chars().next().map_or(0, char::len_utf8);
+            let (name, value) = param.split_at(name_length);
+            parsed.insert(name, value);
+        }
+    }
+
+    Command {
+        command,
+        params: parsed,
+    }
+}
+
+/// Powers of ten that are exactly representable as `f64`
+const POW10: [f64; 23] = [
+    1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15, 1e16,
+    1e17, 1e18, 1e19, 1e20, 1e21, 1e22,
+];
+
+/// Parse a G-Code number like `-12.345`, `.5` or `1.2e-05` without going through the generic
+/// float parser.
+///
+/// Mantissa and power of ten are both exact, so their product or quotient is rounded the same way
+/// as `str::parse`. Anything else (very long numbers, large exponents) falls back to `str::parse`.
+/// Infinite and NaN values are rejected, they would end up in the object outlines.
+pub fn parse_float(value: &str) -> Option<f64> {
+    let bytes = value.as_bytes();
+    let (negative, digits) = match bytes.first() {
+        Some(b'-') => (true, &bytes[1..]),
+        Some(b'+') => (false, &bytes[1..]),
+        _ => (false, bytes),
+    };
+
+    let fallback = || value.parse::<f64>().ok().filter(|value| value.is_finite());
+
+    let mut mantissa: u64 = 0;
+    let mut decimals: Option<i32> = None;
+    let mut any_digit = false;
+    let mut exponent_start = None;
+    for (position, &byte) in digits.iter().enumerate() {
+        match byte {
+            b'0'..=b'9' => {
+                mantissa = mantissa * 10 + u64::from(byte - b'0');
+                if mantissa >= 1 << 53 {
+                    return fallback();
+                }
+                any_digit = true;
+                if let Some(decimals) = decimals.as_mut() {
+                    *decimals += 1;
+                }
+            }
+            b'.' if decimals.is_none() => decimals = Some(0),
+            b'e' | b'E' if any_digit => {
+                exponent_start = Some(position + 1);
+                break;
+            }
+            _ => return fallback(),
+        }
+    }
+    if !any_digit {
+        return fallback();
+    }
+
+    let exponent = match exponent_start {
+        None => 0,
+        Some(start) => match std::str::from_utf8(&digits[start..]).ok()?.parse::<i32>() {
+            Ok(exponent) => exponent,
+            Err(_) => return fallback(),
+        },
+    };
+
+    let power = exponent.saturating_sub(decimals.unwrap_or(0));
+    let value = match usize::try_from(power.unsigned_abs()) {
+        Ok(index) if index < POW10.len() && power >= 0 => mantissa as f64 * POW10[index],
+        Ok(index) if index < POW10.len() => mantissa as f64 / POW10[index],
+        _ => return fallback(),
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_float() {
+        for value in [
+            "0",
+            "-0",
+            "1",
+            "+1",
+            "-1.5",
+            "12.345",
+            ".5",
+            "5.",
+            "0.1",
+            "0.0125",
+            "123.45678",
+            "-0.000001",
+            "9007199254740993",
+            "1e3",
+            "1.5E-2",
+            "0.30000000000000004",
+            "1.2e-05",
+            "-.5",
+            "+.25E+2",
+            "7e22",
+            "123.456e-20",
+            "1e400",
+            "1e",
+            "1e+",
+            "e5",
+            ".e1",
+            "inf",
+            "NaN",
+            "",
+            "-",
+            ".",
+            "1.2.3",
+            "X",
+            "1-2",
+        ] {
+            let expected = value.parse::<f64>().ok().filter(|value| value.is_finite());
+            let parsed = parse_float(value);
+            assert_eq!(
+                parsed.map(f64::to_bits),
+                expected.map(f64::to_bits),
+                "{value}"
+            );
+        }
+    }
+}
@@ -0,0 +1,572 @@
+use crate::output::RESERVED_CHARS;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Device names Windows reserves in every directory, with any extension
+const RESERVED_NAMES: [&str; 24] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "CONIN$",
+    "CONOUT$",
+];
+
+/// Windows error codes for files opened by another program, replacing a file that was opened
+/// without allowing others to delete it fails with access denied
+const ERROR_ACCESS_DENIED: i32 = 5;
+const ERROR_SHARING_VIOLATION: i32 = 32;
+const ERROR_LOCK_VIOLATION: i32 = 33;
+
+/// Attempts to open or replace a file another program has open, like the slicer or a virus
+/// scanner
+pub(crate) const DEFAULT_ATTEMPTS: u32 = 8;
+/// Wait before the first retry, doubled for every further attempt
+pub(crate) const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Longest wait between two attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Most attempts that can be configured
+pub(crate) const MAX_ATTEMPTS: u32 = 100;
+/// Checks of a file that is still being written before giving up on it
+const STABLE_CHECKS: u32 = 60;
+
+/// How often opening or replacing a file in use is attempted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Retry {
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for every further attempt up to 5 seconds
+    pub delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_ATTEMPTS,
+            delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+}
+
+/// A file name that can be created on Windows as well.
+///
+/// Reserved characters are replaced, trailing dots and spaces that Windows would drop are
+/// removed and device names like `CON` or `aux.gcode` are prefixed with `_`. Names that aren't
+/// valid Unicode are returned unchanged.
+pub(crate) fn portable_file_name(name: &OsStr) -> OsString {
+    let Some(name) = name.to_str() else {
+        return name.to_owned();
+    };
+
+    let mut name: String = name
+        .chars()
+        .map(|c| match RESERVED_CHARS.contains(&c) || c.is_control() {
+            true => '_',
+            false => c,
+        })
+        .collect();
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+    if name.is_empty() {
+        return "_".into();
+    }
+
+    let device = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(device))
+    {
+        name.insert(0, '_');
+    }
+
+    name.into()
+}
+
+/// Make every file and directory name of a path portable, see [`portable_file_name`]
+pub(crate) fn portable_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => portable_file_name(name),
+            other => other.as_os_str().to_owned(),
+        })
+        .collect()
+}
+
+/// A file the input is read from
+pub(crate) trait Input: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Input for T {}
+
+/// File operations used to read the input and put the output in place, emulated in tests
+pub(crate) trait FileSystem {
+    /// Whether errors follow Windows semantics
+    fn windows(&self) -> bool {
+        cfg!(windows)
+    }
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Input>> {
+        Ok(Box::new(File::open(path)?))
+    }
+    /// A new temporary file for the output, which is not removed automatically
+    fn create_temp(&self) -> io::Result<(PathBuf, Box<dyn Write>)> {
+        let (file, path) = tempfile::NamedTempFile::new()?.keep()?;
+        Ok((path, Box::new(file)))
+    }
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn sleep(&self, duration: Duration);
+    /// Size and modification time of a file, which change while it is written
+    fn state(&self, path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+        let metadata = std::fs::metadata(path)?;
+        Ok((metadata.len(), metadata.modified().ok()))
+    }
+}
+
+pub(crate) struct NativeFileSystem;
+
+impl FileSystem for NativeFileSystem {
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::copy(from, to).map(|_| ())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Whether the error is caused by another program having the file open, which usually
+/// only lasts a moment
+fn is_sharing_violation(err: &io::Error, windows: bool) -> bool {
+    windows
+        && matches!(
+            err.raw_os_error(),
+            Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        )
+}
+
+/// Whether the error is likely gone on the next attempt, a file in use on Windows or a busy
+/// device elsewhere
+fn is_transient(err: &io::Error, windows: bool) -> bool {
+    is_sharing_violation(err, windows) || err.kind() == io::ErrorKind::ResourceBusy
+}
+
+/// Run a file operation, retrying with increasing delays while it fails transiently
+fn with_retries<T>(
+    fs: &impl FileSystem,
+    retry: &Retry,
+    path: &Path,
+    mut operation: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = retry.delay.min(MAX_RETRY_DELAY);
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(err) if attempt < retry.attempts && is_transient(&err, fs.windows()) => {
+                tracing::debug!("{} is in use, retrying in {:?}", path.display(), delay);
+                fs.sleep(delay);
+                delay = delay
+                    .checked_mul(2)
+                    .map_or(MAX_RETRY_DELAY, |next| next.min(MAX_RETRY_DELAY));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Open a file for reading, retrying while another program has it open
+pub(crate) fn open_file(
+    fs: &impl FileSystem,
+    path: &Path,
+    retry: &Retry,
+) -> io::Result<Box<dyn Input>> {
+    with_retries(fs, retry, path, || fs.open(path))
+}
+
+/// Move a finished file to the destination, replacing an existing file.
+///
+/// Retries with increasing delays while another program has the destination open, and copies
+/// the file if it is on another file system than the destination.
+pub(crate) fn replace_file(
+    fs: &impl FileSystem,
+    from: &Path,
+    to: &Path,
+    retry: &Retry,
+) -> io::Result<()> {
+    with_retries(fs, retry, to, || match fs.rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => copy_into_place(fs, from, to),
+        result => result,
+    })
+}
+
+/// Wait until the size and modification time of a file stop changing for `settle`, e.g. while
+/// the slicer or an upload is still writing it. Gives up after a number of checks.
+pub(crate) fn wait_until_stable(
+    fs: &impl FileSystem,
+    path: &Path,
+    settle: Duration,
+) -> io::Result<()> {
+    let mut state = fs.state(path)?;
+    for _ in 0..STABLE_CHECKS {
+        fs.sleep(settle);
+        let current = fs.state(path)?;
+        if current == state {
+            return Ok(());
+        }
+        tracing::debug!("{} is still being written", path.display());
+        state = current;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} is still being written", path.display()),
+    ))
+}
+
+/// Copy the file next to the destination and rename it there, so a failed copy never leaves a
+/// truncated destination behind
+fn copy_into_place(fs: &impl FileSystem, from: &Path, to: &Path) -> io::Result<()> {
+    let mut part = to.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    match fs.copy(from, &part).and_then(|()| fs.rename(&part, to)) {
+        Ok(()) => {
+            let _result = fs.remove_file(from);
+            Ok(())
+        }
+        Err(err) => {
+            let _result = fs.remove_file(&part);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// Windows semantics on top of a map of file contents: names are case insensitive, `C:` and
+    /// `D:` are different volumes and files opened by another program fail to be replaced for a
+    /// number of attempts
+    #[derive(Default)]
+    struct EmulatedWindows {
+        files: RefCell<HashMap<String, String>>,
+        /// Attempts failing with a sharing violation, per file
+        locked: RefCell<HashMap<String, usize>>,
+        sleeps: RefCell<Vec<Duration>>,
+        /// Bytes appended to every file on each sleep, for this many sleeps
+        growing: RefCell<usize>,
+    }
+
+    impl EmulatedWindows {
+        fn key(path: &Path) -> String {
+            path.to_string_lossy().replace('/', "\\").to_lowercase()
+        }
+
+        fn with_file(self, path: &str, content: &str) -> Self {
+            self.files
+                .borrow_mut()
+                .insert(Self::key(Path::new(path)), content.into());
+            self
+        }
+
+        fn content(&self, path: &str) -> Option<String> {
+            self.files
+                .borrow()
+                .get(&Self::key(Path::new(path)))
+                .cloned()
+        }
+
+        fn check_lock(&self, path: &Path) -> io::Result<()> {
+            match self.locked.borrow_mut().get_mut(&Self::key(path)) {
+                Some(attempts) if *attempts > 0 => {
+                    *attempts -= 1;
+                    Err(io::Error::from_raw_os_error(ERROR_SHARING_VIOLATION))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl FileSystem for EmulatedWindows {
+        fn windows(&self) -> bool {
+            true
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            if Self::key(from)[..2] != Self::key(to)[..2] {
+                return Err(io::ErrorKind::CrossesDevices.into());
+            }
+            self.check_lock(to)?;
+            let content = self
+                .files
+                .borrow_mut()
+                .remove(&Self::key(from))
+                .ok_or(io::ErrorKind::NotFound)?;
+            self.files.borrow_mut().insert(Self::key(to), content);
+            Ok(())
+        }
+
+        fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.check_lock(to)?;
+            let content = self
+                .files
+                .borrow()
+                .get(&Self::key(from))
+                .cloned()
+                .ok_or(io::ErrorKind::NotFound)?;
+            self.files.borrow_mut().insert(Self::key(to), content);
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.check_lock(path)?;
+            self.files
+                .borrow_mut()
+                .remove(&Self::key(path))
+                .map(|_| ())
+                .ok_or(io::ErrorKind::NotFound.into())
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.sleeps.borrow_mut().push(duration);
+            let mut growing = self.growing.borrow_mut();
+            if *growing > 0 {
+                *growing -= 1;
+                self.files
+                    .borrow_mut()
+                    .values_mut()
+                    .for_each(|content| content.push('.'));
+            }
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Input>> {
+            self.check_lock(path)?;
+            let content = self.content(&path.to_string_lossy());
+            let content = content.ok_or(io::ErrorKind::NotFound)?;
+            Ok(Box::new(io::Cursor::new(content.into_bytes())))
+        }
+
+        fn state(&self, path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+            let content = self.content(&path.to_string_lossy());
+            let content = content.ok_or(io::ErrorKind::NotFound)?;
+            Ok((content.len() as u64, None))
+        }
+    }
+
+    #[test]
+    fn test_portable_file_name() {
+        for (name, expected) in [
+            ("plate.gcode", "plate.gcode"),
+            ("CON", "_CON"),
+            ("con.gcode", "_con.gcode"),
+            ("Aux.processed.gcode", "_Aux.processed.gcode"),
+            ("lpt1 .gcode", "_lpt1 .gcode"),
+            ("COM10.gcode", "COM10.gcode"),
+            ("console.gcode", "console.gcode"),
+            ("plate.", "plate"),
+            ("plate. . ", "plate"),
+            ("nul.", "_nul"),
+            ("a:b?.gcode", "a_b_.gcode"),
+            ("...", "_"),
+        ] {
+            assert_eq!(
+                portable_file_name(OsStr::new(name)),
+                OsString::from(expected),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_portable_path() {
+        assert_eq!(
+            portable_path(Path::new("out/aux/../PrusaSlicer./plate.")),
+            PathBuf::from("out/_aux/../PrusaSlicer/plate")
+        );
+    }
+
+    #[test]
+    fn test_replace_file_retries() {
+        let fs = EmulatedWindows::default()
+            .with_file("C:\\Temp\\.tmp1234", "processed")
+            .with_file("C:\\Prints\\Plate.gcode", "original");
+        fs.locked
+            .borrow_mut()
+            .insert("c:\\prints\\plate.gcode".into(), 3);
+
+        replace_file(
+            &fs,
+            Path::new("C:\\Temp\\.tmp1234"),
+            Path::new("C:\\prints\\plate.GCODE"),
+            &Retry::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs.content("C:\\Prints\\Plate.gcode").as_deref(),
+            Some("processed")
+        );
+        assert_eq!(fs.content("C:\\Temp\\.tmp1234"), None);
+        assert_eq!(
+            *fs.sleeps.borrow(),
+            [50, 100, 200].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn test_replace_file_gives_up() {
+        let fs = EmulatedWindows::default()
+            .with_file("C:\\Temp\\.tmp1234", "processed")
+            .with_file("C:\\plate.gcode", "original");
+        fs.locked
+            .borrow_mut()
+            .insert("c:\\plate.gcode".into(), usize::MAX);
+
+        let err = replace_file(
+            &fs,
+            Path::new("C:\\Temp\\.tmp1234"),
+            Path::new("C:\\plate.gcode"),
+            &Retry::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ERROR_SHARING_VIOLATION));
+        assert_eq!(fs.sleeps.borrow().len(), DEFAULT_ATTEMPTS as usize - 1);
+        assert_eq!(fs.content("C:\\plate.gcode").as_deref(), Some("original"));
+    }
+
+    #[test]
+    fn test_replace_file_other_volume() {
+        let fs = EmulatedWindows::default().with_file("C:\\Temp\\.tmp1234", "processed");
+        fs.locked.borrow_mut().insert("d:\\plate.gcode".into(), 1);
+
+        replace_file(
+            &fs,
+            Path::new("C:\\Temp\\.tmp1234"),
+            Path::new("D:\\plate.gcode"),
+            &Retry::default(),
+        )
+        .unwrap();
+        assert_eq!(fs.content("D:\\plate.gcode").as_deref(), Some("processed"));
+        assert_eq!(fs.content("C:\\Temp\\.tmp1234"), None);
+        assert_eq!(fs.content("D:\\plate.gcode.part"), None);
+        assert_eq!(fs.sleeps.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_replace_file_failed_copy() {
+        let fs = EmulatedWindows::default()
+            .with_file("C:\\Temp\\.tmp1234", "processed")
+            .with_file("D:\\plate.gcode", "original");
+        fs.locked
+            .borrow_mut()
+            .insert("d:\\plate.gcode.part".into(), usize::MAX);
+
+        assert!(replace_file(
+            &fs,
+            Path::new("C:\\Temp\\.tmp1234"),
+            Path::new("D:\\plate.gcode"),
+            &Retry::default(),
+        )
+        .is_err());
+        // The destination is left alone and the temporary file can be copied again
+        assert_eq!(fs.content("D:\\plate.gcode").as_deref(), Some("original"));
+        assert_eq!(
+            fs.content("C:\\Temp\\.tmp1234").as_deref(),
+            Some("processed")
+        );
+    }
+
+    #[test]
+    fn test_replace_file_configured_retries() {
+        let fs = EmulatedWindows::default()
+            .with_file("C:\\Temp\\.tmp1234", "processed")
+            .with_file("C:\\plate.gcode", "original");
+        fs.locked.borrow_mut().insert("c:\\plate.gcode".into(), 2);
+        let retry = Retry {
+            attempts: 2,
+            delay: Duration::from_secs(1),
+        };
+
+        let (from, to) = (
+            Path::new("C:\\Temp\\.tmp1234"),
+            Path::new("C:\\plate.gcode"),
+        );
+        assert!(replace_file(&fs, from, to, &retry).is_err());
+        replace_file(&fs, from, to, &retry).unwrap();
+        assert_eq!(*fs.sleeps.borrow(), [Duration::from_secs(1)]);
+    }
+
+    #[test]
+    fn test_retry_delay_capped() {
+        let fs = EmulatedWindows::default().with_file("C:\\plate.gcode", "G28");
+        fs.locked
+            .borrow_mut()
+            .insert("c:\\plate.gcode".into(), usize::MAX);
+        let retry = Retry {
+            attempts: 20,
+            delay: Duration::from_secs(1),
+        };
+
+        assert!(open_file(&fs, Path::new("C:\\plate.gcode"), &retry).is_err());
+        let sleeps = fs.sleeps.borrow();
+        assert_eq!(sleeps.len(), 19);
+        assert_eq!(sleeps[..4], [1, 2, 4, 5].map(Duration::from_secs));
+        assert!(sleeps.iter().all(|sleep| *sleep <= MAX_RETRY_DELAY));
+    }
+
+    #[test]
+    fn test_open_file_retries() {
+        let fs = EmulatedWindows::default().with_file("C:\\plate.gcode", "G28");
+        fs.locked.borrow_mut().insert("c:\\plate.gcode".into(), 2);
+
+        let mut content = String::new();
+        open_file(&fs, Path::new("C:\\plate.gcode"), &Retry::default())
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "G28");
+        assert_eq!(fs.sleeps.borrow().len(), 2);
+        assert!(open_file(&fs, Path::new("C:\\missing.gcode"), &Retry::default()).is_err());
+        assert_eq!(fs.sleeps.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_wait_until_stable() {
+        let fs = EmulatedWindows::default().with_file("C:\\plate.gcode", "G28");
+        let settle = Duration::from_secs(2);
+
+        // Stable once a check finds the file unchanged
+        *fs.growing.borrow_mut() = 3;
+        wait_until_stable(&fs, Path::new("C:\\plate.gcode"), settle).unwrap();
+        assert_eq!(*fs.sleeps.borrow(), [settle; 4]);
+        assert_eq!(fs.content("C:\\plate.gcode").as_deref(), Some("G28..."));
+
+        *fs.growing.borrow_mut() = usize::MAX;
+        let err = wait_until_stable(&fs, Path::new("C:\\plate.gcode"), settle).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(wait_until_stable(&fs, Path::new("C:\\missing.gcode"), settle).is_err());
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(is_transient(&io::ErrorKind::ResourceBusy.into(), false));
+        assert!(!is_transient(&io::ErrorKind::NotFound.into(), true));
+    }
+
+    #[test]
+    fn test_sharing_violation_only_on_windows() {
+        let err = io::Error::from_raw_os_error(ERROR_SHARING_VIOLATION);
+        assert!(is_sharing_violation(&err, true));
+        // EPIPE on Unix
+        assert!(!is_sharing_violation(&err, false));
+        assert!(!is_sharing_violation(&io::ErrorKind::NotFound.into(), true));
+    }
+}
@@ -1,12 +1,45 @@
-use crate::layers::LayerFilter;
-use crate::slicers::{identify_slicer_marker, CancellationPreProcessor, PreProcessorImpl};
-use std::ffi::OsStr;
-use std::fs::{remove_file, rename, DirBuilder, File};
-use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
-use std::path::PathBuf;
-use tempfile::NamedTempFile;
+use crate::cache::{options_hash, CacheEntry, ResultCache};
+use crate::encoding::{is_binary, Encoding};
+use crate::framing::{first_line_number, strip_framing, FramingWriter};
+use crate::gcode::{
+    header_marker_version, known_objects_count, provenance_options, starts_with_marker,
+    tool_version, trailer_version, Provenance,
+};
+use crate::groups::ObjectGroup;
+use crate::hulls::HullOptions;
+use crate::idex::IdexMode;
+use crate::layers::{LayerFilter, LayerTracker};
+use crate::lines::LineReader;
+use crate::motion::ToolOffset;
+use crate::orphans::WipeTowerMode;
+use crate::output::{with_suffix, OutputOptions, SuffixPlacement};
+use crate::paths::{
+    open_file, portable_file_name, replace_file, FileSystem, NativeFileSystem, Retry,
+};
+use crate::report::{OverlapReport, ProcessReport, Warning};
+use crate::resume::ResumeState;
+use crate::slicers::vase::{self, is_spiral_vase, VASE_NAME};
+use crate::slicers::{
+    detect_line_width, identify_slicer_marker, CancellationPreProcessor, ObjectMarkers,
+    PreProcessorImpl,
+};
+use crate::splice::{normalize_markers, strip_markers};
+use crate::transform::{Affine, Transform};
+use crate::verify::{MarkerProblem, MarkerVerifier};
+use std::collections::HashMap;
+use std::fs::DirBuilder;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use thiserror::Error;
 
+/// Default size of the read and write buffers, large enough to keep network filesystems busy
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+/// Bytes at the end of a file searched for the trailer of files processed without header marker
+const TRAILER_WINDOW: u64 = 4096;
+/// Default size of the windows at the start and end of a file scanned for the slicer
+pub(crate) const DEFAULT_SCAN_WINDOW: usize = 1024 * 1024;
+
 #[derive(Debug, Error)]
 pub enum PreprocessError {
     #[error("Error reading/writing file {0}")]
@@ -21,39 +54,874 @@ pub enum PreprocessError {
     InvalidLayerFilter,
     #[error("Error creating output directory")]
     CreateOutputDirectory,
+    #[error("Error creating archive directory")]
+    CreateArchiveDirectory,
+    #[error("{0} exists in the archive directory already")]
+    Archived(String),
     #[error("Error creating temporary working file")]
     TempFile,
     #[error("Error writing changes to temporary working file")]
     FlushTempFile,
     #[error("The slicer that created this G-Code file could not be identified")]
     UnknownSlicer,
+    #[error("Error reading line {0} of the input file: {1}")]
+    ReadLineError(usize, String),
+    #[error("Line {0}: {1}")]
+    MalformedLine(usize, String),
+    #[error("Unbalanced object markers in the output: {0}")]
+    UnbalancedMarkers(String),
+    #[error("The input is not a text G-Code file")]
+    BinaryInput,
+    #[error("No objects found in the {0} file, is object labeling enabled in the slicer?")]
+    NoObjects(String),
+    #[error("The file was processed already")]
+    AlreadyProcessed,
+    #[error("Objects {0} and {1} overlap")]
+    OverlappingObjects(String, String),
+    #[error("{0} warnings treated as errors")]
+    Warnings(usize),
     #[error("Something bad happened :(")]
     Other,
 }
 
+impl PreprocessError {
+    /// Stable identifier of the error for machine-readable output
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::IoError(_) => "io_error",
+            Self::RewindError => "rewind_error",
+            Self::ReadError => "read_error",
+            Self::WriteError => "write_error",
+            Self::InvalidLayerFilter => "invalid_layer_filter",
+            Self::CreateOutputDirectory => "create_output_directory",
+            Self::CreateArchiveDirectory => "create_archive_directory",
+            Self::Archived(_) => "archived",
+            Self::TempFile => "temp_file",
+            Self::FlushTempFile => "flush_temp_file",
+            Self::UnknownSlicer => "unknown_slicer",
+            Self::ReadLineError(..) => "read_line_error",
+            Self::MalformedLine(..) => "malformed_line",
+            Self::UnbalancedMarkers(_) => "unbalanced_markers",
+            Self::BinaryInput => "binary_input",
+            Self::NoObjects(_) => "no_objects",
+            Self::AlreadyProcessed => "already_processed",
+            Self::OverlappingObjects(..) => "overlapping_objects",
+            Self::Warnings(_) => "warnings",
+            Self::Other => "other",
+        }
+    }
+
+    /// Line of the input the error was found on
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::ReadLineError(line, _) | Self::MalformedLine(line, _) => Some(*line),
+            _ => None,
+        }
+    }
+}
+
+/// Writer for the processed output, vectored writes gather several buffers per write call
+#[cfg(feature = "vectored-write")]
+type OutputWriter<W> = crate::vectored::VectoredWriter<W>;
+#[cfg(not(feature = "vectored-write"))]
+type OutputWriter<W> = BufWriter<W>;
+
+/// How files already processed by this tool are handled
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum RefreshMode {
+    /// Pass processed files through unchanged
+    #[default]
+    Never,
+    /// Process files again that were processed by a different version, or with different
+    /// options if the header records them
+    Outdated,
+    /// Always process files again
+    Always,
+}
+
+/// Which markers are used for files marking their objects with both comments and M486 commands
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum MarkerPrecedence {
+    /// Use the slicer comments and comment out the M486 commands
+    #[default]
+    Comments,
+    /// Use the M486 commands
+    M486,
+}
+
+/// Settings controlling object detection and hull generation
+#[derive(Clone, Debug)]
+pub(crate) struct ProcessOptions {
+    pub layer_filter: LayerFilter,
+    pub hull: HullOptions,
+    /// XY offsets of the tools on multi-extruder machines
+    pub tool_offsets: Vec<ToolOffset>,
+    /// XY offset of the outlines, moving them from the slicer's to the printer's coordinates
+    pub xy_offset: (f64, f64),
+    /// Transforms of the outlines, applied in order after the offsets
+    pub transforms: Vec<Transform>,
+    /// Placement of the second print of each object in IDEX copy or mirror mode
+    pub idex_copy: Vec<Transform>,
+    /// Exclude the objects printed only with these tools before the print starts
+    pub cancel_extruders: Vec<u32>,
+    /// Groups of related objects listed after the definitions
+    pub groups: Vec<ObjectGroup>,
+    /// Macro called with the objects of each group
+    pub group_macro: Option<String>,
+    /// Prefix the names of the objects with the name of their group
+    pub group_prefix: bool,
+    /// Fail instead of warning about overlapping objects
+    pub strict: bool,
+    /// Emit a comment with the Z range and layer count of each object
+    pub object_metadata: bool,
+    /// Emit a comment with the outline of each object in Well-Known-Text format
+    pub wkt: bool,
+    /// Emit a comment mapping the original name of each object to its sanitized name
+    pub name_comments: bool,
+    /// Start the header with the marker comment, otherwise processed files are recognized by a
+    /// comment after their last line
+    pub header_marker: bool,
+    /// Comment starting the header instead of the marker
+    pub header_text: Option<String>,
+    /// Emit a comment with the settings, the slicer and a hash of the input, filled in for each
+    /// file when set
+    pub provenance: Option<Provenance>,
+    /// Object names longer than this are shortened, see [`crate::hulls::KnownObject::clean_id`]
+    pub max_name_length: Option<usize>,
+    /// Assign skirt and brim extrusions to the nearest object
+    pub assign_brims: bool,
+    /// Assign support and unmarked extrusions to the object they are printed inside of
+    pub assign_supports: bool,
+    pub wipe_tower: WipeTowerMode,
+    /// Objects with a smaller outline in mm² are not defined
+    pub min_object_area: Option<f64>,
+    /// Objects with less extrusion moves in mm are not defined
+    pub min_extrusion_length: Option<f64>,
+    /// Size of the buffer for reading the input in bytes
+    pub read_buffer: usize,
+    /// Size of the buffer for writing the output in bytes
+    pub write_buffer: usize,
+    /// Attempts to open the input and replace the output while another program has them open
+    pub retry: Retry,
+    /// Directory of the result cache, see [`ResultCache`]
+    pub cache_dir: Option<PathBuf>,
+    /// Keep the analysis of files next to them until the output is written, see [`ResumeState`]
+    pub resume: bool,
+    pub refresh: RefreshMode,
+    /// Bytes at the start and end of a file scanned for the slicer, 0 scans the whole file
+    pub scan_window: usize,
+    /// Comments marking the end of the print in ideaMaker files, in addition to the built-in ones
+    pub end_markers: Vec<String>,
+    /// Warn instead of failing when the markers in the output are unbalanced
+    pub lenient_verification: bool,
+    /// Fail for files with any warnings in their report, nothing is written for them
+    pub warnings_as_errors: bool,
+    pub marker_precedence: MarkerPrecedence,
+    /// Comment out object commands conflicting with the markers used, see [`normalize_markers`]
+    pub normalize: bool,
+    /// Emit a comment counting the layers of the object after each object start
+    pub object_progress: bool,
+    /// Add M73 progress commands after the elapsed time comments of Cura
+    pub progress: bool,
+    /// Stop once the objects and their outlines are found, nothing is written
+    pub analyze_only: bool,
+    /// Comment out M486 commands, set for files that also mark their objects with comments
+    pub strip_m486: bool,
+    /// Define the second print of each object, set for files printed in IDEX copy or mirror mode
+    pub idex: Option<Affine>,
+    /// Fail for files without objects instead of copying them unchanged
+    pub require_objects: bool,
+    /// Fail for files passed through unchanged because they were processed already
+    pub fail_if_processed: bool,
+    /// Add the markers to files defining objects without starting them, instead of copying them
+    pub complete_partial: bool,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            layer_filter: LayerFilter::all(),
+            hull: HullOptions::default(),
+            tool_offsets: Vec::new(),
+            xy_offset: (0.0, 0.0),
+            transforms: Vec::new(),
+            idex_copy: Vec::new(),
+            cancel_extruders: Vec::new(),
+            groups: Vec::new(),
+            group_macro: None,
+            group_prefix: false,
+            strict: false,
+            object_metadata: false,
+            wkt: false,
+            name_comments: false,
+            header_marker: true,
+            header_text: None,
+            provenance: None,
+            max_name_length: None,
+            assign_brims: false,
+            assign_supports: false,
+            wipe_tower: WipeTowerMode::default(),
+            min_object_area: None,
+            min_extrusion_length: None,
+            read_buffer: DEFAULT_BUFFER_SIZE,
+            write_buffer: DEFAULT_BUFFER_SIZE,
+            retry: Retry::default(),
+            cache_dir: None,
+            resume: false,
+            refresh: RefreshMode::default(),
+            scan_window: DEFAULT_SCAN_WINDOW,
+            end_markers: Vec::new(),
+            lenient_verification: false,
+            warnings_as_errors: false,
+            marker_precedence: MarkerPrecedence::default(),
+            normalize: false,
+            object_progress: false,
+            progress: false,
+            analyze_only: false,
+            strip_m486: false,
+            idex: None,
+            require_objects: false,
+            fail_if_processed: false,
+            complete_partial: false,
+        }
+    }
+}
+
+/// What is known about a file before collecting its objects
+#[derive(Default)]
+struct Identification {
+    processor: Option<(&'static str, PreProcessorImpl)>,
+    /// Slicer identified by its banner comment
+    banner: Option<(&'static str, PreProcessorImpl)>,
+    /// M486 commands are present
+    m486: Option<(&'static str, PreProcessorImpl)>,
+    /// The file has both a slicer banner and M486 commands
+    mixed: bool,
+    line_width: Option<f64>,
+    /// Only tracked when the whole file is scanned
+    layers: LayerTracker,
+    /// The file already contains object definitions and markers
+    already_processed: bool,
+    /// The file defines objects but never starts them, e.g. when edited by hand
+    partial: bool,
+    /// The file is printed in spiral vase mode
+    vase: bool,
+    /// The first IDEX copy or mirror mode enabled by the file
+    idex: Option<IdexMode>,
+    defines: bool,
+    starts: bool,
+    /// Used when no slicer banner is found
+    object_markers: ObjectMarkers,
+}
+
+impl Identification {
+    /// Scan a file for its slicer, existing object definitions and extrusion width.
+    ///
+    /// The slicer markers are near the top of the file, settings near the bottom, so only the
+    /// scan window at both ends is read unless the slicer isn't found there or the layer filter
+    /// needs to know the number of layers.
+    fn identify(
+        input: &mut (impl BufRead + Seek),
+        size: u64,
+        options: &ProcessOptions,
+    ) -> io::Result<Self> {
+        let window = options.scan_window as u64;
+        let windowed =
+            window > 0 && size > window.saturating_mul(2) && !options.layer_filter.is_relative();
+
+        if windowed {
+            let mut identification = Self::default();
+            identification.scan(input.by_ref(), Some(window), options)?;
+            if !(identification.defines && identification.starts) {
+                input.seek(SeekFrom::End(-(window as i64)))?;
+                // The window most likely starts in the middle of a line
+                input.skip_until(b'\n')?;
+                identification.scan(input.by_ref(), None, options)?;
+            }
+            input.rewind()?;
+
+            identification.resolve(options);
+            // Markers of partially processed files may be anywhere in between
+            if (identification.processor.is_some() && !identification.partial)
+                || identification.already_processed
+            {
+                return Ok(identification);
+            }
+            tracing::debug!("No slicer found at the start or end of the file, scanning all of it");
+        }
+
+        let mut identification = Self::default();
+        identification.scan(input.by_ref(), None, options)?;
+        input.rewind()?;
+
+        identification.resolve(options);
+        if identification.processor.is_none() && !identification.already_processed {
+            identification.processor = identification.object_markers.processor();
+        }
+
+        Ok(identification)
+    }
+
+    /// Scan up to `limit` bytes of lines, the layers are only tracked when scanning everything
+    fn scan(
+        &mut self,
+        input: impl BufRead,
+        limit: Option<u64>,
+        options: &ProcessOptions,
+    ) -> io::Result<()> {
+        let mut scanned = 0;
+        let mut lines = LineReader::new(input);
+        while let Some((_, line)) = lines.next_line()? {
+            if limit.is_some_and(|limit| scanned > limit) {
+                break;
+            }
+            scanned += line.len() as u64 + 1;
+
+            if limit.is_none() {
+                self.layers.track(line);
+            }
+            if starts_with_marker(line, "EXCLUDE_OBJECT_DEFINE")
+                || starts_with_marker(line, "DEFINE_OBJECT")
+            {
+                self.defines = true;
+            } else if starts_with_marker(line, "EXCLUDE_OBJECT_START") {
+                self.starts = true;
+            }
+            if self.defines && self.starts {
+                break;
+            }
+
+            if options.hull.line_width.is_none() && self.line_width.is_none() {
+                self.line_width = detect_line_width(line);
+            }
+            if !self.vase {
+                self.vase = is_spiral_vase(line);
+            }
+            if self.idex.is_none() {
+                self.idex = IdexMode::from_line(line);
+            }
+
+            if self.banner.is_none() || self.m486.is_none() {
+                match identify_slicer_marker(line) {
+                    Some(found) if found.0 == "M486" => {
+                        self.m486.get_or_insert(found);
+                    }
+                    Some(found) => {
+                        self.banner.get_or_insert(found);
+                    }
+                    None if self.banner.is_none() && self.m486.is_none() => {
+                        self.object_markers.track(line);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Choose the processor, files with a slicer banner and M486 commands use the markers given
+    /// by the precedence
+    fn resolve(&mut self, options: &ProcessOptions) {
+        self.already_processed = self.defines && self.starts;
+        self.partial = self.defines && !self.starts;
+        if self.already_processed {
+            tracing::info!("GCode already supports cancellation");
+        }
+
+        self.processor = match (self.banner.take(), self.m486.take()) {
+            (Some(banner), Some(m486)) => {
+                tracing::info!(
+                    "{} file also contains M486 commands, using {:?} markers",
+                    banner.0,
+                    options.marker_precedence
+                );
+                self.mixed = true;
+                match options.marker_precedence {
+                    MarkerPrecedence::Comments => Some(banner),
+                    MarkerPrecedence::M486 => Some(m486),
+                }
+            }
+            (banner, m486) => banner.or(m486),
+        };
+
+        if let Some((slicer, _)) = &self.processor {
+            tracing::info!("Identified slicer: {}", slicer);
+        }
+    }
+}
+
+/// The header written by this tool when it processed the file before
+#[derive(Debug, Eq, PartialEq)]
+struct ProcessedHeader {
+    version: String,
+    /// All objects announced by the header are defined and there is no second header, which
+    /// runs interrupted while writing the header or older versions processing twice leave behind
+    complete: bool,
+    /// Hash of the options recorded by the provenance comment
+    options: Option<String>,
+}
+
+/// The header of a file processed before.
+///
+/// The header is placed before the first command, so only the comments at the top of the file
+/// and the header itself are read. Headers without the marker start with the object count, the
+/// version is taken from the trailer after the last line then.
+fn processed_header(input: &mut (impl BufRead + Seek)) -> io::Result<Option<ProcessedHeader>> {
+    let mut lines = LineReader::new(&mut *input);
+    let mut header: Option<ProcessedHeader> = None;
+    let mut announced = None;
+    let mut defined = 0;
+    let mut options = None;
+
+    while let Some((_, line)) = lines.next_line()? {
+        if let Some(version) = header_marker_version(line) {
+            if let Some(header) = &mut header {
+                header.complete = false;
+                break;
+            }
+            header = Some(ProcessedHeader {
+                version: version.to_string(),
+                complete: true,
+                options: None,
+            });
+            continue;
+        }
+
+        let line = line.trim();
+        if let Some(count) = known_objects_count(line) {
+            announced = Some(count);
+            continue;
+        }
+        if header.is_none() && announced.is_none() {
+            if !line.is_empty() && !line.starts_with(';') {
+                break;
+            }
+            continue;
+        }
+
+        if let Some(recorded) = provenance_options(line) {
+            options = Some(recorded.to_string());
+        } else if line.starts_with("EXCLUDE_OBJECT_DEFINE") {
+            defined += 1;
+        } else if !line.is_empty() && !line.starts_with(';') {
+            break;
+        }
+    }
+
+    if header.is_none() && announced.is_some() {
+        // The trailer is written last, runs interrupted before leave none behind
+        let version = processed_trailer(input)?;
+        header = Some(ProcessedHeader {
+            complete: version.is_some(),
+            version: version.unwrap_or_default(),
+            options: None,
+        });
+    }
+
+    Ok(header.map(|mut header| {
+        header.complete &= announced == Some(defined);
+        header.options = options;
+        header
+    }))
+}
+
+/// The version of the trailer after the last line of a file, see [`crate::gcode::trailer`]
+fn processed_trailer(input: &mut (impl Read + Seek)) -> io::Result<Option<String>> {
+    let size = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(size.saturating_sub(TRAILER_WINDOW)))?;
+    let mut tail = Vec::new();
+    input.read_to_end(&mut tail)?;
+
+    // The window can start in the middle of a character
+    let tail = String::from_utf8_lossy(&tail);
+    Ok(tail
+        .lines()
+        .rfind(|line| !line.trim().is_empty())
+        .and_then(trailer_version)
+        .map(str::to_string))
+}
+
+fn check_overlaps(
+    overlaps: &[OverlapReport],
+    options: &ProcessOptions,
+) -> Result<(), PreprocessError> {
+    for overlap in overlaps {
+        let (a, b) = &overlap.objects;
+        if options.strict {
+            tracing::error!("Objects {} and {} overlap by {:.1}mm²", a, b, overlap.area);
+            return Err(PreprocessError::OverlappingObjects(a.clone(), b.clone()));
+        }
+        tracing::warn!("Objects {} and {} overlap by {:.1}mm²", a, b, overlap.area);
+    }
+
+    Ok(())
+}
+
+fn check_markers(
+    problems: &[MarkerProblem],
+    options: &ProcessOptions,
+    report: &mut ProcessReport,
+) -> Result<(), PreprocessError> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    for problem in problems {
+        if options.lenient_verification {
+            tracing::warn!("Unbalanced object markers, {}", problem);
+        } else {
+            tracing::error!("Unbalanced object markers, {}", problem);
+        }
+    }
+    if options.lenient_verification {
+        report
+            .warnings
+            .extend(problems.iter().map(|problem| Warning::UnbalancedMarkers {
+                line: problem.line,
+                message: problem.message.clone(),
+            }));
+        return Ok(());
+    }
+
+    let lines = problems.iter().map(|problem| problem.line.to_string());
+    Err(PreprocessError::UnbalancedMarkers(format!(
+        "lines {}",
+        lines.collect::<Vec<_>>().join(", ")
+    )))
+}
+
+/// Fail for files with warnings if they are treated as errors
+fn check_warnings(report: &ProcessReport, options: &ProcessOptions) -> Result<(), PreprocessError> {
+    if !options.warnings_as_errors || report.warnings.is_empty() {
+        return Ok(());
+    }
+
+    for warning in &report.warnings {
+        tracing::error!("{}", warning);
+    }
+    Err(PreprocessError::Warnings(report.warnings.len()))
+}
+
+/// Fail for files that were processed already if asked to, nothing is written for them. Files
+/// only defining objects weren't processed by this tool and don't fail.
+fn check_processed(
+    report: &ProcessReport,
+    options: &ProcessOptions,
+) -> Result<(), PreprocessError> {
+    match options.fail_if_processed && report.already_processed && !report.partial {
+        true => Err(PreprocessError::AlreadyProcessed),
+        false => Ok(()),
+    }
+}
+
+/// Input that remembers whether reading it failed, to tell read from write errors when copying
+/// it to the output
+struct TrackedInput<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R> TrackedInput<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            failed: false,
+        }
+    }
+}
+
+impl<R: Read> Read for TrackedInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        if result
+            .as_ref()
+            .is_err_and(|err| err.kind() != io::ErrorKind::Interrupted)
+        {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+impl<R: Seek> Seek for TrackedInput<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// The error of copying the input to the output
+fn copy_error<R>(input: &BufReader<TrackedInput<R>>) -> PreprocessError {
+    match input.get_ref().failed {
+        true => PreprocessError::ReadError,
+        false => PreprocessError::WriteError,
+    }
+}
+
 fn process(
     input: impl Read + Seek + Send,
     output: &mut impl Write,
-    layer_filter: &LayerFilter,
+    options: &ProcessOptions,
+    report: &mut ProcessReport,
 ) -> Result<(), PreprocessError> {
-    let mut input = BufReader::new(input);
-    let mut processor: Option<PreProcessorImpl> = None;
+    let started = Instant::now();
+    let mut input = BufReader::with_capacity(options.read_buffer, TrackedInput::new(input));
 
-    for line in input.by_ref().lines() {
-        let line = line.map_err(|_err| PreprocessError::ReadError)?;
-        if line.starts_with("EXCLUDE_OBJECT_DEFINE") || line.starts_with("DEFINE_OBJECT") {
-            tracing::info!("GCode already supports cancellation");
+    let start = input
+        .fill_buf()
+        .map_err(|_err| PreprocessError::ReadError)?;
+    if let Some(encoding) = Encoding::detect(start) {
+        tracing::warn!(
+            "The input is encoded as {}, the output is written as UTF-8 for Klipper to read it",
+            encoding
+        );
+        let mut spool = tempfile::tempfile().map_err(|_err| PreprocessError::TempFile)?;
+        let mut writer = BufWriter::with_capacity(options.write_buffer, &mut spool);
+        encoding
+            .transcode(&mut input, &mut writer)
+            .map_err(|_err| PreprocessError::ReadError)?;
+        writer.flush().map_err(|_err| PreprocessError::TempFile)?;
+        drop(writer);
+        spool
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+
+        return process(spool, output, options, report);
+    }
+    if is_binary(start) {
+        if start.starts_with(b"GCDE") {
+            tracing::error!(
+                "Binary G-Code isn't supported, export the file as text G-Code instead"
+            );
+        }
+        return Err(PreprocessError::BinaryInput);
+    }
+
+    let first_line = first_line_number(&mut input).map_err(|_err| PreprocessError::ReadError)?;
+    input
+        .rewind()
+        .map_err(|_err| PreprocessError::RewindError)?;
+    if let Some(first_line) = first_line {
+        tracing::info!("Processing numbered GCode, the lines are numbered again in the output");
+        let mut spool = tempfile::tempfile().map_err(|_err| PreprocessError::TempFile)?;
+        let mut writer = BufWriter::with_capacity(options.write_buffer, &mut spool);
+        strip_framing(&mut input, &mut writer).map_err(|_err| PreprocessError::ReadError)?;
+        writer.flush().map_err(|_err| PreprocessError::TempFile)?;
+        drop(writer);
+        spool
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+
+        // Type erased, so the recursive call is instantiated only once
+        let output: &mut dyn Write = output;
+        let mut framed = FramingWriter::new(output, first_line);
+        let mut unframed: &mut dyn Write = &mut framed;
+        process(spool, &mut unframed, options, report)?;
+        return framed.finish().map_err(|_err| PreprocessError::WriteError);
+    }
+
+    let header = processed_header(&mut input).map_err(|_err| PreprocessError::ReadError)?;
+    input
+        .rewind()
+        .map_err(|_err| PreprocessError::RewindError)?;
+    if let Some(ProcessedHeader {
+        version,
+        complete,
+        options: previous_options,
+    }) = header
+    {
+        report.previous_version = Some(version.clone());
+        let changed_options =
+            previous_options.is_some_and(|previous| previous != options_hash(options));
+        let refresh = match options.refresh {
+            _ if !complete => {
+                tracing::warn!("The header written by version {} is incomplete", version);
+                true
+            }
+            RefreshMode::Never => false,
+            RefreshMode::Outdated if changed_options => {
+                tracing::info!("GCode was processed with different options");
+                true
+            }
+            RefreshMode::Outdated => version != tool_version(),
+            RefreshMode::Always => true,
+        };
+
+        if !refresh {
+            tracing::info!("GCode was already processed by version {}", version);
+            report.already_processed = true;
+            std::io::copy(&mut input, output).map_err(|_err| copy_error(&input))?;
+            return Ok(());
+        }
+
+        tracing::info!("Processing GCode processed by version {} again", version);
+        let mut spool = tempfile::tempfile().map_err(|_err| PreprocessError::TempFile)?;
+        let mut writer = BufWriter::with_capacity(options.write_buffer, &mut spool);
+        strip_markers(&mut input, &mut writer).map_err(|_err| PreprocessError::ReadError)?;
+        writer.flush().map_err(|_err| PreprocessError::TempFile)?;
+        drop(writer);
+        spool
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+
+        return process(spool, output, options, report);
+    }
+
+    // Everything written from here on is verified to start and end every object once
+    let mut output = MarkerVerifier::new(output);
+
+    let cache = options.cache_dir.as_deref().map(ResultCache::new);
+    let cache_key = match &cache {
+        None => None,
+        Some(cache) => {
+            let key = cache
+                .key(&mut input, options)
+                .map_err(|_err| PreprocessError::ReadError)?;
             input
                 .rewind()
                 .map_err(|_err| PreprocessError::RewindError)?;
-            std::io::copy(&mut input, output).map_err(|_err| PreprocessError::WriteError)?;
+            Some(key)
+        }
+    };
 
+    if let Some((cache, key)) = cache.as_ref().zip(cache_key.as_ref()) {
+        if let Some(mut entry) = cache.load(key) {
+            tracing::info!("Using cached result {}", key);
+            entry.restore(report);
+            check_overlaps(&report.overlaps, options)?;
+            report.lines_added = entry
+                .edits
+                .write(&mut input, &mut output, &HashMap::new(), options)
+                .map_err(|_err| copy_error(&input))?;
+            return check_markers(&output.finish(), options, report);
+        }
+    }
+
+    let resume = match options.resume && !report.input.as_os_str().is_empty() {
+        true => Some(
+            ResumeState::new(&report.input, &mut input, options)
+                .map_err(|_err| PreprocessError::ReadError)?,
+        ),
+        false => None,
+    };
+    if let Some(resume) = &resume {
+        if let Some(mut entry) = resume.load() {
+            tracing::info!("Resuming an interrupted run, writing the output");
+            entry.restore(report);
+            report.cached = false;
+            report.resumed = true;
+            check_overlaps(&report.overlaps, options)?;
+            report.lines_added = entry
+                .edits
+                .write(&mut input, &mut output, &HashMap::new(), options)
+                .map_err(|_err| copy_error(&input))?;
+            check_markers(&output.finish(), options, report)?;
+            resume.remove();
             return Ok(());
         }
+    }
 
-        if processor.is_none() {
-            processor = identify_slicer_marker(&line);
+    let size = input
+        .seek(SeekFrom::End(0))
+        .map_err(|_err| PreprocessError::RewindError)?;
+    input
+        .rewind()
+        .map_err(|_err| PreprocessError::RewindError)?;
+
+    let Identification {
+        processor,
+        line_width,
+        layers,
+        already_processed,
+        partial,
+        mixed,
+        vase,
+        idex,
+        defines,
+        starts,
+        ..
+    } = tracing::info_span!("identification")
+        .in_scope(|| Identification::identify(&mut input, size, options))
+        .map_err(|_err| PreprocessError::ReadError)?;
+
+    report.already_processed = already_processed;
+    if let Some((slicer, _)) = &processor {
+        report.slicer = Some(slicer.to_string());
+    }
+    report.timings.identification = started.elapsed().as_secs_f64();
+    report.timings.bytes = size;
+
+    if report.already_processed {
+        input
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+        std::io::copy(&mut input, output.inner()).map_err(|_err| copy_error(&input))?;
+
+        return Ok(());
+    }
+
+    // Exclude object commands that don't both define and start objects weren't written by a
+    // slicer or this tool
+    let strip_m486 = mixed && options.marker_precedence == MarkerPrecedence::Comments;
+    if options.normalize && (defines != starts || strip_m486) {
+        input
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+
+        let mut spool = tempfile::tempfile().map_err(|_err| PreprocessError::TempFile)?;
+        let mut writer = BufWriter::with_capacity(options.write_buffer, &mut spool);
+        let normalized = normalize_markers(&mut input, &mut writer, strip_m486)
+            .map_err(|_err| PreprocessError::ReadError)?;
+        writer.flush().map_err(|_err| PreprocessError::TempFile)?;
+        drop(writer);
+        spool
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+        tracing::warn!(
+            "Commented out {} object commands conflicting with the {:?} markers",
+            normalized,
+            options.marker_precedence
+        );
+
+        let options = ProcessOptions {
+            normalize: false,
+            ..options.clone()
+        };
+        return process(spool, &mut **output.inner(), &options, report);
+    }
+
+    if partial {
+        input
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+
+        if processor.is_none() || !options.complete_partial {
+            tracing::warn!(
+                "GCode defines objects but never starts them, so they can't be cancelled. \
+                Use --complete-partial to add the markers from the slicer comments."
+            );
+            report.already_processed = true;
+            report.partial = true;
+            std::io::copy(&mut input, output.inner()).map_err(|_err| copy_error(&input))?;
+            return Ok(());
         }
+
+        tracing::info!("GCode defines objects without starting them, adding the missing markers");
+        let mut spool = tempfile::tempfile().map_err(|_err| PreprocessError::TempFile)?;
+        let mut writer = BufWriter::with_capacity(options.write_buffer, &mut spool);
+        strip_markers(&mut input, &mut writer).map_err(|_err| PreprocessError::ReadError)?;
+        writer.flush().map_err(|_err| PreprocessError::TempFile)?;
+        drop(writer);
+        spool
+            .rewind()
+            .map_err(|_err| PreprocessError::RewindError)?;
+
+        // Definitions surviving the stripping are passed through instead of stripped again
+        let options = ProcessOptions {
+            complete_partial: false,
+            ..options.clone()
+        };
+        return process(spool, &mut **output.inner(), &options, report);
     }
 
     match &processor {
@@ -61,13 +929,131 @@ fn process(
             tracing::error!("Could not identify slicer");
             Err(PreprocessError::UnknownSlicer)
         }
-        Some(processor) => {
+        Some((slicer, processor)) => {
             input
                 .rewind()
                 .map_err(|_err| PreprocessError::RewindError)?;
 
-            for line in processor.process(input.into_inner(), layer_filter) {
-                write!(output, "{}", line).map_err(|_err| PreprocessError::WriteError)?;
+            // The options as given, the provenance comment records their hash to compare it to
+            // the options processing the file again
+            let given = options;
+            let mut options = options.clone();
+            if let Some(provenance) = &mut options.provenance {
+                *provenance = Provenance::new(slicer, &mut input, given)
+                    .map_err(|_err| copy_error(&input))?;
+                input
+                    .rewind()
+                    .map_err(|_err| PreprocessError::RewindError)?;
+            }
+            options.hull.budget = options.hull.budget.fresh();
+            options.strip_m486 = strip_m486;
+            if let Some(mode) = idex {
+                options.idex = mode.placement(&options.idex_copy);
+                match options.idex {
+                    Some(_) => {
+                        tracing::info!("Printed in IDEX {} mode, defining both prints", mode)
+                    }
+                    None => tracing::warn!(
+                        "Printed in IDEX {} mode, use --idex-copy to define the second print \
+                        of each object",
+                        mode
+                    ),
+                }
+            }
+            if let Some(width) = line_width {
+                tracing::info!("Detected extrusion width: {:.2}mm", width);
+                options.hull.line_width = Some(width);
+            }
+
+            if options.layer_filter.is_relative() {
+                options.layer_filter = match layers.layer() {
+                    Some(last) => {
+                        tracing::info!("Found {} layers", last + 1);
+                        options.layer_filter.resolve(last + 1)
+                    }
+                    None => {
+                        tracing::warn!("No layer changes found, collecting points from all layers");
+                        LayerFilter::all()
+                    }
+                };
+            }
+
+            report.layer_filter = Some(options.layer_filter.clone());
+            let started = Instant::now();
+            let phase = tracing::info_span!("collection").entered();
+            let mut collected = processor.collect(&mut input, &options)?;
+            if vase {
+                tracing::warn!(
+                    "The {} file is printed in spiral vase mode, the whole print is a single \
+                    object that can only be cancelled as a whole",
+                    slicer
+                );
+                // Named like the object of the slicer, if its markers were found
+                let name = collected
+                    .known_objects
+                    .keys()
+                    .min()
+                    .map_or(VASE_NAME.to_string(), String::clone);
+                input
+                    .rewind()
+                    .map_err(|_err| PreprocessError::RewindError)?;
+                collected = vase::collect(&mut input, &name, &options)?;
+            }
+            report.timings.collection = started.elapsed().as_secs_f64();
+            drop(phase);
+
+            let started = Instant::now();
+            let phase = tracing::info_span!("hulls").entered();
+            let rewrite = collected.finish(&options, report);
+            report.find_overlaps();
+            check_overlaps(&report.overlaps, &options)?;
+            report.timings.hulls = started.elapsed().as_secs_f64();
+            drop(phase);
+            if options.analyze_only {
+                return Ok(());
+            }
+
+            input
+                .rewind()
+                .map_err(|_err| PreprocessError::RewindError)?;
+            if report.objects.is_empty() {
+                if options.require_objects {
+                    return Err(PreprocessError::NoObjects(slicer.to_string()));
+                }
+                tracing::warn!(
+                    "No objects found in the {} file, copying it unchanged. \
+                    Enable object labeling in the slicer to cancel objects.",
+                    slicer
+                );
+                std::io::copy(&mut input, output.inner()).map_err(|_err| copy_error(&input))?;
+                return Ok(());
+            }
+
+            if let Some(resume) = &resume {
+                let entry = CacheEntry::new(report, rewrite.rendered_edits(&options));
+                if let Err(err) = resume.store(entry) {
+                    tracing::warn!("Could not store the state to resume from: {}", err);
+                }
+            }
+
+            let started = Instant::now();
+            let phase = tracing::info_span!("rewrite").entered();
+            report.lines_added = rewrite
+                .write(&mut input, &mut output, &options)
+                .map_err(|_err| copy_error(&input))?;
+            check_markers(&output.finish(), &options, report)?;
+            if let Some(resume) = &resume {
+                resume.remove();
+            }
+            report.timings.rewrite = started.elapsed().as_secs_f64();
+            report.timings.finish();
+            drop(phase);
+
+            if let Some((cache, key)) = cache.as_ref().zip(cache_key.as_ref()) {
+                let entry = CacheEntry::new(report, rewrite.rendered_edits(&options));
+                if let Err(err) = cache.store(key, &entry) {
+                    tracing::warn!("Could not store result in cache: {}", err);
+                }
             }
 
             Ok(())
@@ -75,90 +1061,211 @@ fn process(
     }
 }
 
+/// The final output name PrusaSlicer (and derivatives) passes to post-processing scripts.
+///
+/// When set, the file given on the command line is a temporary `.pp` file that has to be
+/// modified in place. Renaming the final output is done by writing the new file name into
+/// a `<file>.output_name` file next to the temporary file.
+pub(crate) fn slic3r_output_name() -> Option<PathBuf> {
+    std::env::var_os("SLIC3R_PP_OUTPUT_NAME")
+        .filter(|name| !name.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Ask PrusaSlicer to store the processed file under a name with the given suffix
+pub(crate) fn slic3r_rename_output(
+    src: &Path,
+    output_name: &Path,
+    suffix: &str,
+    placement: SuffixPlacement,
+) -> Result<PathBuf, PreprocessError> {
+    let mut new_name = with_suffix(output_name, suffix, placement);
+    let file_name = portable_file_name(new_name.file_name().ok_or(PreprocessError::Other)?);
+    new_name.set_file_name(&file_name);
+
+    let mut marker = src.as_os_str().to_owned();
+    marker.push(".output_name");
+
+    std::fs::write(&marker, file_name.to_string_lossy().as_bytes())
+        .map_err(|_err| PreprocessError::IoError(marker.to_string_lossy().to_string()))?;
+
+    Ok(new_name)
+}
+
+/// Find the slicer, the objects and their outlines of a file without writing anything
+pub(crate) fn analyze(
+    input: impl Read + Seek + Send,
+    options: &ProcessOptions,
+) -> Result<ProcessReport, PreprocessError> {
+    let options = ProcessOptions {
+        analyze_only: true,
+        ..options.clone()
+    };
+    let mut report = ProcessReport::default();
+    process(input, &mut io::sink(), &options, &mut report)?;
+
+    Ok(report)
+}
+
+/// Process G-Code from a non-seekable stream, e.g. when called from a Cura post-processing script.
+///
+/// The input is spooled into a temporary file first as the processors need to read it twice.
+pub(crate) fn stream(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    options: &ProcessOptions,
+) -> Result<ProcessReport, PreprocessError> {
+    let mut spool = tempfile::tempfile().map_err(|_err| PreprocessError::TempFile)?;
+    std::io::copy(input, &mut spool).map_err(|_err| PreprocessError::ReadError)?;
+    spool
+        .rewind()
+        .map_err(|_err| PreprocessError::RewindError)?;
+
+    let mut report = ProcessReport::default();
+    let mut writer = OutputWriter::with_capacity(options.write_buffer, output);
+    process(spool, &mut writer, options, &mut report)?;
+    check_warnings(&report, options)?;
+    check_processed(&report, options)?;
+    writer.flush().map_err(|_err| PreprocessError::WriteError)?;
+
+    Ok(report)
+}
+
 pub(crate) fn file(
-    src: &PathBuf,
-    output_suffix: &Option<String>,
-    output_dir: &Option<PathBuf>,
-    layers: &str,
-) -> Result<(), PreprocessError> {
-    let mut dest_path = src.clone();
+    src: &Path,
+    output: &OutputOptions,
+    options: &ProcessOptions,
+) -> Result<ProcessReport, PreprocessError> {
+    file_with(&NativeFileSystem, src, output, options)
+}
 
-    if let Some(dir) = output_dir {
-        dest_path = dir.join(src.file_name().ok_or(PreprocessError::Other)?);
-        DirBuilder::new()
-            .recursive(true)
-            .create(
-                dest_path
-                    .parent()
-                    .ok_or(PreprocessError::CreateOutputDirectory)?,
-            )
-            .map_err(|_| PreprocessError::CreateOutputDirectory)?;
+/// Process a file and keep the untouched original in the archive directory.
+///
+/// The original is moved into the archive first and processed from there, so a file rewritten in
+/// place ends up processed in its directory. It is moved back if processing fails. Originals are
+/// never overwritten, files already in the archive fail instead.
+pub(crate) fn file_archived(
+    src: &Path,
+    archive_dir: &Path,
+    output: &OutputOptions,
+    options: &ProcessOptions,
+) -> Result<ProcessReport, PreprocessError> {
+    let archived = archive_dir.join(src.file_name().ok_or(PreprocessError::Other)?);
+    if archived.exists() {
+        return Err(PreprocessError::Archived(
+            archived.to_string_lossy().to_string(),
+        ));
     }
+    DirBuilder::new()
+        .recursive(true)
+        .create(archive_dir)
+        .map_err(|_| PreprocessError::CreateArchiveDirectory)?;
+    replace_file(&NativeFileSystem, src, &archived, &options.retry)
+        .map_err(|_err| PreprocessError::IoError(src.to_string_lossy().to_string()))?;
 
-    if let Some(suffix) = output_suffix {
-        match dest_path.extension() {
-            Some(extension) => {
-                let mut ext = OsStr::new(suffix).to_owned();
-                ext.push(".");
-                ext.push(extension);
-
-                dest_path.set_extension(ext);
-            }
-            None => {
-                dest_path.set_extension(suffix);
-            }
+    let mut output = output.clone();
+    if output.dir.is_none() {
+        output.dir = Some(src.parent().unwrap_or(Path::new("")).to_path_buf());
+    }
+    let result = file(&archived, &output, options);
+    if result.is_err() {
+        if let Err(err) = replace_file(&NativeFileSystem, &archived, src, &options.retry) {
+            tracing::error!(
+                "Could not move {} back to {}: {}",
+                archived.display(),
+                src.display(),
+                err
+            );
         }
     }
+    result
+}
 
-    let layer_filter: LayerFilter = layers
-        .try_into()
-        .map_err(|_err| PreprocessError::InvalidLayerFilter)?;
+/// Process a file on the given file system, see [`file`].
+///
+/// The output is written to a temporary file that is moved into place once it is complete. It
+/// is removed again whatever fails, the destination is either replaced or left alone.
+pub(crate) fn file_with(
+    fs: &impl FileSystem,
+    src: &Path,
+    output: &OutputOptions,
+    options: &ProcessOptions,
+) -> Result<ProcessReport, PreprocessError> {
+    let (tempfile, writer) = fs.create_temp().map_err(|_err| PreprocessError::TempFile)?;
 
-    let tempfile = NamedTempFile::new().map_err(|_err| PreprocessError::TempFile)?;
+    let result = write_file(fs, src, writer, &tempfile, output, options);
+    if result.is_err() {
+        let _result = fs.remove_file(&tempfile);
+    }
+    result
+}
 
-    let reader = BufReader::new(
-        File::open(src)
-            .map_err(|_err| PreprocessError::IoError(src.to_string_lossy().to_string()))?,
-    );
-    let mut writer = BufWriter::new(&tempfile);
-    match process(reader, &mut writer, &layer_filter) {
-        Ok(_) => {
-            writer
-                .flush()
-                .map_err(|_err| PreprocessError::FlushTempFile)?;
+fn write_file(
+    fs: &impl FileSystem,
+    src: &Path,
+    writer: Box<dyn Write>,
+    tempfile: &Path,
+    output: &OutputOptions,
+    options: &ProcessOptions,
+) -> Result<ProcessReport, PreprocessError> {
+    let reader = open_file(fs, src, &options.retry)
+        .map_err(|_err| PreprocessError::IoError(src.to_string_lossy().to_string()))?;
+    let mut writer = OutputWriter::with_capacity(options.write_buffer, writer);
+    let mut report = ProcessReport {
+        input: src.to_path_buf(),
+        plate: output.plate,
+        ..Default::default()
+    };
+    process(reader, &mut writer, options, &mut report)?;
+    check_warnings(&report, options)?;
+    check_processed(&report, options)?;
+    writer
+        .flush()
+        .map_err(|_err| PreprocessError::FlushTempFile)?;
+    // Closed before it is moved, Windows doesn't move open files
+    drop(writer);
 
-            if dest_path.exists() {
-                remove_file(&dest_path).map_err(|_err| {
-                    PreprocessError::IoError(dest_path.to_string_lossy().to_string())
-                })?;
-            }
+    // The destination can depend on the processing results, e.g. the detected slicer
+    let dest_path = output
+        .destination(src, &report)
+        .ok_or(PreprocessError::Other)?;
+    if let Some(parent) = dest_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        DirBuilder::new()
+            .recursive(true)
+            .create(parent)
+            .map_err(|_| PreprocessError::CreateOutputDirectory)?;
+    }
 
-            rename(&tempfile, &dest_path).map_err(|_err| {
-                PreprocessError::IoError(dest_path.to_string_lossy().to_string())
-            })?;
+    replace_file(fs, tempfile, &dest_path, &options.retry)
+        .map_err(|_err| PreprocessError::IoError(dest_path.to_string_lossy().to_string()))?;
 
-            Ok(())
-        }
-        Err(e) => {
-            let _result = remove_file(&tempfile);
-            Err(e)
-        }
-    }
+    report.output = dest_path;
+    Ok(report)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::gcode::{parse_gcode, Command};
+    use crate::hulls::HullMode;
+    use crate::lines::MAX_LINE_LENGTH;
+    use crate::paths::Input;
+    use itertools::Itertools;
     use once_cell::sync::Lazy;
     use ordered_float::OrderedFloat;
-    use std::io::Cursor;
-    use std::path::Path;
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io::{BufRead, Cursor};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     static GCODE_PATH: Lazy<PathBuf> =
         Lazy::new(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("GCode"));
 
-    static TEST_CASES: Lazy<Vec<(&str, &str, (f64, f64))>> = Lazy::new(|| {
+    type LayerFilterTestCase = (&'static str, &'static str, (f64, f64));
+
+    static TEST_CASES: Lazy<Vec<LayerFilterTestCase>> = Lazy::new(|| {
         vec![
             ("inverted_pyramid", "0", (10.0, 10.0)),
             ("inverted_pyramid", "*/5", (28.0, 28.0)),
@@ -166,6 +1273,1630 @@ mod tests {
         ]
     });
 
+    #[test]
+    fn test_slic3r_rename_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("plate.gcode.pp");
+
+        let new_name = slic3r_rename_output(
+            &src,
+            Path::new("/output/plate.gcode"),
+            "processed",
+            SuffixPlacement::default(),
+        )
+        .unwrap();
+
+        assert_eq!(new_name, PathBuf::from("/output/plate.processed.gcode"));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("plate.gcode.pp.output_name")).unwrap(),
+            "plate.processed.gcode"
+        );
+    }
+
+    /// Where the I/O of [`FailingFileSystem`] fails
+    #[derive(Clone, Copy, Debug)]
+    enum Failure {
+        None,
+        Open,
+        CreateTemp,
+        /// Reading fails after this many bytes, counted over every pass over the input
+        Read(u64),
+        /// Writing the temporary file fails after this many bytes
+        Write(u64),
+        Rename,
+        /// The temporary file is on another file system than the destination
+        CrossDevice,
+        /// Like `CrossDevice`, and copying fails halfway
+        Copy,
+    }
+
+    /// Counts the bytes passing through and fails once the limit is reached
+    struct Failing<T> {
+        inner: T,
+        count: Arc<AtomicU64>,
+        limit: Option<u64>,
+    }
+
+    impl<T> Failing<T> {
+        /// How many of the bytes may pass, an error once none may
+        fn allowance(&self, len: usize) -> io::Result<usize> {
+            let count = self.count.load(Ordering::Relaxed);
+            match self.limit {
+                Some(limit) if count >= limit => Err(io::Error::other("injected failure")),
+                Some(limit) => Ok(len.min((limit - count) as usize)),
+                None => Ok(len),
+            }
+        }
+    }
+
+    impl<T: Read> Read for Failing<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.allowance(buf.len())?;
+            let read = self.inner.read(&mut buf[..len])?;
+            self.count.fetch_add(read as u64, Ordering::Relaxed);
+            Ok(read)
+        }
+    }
+
+    impl<T: Seek> Seek for Failing<T> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl<T: Write> Write for Failing<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = self.allowance(buf.len())?;
+            let written = self.inner.write(&buf[..len])?;
+            self.count.fetch_add(written as u64, Ordering::Relaxed);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Real files with I/O failing at one point, temporary files are created in their own
+    /// directory to find the ones left behind
+    struct FailingFileSystem {
+        failure: Failure,
+        temp_dir: tempfile::TempDir,
+        read: Arc<AtomicU64>,
+        written: Arc<AtomicU64>,
+    }
+
+    impl FailingFileSystem {
+        fn new(failure: Failure) -> Self {
+            Self {
+                failure,
+                temp_dir: tempfile::tempdir().unwrap(),
+                read: Arc::default(),
+                written: Arc::default(),
+            }
+        }
+    }
+
+    impl FileSystem for FailingFileSystem {
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Input>> {
+            if let Failure::Open = self.failure {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            Ok(Box::new(Failing {
+                inner: File::open(path)?,
+                count: self.read.clone(),
+                limit: match self.failure {
+                    Failure::Read(limit) => Some(limit),
+                    _ => None,
+                },
+            }))
+        }
+
+        fn create_temp(&self) -> io::Result<(PathBuf, Box<dyn Write>)> {
+            if let Failure::CreateTemp = self.failure {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            let (file, path) = tempfile::NamedTempFile::new_in(self.temp_dir.path())?.keep()?;
+            let writer = Failing {
+                inner: file,
+                count: self.written.clone(),
+                limit: match self.failure {
+                    Failure::Write(limit) => Some(limit),
+                    _ => None,
+                },
+            };
+            Ok((path, Box::new(writer)))
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            match self.failure {
+                Failure::Rename => Err(io::ErrorKind::PermissionDenied.into()),
+                Failure::CrossDevice | Failure::Copy if from.starts_with(self.temp_dir.path()) => {
+                    Err(io::ErrorKind::CrossesDevices.into())
+                }
+                _ => std::fs::rename(from, to),
+            }
+        }
+
+        fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+            let content = std::fs::read(from)?;
+            if let Failure::Copy = self.failure {
+                std::fs::write(to, &content[..content.len() / 2])?;
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            std::fs::write(to, content)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            std::fs::remove_file(path)
+        }
+
+        fn sleep(&self, _duration: Duration) {}
+    }
+
+    /// Process a fixture in place with the failure, checks that the temporary file is removed
+    /// and the destination is either processed or unchanged. Returns the result and the bytes
+    /// read and written.
+    fn process_failing(
+        failure: Failure,
+        options: &ProcessOptions,
+    ) -> (Result<ProcessReport, PreprocessError>, u64, u64) {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("plate.gcode");
+        let original = std::fs::read(GCODE_PATH.join("slic3r.gcode")).unwrap();
+        std::fs::write(&src, &original).unwrap();
+
+        let fs = FailingFileSystem::new(failure);
+        let result = file_with(&fs, &src, &OutputOptions::default(), options);
+
+        let leftovers = |dir: &Path| {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(leftovers(fs.temp_dir.path()).len(), 0, "{failure:?}");
+        assert_eq!(leftovers(dir.path()), ["plate.gcode"], "{failure:?}");
+        let content = std::fs::read(&src).unwrap();
+        match &result {
+            Ok(_) => assert!(
+                String::from_utf8_lossy(&content).contains("EXCLUDE_OBJECT_DEFINE"),
+                "{failure:?}"
+            ),
+            Err(_) => assert!(content == original, "{failure:?} changed the destination"),
+        }
+
+        let read = fs.read.load(Ordering::Relaxed);
+        let written = fs.written.load(Ordering::Relaxed);
+        (result, read, written)
+    }
+
+    #[test]
+    fn test_file_failures() {
+        let options = ProcessOptions::default();
+        let (result, read, written) = process_failing(Failure::None, &options);
+        assert!(result.is_ok());
+        assert!(read > 0 && written > 0);
+        assert!(process_failing(Failure::CrossDevice, &options).0.is_ok());
+
+        let path_error = |failure| match process_failing(failure, &options).0 {
+            Err(PreprocessError::IoError(path)) => path,
+            result => panic!("{failure:?}: {result:?}"),
+        };
+        for failure in [Failure::Open, Failure::Rename, Failure::Copy] {
+            assert!(path_error(failure).ends_with("plate.gcode"), "{failure:?}");
+        }
+        assert!(matches!(
+            process_failing(Failure::CreateTemp, &options).0,
+            Err(PreprocessError::TempFile)
+        ));
+        // The output is smaller than the write buffer and only written when it is flushed
+        assert!(matches!(
+            process_failing(Failure::Write(0), &options).0,
+            Err(PreprocessError::FlushTempFile)
+        ));
+    }
+
+    #[test]
+    fn test_file_archived() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("slic3r.gcode");
+        let archive = dir.path().join("archive");
+        let original = std::fs::read(GCODE_PATH.join("slic3r.gcode")).unwrap();
+        std::fs::write(&src, &original).unwrap();
+        let options = ProcessOptions::default();
+
+        let report = file_archived(&src, &archive, &OutputOptions::default(), &options).unwrap();
+        assert_eq!(report.output, src);
+        assert_eq!(
+            std::fs::read(archive.join("slic3r.gcode")).unwrap(),
+            original
+        );
+        assert!(std::fs::read_to_string(&src)
+            .unwrap()
+            .contains("EXCLUDE_OBJECT_DEFINE"));
+
+        // Originals in the archive are never overwritten
+        let processed = std::fs::read(&src).unwrap();
+        assert!(matches!(
+            file_archived(&src, &archive, &OutputOptions::default(), &options),
+            Err(PreprocessError::Archived(_))
+        ));
+        assert_eq!(std::fs::read(&src).unwrap(), processed);
+
+        // Failed files are moved back
+        let unknown = dir.path().join("unknown.gcode");
+        std::fs::write(&unknown, "G28\nG1 X1 Y1 E1\n").unwrap();
+        assert!(file_archived(&unknown, &archive, &OutputOptions::default(), &options).is_err());
+        assert!(unknown.exists());
+        assert!(!archive.join("unknown.gcode").exists());
+    }
+
+    /// Reads and writes failing at any point of processing are reported as such
+    #[test]
+    fn test_file_io_failures() {
+        let options = ProcessOptions {
+            write_buffer: 4096,
+            ..Default::default()
+        };
+        let (_, read, written) = process_failing(Failure::None, &options);
+
+        for step in 0..8 {
+            let failure = Failure::Read(read * step / 8);
+            let result = process_failing(failure, &options).0;
+            assert!(
+                matches!(
+                    result,
+                    Err(PreprocessError::ReadError | PreprocessError::ReadLineError(..))
+                ),
+                "{failure:?}: {result:?}"
+            );
+
+            let failure = Failure::Write(written * step / 8);
+            let result = process_failing(failure, &options).0;
+            assert!(
+                matches!(result, Err(PreprocessError::WriteError)),
+                "{failure:?}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_resume_interrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("plate.gcode");
+        std::fs::copy(GCODE_PATH.join("slic3r.gcode"), &src).unwrap();
+        let sidecar = dir.path().join("plate.gcode.resume.json");
+        let output = OutputOptions {
+            dir: Some(dir.path().join("out")),
+            ..Default::default()
+        };
+        let options = ProcessOptions {
+            resume: true,
+            write_buffer: 4096,
+            ..Default::default()
+        };
+
+        // Interrupted while writing the output, after the analysis
+        let fs = FailingFileSystem::new(Failure::Write(10_000));
+        assert!(file_with(&fs, &src, &output, &options).is_err());
+        assert!(sidecar.exists());
+
+        let fs = FailingFileSystem::new(Failure::None);
+        let report = file_with(&fs, &src, &output, &options).unwrap();
+        assert!(report.resumed && !report.cached);
+        assert!(!sidecar.exists());
+        let resumed = std::fs::read(&report.output).unwrap();
+
+        let report = file_with(&fs, &src, &output, &options).unwrap();
+        assert!(!report.resumed);
+        // The same lines, the order of the definitions differs between runs
+        let lines = |output: Vec<u8>| {
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .sorted()
+                .join("\n")
+        };
+        assert_eq!(
+            lines(std::fs::read(&report.output).unwrap()),
+            lines(resumed)
+        );
+        assert!(!sidecar.exists());
+
+        // Interrupted before the analysis is complete
+        let fs = FailingFileSystem::new(Failure::Read(1000));
+        assert!(file_with(&fs, &src, &output, &options).is_err());
+        assert!(!sidecar.exists());
+    }
+
+    #[test]
+    fn test_stream_cura_settings_before_banner() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("cura.gcode")).unwrap();
+        let mut input = Cursor::new(format!(
+            ";POSTPROCESSED\n;SETTING_3 {{\"global_quality\": \"[general]\\\\nversion = 4\"}}\n{gcode}"
+        ));
+        let mut output = Vec::new();
+
+        let report = stream(&mut input, &mut output, &ProcessOptions::default()).unwrap();
+        assert_eq!(report.objects.len(), 4);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(";POSTPROCESSED\n;SETTING_3"));
+        assert!(output.contains("EXCLUDE_OBJECT_START NAME=cube_1_stl\n"));
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = ProcessOptions {
+            cache_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let mut first = Vec::new();
+        let mut report = ProcessReport::default();
+        let input = File::open(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        process(&input, &mut first, &options, &mut report).unwrap();
+        assert!(!report.cached);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut second = Vec::new();
+        let mut cached = ProcessReport::default();
+        let input = File::open(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        process(&input, &mut second, &options, &mut cached).unwrap();
+        assert!(cached.cached);
+        assert_eq!(cached.slicer, report.slicer);
+        assert_eq!(cached.object_names(), report.object_names());
+        assert_eq!(
+            String::from_utf8(second).unwrap(),
+            String::from_utf8(first).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_provenance() {
+        let process_with = |input: &[u8], options: &ProcessOptions| {
+            let mut output = Vec::new();
+            let mut report = ProcessReport::default();
+            process(Cursor::new(input), &mut output, options, &mut report).unwrap();
+            (String::from_utf8(output).unwrap(), report)
+        };
+        let options = ProcessOptions {
+            provenance: Some(Provenance::default()),
+            refresh: RefreshMode::Outdated,
+            ..Default::default()
+        };
+
+        let input = std::fs::read(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let (processed, _) = process_with(&input, &options);
+        let provenance: Vec<_> = processed
+            .lines()
+            .filter(|line| line.starts_with("; provenance "))
+            .collect();
+        let expected = Provenance::new("SuperSlicer", &mut input.as_slice(), &options).unwrap();
+        assert_eq!(
+            provenance,
+            [format!(
+                "; provenance slicer=SuperSlicer layers=* hull=convex input={} options={}",
+                expected.input, expected.options
+            )]
+        );
+
+        // Same options: left alone, different options: processed again with the new ones
+        let (output, report) = process_with(processed.as_bytes(), &options);
+        assert!(report.already_processed);
+        assert_eq!(output, processed);
+        let concave = ProcessOptions {
+            hull: HullOptions {
+                mode: HullMode::Concave,
+                ..Default::default()
+            },
+            ..options.clone()
+        };
+        let (output, report) = process_with(processed.as_bytes(), &concave);
+        assert!(!report.already_processed);
+        assert_eq!(
+            comparable_lines(output.as_bytes(), false),
+            comparable_lines(process_with(&input, &concave).0.as_bytes(), false)
+        );
+        assert_eq!(output.matches("; provenance ").count(), 1);
+
+        // Processing without recording the options again counts as changed options
+        let (_, report) = process_with(
+            processed.as_bytes(),
+            &ProcessOptions {
+                provenance: None,
+                ..options
+            },
+        );
+        assert!(!report.already_processed);
+    }
+
+    #[test]
+    fn test_refresh() {
+        for filename in ["superslicer.gcode", "m486.gcode"] {
+            let input = File::open(GCODE_PATH.join(filename)).unwrap();
+            let mut processed = Vec::new();
+            process(
+                &input,
+                &mut processed,
+                &ProcessOptions::default(),
+                &mut ProcessReport::default(),
+            )
+            .unwrap();
+            let processed = String::from_utf8(processed).unwrap();
+            let outdated = processed.replace(
+                &format!("preprocess_cancellation v{}", tool_version()),
+                "preprocess_cancellation v0.0.1",
+            );
+
+            for (input, refresh, expect_refresh) in [
+                (&processed, RefreshMode::Never, false),
+                (&processed, RefreshMode::Outdated, false),
+                (&processed, RefreshMode::Always, true),
+                (&outdated, RefreshMode::Never, false),
+                (&outdated, RefreshMode::Outdated, true),
+            ] {
+                let options = ProcessOptions {
+                    refresh,
+                    ..Default::default()
+                };
+                let mut output = Vec::new();
+                let mut report = ProcessReport::default();
+                process(Cursor::new(input), &mut output, &options, &mut report).unwrap();
+                let output = String::from_utf8(output).unwrap();
+
+                assert_eq!(report.already_processed, !expect_refresh, "{filename}");
+                if expect_refresh {
+                    // Definitions are written in no particular order
+                    assert_eq!(
+                        output.lines().sorted().collect::<Vec<_>>(),
+                        processed.lines().sorted().collect::<Vec<_>>(),
+                        "{filename} {refresh:?}"
+                    );
+                } else {
+                    assert_eq!(&output, input, "{filename} {refresh:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fail_if_processed() {
+        let input = std::fs::read(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let mut processed = Vec::new();
+        stream(
+            &mut Cursor::new(&input),
+            &mut processed,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+
+        let options = ProcessOptions {
+            fail_if_processed: true,
+            ..Default::default()
+        };
+        assert!(stream(&mut Cursor::new(&input), &mut Vec::new(), &options).is_ok());
+        let result = stream(&mut Cursor::new(&processed), &mut Vec::new(), &options);
+        assert!(
+            matches!(result, Err(PreprocessError::AlreadyProcessed)),
+            "{result:?}"
+        );
+
+        // Files processed again don't fail
+        let options = ProcessOptions {
+            refresh: RefreshMode::Always,
+            ..options
+        };
+        assert!(stream(&mut Cursor::new(&processed), &mut Vec::new(), &options).is_ok());
+
+        // Files only defining objects were written by hand or another tool
+        let options = ProcessOptions {
+            fail_if_processed: true,
+            ..Default::default()
+        };
+        let partial = "; generated by PrusaSlicer 2.6.0\n\
+            EXCLUDE_OBJECT_DEFINE NAME=cube CENTER=1,1 POLYGON=[[0,0],[2,0],[2,2]]\n\
+            G28\n; printing object cube\nG1 X1 Y1 E1\n; stop printing object cube\n";
+        let mut output = Vec::new();
+        let report = stream(&mut Cursor::new(partial), &mut output, &options).unwrap();
+        assert!(report.partial);
+        assert_eq!(output, partial.as_bytes());
+    }
+
+    /// Sorted lines of an output, to compare outputs regardless of where the markers are placed.
+    /// Copies of an object share their outline depending on the points sampled, so only the
+    /// names are kept from object definitions.
+    fn comparable_lines(output: &[u8], skip_blank: bool) -> Vec<String> {
+        String::from_utf8_lossy(output)
+            .lines()
+            .filter(|line| !(skip_blank && line.is_empty()))
+            .map(|line| match line.starts_with("EXCLUDE_OBJECT_DEFINE") {
+                true => line.split(" CENTER=").next().unwrap_or(line).to_string(),
+                false => line.to_string(),
+            })
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn test_processed_header() {
+        let marker = format!(
+            "; Pre-Processed for Cancel-Object support by preprocess_cancellation v{}",
+            tool_version()
+        );
+        let header = |gcode: &str| processed_header(&mut Cursor::new(gcode)).unwrap();
+        let complete = |complete| {
+            Some(ProcessedHeader {
+                version: tool_version().to_string(),
+                complete,
+                options: None,
+            })
+        };
+
+        assert_eq!(header("; generated by SuperSlicer\nG28\n"), None);
+        assert_eq!(header(&format!("G28\n{marker}\n")), None);
+        assert_eq!(
+            header(&format!(
+                "; generated by SuperSlicer\n\n\n{marker}\n; 2 known objects\n\
+                ; object NAME=a LAYERS=1\nEXCLUDE_OBJECT_DEFINE NAME=a\n\
+                EXCLUDE_OBJECT_DEFINE NAME=b\nG28\n{marker}\n"
+            )),
+            complete(true)
+        );
+        // Interrupted before writing the definitions
+        assert_eq!(
+            header(&format!("{marker}\n; 2 known objects\nG28\n")),
+            complete(false)
+        );
+        assert_eq!(header(&format!("{marker}\nG28\n")), complete(false));
+        // Processed twice
+        assert_eq!(
+            header(&format!(
+                "{marker}\n; 1 known objects\n\n\n{marker}\n; 1 known objects\n\
+                EXCLUDE_OBJECT_DEFINE NAME=a\nG28\n"
+            )),
+            complete(false)
+        );
+
+        // Without the marker the version is taken from the trailer, which is written last
+        let trailer = format!(
+            "; Cancel-Object support added by preprocess_cancellation v{}",
+            tool_version()
+        );
+        let unmarked = "; generated by SuperSlicer\n\n\n; custom\n; 1 known objects\n\
+            EXCLUDE_OBJECT_DEFINE NAME=a\nG28\nG1 X1\n";
+        assert_eq!(header(&format!("{unmarked}{trailer}\n")), complete(true));
+        assert_eq!(
+            header(unmarked),
+            Some(ProcessedHeader {
+                version: String::new(),
+                complete: false,
+                options: None,
+            })
+        );
+        assert_eq!(header(&format!("G28\n{trailer}\n")), None);
+    }
+
+    #[test]
+    fn test_header_marker() {
+        let input = std::fs::read(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let process_with = |input: &[u8], options: &ProcessOptions| {
+            let mut output = Vec::new();
+            let mut report = ProcessReport::default();
+            process(Cursor::new(input), &mut output, options, &mut report).unwrap();
+            (String::from_utf8(output).unwrap(), report)
+        };
+        let (marked, _) = process_with(&input, &ProcessOptions::default());
+
+        for (header_marker, header_text) in [(false, None), (true, Some("; cancel objects"))] {
+            let options = ProcessOptions {
+                header_marker,
+                header_text: header_text.map(String::from),
+                ..Default::default()
+            };
+            let (processed, _) = process_with(&input, &options);
+            assert!(!processed.contains("; Pre-Processed for Cancel-Object support"));
+            assert!(processed.ends_with(&format!(
+                "\n; Cancel-Object support added by preprocess_cancellation v{}\n",
+                tool_version()
+            )));
+            if let Some(text) = header_text {
+                assert!(processed.contains(&format!("\n\n\n{text}\n; 4 known objects\n")));
+            }
+
+            // Still recognized as processed, and processed again without leftovers
+            let (output, report) = process_with(processed.as_bytes(), &options);
+            assert!(report.already_processed);
+            assert_eq!(output, processed);
+            let outdated = processed.replace(
+                &format!("preprocess_cancellation v{}", tool_version()),
+                "preprocess_cancellation v0.0.1",
+            );
+            let refresh = ProcessOptions {
+                refresh: RefreshMode::Outdated,
+                ..Default::default()
+            };
+            let (output, report) = process_with(outdated.as_bytes(), &refresh);
+            assert!(!report.already_processed);
+            assert_eq!(
+                comparable_lines(output.as_bytes(), false),
+                comparable_lines(marked.as_bytes(), false)
+            );
+        }
+    }
+
+    #[test]
+    fn test_incomplete_header() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let mut processed = Vec::new();
+        stream(
+            &mut Cursor::new(&gcode),
+            &mut processed,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        let processed = String::from_utf8(processed).unwrap();
+        let stale = processed
+            .lines()
+            .filter(|line| !line.starts_with("EXCLUDE_OBJECT_DEFINE"))
+            .join("\n");
+
+        let mut output = Vec::new();
+        let report = stream(
+            &mut Cursor::new(&stale),
+            &mut output,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert!(!report.already_processed);
+        assert_eq!(report.previous_version.as_deref(), Some(tool_version()));
+
+        assert_eq!(
+            comparable_lines(&output, false),
+            comparable_lines(processed.as_bytes(), false)
+        );
+    }
+
+    #[test]
+    fn test_read_error_keeps_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("plate.gcode");
+        let mut gcode = std::fs::read(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let middle = gcode.len() / 2;
+        gcode.splice(middle..middle, b"\n; \xff\xfe\n".iter().copied());
+        std::fs::write(&src, &gcode).unwrap();
+
+        // The invalid line isn't part of the scan windows, collecting the objects reads it
+        let options = ProcessOptions {
+            scan_window: 64 * 1024,
+            ..Default::default()
+        };
+        let result = file(&src, &OutputOptions::default(), &options);
+        assert!(
+            matches!(result, Err(PreprocessError::ReadLineError(..))),
+            "{result:?}"
+        );
+        assert_eq!(std::fs::read(&src).unwrap(), gcode);
+    }
+
+    #[test]
+    fn test_numbered_lines() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let mut numbered = Vec::new();
+        let mut writer = FramingWriter::new(&mut numbered, 1);
+        writer.write_all(gcode.as_bytes()).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let mut output = Vec::new();
+        let report = stream(
+            &mut numbered.as_slice(),
+            &mut output,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.slicer.as_deref(), Some("SuperSlicer"));
+        assert_eq!(report.objects.len(), 4);
+
+        // Every command, including the injected ones, is numbered in sequence
+        let output = String::from_utf8(output).unwrap();
+        let mut next = 1;
+        for line in output.lines() {
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let (number, command) = crate::framing::unframe(line).unwrap();
+            assert_eq!(number, next, "{line}");
+            let (content, checksum) = line.rsplit_once('*').unwrap();
+            assert_eq!(
+                checksum.parse::<u8>().unwrap(),
+                crate::framing::checksum(content.as_bytes())
+            );
+            assert!(!command.starts_with('N'));
+            next += 1;
+        }
+        assert!(output.contains(" EXCLUDE_OBJECT_DEFINE NAME="));
+        assert!(output.contains(" EXCLUDE_OBJECT_START NAME="));
+    }
+
+    #[test]
+    fn test_identify_without_banner() {
+        for (filename, slicer) in [
+            ("superslicer.gcode", "Slic3r"),
+            ("cura.gcode", "Cura"),
+            ("ideamaker.gcode", "ideaMaker"),
+            // Comments are preferred over M486 commands when a slicer emits both
+            ("m486.gcode", "Slic3r"),
+        ] {
+            let gcode = std::fs::read_to_string(GCODE_PATH.join(filename)).unwrap();
+            let gcode = format!(
+                "; Postprocessed by [ArcWelder](https://github.com/FormerLurker/ArcWelderLib)\n\
+                ; Copyright(C) 2020 - Brad Hochgesang\n{}",
+                gcode
+                    .lines()
+                    .filter(|line| identify_slicer_marker(line).is_none())
+                    .join("\n")
+            );
+
+            for scan_window in [0, 1024] {
+                let options = ProcessOptions {
+                    scan_window,
+                    ..Default::default()
+                };
+                let identification = Identification::identify(
+                    &mut Cursor::new(&gcode),
+                    gcode.len() as u64,
+                    &options,
+                )
+                .unwrap();
+                assert_eq!(
+                    identification.processor.map(|(slicer, _)| slicer),
+                    Some(slicer),
+                    "{filename}"
+                );
+            }
+        }
+
+        let gcode = "; generated by Klipper Estimator\nG28\nG1 X1 Y1\n";
+        let identification = Identification::identify(
+            &mut Cursor::new(gcode),
+            gcode.len() as u64,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert!(identification.processor.is_none());
+    }
+
+    #[test]
+    fn test_complete_partial() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let mut processed = Vec::new();
+        stream(
+            &mut Cursor::new(&gcode),
+            &mut processed,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        let processed = String::from_utf8(processed).unwrap();
+        // Hand-edited: the definitions were pasted at the top without any markers
+        let partial = format!(
+            "{}\n{gcode}",
+            processed
+                .lines()
+                .filter(|line| line.starts_with("EXCLUDE_OBJECT_DEFINE"))
+                .join("\n")
+        );
+
+        let mut output = Vec::new();
+        let report = stream(
+            &mut Cursor::new(&partial),
+            &mut output,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert!(report.already_processed);
+        assert_eq!(String::from_utf8(output).unwrap(), partial);
+
+        for scan_window in [0, 1024] {
+            let options = ProcessOptions {
+                complete_partial: true,
+                scan_window,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let report = stream(&mut Cursor::new(&partial), &mut output, &options).unwrap();
+            assert!(!report.already_processed);
+            // The scan window affects the detected line width
+            let mut expected = Vec::new();
+            stream(&mut Cursor::new(&gcode), &mut expected, &options).unwrap();
+            assert_eq!(report.objects.len(), 4);
+
+            // Only the blank lines before the header may differ
+            assert_eq!(
+                comparable_lines(&output, true),
+                comparable_lines(&expected, true)
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_objects() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("cura.gcode")).unwrap();
+        let gcode = gcode
+            .lines()
+            .filter(|line| !line.starts_with(";MESH:"))
+            .join("\n");
+
+        let mut output = Vec::new();
+        let report = stream(
+            &mut Cursor::new(&gcode),
+            &mut output,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert!(report.objects.is_empty());
+        assert_eq!(String::from_utf8(output).unwrap(), gcode);
+
+        let options = ProcessOptions {
+            require_objects: true,
+            ..Default::default()
+        };
+        let result = stream(&mut Cursor::new(&gcode), &mut Vec::new(), &options);
+        assert!(
+            matches!(&result, Err(PreprocessError::NoObjects(slicer)) if slicer == "Cura"),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn test_binary_input() {
+        let mut binary = b"GCDE\x01\x00\x00\x00".to_vec();
+        binary.extend((0..=255u8).cycle().take(4096));
+        let result = stream(
+            &mut Cursor::new(binary),
+            &mut Vec::new(),
+            &ProcessOptions::default(),
+        );
+        assert!(
+            matches!(result, Err(PreprocessError::BinaryInput)),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn test_long_lines() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let long = format!("; thumbnail {}", "A".repeat(MAX_LINE_LENGTH * 3));
+        let gcode = gcode.replacen("\n", &format!("\n{long}\n"), 1);
+
+        let mut output = Vec::new();
+        let report = stream(
+            &mut Cursor::new(&gcode),
+            &mut output,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.objects.len(), 4);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.lines().any(|line| line == long));
+    }
+
+    #[test]
+    fn test_utf16_input() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let mut expected = Vec::new();
+        stream(
+            &mut Cursor::new(&gcode),
+            &mut expected,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+
+        let utf16: Vec<u8> = "\u{FEFF}"
+            .encode_utf16()
+            .chain(gcode.encode_utf16())
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let mut output = Vec::new();
+        let report = stream(
+            &mut Cursor::new(utf16),
+            &mut output,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.slicer.as_deref(), Some("SuperSlicer"));
+        assert_eq!(
+            comparable_lines(&output, false),
+            comparable_lines(&expected, false)
+        );
+    }
+
+    #[test]
+    fn test_xy_offset() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("cura.gcode")).unwrap();
+        let process = |xy_offset| {
+            let options = ProcessOptions {
+                xy_offset,
+                ..Default::default()
+            };
+            stream(&mut Cursor::new(&gcode), &mut Vec::new(), &options).unwrap()
+        };
+
+        let report = process((0.0, 0.0));
+        let moved = process((-150.0, 2.5));
+        for object in &report.objects {
+            let moved = moved
+                .objects
+                .iter()
+                .find(|moved| moved.name == object.name)
+                .unwrap();
+            let (x, y) = object.center.unwrap();
+            let (moved_x, moved_y) = moved.center.unwrap();
+            assert!((moved_x - (x - 150.0)).abs() < 1e-6, "{}", object.name);
+            assert!((moved_y - (y + 2.5)).abs() < 1e-6, "{}", object.name);
+            assert_eq!(moved.polygon.len(), object.polygon.len());
+        }
+    }
+
+    #[test]
+    fn test_transforms() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("cura.gcode")).unwrap();
+        let process = |transforms: &[&str]| {
+            let options = ProcessOptions {
+                xy_offset: (-150.0, 0.0),
+                transforms: transforms.iter().map(|t| t.parse().unwrap()).collect(),
+                ..Default::default()
+            };
+            stream(&mut Cursor::new(&gcode), &mut Vec::new(), &options).unwrap()
+        };
+
+        // Mirrored after the offset
+        let report = process(&[]);
+        let mirrored = process(&["mirror-x=0", "translate=0,1"]);
+        for object in &report.objects {
+            let mirrored = mirrored
+                .objects
+                .iter()
+                .find(|mirrored| mirrored.name == object.name)
+                .unwrap();
+            let (x, y) = object.center.unwrap();
+            let (mirrored_x, mirrored_y) = mirrored.center.unwrap();
+            assert!((mirrored_x + x).abs() < 1e-6, "{}", object.name);
+            assert!((mirrored_y - (y + 1.0)).abs() < 1e-6, "{}", object.name);
+        }
+    }
+
+    #[test]
+    fn test_idex_copies() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("cura.gcode")).unwrap();
+        let process = |gcode: &str, idex_copy: &[&str]| {
+            let options = ProcessOptions {
+                idex_copy: idex_copy.iter().map(|t| t.parse().unwrap()).collect(),
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let report = stream(&mut Cursor::new(gcode), &mut output, &options).unwrap();
+            (report, String::from_utf8(output).unwrap())
+        };
+        let (plain, _) = process(&gcode, &[]);
+
+        // The offset of the command places the copies
+        let copy_mode = gcode.replacen("\n", "\nM605 S2 X100\n", 1);
+        let (report, output) = process(&copy_mode, &[]);
+        assert_eq!(report.objects.len(), plain.objects.len() * 2);
+        for object in &plain.objects {
+            let copy = report
+                .objects
+                .iter()
+                .find(|copy| copy.name == format!("{}_copy", object.name))
+                .unwrap();
+            let (x, y) = object.center.unwrap();
+            let (copy_x, copy_y) = copy.center.unwrap();
+            assert!((copy_x - (x + 100.0)).abs() < 1e-6, "{}", object.name);
+            assert!((copy_y - y).abs() < 1e-6, "{}", object.name);
+            // Both prints share the moves marked for the object
+            assert!(!output.contains(&format!("EXCLUDE_OBJECT_START NAME={}\n", copy.name)));
+        }
+
+        // Mirror mode needs the placement
+        let mirror_mode = gcode.replacen("\n", "\nSET_DUAL_CARRIAGE CARRIAGE=1 MODE=MIRROR\n", 1);
+        let (report, _) = process(&mirror_mode, &[]);
+        assert_eq!(report.objects.len(), plain.objects.len());
+        let (report, _) = process(&mirror_mode, &["mirror-x=150"]);
+        assert_eq!(report.objects.len(), plain.objects.len() * 2);
+
+        // Without the mode the placement is ignored
+        let (report, _) = process(&gcode, &["mirror-x=150"]);
+        assert_eq!(report.objects.len(), plain.objects.len());
+    }
+
+    #[test]
+    fn test_warnings() {
+        // Named the same once sanitized, the second object is a single line
+        let gcode = "; generated by PrusaSlicer 2.6.0\nG28\nM83\n;LAYER_CHANGE\n;Z:0.2\n\
+            ; printing object Würfel\nG1 X0 Y0\nG1 X10 Y0 E1\nG1 X10 Y10 E1\nG1 X0 Y10 E1\n\
+            ; stop printing object Würfel\n\
+            ; printing object Wurfel\nG1 X20 Y0\nG1 X30 Y0 E1\n; stop printing object Wurfel\n";
+
+        let report = stream(
+            &mut Cursor::new(gcode),
+            &mut Vec::new(),
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            report.warnings,
+            [
+                Warning::NameCollision {
+                    object: "Würfel".into(),
+                    name: "Wurfel".into(),
+                    renamed: "Wurfel_2".into(),
+                },
+                Warning::DegenerateHull {
+                    object: "Wurfel".into()
+                },
+            ]
+        );
+        assert!(report
+            .to_json()
+            .contains(r#""warnings":[{"kind":"name_collision","object":"Würfel""#));
+
+        let options = ProcessOptions {
+            warnings_as_errors: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            stream(&mut Cursor::new(gcode), &mut Vec::new(), &options),
+            Err(PreprocessError::Warnings(2))
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("plate.gcode");
+        std::fs::write(&src, gcode).unwrap();
+        assert!(file(&src, &OutputOptions::default(), &options).is_err());
+        assert_eq!(std::fs::read_to_string(&src).unwrap(), gcode);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("cura.gcode")).unwrap();
+        // A purge macro of the start G-Code marking itself as an object
+        let mixed = gcode.replacen(
+            "\nM141 S36",
+            "\nEXCLUDE_OBJECT_START NAME=purge\nM141 S36\nEXCLUDE_OBJECT_END NAME=purge",
+            1,
+        );
+        let options = ProcessOptions {
+            normalize: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let report = stream(&mut Cursor::new(&mixed), &mut output, &options).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(report.objects.len(), 4);
+        assert!(!report.already_processed);
+        assert!(output.contains("\n; EXCLUDE_OBJECT_START NAME=purge\nM141 S36\n"));
+        assert!(!output.contains("NAME=purge\nEXCLUDE_OBJECT_START NAME=purge"));
+
+        // Processing the output again leaves it unchanged
+        let mut again = Vec::new();
+        stream(&mut Cursor::new(&output), &mut again, &options).unwrap();
+        assert_eq!(String::from_utf8(again).unwrap(), output);
+
+        // Files without conflicts are processed as usual
+        let mut normalized = Vec::new();
+        stream(&mut Cursor::new(&gcode), &mut normalized, &options).unwrap();
+        let mut expected = Vec::new();
+        stream(
+            &mut Cursor::new(&gcode),
+            &mut expected,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            comparable_lines(&normalized, false),
+            comparable_lines(&expected, false)
+        );
+    }
+
+    #[test]
+    fn test_mixed_markers() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("m486.gcode")).unwrap();
+
+        for (precedence, slicer) in [
+            (MarkerPrecedence::Comments, "PrusaSlicer"),
+            (MarkerPrecedence::M486, "M486"),
+        ] {
+            let options = ProcessOptions {
+                marker_precedence: precedence,
+                ..Default::default()
+            };
+            let identification =
+                Identification::identify(&mut Cursor::new(&gcode), gcode.len() as u64, &options)
+                    .unwrap();
+            assert_eq!(
+                identification.processor.map(|(slicer, _)| slicer),
+                Some(slicer)
+            );
+            assert!(identification.mixed);
+
+            let mut output = Vec::new();
+            let report = stream(&mut Cursor::new(&gcode), &mut output, &options).unwrap();
+            assert_eq!(report.objects.len(), 4, "{precedence:?}");
+
+            let output = String::from_utf8(output).unwrap();
+            let starts = output
+                .lines()
+                .filter(|line| line.starts_with("EXCLUDE_OBJECT_START"))
+                .count();
+            let m486 = output
+                .lines()
+                .filter(|line| line.starts_with("M486"))
+                .count();
+            match precedence {
+                MarkerPrecedence::Comments => {
+                    assert_eq!(starts, gcode.matches("\n; printing object").count());
+                    assert_eq!(m486, 0);
+                }
+                MarkerPrecedence::M486 => assert_eq!(starts, gcode.matches("\nM486 S").count() / 2),
+            }
+        }
+    }
+
+    #[test]
+    fn test_identify_indented_definitions() {
+        let gcode = "; generated by PrusaSlicer 2.6.0\n  exclude_object_define NAME=a\nG28\n\
+            \texclude_object_start NAME=a\n";
+        let identification = Identification::identify(
+            &mut Cursor::new(gcode),
+            gcode.len() as u64,
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert!(identification.already_processed);
+    }
+
+    #[test]
+    fn test_scan_window() {
+        let options = ProcessOptions {
+            scan_window: 64 * 1024,
+            ..Default::default()
+        };
+
+        for filename in [
+            "superslicer.gcode",
+            "cura.gcode",
+            "ideamaker.gcode",
+            "m486.gcode",
+        ] {
+            let gcode = std::fs::read_to_string(GCODE_PATH.join(filename)).unwrap();
+            let size = gcode.len() as u64;
+            let identification =
+                Identification::identify(&mut Cursor::new(&gcode), size, &options).unwrap();
+            let full = Identification::identify(
+                &mut Cursor::new(&gcode),
+                size,
+                &ProcessOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(
+                identification.processor.map(|(slicer, _)| slicer),
+                full.processor.map(|(slicer, _)| slicer),
+                "{filename}"
+            );
+            assert_eq!(identification.line_width, full.line_width, "{filename}");
+        }
+
+        // Slicer markers in the middle of the file are found by scanning all of it
+        let options = ProcessOptions {
+            scan_window: 1024,
+            ..Default::default()
+        };
+        let gcode = format!(
+            "{}; generated by PrusaSlicer\n{}",
+            "G1 X1\n".repeat(1000),
+            "G1 X2\n".repeat(1000)
+        );
+        let identification =
+            Identification::identify(&mut Cursor::new(&gcode), gcode.len() as u64, &options)
+                .unwrap();
+        assert_eq!(
+            identification.processor.map(|(slicer, _)| slicer),
+            Some("PrusaSlicer")
+        );
+
+        // Only the footer contains the marker
+        let gcode = format!("{};Sliced by ideaMaker 4.2.0\n", "G1 X1\n".repeat(1000));
+        let mut input = Cursor::new(&gcode);
+        let identification =
+            Identification::identify(&mut input, gcode.len() as u64, &options).unwrap();
+        assert_eq!(
+            identification.processor.map(|(slicer, _)| slicer),
+            Some("ideaMaker")
+        );
+        assert_eq!(input.position(), 0);
+    }
+
+    #[test]
+    fn test_object_metadata() {
+        let input = File::open(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let mut output = Vec::new();
+        let options = ProcessOptions {
+            object_metadata: true,
+            ..Default::default()
+        };
+        let mut report = ProcessReport::default();
+        process(&input, &mut output, &options, &mut report).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("; object NAME=cube_1_id_0_copy_0 Z_MIN=0.2 Z_MAX=5 LAYERS=25\n"));
+        assert!(report
+            .objects
+            .iter()
+            .all(|o| o.z_range == Some((0.2, 5.0)) && o.layers == 25));
+    }
+
+    /// Object a is printed with T0, b with T0 and T1, c with T1
+    const MULTI_TOOL_GCODE: &str = "; generated by PrusaSlicer 2.6.0\nG28\nM83\n;LAYER_CHANGE\n\
+        ;Z:0.2\nT0\n; printing object a\nG1 X0 Y0\nG1 X10 Y0 E1\nG1 X10 Y10 E1\n\
+        ; stop printing object a\n\
+        ; printing object b\nG1 X20 Y0\nG1 X30 Y0 E1\nG1 X30 Y10 E1\nT1\nG1 X20 Y10 E1\n\
+        ; stop printing object b\n\
+        ; printing object c\nG1 X40 Y0\nG1 X50 Y0 E1\nG1 X50 Y10 E1\n; stop printing object c\n";
+
+    #[test]
+    fn test_extruder_metadata() {
+        let gcode = MULTI_TOOL_GCODE;
+        let options = ProcessOptions {
+            object_metadata: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let report = stream(&mut Cursor::new(gcode), &mut output, &options).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("; extruder 0 OBJECTS=a,b\n; extruder 1 OBJECTS=b,c\n"));
+        assert!(output.contains("; object NAME=b LAYERS=1 EXTRUDERS=0,1\n"));
+        assert_eq!(
+            report.extruders,
+            BTreeMap::from([
+                (0, vec!["a".to_string(), "b".to_string()]),
+                (1, vec!["b".to_string(), "c".to_string()])
+            ])
+        );
+        assert!(report
+            .to_json()
+            .contains(r#""extruders":{"0":["a","b"],"1":["b","c"]}"#));
+
+        // Reprocessing strips the comments with the rest of the header
+        let mut reprocessed = Vec::new();
+        strip_markers(&mut output.as_bytes(), &mut reprocessed).unwrap();
+        assert!(!String::from_utf8(reprocessed)
+            .unwrap()
+            .contains("; extruder"));
+    }
+
+    #[test]
+    fn test_groups() {
+        let process = |group_prefix| {
+            let options = ProcessOptions {
+                groups: vec!["pair=A,b".parse().unwrap(), "none=x*".parse().unwrap()],
+                group_macro: Some("DEFINE_OBJECT_GROUP".into()),
+                group_prefix,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let report = stream(&mut Cursor::new(MULTI_TOOL_GCODE), &mut output, &options).unwrap();
+            (report, String::from_utf8(output).unwrap())
+        };
+
+        let (report, output) = process(false);
+        assert!(output.contains(
+            "; group NAME=pair OBJECTS=a,b\nDEFINE_OBJECT_GROUP GROUP=pair OBJECTS=a,b\n"
+        ));
+        assert_eq!(
+            report.groups,
+            BTreeMap::from([("pair".to_string(), vec!["a".to_string(), "b".to_string()])])
+        );
+        let groups: HashMap<&str, Option<&str>> = report
+            .objects
+            .iter()
+            .map(|o| (o.name.as_str(), o.group.as_deref()))
+            .collect();
+        assert_eq!(
+            groups,
+            HashMap::from([("a", Some("pair")), ("b", Some("pair")), ("c", None)])
+        );
+
+        let mut reprocessed = Vec::new();
+        strip_markers(&mut output.as_bytes(), &mut reprocessed).unwrap();
+        let reprocessed = String::from_utf8(reprocessed).unwrap();
+        assert!(!reprocessed.contains("GROUP"), "{reprocessed}");
+        assert!(!reprocessed.contains("; group"), "{reprocessed}");
+
+        // The markers use the prefixed names
+        let (report, output) = process(true);
+        assert_eq!(report.groups["pair"], ["pair_a", "pair_b"]);
+        assert!(output.contains("EXCLUDE_OBJECT_START NAME=pair_a\n"));
+        assert!(output.contains("EXCLUDE_OBJECT_START NAME=c\n"));
+    }
+
+    #[test]
+    fn test_cancel_extruder() {
+        let process = |cancel_extruders: Vec<u32>| {
+            let options = ProcessOptions {
+                cancel_extruders,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            stream(&mut Cursor::new(MULTI_TOOL_GCODE), &mut output, &options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        // Only objects printed exclusively with the tool are excluded, after all definitions
+        let output = process(vec![1]);
+        let excluded = output
+            .lines()
+            .filter(|line| line.starts_with("EXCLUDE_OBJECT NAME="))
+            .collect_vec();
+        assert_eq!(excluded, ["EXCLUDE_OBJECT NAME=c"]);
+        assert!(
+            output.find("EXCLUDE_OBJECT NAME=c").unwrap()
+                > output.rfind("EXCLUDE_OBJECT_DEFINE").unwrap()
+        );
+        assert!(process(vec![0, 1])
+            .contains("EXCLUDE_OBJECT NAME=a\nEXCLUDE_OBJECT NAME=b\nEXCLUDE_OBJECT NAME=c\n"));
+        assert!(!process(vec![2]).contains("EXCLUDE_OBJECT NAME="));
+
+        let mut reprocessed = Vec::new();
+        strip_markers(&mut output.as_bytes(), &mut reprocessed).unwrap();
+        assert!(!String::from_utf8(reprocessed)
+            .unwrap()
+            .contains("EXCLUDE_OBJECT"));
+    }
+
+    #[test]
+    fn test_name_comments() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let options = ProcessOptions {
+            name_comments: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let report = stream(&mut Cursor::new(&gcode), &mut output, &options).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("; object 'cube_1 id:0 copy 0' -> NAME=cube_1_id_0_copy_0\n"));
+        assert!(report
+            .objects
+            .iter()
+            .any(|o| o.original_name == "cube_1 id:0 copy 0" && o.name == "cube_1_id_0_copy_0"));
+
+        let mut stripped = Vec::new();
+        strip_markers(output.as_bytes(), &mut stripped).unwrap();
+        assert!(!String::from_utf8(stripped).unwrap().contains("; object '"));
+    }
+
+    #[test]
+    fn test_object_progress() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let options = ProcessOptions {
+            object_progress: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        stream(&mut Cursor::new(&gcode), &mut output, &options).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let name = "cube_1_id_0_copy_0";
+        let starts = output
+            .matches(&format!("EXCLUDE_OBJECT_START NAME={name}\n"))
+            .count();
+        assert!(starts > 1);
+        assert!(output.contains(&format!(
+            "EXCLUDE_OBJECT_START NAME={name}\n; object {name} layer 1/{starts}\n"
+        )));
+        assert!(output.contains(&format!("; object {name} layer {starts}/{starts}\n")));
+        assert_eq!(
+            output.matches(&format!("; object {name} layer ")).count(),
+            starts
+        );
+
+        let mut stripped = Vec::new();
+        strip_markers(output.as_bytes(), &mut stripped).unwrap();
+        assert!(!String::from_utf8(stripped).unwrap().contains(" layer 1/"));
+    }
+
+    #[test]
+    fn test_max_name_length() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let options = ProcessOptions {
+            max_name_length: Some(16),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let report = stream(&mut Cursor::new(&gcode), &mut output, &options).unwrap();
+
+        let names = report.object_names();
+        assert!(names.iter().all(|name| name.len() <= 16), "{names:?}");
+        assert_eq!(names.iter().unique().count(), report.objects.len());
+        let output = String::from_utf8(output).unwrap();
+        for name in &names {
+            assert!(output.contains(&format!("EXCLUDE_OBJECT_START NAME={name}\n")));
+        }
+    }
+
+    #[test]
+    fn test_extract_processed_object() {
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("superslicer.gcode")).unwrap();
+        let mut processed = Vec::new();
+        stream(
+            &mut Cursor::new(&gcode),
+            &mut processed,
+            &Default::default(),
+        )
+        .unwrap();
+
+        let mut extracted = Vec::new();
+        let starts = crate::extract::extract_object(
+            processed.as_slice(),
+            "cube_1_id_0_copy_0",
+            &mut extracted,
+        )
+        .unwrap();
+        assert_eq!(starts, 25);
+
+        let verification = crate::klipper::verify(extracted.as_slice()).unwrap();
+        assert_eq!(
+            verification.objects.keys().collect_vec(),
+            ["CUBE_1_ID_0_COPY_0"]
+        );
+        assert_eq!(verification.issues.len(), 0);
+        assert!(extracted.len() < processed.len() / 2);
+    }
+
+    fn polygon_extents(output: &[u8]) -> Vec<(String, (f64, f64))> {
+        let mut extents: Vec<_> = output
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| line.starts_with("EXCLUDE_OBJECT_DEFINE"))
+            .map(|definition| {
+                let Command { params, .. } = parse_gcode(&definition);
+                let points: Vec<(f64, f64)> =
+                    serde_json::from_str(params.get("POLYGON").unwrap_or(&"{}")).unwrap();
+                let xmin = points.iter().map(|p| OrderedFloat(p.0)).min().unwrap();
+                let xmax = points.iter().map(|p| OrderedFloat(p.0)).max().unwrap();
+                let ymin = points.iter().map(|p| OrderedFloat(p.1)).min().unwrap();
+                let ymax = points.iter().map(|p| OrderedFloat(p.1)).max().unwrap();
+                (
+                    params.get("NAME").unwrap().to_string(),
+                    ((xmax - xmin).into_inner(), (ymax - ymin).into_inner()),
+                )
+            })
+            .collect();
+        extents.sort_by(|a, b| a.0.cmp(&b.0));
+        extents
+    }
+
+    #[test]
+    fn test_first_layer_filter() {
+        let run = |layer_filter: LayerFilter| {
+            let input = File::open(GCODE_PATH.join("tapered_cube_superslicer.gcode")).unwrap();
+            let mut output = Vec::new();
+            let options = ProcessOptions {
+                layer_filter,
+                ..Default::default()
+            };
+            process(&input, &mut output, &options, &mut ProcessReport::default()).unwrap();
+            polygon_extents(&output)
+        };
+
+        let first = run(LayerFilter::first_layer());
+        let all = run(LayerFilter::all());
+        assert_eq!(first, run(LayerFilter::try_from("0").unwrap()));
+        // The cube widens towards the top, the first layer is smaller
+        assert!(first[0].1 .0 < all[0].1 .0);
+        assert!(first[0].1 .1 < all[0].1 .1);
+    }
+
+    #[test]
+    fn test_detected_line_width() {
+        let run = |line_width: Option<f64>| {
+            let input = File::open(GCODE_PATH.join("superslicer.gcode")).unwrap();
+            let mut output = Vec::new();
+            let mut options = ProcessOptions::default();
+            options.hull.line_width = line_width;
+            process(&input, &mut output, &options, &mut ProcessReport::default()).unwrap();
+            polygon_extents(&output)
+        };
+
+        let centerline = run(Some(0.0));
+        let detected = run(None);
+        assert_eq!(centerline.len(), 4);
+        for ((name, (cx, cy)), (_, (dx, dy))) in centerline.iter().zip(detected.iter()) {
+            // Grown by half the 0.42mm line width on each side
+            assert!((dx - cx - 0.42).abs() < 0.01, "{name}: {dx} vs {cx}");
+            assert!((dy - cy - 0.42).abs() < 0.01, "{name}: {dy} vs {cy}");
+        }
+    }
+
+    /// The inverted pyramid sliced by every supported slicer, layers are counted from the first
+    /// layer so each filter selects roughly the same height of the scene
+    #[test]
+    fn test_cross_slicer_matrix() {
+        // Layer heights differ between the slicers, the pyramid grows by 0.4mm per 0.2mm layer
+        const SIZE_TOLERANCE: f64 = 0.5;
+        const Z_TOLERANCE: f64 = 0.25;
+
+        /// Width, height, offset of the center from the middle of the outline and top of each object
+        fn measure(report: &ProcessReport) -> Vec<[f64; 5]> {
+            let mut measures: Vec<[f64; 5]> = report
+                .objects
+                .iter()
+                .map(|object| {
+                    let (xs, ys): (Vec<f64>, Vec<f64>) = object.polygon.iter().copied().unzip();
+                    let min = |values: &[f64]| values.iter().copied().fold(f64::MAX, f64::min);
+                    let max = |values: &[f64]| values.iter().copied().fold(f64::MIN, f64::max);
+                    let (x, y) = object.center.unwrap();
+                    [
+                        max(&xs) - min(&xs),
+                        max(&ys) - min(&ys),
+                        x - (max(&xs) + min(&xs)) / 2.0,
+                        y - (max(&ys) + min(&ys)) / 2.0,
+                        object.z_range.unwrap().1,
+                    ]
+                })
+                .collect();
+            measures.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            measures
+        }
+
+        // Layer numbers are at different heights with different layer heights, only the first
+        // and last layers and shares of the print are comparable
+        for layers in ["0", "*", "*/5", "-1", "~5", "%40-60", "%0-10"] {
+            let filter = LayerFilter::try_from(layers).unwrap();
+            let mut results = Vec::new();
+            for slicer in ["superslicer", "cura", "ideamaker", "m486"] {
+                // Without layer comments the layer count isn't known and relative filters fall
+                // back to all layers
+                if slicer == "m486" && filter.is_relative() {
+                    continue;
+                }
+
+                let mut input = File::open(
+                    GCODE_PATH
+                        .join("inverted_pyramid")
+                        .join(format!("{slicer}.gcode")),
+                )
+                .unwrap();
+                let options = ProcessOptions {
+                    layer_filter: filter.clone(),
+                    ..Default::default()
+                };
+                let report = stream(&mut input, &mut std::io::sink(), &options).unwrap();
+                assert_eq!(report.objects.len(), 2, "{slicer} {layers}");
+                results.push((slicer, measure(&report)));
+            }
+
+            let (reference, expected) = &results[0];
+            for (slicer, measures) in &results[1..] {
+                let tolerances = [SIZE_TOLERANCE, SIZE_TOLERANCE, 0.01, 0.01, Z_TOLERANCE];
+                let agree = measures.iter().zip(expected).all(|(actual, expected)| {
+                    actual.iter().zip(expected).zip(tolerances).all(
+                        |((actual, expected), tolerance)| (actual - expected).abs() <= tolerance,
+                    )
+                });
+                assert!(
+                    agree,
+                    "{layers}: {slicer} {measures:?} differs from {reference} {expected:?}"
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_slicer_layerfilters() {
         for slicer in ["m486"] {
@@ -173,9 +2904,12 @@ mod tests {
                 let input =
                     File::open(GCODE_PATH.join(filename).join(format!("{slicer}.gcode"))).unwrap();
                 let mut output = Cursor::new(Vec::new());
-                let layer_filter = LayerFilter::try_from(*layers).unwrap();
+                let options = ProcessOptions {
+                    layer_filter: LayerFilter::try_from(*layers).unwrap(),
+                    ..Default::default()
+                };
 
-                process(&input, &mut output, &layer_filter).unwrap();
+                process(&input, &mut output, &options, &mut ProcessReport::default()).unwrap();
 
                 output.rewind().unwrap();
                 let definitions: Vec<_> = output
@@ -0,0 +1,300 @@
+//! Invariants of the parsers, the layer filters and processing checked on random input.
+//!
+//! Processing is checked on synthetic plates of random size in every dialect, with unrelated
+//! commands between the objects. Failing cases are shrunk to a minimal plate and saved to
+//! `proptest-regressions/`, so they are run again first. `PROPTEST_CASES` runs more cases than
+//! the default, e.g. before a release.
+
+use crate::hulls::KnownObject;
+use crate::layers::LayerFilter;
+use crate::parser::{parse_float, parse_gcode};
+use crate::preprocess::{self, ProcessOptions};
+use crate::synthetic::{Dialect, SyntheticGcode};
+use geo::{Contains, EuclideanDistance, Point, Polygon};
+use proptest::prelude::*;
+use proptest::sample::select;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+
+/// Half the width of the extrusions, outlines are allowed to cut the corners of their centers
+const TOLERANCE: f64 = 0.25;
+
+/// A synthetic plate and the commands between its objects
+#[derive(Debug)]
+struct RandomPlate {
+    plate: SyntheticGcode,
+    /// Lines added after each layer change and object, used in turn
+    unrelated: Vec<Vec<String>>,
+}
+
+impl RandomPlate {
+    fn gcode(&self) -> String {
+        let mut gcode = String::new();
+        let mut unrelated = self.unrelated.iter().cycle();
+        for line in self.plate.to_string().lines() {
+            gcode.push_str(line);
+            gcode.push('\n');
+            let between_objects = line.starts_with("G1 Z")
+                || line.starts_with("; stop printing object")
+                || matches!(line, ";MESH:NONMESH" | ";PRINTING_ID: -1" | "M486 S-1");
+            if between_objects {
+                for line in unrelated.next().into_iter().flatten() {
+                    gcode.push_str(line);
+                    gcode.push('\n');
+                }
+            }
+        }
+        gcode
+    }
+
+    /// The extrusions of each object by its name
+    fn extrusions(&self) -> HashMap<String, Vec<(f64, f64)>> {
+        (0..self.plate.objects)
+            .map(|object| {
+                let extrusions = (0..self.plate.perimeters)
+                    .flat_map(|perimeter| self.plate.perimeter(object, perimeter))
+                    .collect();
+                (self.plate.object_name(object), extrusions)
+            })
+            .collect()
+    }
+}
+
+/// Strings of up to `max` of the pieces
+fn pieces(pieces: &[&'static str], max: usize) -> impl Strategy<Value = String> {
+    prop::collection::vec(select(pieces.to_vec()), 0..=max).prop_map(|pieces| pieces.concat())
+}
+
+/// Text as users write it, with spaces, punctuation and accents
+fn text() -> impl Strategy<Value = String> {
+    "[abcXYZ019 .()üé#-]{1,11}"
+}
+
+/// Commands and comments outside of objects that have to pass through unchanged
+fn unrelated() -> impl Strategy<Value = String> {
+    prop_oneof![
+        any::<u8>().prop_map(|speed| format!("M106 S{speed}")),
+        (0..1000u16).prop_map(|time| format!("G4 P{time}")),
+        text().prop_map(|text| format!("; note {text}")),
+        text().prop_map(|text| format!("M117 {text}")),
+        (0.0..200.0, 0.0..200.0).prop_map(|(x, y)| format!("G0 X{x:.3} Y{y:.3} F9000")),
+    ]
+}
+
+fn random_plate() -> impl Strategy<Value = RandomPlate> {
+    (
+        select(Dialect::ALL.to_vec()),
+        1..=5usize,
+        1..=4usize,
+        1..=3usize,
+        3..=12usize,
+        prop::collection::vec(prop::collection::vec(unrelated(), 0..3), 1..8),
+    )
+        .prop_map(
+            |(dialect, objects, layers, perimeters, segments, unrelated)| RandomPlate {
+                plate: SyntheticGcode::new(dialect)
+                    .objects(objects)
+                    .layers(layers)
+                    .perimeters(perimeters)
+                    .segments(segments),
+                unrelated,
+            },
+        )
+}
+
+/// The value of a parameter of an `EXCLUDE_OBJECT_*` line, up to the next parameter
+fn param<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!(" {name}="))? + name.len() + 2;
+    let value = &line[start..];
+    Some(
+        match value.find(" CENTER=").or_else(|| value.find(" POLYGON=")) {
+            Some(end) => &value[..end],
+            None => value,
+        },
+    )
+}
+
+/// Starts and ends alternate, every started object is defined and nothing is left open
+fn check_markers(output: &str) -> Result<(), String> {
+    let defined: HashSet<&str> = output
+        .lines()
+        .filter(|line| line.starts_with("EXCLUDE_OBJECT_DEFINE"))
+        .filter_map(|line| param(line, "NAME"))
+        .collect();
+
+    let mut current: Option<&str> = None;
+    for (number, line) in output.lines().enumerate() {
+        if line.starts_with("EXCLUDE_OBJECT_START") {
+            let name = param(line, "NAME").ok_or(format!("{number}: START without NAME"))?;
+            if let Some(open) = current {
+                return Err(format!("{number}: {name} started inside of {open}"));
+            }
+            if !defined.contains(name) {
+                return Err(format!("{number}: {name} started but not defined"));
+            }
+            current = Some(name);
+        } else if line.starts_with("EXCLUDE_OBJECT_END") {
+            let name = param(line, "NAME").ok_or(format!("{number}: END without NAME"))?;
+            if current != Some(name) {
+                return Err(format!("{number}: {name} ended, but {current:?} is open"));
+            }
+            current = None;
+        }
+    }
+
+    match current {
+        Some(open) => Err(format!("{open} is never ended")),
+        None => Ok(()),
+    }
+}
+
+/// Every input line is written in the same order, commented out at most, and the lines added
+/// are only markers and comments
+fn check_passthrough(input: &str, output: &str) -> Result<(), String> {
+    let mut input = input.lines().peekable();
+    for (number, line) in output.lines().enumerate() {
+        let Some(expected) = input.peek() else {
+            if !line.is_empty() && !line.starts_with(';') && !line.starts_with("EXCLUDE_OBJECT") {
+                return Err(format!("{number}: {line} added at the end"));
+            }
+            continue;
+        };
+
+        let commented = line.trim_start().starts_with(';') && line.ends_with(expected.trim());
+        if line == *expected || (commented && !expected.trim().is_empty()) {
+            input.next();
+        } else if !line.is_empty() && !line.starts_with(';') && !line.starts_with("EXCLUDE_OBJECT")
+        {
+            return Err(format!("{number}: {line} added before {expected}"));
+        }
+    }
+
+    match input.next() {
+        Some(missing) => Err(format!("{missing} is missing")),
+        None => Ok(()),
+    }
+}
+
+/// Outlines are finite polygons with an area that cover every extrusion of their object
+fn check_polygons(
+    output: &str,
+    extrusions: &HashMap<String, Vec<(f64, f64)>>,
+) -> Result<(), String> {
+    let defines = output
+        .lines()
+        .filter(|line| line.starts_with("EXCLUDE_OBJECT_DEFINE"));
+    let mut polygons = 0;
+    for line in defines {
+        let polygon = param(line, "POLYGON").ok_or(format!("{line} has no polygon"))?;
+        let points: Vec<(f64, f64)> = serde_json::from_str::<Vec<[f64; 2]>>(polygon)
+            .map_err(|err| format!("{line}: {err}"))?
+            .into_iter()
+            .map(|[x, y]| (x, y))
+            .collect();
+        if points.len() < 3 || points.iter().any(|(x, y)| !x.is_finite() || !y.is_finite()) {
+            return Err(format!("{line} is not a valid polygon"));
+        }
+
+        let polygon = Polygon::new(points.into(), vec![]);
+        let Some((_, extrusions)) = extrusions.iter().find(|(name, _)| {
+            param(line, "NAME").is_some_and(|defined| {
+                defined.eq_ignore_ascii_case(&KnownObject::clean_id(name, None))
+            })
+        }) else {
+            return Err(format!("{line} defines an unknown object"));
+        };
+        for &(x, y) in extrusions {
+            let point = Point::new(x, y);
+            if !polygon.contains(&point) && point.euclidean_distance(&polygon) > TOLERANCE {
+                return Err(format!("{x},{y} is outside of {line}"));
+            }
+        }
+        polygons += 1;
+    }
+
+    match polygons == extrusions.len() {
+        true => Ok(()),
+        false => Err(format!(
+            "{polygons} objects defined, {} printed",
+            extrusions.len()
+        )),
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_parse_float_properties(
+        value in pieces(&["0", "1", "5", "9", ".", "-", "+", "e", "E", "00000000000"], 12)
+    ) {
+        let expected = value.parse::<f64>().ok().filter(|value| value.is_finite());
+        prop_assert_eq!(parse_float(&value).map(f64::to_bits), expected.map(f64::to_bits));
+    }
+
+    #[test]
+    fn test_parse_gcode_properties(
+        line in pieces(
+            &[
+                "G1", " ", "  ", "\t", "X1.5", "Y", "E-0.1", ";", "; comment", "NAME=a", "=",
+                "ü", "M486", "S-1", "\r",
+            ],
+            10,
+        )
+    ) {
+        let code = line.split(';').next().unwrap_or_default();
+        let parsed = parse_gcode(&line);
+
+        prop_assert_eq!(parsed.command.is_none(), code.trim().is_empty());
+        if let Some(command) = parsed.command {
+            prop_assert!(code.contains(command));
+            prop_assert!(!command.contains(char::is_whitespace));
+        }
+        for (key, value) in &parsed.params {
+            prop_assert!(!key.contains([';', ' ']));
+            prop_assert!(!value.contains([';', ' ']));
+        }
+    }
+
+    /// Any input is either rejected or displayed as a spec parsing to the same filter
+    #[test]
+    fn test_layer_filter_properties(
+        spec in pieces(
+            &["0", "1", "7", "12", "-", "/", "+", "*", ",", "~", "%", "odd", "even", "first", ""],
+            7,
+        )
+    ) {
+        if let Ok(filter) = LayerFilter::try_from(spec.as_str()) {
+            let display = filter.to_string();
+            prop_assert_eq!(LayerFilter::try_from(display.as_str()), Ok(filter));
+        }
+    }
+
+    #[test]
+    fn test_processing_properties(plate in random_plate()) {
+        let gcode = plate.gcode();
+        let mut output = Vec::new();
+        preprocess::stream(
+            &mut Cursor::new(&gcode),
+            &mut output,
+            &ProcessOptions::default(),
+        )
+        .map_err(|err| TestCaseError::fail(format!("{err}\n{gcode}")))?;
+        let output = String::from_utf8(output).unwrap();
+
+        check_markers(&output)
+            .and_then(|()| check_passthrough(&gcode, &output))
+            .and_then(|()| check_polygons(&output, &plate.extrusions()))
+            .map_err(|err| TestCaseError::fail(format!("{err}\n{output}")))?;
+    }
+}
+
+#[test]
+fn test_checks_detect_violations() {
+    assert!(check_markers("EXCLUDE_OBJECT_START NAME=a\n").is_err());
+    assert!(check_markers(
+        "EXCLUDE_OBJECT_DEFINE NAME=a\nEXCLUDE_OBJECT_START NAME=a\nEXCLUDE_OBJECT_END NAME=b\n"
+    )
+    .is_err());
+    assert!(check_passthrough("G28\nG1 X1\n", "G28\n").is_err());
+    assert!(check_passthrough("G28\n", "G28\nG1 X1\n").is_err());
+    assert!(check_passthrough("M486 S1\n", "; M486 S1\nEXCLUDE_OBJECT_START NAME=a\n").is_ok());
+}
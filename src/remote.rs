@@ -0,0 +1,259 @@
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("Could not determine a file name for {0}")]
+    FileName(String),
+    #[error("Error running {0}, is it installed?")]
+    Spawn(&'static str),
+    #[error("Error transferring {0}")]
+    Transfer(String),
+    #[error("Invalid upload destination {0}")]
+    Destination(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Scheme {
+    Http,
+    S3,
+}
+
+/// A G-Code file stored on a web server or in S3 compatible object storage.
+///
+/// Transfers are delegated to `curl` and the `aws` command line tools so that credentials,
+/// proxies and endpoints are configured the same way as for any other tooling on the host.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct RemoteFile {
+    scheme: Scheme,
+    url: String,
+}
+
+impl RemoteFile {
+    pub fn parse(location: &Path) -> Option<Self> {
+        let url = location.to_str()?;
+        let scheme = if url.starts_with("http://") || url.starts_with("https://") {
+            Scheme::Http
+        } else if url.starts_with("s3://") {
+            Scheme::S3
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            scheme,
+            url: url.to_string(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The last path segment of the URL, without query string or fragment
+    pub fn file_name(&self) -> Result<&str, RemoteError> {
+        let path = self
+            .url
+            .split(['?', '#'])
+            .next()
+            .and_then(|url| url.split_once("://"))
+            .map(|(_, path)| path)
+            .unwrap_or_default();
+
+        match path.rsplit_once('/') {
+            Some((_, name)) if !name.is_empty() => Ok(name),
+            _ => Err(RemoteError::FileName(self.url.clone())),
+        }
+    }
+
+    /// A remote file in the same location with a different file name, query parameters are dropped
+    pub fn with_file_name(&self, name: &str) -> Result<Self, RemoteError> {
+        let current = self.file_name()?;
+        let location = self.url.split(['?', '#']).next().unwrap_or_default();
+        let location = &location[..location.len() - current.len()];
+
+        Ok(Self {
+            scheme: self.scheme.clone(),
+            url: format!("{location}{name}"),
+        })
+    }
+
+    pub fn fetch(&self, dest: &Path) -> Result<(), RemoteError> {
+        tracing::info!("Downloading {}", self.url);
+        match self.scheme {
+            Scheme::Http => run(
+                "curl",
+                Command::new("curl")
+                    .args(["--fail", "--silent", "--show-error", "--location"])
+                    .arg("--output")
+                    .arg(dest)
+                    .arg(&self.url),
+                &self.url,
+            ),
+            Scheme::S3 => run(
+                "aws",
+                Command::new("aws")
+                    .args(["s3", "cp", "--only-show-errors", &self.url])
+                    .arg(dest),
+                &self.url,
+            ),
+        }
+    }
+
+    pub fn upload(&self, src: &Path) -> Result<(), RemoteError> {
+        tracing::info!("Uploading {}", self.url);
+        match self.scheme {
+            Scheme::Http => run(
+                "curl",
+                Command::new("curl")
+                    .args(["--fail", "--silent", "--show-error"])
+                    .arg("--upload-file")
+                    .arg(src)
+                    .arg(&self.url),
+                &self.url,
+            ),
+            Scheme::S3 => run(
+                "aws",
+                Command::new("aws")
+                    .args(["s3", "cp", "--only-show-errors"])
+                    .arg(src)
+                    .arg(&self.url),
+                &self.url,
+            ),
+        }
+    }
+}
+
+/// A remote host processed files are copied to via `scp`
+///
+/// Accepts `sftp://[user@]host[:port]/path` URLs as well as scp style `[user@]host:path`
+/// destinations. Paths ending with a `/` are treated as directories.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct UploadTarget {
+    host: String,
+    port: Option<u16>,
+    path: String,
+}
+
+impl FromStr for UploadTarget {
+    type Err = RemoteError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || RemoteError::Destination(value.to_string());
+
+        let (host, port, path) = match value.strip_prefix("sftp://") {
+            Some(url) => {
+                let (authority, path) = url.split_once('/').ok_or_else(invalid)?;
+                let (host, port) = match authority.rsplit_once(':') {
+                    Some((host, port)) => (host, Some(port.parse().map_err(|_err| invalid())?)),
+                    None => (authority, None),
+                };
+                (host, port, format!("/{path}"))
+            }
+            None => {
+                let (host, path) = value.split_once(':').ok_or_else(invalid)?;
+                (host, None, path.to_string())
+            }
+        };
+
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+impl UploadTarget {
+    fn destination(&self) -> String {
+        format!("{}:{}", self.host, self.path)
+    }
+
+    pub fn upload(&self, src: &Path) -> Result<(), RemoteError> {
+        let destination = self.destination();
+        tracing::info!("Uploading {} to {}", src.to_string_lossy(), destination);
+
+        let mut command = Command::new("scp");
+        command.args(["-q", "-B"]);
+        if let Some(port) = self.port {
+            command.arg("-P").arg(port.to_string());
+        }
+        command.arg(src).arg(&destination);
+
+        run("scp", &mut command, &destination)
+    }
+}
+
+fn run(program: &'static str, command: &mut Command, url: &str) -> Result<(), RemoteError> {
+    let status = command
+        .status()
+        .map_err(|_err| RemoteError::Spawn(program))?;
+
+    if !status.success() {
+        return Err(RemoteError::Transfer(url.to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remote_locations() {
+        assert!(RemoteFile::parse(Path::new("plate.gcode")).is_none());
+        assert!(RemoteFile::parse(Path::new("/tmp/http/plate.gcode")).is_none());
+
+        let http =
+            RemoteFile::parse(Path::new("https://farm.local/files/plate.gcode?v=1")).unwrap();
+        assert_eq!(http.scheme, Scheme::Http);
+        assert_eq!(http.file_name().unwrap(), "plate.gcode");
+
+        let s3 = RemoteFile::parse(Path::new("s3://bucket/plates/plate.gcode")).unwrap();
+        assert_eq!(s3.scheme, Scheme::S3);
+        assert_eq!(s3.file_name().unwrap(), "plate.gcode");
+
+        assert!(RemoteFile::parse(Path::new("s3://bucket/"))
+            .unwrap()
+            .file_name()
+            .is_err());
+    }
+
+    #[test]
+    fn test_remote_with_file_name() {
+        let s3 = RemoteFile::parse(Path::new("s3://bucket/plates/plate.gcode")).unwrap();
+        assert_eq!(
+            s3.with_file_name("plate.processed.gcode").unwrap().url(),
+            "s3://bucket/plates/plate.processed.gcode"
+        );
+    }
+
+    #[test]
+    fn test_upload_targets() {
+        assert_eq!(
+            UploadTarget::from_str("sftp://pi@printer:2222/home/pi/gcodes/").unwrap(),
+            UploadTarget {
+                host: "pi@printer".into(),
+                port: Some(2222),
+                path: "/home/pi/gcodes/".into()
+            }
+        );
+        assert_eq!(
+            UploadTarget::from_str("printer:gcodes/")
+                .unwrap()
+                .destination(),
+            "printer:gcodes/"
+        );
+
+        assert!(UploadTarget::from_str("gcodes/plate.gcode").is_err());
+        assert!(UploadTarget::from_str("sftp://printer").is_err());
+        assert!(UploadTarget::from_str("sftp://printer:ssh/gcodes").is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use crate::hulls::KnownObject;
+use crate::layers::LayerFilter;
+use geo::{Area, BooleanOps, LineString, Polygon};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Overlaps larger than this fraction of the smaller object are reported
+const OVERLAP_THRESHOLD: f64 = 0.1;
+
+/// An object found in a file and its outline
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ObjectReport {
+    pub name: String,
+    /// Name of the object in the slicer
+    #[serde(default)]
+    pub original_name: String,
+    pub center: Option<(f64, f64)>,
+    pub polygon: Vec<(f64, f64)>,
+    pub extruders: Vec<u32>,
+    /// Group of related objects the object belongs to
+    #[serde(default)]
+    pub group: Option<String>,
+    pub z_range: Option<(f64, f64)>,
+    pub layers: usize,
+}
+
+impl From<&KnownObject> for ObjectReport {
+    fn from(known_object: &KnownObject) -> Self {
+        Self {
+            name: known_object.name.clone(),
+            original_name: known_object.original_name.clone(),
+            center: known_object.hull.center().map(|p| (p.x(), p.y())),
+            polygon: known_object
+                .hull
+                .exterior()
+                .iter()
+                .map(|p| (p.x(), p.y()))
+                .collect(),
+            extruders: known_object.extruders.iter().copied().collect(),
+            group: known_object.group.clone(),
+            z_range: known_object.z_range,
+            layers: known_object.layers(),
+        }
+    }
+}
+
+impl ObjectReport {
+    fn as_polygon(&self) -> Polygon {
+        Polygon::new(LineString::from(self.polygon.clone()), vec![])
+    }
+}
+
+/// Two objects whose outlines overlap significantly
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OverlapReport {
+    pub objects: (String, String),
+    /// Area of the intersection in mm²
+    pub area: f64,
+}
+
+/// A problem found while processing a file that didn't stop it
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Warning {
+    /// The extrusions of the object don't enclose an area, it is outlined by a padded bounding
+    /// box instead
+    DegenerateHull { object: String },
+    /// The outlines of two objects overlap significantly
+    Overlap {
+        objects: (String, String),
+        /// Area of the intersection in mm²
+        area: f64,
+    },
+    /// Two objects have the same name once sanitized, the second one was renamed
+    NameCollision {
+        object: String,
+        name: String,
+        renamed: String,
+    },
+    /// The markers of an object in the output are unbalanced, only a warning with lenient
+    /// verification
+    UnbalancedMarkers { line: usize, message: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::DegenerateHull { object } => write!(f, "Object {object} has no area"),
+            Warning::Overlap { objects, area } => write!(
+                f,
+                "Objects {} and {} overlap by {:.1}mm²",
+                objects.0, objects.1, area
+            ),
+            Warning::NameCollision {
+                object,
+                name,
+                renamed,
+            } => write!(
+                f,
+                "Object {object} is named {name} as well, renamed to {renamed}"
+            ),
+            Warning::UnbalancedMarkers { line, message } => {
+                write!(f, "Unbalanced object markers, line {line}: {message}")
+            }
+        }
+    }
+}
+
+/// Time spent in each phase of processing a file, in seconds
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Timings {
+    /// Scanning the file for the slicer and its layers
+    pub identification: f64,
+    /// Collecting the objects and their extrusion moves
+    pub collection: f64,
+    /// Computing the object outlines and checking them for overlaps
+    pub hulls: f64,
+    /// Writing the processed file
+    pub rewrite: f64,
+    /// Size of the input in bytes
+    pub bytes: u64,
+    /// Input processed over all phases in MB/s
+    pub throughput: f64,
+}
+
+impl Timings {
+    pub fn total(&self) -> f64 {
+        self.identification + self.collection + self.hulls + self.rewrite
+    }
+
+    /// Calculate the throughput once all phases are done and log the timings
+    pub fn finish(&mut self) {
+        let total = self.total();
+        if total > 0.0 {
+            self.throughput = self.bytes as f64 / 1_000_000.0 / total;
+        }
+
+        tracing::info!(
+            "Processed {:.1}MB in {:.3}s ({:.1}MB/s): identification {:.3}s, collection {:.3}s, hulls {:.3}s, rewrite {:.3}s",
+            self.bytes as f64 / 1_000_000.0,
+            total,
+            self.throughput,
+            self.identification,
+            self.collection,
+            self.hulls,
+            self.rewrite
+        );
+    }
+}
+
+/// Summary of a single processed G-Code file
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct ProcessReport {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub slicer: Option<String>,
+    /// Number of the plate of the 3MF container the file was extracted from
+    pub plate: Option<usize>,
+    /// The file already contained object definitions and was passed through unchanged
+    pub already_processed: bool,
+    /// The file defines objects but never starts them, it was passed through unchanged as well
+    pub partial: bool,
+    /// The markers were taken from the result cache
+    pub cached: bool,
+    /// The analysis was taken from an interrupted run, only the output was written
+    pub resumed: bool,
+    /// Version of this tool that processed the input before
+    pub previous_version: Option<String>,
+    /// Layers the object outlines were collected from
+    pub layer_filter: Option<LayerFilter>,
+    pub objects: Vec<ObjectReport>,
+    /// Names of the objects printed by each tool, to cancel all objects of a failed extruder
+    pub extruders: BTreeMap<u32, Vec<String>>,
+    /// Names of the objects of each group
+    pub groups: BTreeMap<String, Vec<String>>,
+    pub overlaps: Vec<OverlapReport>,
+    /// Problems that didn't stop processing, in the order they were found
+    pub warnings: Vec<Warning>,
+    /// Lines written for the object definitions and markers, including commented out commands
+    pub lines_added: usize,
+    pub timings: Timings,
+}
+
+impl ProcessReport {
+    pub fn add_objects<'a>(&mut self, known_objects: impl IntoIterator<Item = &'a KnownObject>) {
+        self.objects
+            .extend(known_objects.into_iter().map(ObjectReport::from));
+        self.index_objects();
+    }
+
+    /// Group the objects by the tools printing them and by their groups
+    pub fn index_objects(&mut self) {
+        self.extruders.clear();
+        self.groups.clear();
+        for object in self.objects.iter().sorted_by(|a, b| a.name.cmp(&b.name)) {
+            for extruder in &object.extruders {
+                self.extruders
+                    .entry(*extruder)
+                    .or_default()
+                    .push(object.name.clone());
+            }
+            if let Some(group) = &object.group {
+                self.groups
+                    .entry(group.clone())
+                    .or_default()
+                    .push(object.name.clone());
+            }
+        }
+    }
+
+    /// Find pairs of objects whose outlines overlap by more than 10% of the smaller object, a
+    /// warning is added for each
+    pub fn find_overlaps(&mut self) -> &[OverlapReport] {
+        let polygons: Vec<(&str, Polygon)> = self
+            .objects
+            .iter()
+            .filter(|o| o.polygon.len() >= 4)
+            .map(|o| (o.name.as_str(), o.as_polygon()))
+            .collect();
+
+        self.overlaps = polygons
+            .iter()
+            .tuple_combinations()
+            .filter_map(|((a, pa), (b, pb))| {
+                let area = pa.intersection(pb).unsigned_area();
+                let smaller = pa.unsigned_area().min(pb.unsigned_area());
+                (area > 0.0 && area > smaller * OVERLAP_THRESHOLD).then(|| OverlapReport {
+                    objects: (a.to_string(), b.to_string()),
+                    area,
+                })
+            })
+            .collect();
+        self.warnings
+            .extend(self.overlaps.iter().map(|overlap| Warning::Overlap {
+                objects: overlap.objects.clone(),
+                area: overlap.area,
+            }));
+
+        &self.overlaps
+    }
+
+    pub fn object_names(&self) -> Vec<&str> {
+        self.objects.iter().map(|o| o.name.as_str()).collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Outcome of processing one file of a batch run
+pub(crate) struct BatchEntry {
+    pub file: String,
+    /// The report, or the error processing the file failed with
+    pub result: Result<ProcessReport, String>,
+    pub duration: Duration,
+}
+
+impl BatchEntry {
+    fn status(&self) -> String {
+        match &self.result {
+            Err(err) => format!("failed: {err}"),
+            Ok(report) if report.already_processed => "unchanged".into(),
+            Ok(report) if report.cached => "cached".into(),
+            Ok(report) if report.resumed => "resumed".into(),
+            Ok(report) if report.objects.is_empty() => "no objects".into(),
+            Ok(report) if !report.warnings.is_empty() => {
+                format!("processed, {} warnings", report.warnings.len())
+            }
+            Ok(_) => "processed".into(),
+        }
+    }
+
+    fn columns(&self) -> [String; 6] {
+        let (slicer, objects, lines) = match &self.result {
+            Ok(report) => (
+                report.slicer.clone().unwrap_or_else(|| "-".into()),
+                report.objects.len().to_string(),
+                report.lines_added.to_string(),
+            ),
+            Err(_) => ("-".into(), "-".into(), "-".into()),
+        };
+        let duration = format!("{:.2}s", self.duration.as_secs_f64());
+
+        [
+            self.file.clone(),
+            slicer,
+            objects,
+            lines,
+            duration,
+            self.status(),
+        ]
+    }
+}
+
+/// A table summarizing a batch run, one line per file with aligned columns
+pub(crate) fn summary_table(entries: &[BatchEntry]) -> String {
+    const HEADER: [&str; 6] = [
+        "File",
+        "Slicer",
+        "Objects",
+        "Lines added",
+        "Duration",
+        "Status",
+    ];
+    /// Numbers are right-aligned
+    const NUMERIC: [bool; 6] = [false, false, true, true, true, false];
+
+    let rows = entries.iter().map(BatchEntry::columns).collect_vec();
+    let widths = HEADER.map(str::len);
+    let widths: Vec<usize> = (0..HEADER.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .fold(widths[column], usize::max)
+        })
+        .collect();
+
+    let format_row = |row: &[String]| {
+        let cells = row.iter().enumerate().map(|(column, cell)| {
+            let width = widths[column];
+            match NUMERIC[column] {
+                true => format!("{cell:>width$}"),
+                false => format!("{cell:<width$}"),
+            }
+        });
+        format!("{}\n", cells.collect_vec().join("  ").trim_end())
+    };
+
+    let mut table = format_row(&HEADER.map(String::from));
+    table.push_str(&format_row(
+        &widths.iter().map(|width| "-".repeat(*width)).collect_vec(),
+    ));
+    for row in &rows {
+        table.push_str(&format_row(row));
+    }
+
+    let failed = entries.iter().filter(|entry| entry.result.is_err()).count();
+    let total: Duration = entries.iter().map(|entry| entry.duration).sum();
+    table.push_str(&format!(
+        "{} files, {} failed, {:.2}s\n",
+        entries.len(),
+        failed,
+        total.as_secs_f64()
+    ));
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(name: &str, x: f64, y: f64, size: f64) -> ObjectReport {
+        ObjectReport {
+            name: name.to_string(),
+            polygon: vec![
+                (x, y),
+                (x + size, y),
+                (x + size, y + size),
+                (x, y + size),
+                (x, y),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_find_overlaps() {
+        let mut report = ProcessReport {
+            objects: vec![
+                square("a", 0.0, 0.0, 10.0),
+                // Overlaps a by 25mm²
+                square("b", 5.0, 5.0, 10.0),
+                // Barely touches b
+                square("c", 14.9, 14.9, 10.0),
+                square("d", 50.0, 50.0, 10.0),
+            ],
+            ..Default::default()
+        };
+
+        let overlaps = report.find_overlaps();
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(overlaps[0].objects, ("a".to_string(), "b".to_string()));
+        assert!((overlaps[0].area - 25.0).abs() < 1e-9);
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(
+            report.warnings[0].to_string(),
+            "Objects a and b overlap by 25.0mm²"
+        );
+    }
+
+    #[test]
+    fn test_summary_table() {
+        let processed = ProcessReport {
+            slicer: Some("PrusaSlicer".into()),
+            objects: vec![square("a", 0.0, 0.0, 1.0), square("b", 5.0, 5.0, 1.0)],
+            lines_added: 52,
+            warnings: vec![Warning::DegenerateHull { object: "a".into() }],
+            ..Default::default()
+        };
+        let unchanged = ProcessReport {
+            slicer: Some("Cura".into()),
+            already_processed: true,
+            ..Default::default()
+        };
+        let entries = [
+            BatchEntry {
+                file: "plates/benchy.gcode".into(),
+                result: Ok(processed),
+                duration: Duration::from_millis(1250),
+            },
+            BatchEntry {
+                file: "würfel.gcode".into(),
+                result: Ok(unchanged),
+                duration: Duration::from_millis(20),
+            },
+            BatchEntry {
+                file: "photo.gcode".into(),
+                result: Err("The input is not a text G-Code file".into()),
+                duration: Duration::from_millis(1),
+            },
+        ];
+
+        assert_eq!(
+            summary_table(&entries),
+            "\
+File                 Slicer       Objects  Lines added  Duration  Status
+-------------------  -----------  -------  -----------  --------  -------------------------------------------
+plates/benchy.gcode  PrusaSlicer        2           52     1.25s  processed, 1 warnings
+würfel.gcode         Cura               0            0     0.02s  unchanged
+photo.gcode          -                  -            -     0.00s  failed: The input is not a text G-Code file
+3 files, 1 failed, 1.27s
+"
+        );
+    }
+
+    #[test]
+    fn test_timings() {
+        let mut timings = Timings {
+            identification: 0.5,
+            collection: 1.0,
+            hulls: 0.25,
+            rewrite: 0.25,
+            bytes: 10_000_000,
+            ..Default::default()
+        };
+        timings.finish();
+        assert_eq!(timings.total(), 2.0);
+        assert_eq!(timings.throughput, 5.0);
+
+        let report = ProcessReport {
+            timings,
+            ..Default::default()
+        };
+        assert!(report
+            .to_json()
+            .contains(r#""timings":{"identification":0.5,"#));
+    }
+}
@@ -0,0 +1,161 @@
+//! State of interrupted runs, so processing a huge file again continues with writing it.
+//!
+//! The analysis of a file, its slicer, objects, outlines and the markers to insert, is stored in
+//! a sidecar next to it before the output is written and removed once the output is complete.
+//! Processing the same file with the same options after an interruption skips straight to
+//! writing the output.
+
+use crate::cache::{fingerprint, CacheEntry, Fnv64};
+use crate::preprocess::ProcessOptions;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Bytes at the start and end of the input that identify it, hashing all of a huge file would
+/// take almost as long as analysing it again
+const SAMPLE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Deserialize, Serialize)]
+struct Sidecar {
+    key: String,
+    entry: CacheEntry,
+}
+
+/// The sidecar of the file being processed
+pub(crate) struct ResumeState {
+    path: PathBuf,
+    key: String,
+}
+
+impl ResumeState {
+    /// Identify the input by the size and modification time of the file, the options and the
+    /// content at its start and end
+    pub fn new(
+        src: &Path,
+        input: &mut (impl Read + Seek),
+        options: &ProcessOptions,
+    ) -> io::Result<Self> {
+        let mut hash = Fnv64::new();
+        hash.write(fingerprint(options).as_bytes());
+
+        let metadata = fs::metadata(src)?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_nanos());
+        hash.write(&metadata.len().to_le_bytes());
+        hash.write(&modified.to_le_bytes());
+
+        let size = input.seek(SeekFrom::End(0))?;
+        hash.write(&size.to_le_bytes());
+        for start in [0, size.saturating_sub(SAMPLE_SIZE)] {
+            input.seek(SeekFrom::Start(start))?;
+            let mut sample = Vec::new();
+            input.by_ref().take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+            hash.write(&sample);
+        }
+        input.rewind()?;
+
+        let mut path = src.as_os_str().to_owned();
+        path.push(".resume.json");
+        Ok(Self {
+            path: path.into(),
+            key: format!("{:016x}", hash.0),
+        })
+    }
+
+    /// The analysis of an interrupted run of the same input and options
+    pub fn load(&self) -> Option<CacheEntry> {
+        let file = File::open(&self.path).ok()?;
+        match serde_json::from_reader::<_, Sidecar>(BufReader::new(file)) {
+            Ok(sidecar) if sidecar.key == self.key => Some(sidecar.entry),
+            Ok(_) => {
+                tracing::debug!(
+                    "Ignoring {}, the file or the options changed",
+                    self.path.display()
+                );
+                None
+            }
+            Err(err) => {
+                tracing::warn!("Ignoring invalid {}: {}", self.path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Store the analysis, written to a temporary file first so an interruption never leaves a
+    /// partial sidecar
+    pub fn store(&self, entry: CacheEntry) -> io::Result<()> {
+        let sidecar = Sidecar {
+            key: self.key.clone(),
+            entry,
+        };
+
+        let partial = self.path.with_extension("json.partial");
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut writer, &sidecar)?;
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(partial, &self.path)
+    }
+
+    /// Remove the sidecar once the output is complete
+    pub fn remove(&self) {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                tracing::warn!("Could not remove {}: {}", self.path.display(), err);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::ProcessReport;
+    use crate::splice::Edits;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("plate.gcode");
+        fs::write(&src, "G28\nG1 X1\n").unwrap();
+        let options = ProcessOptions::default();
+        let state = |content: &str, options: &ProcessOptions| {
+            ResumeState::new(&src, &mut Cursor::new(content), options).unwrap()
+        };
+
+        let resume = state("G28\nG1 X1\n", &options);
+        assert!(resume.load().is_none());
+        let report = ProcessReport {
+            slicer: Some("Cura".into()),
+            ..Default::default()
+        };
+        resume
+            .store(CacheEntry::new(&report, Edits::default()))
+            .unwrap();
+        assert!(dir.path().join("plate.gcode.resume.json").exists());
+        assert_eq!(
+            state("G28\nG1 X1\n", &options).load().unwrap().slicer,
+            report.slicer
+        );
+
+        // A different input or different options analyse the file again
+        assert!(state("G28\nG1 X2\n", &options).load().is_none());
+        let wkt = ProcessOptions {
+            wkt: true,
+            ..Default::default()
+        };
+        assert!(state("G28\nG1 X1\n", &wkt).load().is_none());
+
+        resume.remove();
+        resume.remove();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
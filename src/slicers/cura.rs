@@ -1,114 +1,273 @@
-use crate::gcode::{exclude_object_end, exclude_object_header, exclude_object_start};
-use crate::hulls::KnownObject;
-use crate::layers::LayerFilter;
-use crate::slicers::{maybe_add_point, CancellationPreProcessor};
-use generator::{done, Gn};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Seek};
-
-pub(crate) struct CuraProcessor {}
+use crate::gcode::{progress_command, starts_with_marker, strip_marker};
+use crate::hulls::{HullOptions, KnownObject, PointBudget};
+use crate::layers::{LayerFilter, LayerTracker};
+use crate::lines::LineReader;
+use crate::motion::Toolhead;
+use crate::orphans::OrphanTracker;
+use crate::preprocess::{PreprocessError, ProcessOptions};
+use crate::slicers::{maybe_add_point, read_line, strip_m486, CancellationPreProcessor, Collected};
+use crate::splice::{Edits, Marker};
+use geo::EuclideanDistance;
+use itertools::Itertools;
+use ordered_float::OrderedFloat;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+
+/// Extrusions of a single `;MESH:` section
+struct MeshSection {
+    mesh: String,
+    line_no: usize,
+    layer: Option<usize>,
+    object: KnownObject,
+}
 
-impl CuraProcessor {
-    pub fn new() -> Self {
-        Self {}
+impl MeshSection {
+    fn new(mesh: &str, line_no: usize, layer: Option<usize>, hull_options: &HullOptions) -> Self {
+        Self {
+            mesh: mesh.to_string(),
+            line_no,
+            layer,
+            // Only the points merged into the instances count against the budget
+            object: KnownObject::new(
+                mesh,
+                &HullOptions {
+                    budget: PointBudget::default(),
+                    ..hull_options.clone()
+                },
+            ),
+        }
     }
 }
 
-impl CancellationPreProcessor for CuraProcessor {
-    fn process<'a>(
-        &'a self,
-        input: impl Read + Seek + Send + 'a,
-        layer_filter: &'a LayerFilter,
-    ) -> generator::Generator<'a, (), String> {
-        let mut input = BufReader::new(input);
-        let mut known_objects: HashMap<String, KnownObject> = HashMap::new();
-        let mut current_object: Option<&mut KnownObject> = None;
-        let mut last_time_elapsed: Option<String> = None;
-
-        for line in input.by_ref().lines() {
-            let line = line.unwrap_or("".to_string());
-            if line.starts_with(";MESH:") {
-                if let Some(object_id) = line.split_once(':').map(|(_, name)| name.trim()) {
-                    if object_id == "NONMESH" {
-                        continue;
-                    }
+/// Splits meshes printed more than once per layer into separate instances.
+///
+/// Cura reuses the mesh name for every copy of a model. Each section is assigned to the
+/// nearest instance of its mesh that has not been printed on the current layer yet.
+#[derive(Default)]
+struct InstanceTracker {
+    /// Keys of the known objects for each instance of a mesh
+    instances: HashMap<String, Vec<String>>,
+    /// Instances already printed on the current layer
+    printed: HashSet<String>,
+    /// Key of the known object by line number of the `;MESH:` comment
+    sections: HashMap<usize, String>,
+}
 
-                    if !known_objects.contains_key(object_id) {
-                        tracing::info!("Found object {}", object_id);
-                        known_objects.insert(object_id.into(), KnownObject::new(object_id));
-                    }
+impl InstanceTracker {
+    fn next_layer(&mut self) {
+        self.printed.clear();
+    }
 
-                    known_objects
-                        .entry(object_id.to_string())
-                        .and_modify(|ko| ko.layer += 1);
-                    current_object = known_objects.get_mut(object_id);
-                }
-            }
+    fn finish(
+        &mut self,
+        section: Option<MeshSection>,
+        known_objects: &mut HashMap<String, KnownObject>,
+        options: &ProcessOptions,
+    ) {
+        let Some(section) = section else {
+            return;
+        };
+
+        let instances = self.instances.entry(section.mesh.clone()).or_default();
+        let center = section.object.hull.center();
+        let nearest = instances
+            .iter()
+            .filter(|key| !self.printed.contains(*key))
+            .min_by_key(|key| {
+                let distance = center
+                    .zip(known_objects.get(*key).and_then(|ko| ko.hull.center()))
+                    .map_or(f64::MAX, |(a, b)| a.euclidean_distance(&b));
+                OrderedFloat(distance)
+            })
+            .cloned();
+
+        let key = nearest.unwrap_or_else(|| {
+            let key = match instances.len() {
+                0 => section.mesh.clone(),
+                count => format!("{}_{}", section.mesh, count + 1),
+            };
+            tracing::info!("Found object {}", key);
+            instances.push(key.clone());
+            let mut known_object = KnownObject::new(&key, &options.hull);
+            known_object.original_name = section.mesh.clone();
+            known_objects.insert(key.clone(), known_object);
+            key
+        });
+
+        if let Some(known_object) = known_objects.get_mut(&key) {
+            known_object.layer += 1;
+            let layer = section.layer.unwrap_or(known_object.layer as usize);
+            let with_points = section.object.extrusion_length > 0.0
+                && known_object.collects_layer(layer, &options.layer_filter);
+            known_object.merge(&section.object, with_points);
+        }
 
-            maybe_add_point(&line, &current_object, layer_filter);
+        self.printed.insert(key.clone());
+        self.sections.insert(section.line_no, key);
+    }
 
-            if line.starts_with(";TIME_ELAPSED:") {
-                last_time_elapsed = Some(line);
+    /// Start the instance of each `;MESH:` section, the last section ends with the print
+    fn place_markers(&self, mesh_lines: &[usize], end_of_print: Option<usize>, edits: &mut Edits) {
+        let mut printing: Option<&String> = None;
+        for line_no in mesh_lines.iter().copied().chain(end_of_print).sorted() {
+            if let Some(key) = printing.take() {
+                edits.after(line_no, Marker::End(key.clone()));
             }
-        }
 
-        input.rewind().unwrap();
-
-        Gn::new_scoped(move |mut s| {
-            let mut current_object: Option<&KnownObject> = None;
+            if Some(line_no) != end_of_print {
+                printing = self.sections.get(&line_no);
+                if let Some(key) = printing {
+                    edits.after(line_no, Marker::Start(key.clone()));
+                }
+            }
+        }
 
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
+        if let Some(key) = printing {
+            edits.at_end(Marker::End(key.clone()));
+        }
+    }
 
-                if !line.trim().is_empty() && !line.starts_with(';') {
-                    s.yield_from(exclude_object_header(&known_objects));
-                }
+    /// Number the first instance of meshes with copies as well
+    fn name_duplicates(&self, known_objects: &mut HashMap<String, KnownObject>) {
+        for (mesh, instances) in &self.instances {
+            if instances.len() < 2 {
+                continue;
+            }
+            if let Some(known_object) = known_objects.get_mut(mesh) {
+                known_object.name = KnownObject::clean_id(&format!("{mesh}_1"), None);
+            }
+        }
+    }
+}
 
-                s.yield_with(format!("{}\n", &line));
+/// M73 progress commands for the `;TIME_ELAPSED:` comments, calculated from the print time in
+/// the `;TIME:` comment of the header. They are only added to files not reporting their progress
+/// already.
+#[derive(Default)]
+struct ProgressTracker {
+    total: Option<f64>,
+    commands: Vec<(usize, String)>,
+    reported: bool,
+}
 
-                if !line.trim().is_empty() && !line.starts_with(';') {
-                    break;
-                }
+impl ProgressTracker {
+    fn track(&mut self, line_no: usize, line: &str, in_body: bool) {
+        if let Some(total) = strip_marker(line, ";TIME:") {
+            self.total = total.trim().parse().ok().filter(|total| *total > 0.0);
+        } else if starts_with_marker(line, "M73") {
+            self.reported = true;
+        } else if let Some(elapsed) = strip_marker(line, ";TIME_ELAPSED:") {
+            let elapsed = elapsed.trim().parse().ok();
+            if let (true, Some(elapsed), Some(total)) = (in_body, elapsed, self.total) {
+                self.commands
+                    .push((line_no, progress_command(elapsed, total)));
             }
+        }
+    }
 
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
+    fn place_commands(self, edits: &mut Edits) {
+        if self.reported {
+            tracing::info!("The file reports its progress already, not adding M73 commands");
+            return;
+        }
+        for (line_no, command) in self.commands {
+            edits.after(line_no, Marker::Text(command));
+        }
+    }
+}
 
-                s.yield_with(format!("{}\n", &line));
+/// The mesh of a `;MESH:` comment without its directories, Cura sometimes writes the full path
+/// of the model file, e.g. `;MESH:C:\Users\me\part.stl`
+fn mesh_name(line: &str) -> Option<&str> {
+    let mesh = strip_marker(line, ";MESH:")?.trim();
+    mesh.rsplit(['/', '\\'])
+        .find(|name| !name.is_empty())
+        .or(Some(mesh))
+}
 
-                if line.starts_with(";MESH:") {
-                    if let Some(ref mut object) = current_object {
-                        s.yield_from(exclude_object_end(&object.name));
-                        current_object = None;
-                    }
+pub(crate) struct CuraProcessor {}
 
-                    if let Some(object_name) = line.split_once(':').map(|(_, name)| name.trim()) {
-                        if object_name == "NONMESH" {
-                            continue;
-                        }
+impl CuraProcessor {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
-                        current_object = known_objects.get(object_name);
-                        if let Some(object) = current_object {
-                            s.yield_from(exclude_object_start(&object.name));
-                        }
-                    }
+impl CancellationPreProcessor for CuraProcessor {
+    fn collect(
+        &self,
+        input: impl BufRead,
+        options: &ProcessOptions,
+    ) -> Result<Collected, PreprocessError> {
+        let mut known_objects: HashMap<String, KnownObject> = HashMap::new();
+        let mut toolhead = Toolhead::from_options(options);
+        let mut orphans = OrphanTracker::new(options);
+        let mut edits = Edits::default();
+        let mut mesh_lines: Vec<usize> = Vec::new();
+        let mut last_time_elapsed: Option<usize> = None;
+        let mut progress = ProgressTracker::default();
+
+        let all_layers = LayerFilter::all();
+        let mut instances = InstanceTracker::default();
+        let mut section: Option<MeshSection> = None;
+        let mut layers = LayerTracker::default();
+
+        let mut lines = LineReader::new(input);
+        while let Some((line_no, line)) = read_line(&mut lines)? {
+            let in_body = edits.track(line_no, line);
+            layers.track(line);
+            strip_m486(line_no, line, options, &mut edits);
+            if starts_with_marker(line, ";LAYER:") {
+                instances.finish(section.take(), &mut known_objects, options);
+                instances.next_layer();
+            }
+
+            if starts_with_marker(line, ";MESH:") {
+                instances.finish(section.take(), &mut known_objects, options);
+                if in_body {
+                    mesh_lines.push(line_no);
                 }
 
-                if let Some(ref last_time_elapsed) = last_time_elapsed {
-                    if &line == last_time_elapsed {
-                        if let Some(object) = current_object {
-                            s.yield_from(exclude_object_end(&object.name));
-                            current_object = None;
-                        }
+                if let Some(mesh) = mesh_name(line) {
+                    if mesh != "NONMESH" {
+                        section = Some(MeshSection::new(
+                            mesh,
+                            line_no,
+                            layers.layer(),
+                            &options.hull,
+                        ));
                     }
                 }
             }
 
-            if let Some(object) = current_object {
-                s.yield_from(exclude_object_end(&object.name));
+            // Layers are filtered when merging the section into its instance
+            let in_object = section.is_some();
+            let toolhead_move = maybe_add_point(
+                line,
+                section.as_mut().map(|section| &mut section.object),
+                None,
+                &all_layers,
+                &mut toolhead,
+            );
+            orphans.track(line_no, line, in_object, &toolhead_move);
+
+            if in_body && starts_with_marker(line, ";TIME_ELAPSED:") {
+                last_time_elapsed = Some(line_no);
             }
+            if options.progress {
+                progress.track(line_no, line, in_body);
+            }
+        }
+
+        instances.finish(section.take(), &mut known_objects, options);
+        instances.name_duplicates(&mut known_objects);
+        instances.place_markers(&mesh_lines, last_time_elapsed, &mut edits);
+        progress.place_commands(&mut edits);
 
-            done!();
+        Ok(Collected {
+            known_objects,
+            orphans,
+            edits,
         })
     }
 }
@@ -116,7 +275,9 @@ impl CancellationPreProcessor for CuraProcessor {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::slicers::tests::collect_definitions;
+    use crate::report::ProcessReport;
+    use crate::slicers::tests::{collect_definitions, process};
+    use crate::synthetic::{Dialect, SyntheticGcode};
     use once_cell::sync::Lazy;
     use std::fs::File;
     use std::path::{Path, PathBuf};
@@ -128,9 +289,9 @@ mod tests {
     fn test_cura() {
         let processor = CuraProcessor::new();
         let input = File::open(GCODE_PATH.join("cura.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
         let definitions = collect_definitions(&result);
 
@@ -201,4 +362,171 @@ mod tests {
             25
         );
     }
+
+    #[test]
+    fn test_point_budget() {
+        let gcode = SyntheticGcode::new(Dialect::Cura)
+            .objects(4)
+            .layers(10)
+            .to_string();
+        let options = ProcessOptions {
+            hull: HullOptions {
+                budget: PointBudget::from_megabytes(64),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Only the points of the objects count, not those of the sections merged into them
+        let collected = CuraProcessor::new()
+            .collect(gcode.as_bytes(), &options)
+            .unwrap();
+        let stored: usize = collected
+            .known_objects
+            .values()
+            .map(|known_object| known_object.hull.stored())
+            .sum();
+        assert!(stored > 0);
+        assert_eq!(options.hull.budget.used(), stored);
+
+        drop(collected);
+        assert_eq!(options.hull.budget.used(), 0);
+    }
+
+    #[test]
+    fn test_mesh_name() {
+        assert_eq!(mesh_name(";MESH:part.stl"), Some("part.stl"));
+        assert_eq!(mesh_name(";MESH: part:1.stl "), Some("part:1.stl"));
+        assert_eq!(mesh_name(";MESH:C:\\Users\\me\\part.stl"), Some("part.stl"));
+        assert_eq!(mesh_name(";MESH:/home/me/part.stl"), Some("part.stl"));
+        assert_eq!(mesh_name(";MESH:NONMESH"), Some("NONMESH"));
+        assert_eq!(mesh_name(";MESH:"), Some(""));
+        assert_eq!(mesh_name(";LAYER:1"), None);
+    }
+
+    #[test]
+    fn test_cura_mesh_paths() {
+        let gcode = ";Generated with Cura_SteamEngine 5.0.0\nM82\n\
+             ;LAYER:0\n;MESH:C:\\Users\\me\\part.stl\nG0 X0 Y0\nG1 X10 Y0 E1\nG1 X10 Y10 E2\n\
+             ;MESH:NONMESH\n\
+             ;LAYER:1\n;MESH:C:\\Users\\me\\part.stl\nG0 X0 Y0\nG1 X10 Y0 E3\nG1 X10 Y10 E4\n\
+             ;MESH:NONMESH\n;TIME_ELAPSED:10.0\n";
+
+        let processor = CuraProcessor::new();
+        let options = ProcessOptions::default();
+        let mut report = ProcessReport::default();
+        let result = process(&processor, gcode.as_bytes(), &options, &mut report);
+        let result: Vec<&str> = result.split('\n').collect();
+
+        assert_eq!(report.object_names(), vec!["part_stl"]);
+        let definitions = collect_definitions(&result);
+        assert!(definitions.contains("EXCLUDE_OBJECT_DEFINE NAME=part_stl"));
+        assert_eq!(
+            result
+                .iter()
+                .filter(|line| **line == "EXCLUDE_OBJECT_START NAME=part_stl")
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_cura_duplicate_meshes() {
+        let square = |x: f64, e: f64| {
+            format!(
+                "G0 X{x} Y0\nG1 X{} Y0 E{}\nG1 X{} Y10 E{}\nG1 X{x} Y10 E{}\n",
+                x + 10.0,
+                e + 1.0,
+                x + 10.0,
+                e + 2.0,
+                e + 3.0
+            )
+        };
+        let gcode = format!(
+            ";Generated with Cura_SteamEngine 5.0.0\nM82\n\
+             ;LAYER:0\n;MESH:model.stl\n{};MESH:model.stl\n{};MESH:NONMESH\n\
+             ;LAYER:1\n;MESH:model.stl\n{};MESH:model.stl\n{};MESH:NONMESH\n\
+             ;TIME_ELAPSED:10.0\n",
+            square(0.0, 0.0),
+            square(50.0, 3.0),
+            square(50.0, 6.0),
+            square(0.0, 9.0),
+        );
+
+        let processor = CuraProcessor::new();
+        let options = ProcessOptions::default();
+        let mut report = ProcessReport::default();
+        let result = process(&processor, gcode.as_bytes(), &options, &mut report);
+        let result: Vec<&str> = result.split('\n').collect();
+
+        let definitions = collect_definitions(&result);
+        assert!(definitions.contains("EXCLUDE_OBJECT_DEFINE NAME=model_stl_1"));
+        assert!(definitions.contains("EXCLUDE_OBJECT_DEFINE NAME=model_stl_2"));
+
+        let centers: HashMap<&str, Option<(f64, f64)>> = report
+            .objects
+            .iter()
+            .map(|o| (o.name.as_str(), o.center))
+            .collect();
+        assert_eq!(centers["model_stl_1"], Some((5.0, 5.0)));
+        assert_eq!(centers["model_stl_2"], Some((55.0, 5.0)));
+
+        // The second layer prints the copies in reverse order
+        let starts: Vec<&str> = result
+            .iter()
+            .filter(|line| line.starts_with("EXCLUDE_OBJECT_START"))
+            .copied()
+            .collect();
+        assert_eq!(
+            starts,
+            vec![
+                "EXCLUDE_OBJECT_START NAME=model_stl_1",
+                "EXCLUDE_OBJECT_START NAME=model_stl_2",
+                "EXCLUDE_OBJECT_START NAME=model_stl_2",
+                "EXCLUDE_OBJECT_START NAME=model_stl_1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cura_progress() {
+        let layer = |layer: usize, elapsed: f64| {
+            format!(";LAYER:{layer}\n;MESH:cube.stl\nG0 X0 Y0\nG1 X10 Y0 E1\nG1 X10 Y10 E1\n;TIME_ELAPSED:{elapsed}\n")
+        };
+        let gcode = format!(
+            ";FLAVOR:Marlin\n;TIME:600\n;Generated with Cura_SteamEngine 5.0.0\nM83\n{}{}",
+            layer(0, 150.0),
+            layer(1, 600.0)
+        );
+
+        let processor = CuraProcessor::new();
+        let options = ProcessOptions {
+            progress: true,
+            ..Default::default()
+        };
+        let result = process(
+            &processor,
+            gcode.as_bytes(),
+            &options,
+            &mut ProcessReport::default(),
+        );
+        assert!(
+            result.contains(";TIME_ELAPSED:150\nM73 P25 R8 ; progress\n"),
+            "{result}"
+        );
+        assert!(
+            result.contains("EXCLUDE_OBJECT_END NAME=cube_stl\nM73 P100 R0 ; progress\n"),
+            "{result}"
+        );
+
+        // Files reporting their progress already are left alone
+        let gcode = gcode.replace("M83\n", "M83\nM73 P0\n");
+        let result = process(
+            &processor,
+            gcode.as_bytes(),
+            &options,
+            &mut ProcessReport::default(),
+        );
+        assert!(!result.contains("; progress"), "{result}");
+    }
 }
@@ -1,10 +1,17 @@
-use crate::gcode::{exclude_object_end, exclude_object_header, exclude_object_start};
+use crate::gcode::{starts_with_marker, strip_marker};
 use crate::hulls::KnownObject;
-use crate::layers::LayerFilter;
-use crate::slicers::{maybe_add_point, CancellationPreProcessor};
-use generator::{done, Gn};
+use crate::layers::LayerTracker;
+use crate::lines::LineReader;
+use crate::motion::Toolhead;
+use crate::orphans::OrphanTracker;
+use crate::preprocess::{PreprocessError, ProcessOptions};
+use crate::slicers::{maybe_add_point, read_line, strip_m486, CancellationPreProcessor, Collected};
+use crate::splice::{Edits, Marker};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Seek};
+use std::io::BufRead;
+
+/// Comments ideaMaker writes after the last extrusion of the print
+const END_MARKERS: [&str; 1] = [";REMAINING_TIME: 0"];
 
 pub(crate) struct IdeaMakerProcessor {}
 
@@ -15,118 +22,120 @@ impl IdeaMakerProcessor {
 }
 
 impl CancellationPreProcessor for IdeaMakerProcessor {
-    fn process<'a>(
-        &'a self,
-        input: impl Read + Seek + Send + 'a,
-        layer_filter: &'a LayerFilter,
-    ) -> generator::Generator<'a, (), String> {
-        let mut input = BufReader::new(input);
+    fn collect(
+        &self,
+        input: impl BufRead,
+        options: &ProcessOptions,
+    ) -> Result<Collected, PreprocessError> {
         let mut known_objects: HashMap<String, KnownObject> = HashMap::new();
         let mut current_object: Option<&mut KnownObject> = None;
+        let mut toolhead = Toolhead::from_options(options);
+        let mut orphans = OrphanTracker::new(options);
+        let mut layers = LayerTracker::default();
+        let mut edits = Edits::default();
 
         let mut object_name: Option<String> = None;
+        let mut printing: Option<String> = None;
+        let end_markers: Vec<&str> = END_MARKERS
+            .into_iter()
+            .chain(options.end_markers.iter().map(|marker| marker.trim()))
+            .collect();
+
+        let mut lines = LineReader::new(input);
+        while let Some((line_no, line)) = read_line(&mut lines)? {
+            let in_body = edits.track(line_no, line);
+            layers.track(line);
+            strip_m486(line_no, line, options, &mut edits);
+
+            if in_body && starts_with_marker(line, ";PRINTING_ID:") {
+                let printing_id = printing_id(line_no, line)?;
+                if let Some(object) = printing.take() {
+                    edits.after(line_no, Marker::End(object));
+                }
+
+                if printing_id != "-1" {
+                    edits.after(line_no, Marker::Start(printing_id.into()));
+                    printing = Some(printing_id.into());
+                }
+            }
+
+            // The end G-Code doesn't belong to the last object printed
+            if in_body && end_markers.contains(&line.trim()) {
+                if let Some(object) = printing.take() {
+                    edits.after(line_no, Marker::End(object));
+                }
+                object_name = None;
+                current_object = None;
+            }
 
-        for line in input.by_ref().lines() {
-            let line = line.unwrap_or("".to_string());
-            if line.starts_with(";PRINTING:") {
-                object_name = line.split_once(':').map(|(_, name)| name.trim().into());
+            if let Some(name) = strip_marker(line, ";PRINTING:") {
+                object_name = Some(name.trim().into());
                 continue;
             }
 
             if let Some(name) = &object_name {
-                if line.starts_with(";PRINTING_ID:") {
-                    if let Some(object_id) =
-                        line.split_once(':').map(|(_, object_id)| object_id.trim())
-                    {
-                        if object_id == "-1" {
-                            continue;
-                        }
-
-                        if !known_objects.contains_key(object_id) {
-                            tracing::info!("Found object {}", object_id);
-                            known_objects.insert(object_id.into(), KnownObject::new(name));
-                            object_name = None;
-                        }
+                if let Some(object_id) = strip_marker(line, ";PRINTING_ID:").map(str::trim) {
+                    if object_id == "-1" {
+                        continue;
+                    }
 
+                    if !known_objects.contains_key(object_id) {
+                        tracing::info!("Found object {}", object_id);
                         known_objects
-                            .entry(object_id.to_string())
-                            .and_modify(|ko| ko.layer += 1);
-                        current_object = known_objects.get_mut(object_id);
+                            .insert(object_id.into(), KnownObject::new(name, &options.hull));
+                        object_name = None;
                     }
+
+                    known_objects
+                        .entry(object_id.to_string())
+                        .and_modify(|ko| ko.layer += 1);
+                    current_object = known_objects.get_mut(object_id);
                 } else {
                     object_name = None
                 }
             }
 
-            maybe_add_point(&line, &current_object, layer_filter);
+            let in_object = current_object.is_some();
+            let toolhead_move = maybe_add_point(
+                line,
+                current_object.as_deref_mut(),
+                layers.layer(),
+                &options.layer_filter,
+                &mut toolhead,
+            );
+            orphans.track(line_no, line, in_object, &toolhead_move);
         }
 
-        input.rewind().unwrap();
-
-        Gn::new_scoped(move |mut s| {
-            let mut current_object: Option<&KnownObject> = None;
-
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
-
-                if !line.trim().is_empty() && !line.starts_with(';') {
-                    s.yield_from(exclude_object_header(&known_objects));
-                }
-
-                s.yield_with(format!("{}\n", &line));
-
-                if !line.trim().is_empty() && !line.starts_with(';') {
-                    break;
-                }
-            }
-
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
-
-                s.yield_with(format!("{}\n", &line));
-
-                if line.starts_with(";PRINTING_ID:") {
-                    match line.split_once(':').map(|(_, name)| name.trim()) {
-                        None => panic!("Could not determine currently printing object"),
-                        Some(printing_id) => {
-                            if let Some(object) = current_object {
-                                s.yield_from(exclude_object_end(&object.name));
-                                current_object = None
-                            }
-
-                            if printing_id == "-1" {
-                                continue;
-                            }
-
-                            current_object = known_objects.get(printing_id);
-                            if let Some(current_object) = current_object {
-                                s.yield_from(exclude_object_start(&current_object.name));
-                            }
-                        }
-                    }
-                }
-
-                if line == ";REMAINING_TIME: 0\n" {
-                    if let Some(object) = current_object {
-                        s.yield_from(exclude_object_end(&object.name));
-                        current_object = None;
-                    }
-                }
-            }
-
-            if let Some(current_object) = current_object {
-                s.yield_from(exclude_object_end(&current_object.name));
-            }
+        if let Some(object) = printing {
+            edits.at_end(Marker::End(object));
+        }
 
-            done!();
+        Ok(Collected {
+            known_objects,
+            orphans,
+            edits,
         })
     }
 }
 
+/// The id of the object printed next, ideaMaker numbers objects from 0 and uses -1 in between
+fn printing_id(line_no: usize, line: &str) -> Result<&str, PreprocessError> {
+    line.split_once(':')
+        .map(|(_, printing_id)| printing_id.trim())
+        .filter(|printing_id| printing_id.parse::<i64>().is_ok())
+        .ok_or_else(|| {
+            PreprocessError::MalformedLine(
+                line_no + 1,
+                format!("Could not determine the printing object from {line:?}"),
+            )
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::slicers::tests::collect_definitions;
+    use crate::report::ProcessReport;
+    use crate::slicers::tests::{collect_definitions, process};
     use once_cell::sync::Lazy;
     use std::fs::File;
     use std::path::{Path, PathBuf};
@@ -138,9 +147,9 @@ mod tests {
     fn test_ideamaker() {
         let processor = IdeaMakerProcessor::new();
         let input = File::open(GCODE_PATH.join("ideamaker.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
 
         let definitions = collect_definitions(&result);
@@ -212,4 +221,71 @@ mod tests {
             33
         );
     }
+
+    #[test]
+    fn test_malformed_printing_id() {
+        let processor = IdeaMakerProcessor::new();
+        let gcode = ";Sliced by ideaMaker 4.2.0\nG28\n;PRINTING: cube.3mf\n;PRINTING_ID: 0\n\
+            G1 X1 Y1 E1\n;PRINTING: NON-OBJECT\n;PRINTING_ID:\nG1 X2 Y2\n";
+
+        let err = processor
+            .collect(gcode.as_bytes(), &ProcessOptions::default())
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, PreprocessError::MalformedLine(7, _)),
+            "{err:?}"
+        );
+        assert!(err.to_string().starts_with("Line 7: "));
+
+        assert_eq!(printing_id(0, ";PRINTING_ID: -1").unwrap(), "-1");
+        assert!(printing_id(0, ";PRINTING_ID: cube").is_err());
+    }
+
+    #[test]
+    fn test_end_of_print() {
+        let processor = IdeaMakerProcessor::new();
+        let path = GCODE_PATH
+            .join("regressions")
+            .join("ideamaker_end_of_print.gcode");
+        let options = ProcessOptions::default();
+
+        let result: String = process(
+            &processor,
+            File::open(&path).unwrap(),
+            &options,
+            &mut ProcessReport::default(),
+        );
+
+        // The last object ends with the print, not with the file
+        let end = result.find(";REMAINING_TIME: 0\nEXCLUDE_OBJECT_END NAME=cube_b_3mf\n");
+        assert!(end.is_some(), "{result}");
+        assert_eq!(
+            result.matches("EXCLUDE_OBJECT_END NAME=cube_b_3mf").count(),
+            1
+        );
+
+        // The purge line of the end G-Code is not part of the last object
+        let definition = result
+            .lines()
+            .find(|line| line.starts_with("EXCLUDE_OBJECT_DEFINE NAME=cube_b_3mf"))
+            .unwrap();
+        assert!(!definition.contains("200.0"), "{definition}");
+
+        // Additional end markers
+        let gcode = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace(";REMAINING_TIME: 0", ";END_OF_PRINT");
+        let options = ProcessOptions {
+            end_markers: vec![";END_OF_PRINT".into()],
+            ..Default::default()
+        };
+        let result: String = process(
+            &processor,
+            gcode.as_bytes(),
+            &options,
+            &mut ProcessReport::default(),
+        );
+        assert!(result.contains(";END_OF_PRINT\nEXCLUDE_OBJECT_END NAME=cube_b_3mf\n"));
+    }
 }
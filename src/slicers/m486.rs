@@ -1,12 +1,14 @@
-use crate::gcode::{
-    exclude_object_end, exclude_object_header, exclude_object_start, parse_gcode, Command,
-};
+use crate::gcode::{parse_gcode, starts_with_marker, Command};
 use crate::hulls::KnownObject;
-use crate::layers::LayerFilter;
-use crate::slicers::{maybe_add_point, CancellationPreProcessor};
-use generator::{done, Gn};
+use crate::layers::LayerTracker;
+use crate::lines::LineReader;
+use crate::motion::Toolhead;
+use crate::orphans::OrphanTracker;
+use crate::preprocess::{PreprocessError, ProcessOptions};
+use crate::slicers::{maybe_add_point, read_line, CancellationPreProcessor, Collected};
+use crate::splice::{Edits, Marker};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Seek};
+use std::io::BufRead;
 
 pub(crate) struct M486Processor {}
 
@@ -17,29 +19,56 @@ impl M486Processor {
 }
 
 impl CancellationPreProcessor for M486Processor {
-    fn process<'a>(
-        &'a self,
-        input: impl Read + Seek + Send + 'a,
-        layer_filter: &'a LayerFilter,
-    ) -> generator::Generator<'a, (), String> {
-        let mut input = BufReader::new(input);
+    fn collect(
+        &self,
+        input: impl BufRead,
+        options: &ProcessOptions,
+    ) -> Result<Collected, PreprocessError> {
         let mut known_objects: HashMap<String, KnownObject> = HashMap::new();
         let mut current_object: Option<String> = None;
+        let mut toolhead = Toolhead::from_options(options);
+        let mut orphans = OrphanTracker::new(options);
+        let mut layers = LayerTracker::default();
+        let mut edits = Edits::default();
 
-        for line in input.by_ref().lines() {
-            let line = line.unwrap_or("".to_string());
-            if line.starts_with("M486") {
-                let Command { params, .. } = parse_gcode(&line);
-                if let Some(object_id) = params.get("T") {
+        let mut printing: Option<String> = None;
+
+        let mut lines = LineReader::new(input);
+        while let Some((line_no, line)) = read_line(&mut lines)? {
+            let in_body = edits.track(line_no, line);
+            layers.track(line);
+
+            let m486 = starts_with_marker(line, "M486");
+            if in_body && m486 {
+                let Command { params, .. } = parse_gcode(line);
+
+                if let Some(object_id) = param(&params, "S") {
+                    if let Some(object) = printing.take() {
+                        edits.after(line_no, Marker::End(object));
+                    }
+
+                    if object_id != "-1" {
+                        edits.after(line_no, Marker::Start(object_id.to_string()));
+                        printing = Some(object_id.to_string());
+                    }
+                }
+
+                // Comment out the original M486 lines
+                edits.replace(line_no, Marker::Text(format!("; {line}\n")));
+            }
+
+            if m486 {
+                let Command { params, .. } = parse_gcode(line);
+                if let Some(object_id) = param(&params, "T") {
                     if let Ok(end) = object_id.parse::<isize>() {
                         for i in -1..end {
                             tracing::info!("Found object {}", i);
                             known_objects
                                 .entry(format!("{i}"))
-                                .or_insert(KnownObject::new(&format!("{i}")));
+                                .or_insert(KnownObject::new(&format!("{i}"), &options.hull));
                         }
                     }
-                } else if let Some(object_id) = params.get("S") {
+                } else if let Some(object_id) = param(&params, "S") {
                     known_objects
                         .entry(object_id.to_string())
                         .and_modify(|ko| ko.layer += 1);
@@ -48,73 +77,45 @@ impl CancellationPreProcessor for M486Processor {
                 }
             }
 
-            if let Some(current_object_name) = &current_object {
-                let current_object = known_objects.get_mut(current_object_name);
-                maybe_add_point(&line, &current_object, layer_filter);
-            }
+            let in_object = current_object.as_ref().is_some_and(|name| name != "-1");
+            let known_object = current_object
+                .as_ref()
+                .and_then(|name| known_objects.get_mut(name));
+            let toolhead_move = maybe_add_point(
+                line,
+                known_object,
+                layers.layer(),
+                &options.layer_filter,
+                &mut toolhead,
+            );
+            orphans.track(line_no, line, in_object, &toolhead_move);
         }
 
-        input.rewind().unwrap();
-
-        Gn::new_scoped(move |mut s| {
-            let mut current_object: Option<&KnownObject> = None;
-
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
-
-                if !line.trim().is_empty() && !line.starts_with(';') {
-                    let objects: HashMap<String, KnownObject> = known_objects
-                        .iter()
-                        .filter(|(name, _)| *name != "-1")
-                        .map(|(name, o)| (name.to_owned(), o.to_owned()))
-                        .collect();
-                    s.yield_from(exclude_object_header(&objects));
-                }
-
-                s.yield_with(format!("{}\n", &line));
-
-                if line.trim().is_empty() && !line.starts_with(';') {
-                    break;
-                }
-            }
-
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
-
-                s.yield_with(format!("{}\n", &line));
-
-                if line.to_uppercase().starts_with("M486") {
-                    let Command { params, .. } = parse_gcode(&line);
-
-                    if let Some(object_id) = params.get("S") {
-                        if let Some(obj) = &current_object {
-                            s.yield_from(exclude_object_end(&obj.name));
-                            current_object = None
-                        }
-
-                        if *object_id != "-1" {
-                            current_object = known_objects.get(*object_id);
-                            if let Some(known_object) = current_object {
-                                s.yield_from(exclude_object_start(&known_object.name));
-                            }
-                        }
-                    }
+        // Extrusions outside of any object are tracked as object -1, which is never defined
+        known_objects.remove("-1");
 
-                    s.yield_with("; ".to_string()) // Comment out the original M486 lines
-                }
-
-                s.yield_with(format!("{}\n", &line));
-            }
-
-            done!();
+        Ok(Collected {
+            known_objects,
+            orphans,
+            edits,
         })
     }
 }
 
+/// A parameter of a command, which may be written in lower case
+fn param<'a>(params: &HashMap<&str, &'a str>, key: &str) -> Option<&'a str> {
+    params
+        .get(key)
+        .or_else(|| params.get(key.to_lowercase().as_str()))
+        .copied()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::slicers::tests::collect_definitions;
+    use crate::report::ProcessReport;
+    use crate::slicers::tests::{collect_definitions, process};
+    use itertools::Itertools;
     use once_cell::sync::Lazy;
     use std::fs::File;
     use std::path::{Path, PathBuf};
@@ -126,9 +127,9 @@ mod tests {
     fn test_m486() {
         let processor = M486Processor::new();
         let input = File::open(GCODE_PATH.join("m486.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
 
         let definitions = collect_definitions(&result);
@@ -200,4 +201,73 @@ mod tests {
             25
         );
     }
+
+    #[test]
+    fn test_m486_mixed_case() {
+        let processor = M486Processor::new();
+        let gcode = std::fs::read_to_string(GCODE_PATH.join("m486.gcode")).unwrap();
+        let options = ProcessOptions::default();
+        let expected = process(
+            &processor,
+            gcode.as_bytes(),
+            &options,
+            &mut ProcessReport::default(),
+        );
+
+        let mixed = gcode
+            .replace("M486 S", "  m486 s")
+            .replace("M486 T", "m486 t");
+        let result = process(
+            &processor,
+            mixed.as_bytes(),
+            &options,
+            &mut ProcessReport::default(),
+        );
+
+        let markers = |output: &str| -> Vec<String> {
+            output
+                .lines()
+                .filter(|line| line.starts_with("EXCLUDE_OBJECT_"))
+                .map(str::to_string)
+                .sorted()
+                .collect()
+        };
+        assert_eq!(markers(&result), markers(&expected));
+        assert!(result.contains("\n;   m486 s0\nEXCLUDE_OBJECT_START NAME=0\n"));
+    }
+
+    #[test]
+    fn test_m486_commented_once() {
+        let processor = M486Processor::new();
+        let original = std::fs::read_to_string(GCODE_PATH.join("m486.gcode")).unwrap();
+        let input = File::open(GCODE_PATH.join("m486.gcode")).unwrap();
+        let options = ProcessOptions::default();
+
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
+        let written: Vec<&str> = result
+            .lines()
+            .filter(|line| !line.is_empty())
+            .filter(|line| !line.starts_with("EXCLUDE_OBJECT_"))
+            .filter(|line| !line.starts_with("; Pre-Processed for Cancel-Object support"))
+            .filter(|line| !line.ends_with(" known objects"))
+            .collect();
+
+        // Every input line is written exactly once, M486 lines in the body commented out
+        let lines: Vec<&str> = original.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(written.len(), lines.len());
+
+        let commands = lines
+            .iter()
+            .filter(|line| line.starts_with("M486 S"))
+            .count();
+        assert!(commands > 0);
+        assert_eq!(
+            written
+                .iter()
+                .filter(|line| line.starts_with("; M486 S"))
+                .count(),
+            commands
+        );
+        assert!(!written.iter().any(|line| line.starts_with("M486 S")));
+    }
 }
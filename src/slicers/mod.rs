@@ -1,13 +1,26 @@
-use std::io::{Read, Seek};
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
 
 pub(crate) mod cura;
 pub(crate) mod ideamaker;
 pub(crate) mod m486;
 pub(crate) mod slic3r;
+pub(crate) mod vase;
 
-use crate::gcode::{parse_gcode, Command};
-use crate::hulls::KnownObject;
+use crate::gcode::starts_with_marker;
+use crate::groups::assign_groups;
+use crate::hulls::{compute_outlines, KnownObject};
+use crate::idex::add_copies;
 use crate::layers::LayerFilter;
+use crate::lines::LineReader;
+use crate::motion::{Move, Toolhead};
+use crate::orphans::{is_wipe_tower, OrphanTracker, WipeTowerMode, WIPE_TOWER_NAME};
+use crate::preprocess::{PreprocessError, ProcessOptions};
+use crate::report::{ProcessReport, Warning};
+use crate::splice::{Edits, Marker};
 use cura::CuraProcessor as Cura;
 use ideamaker::IdeaMakerProcessor as IdeaMaker;
 use m486::M486Processor as M486;
@@ -23,68 +36,382 @@ pub(crate) enum PreProcessorImpl {
 
 #[enum_dispatch::enum_dispatch(PreProcessorImpl)]
 pub(crate) trait CancellationPreProcessor {
-    fn process<'a>(
-        &'a self,
-        input: impl Read + Seek + Send + 'a,
-        layer_filter: &'a LayerFilter,
-    ) -> generator::Generator<'a, (), String>;
-}
-
-pub(crate) fn identify_slicer_marker(line: &str) -> Option<PreProcessorImpl> {
-    let line = line.trim();
-    if line.starts_with("; generated by SuperSlicer") {
-        tracing::info!("Identified slicer: SuperSlicer");
-        Some(Slic3r::new().into())
-    } else if line.starts_with("; generated by PrusaSlicer") {
-        tracing::info!("Identified slicer: PrusaSlicer");
-        Some(Slic3r::new().into())
-    } else if line.starts_with("; generated by OrcaSlicer") {
-        tracing::info!("Identified slicer: OrcaSlicer");
-        Some(Slic3r::new().into())
-    } else if line.starts_with("; generated by Slic3r") {
-        tracing::info!("Identified slicer: Slic3r");
-        Some(Slic3r::new().into())
-    } else if line.starts_with(";Generated with Cura_SteamEngine") {
-        tracing::info!("Identified slicer: Cura");
-        Some(Cura::new().into())
-    } else if line.starts_with(";Sliced by ideaMaker") {
-        tracing::info!("Identified slicer: ideaMaker");
-        Some(IdeaMaker::new().into())
-    } else if line.starts_with("M486") {
-        tracing::info!("Identified slicer: M486");
-        Some(M486::new().into())
-    } else {
-        None
+    /// Find the objects and where their markers go in a single pass over the input
+    fn collect(
+        &self,
+        input: impl BufRead,
+        options: &ProcessOptions,
+    ) -> Result<Collected, PreprocessError>;
+}
+
+/// Objects and markers found in a G-Code file
+pub(crate) struct Collected {
+    pub known_objects: HashMap<String, KnownObject>,
+    pub orphans: OrphanTracker,
+    pub edits: Edits,
+}
+
+impl Collected {
+    /// Drop filtered objects, assign orphaned extrusions and resolve the markers to object names
+    pub fn finish(self, options: &ProcessOptions, report: &mut ProcessReport) -> Rewrite {
+        let Self {
+            mut known_objects,
+            orphans,
+            mut edits,
+        } = self;
+
+        compute_outlines(known_objects.values());
+        filter_objects(&mut known_objects, &orphans, options);
+        let copies = options
+            .idex
+            .map(|placement| add_copies(&mut known_objects, &placement))
+            .unwrap_or_default();
+        assign_groups(&mut known_objects, &options.groups, options.group_prefix);
+        report
+            .warnings
+            .extend(disambiguate_names(&mut known_objects));
+        limit_names(&mut known_objects, options.max_name_length);
+        report.add_objects(known_objects.values());
+        log_cancelled(&known_objects, &options.cancel_extruders);
+        let degenerate = known_objects
+            .values()
+            .filter(|ko| ko.hull.outline().1)
+            .map(|ko| ko.name.clone())
+            .sorted();
+        report
+            .warnings
+            .extend(degenerate.map(|object| Warning::DegenerateHull { object }));
+        edits.resolve(&known_objects);
+        // Orphaned extrusions belong to the objects, not to their second prints
+        let objects = known_objects
+            .iter()
+            .filter(|(key, _)| !copies.contains(*key))
+            .map(|(_, known_object)| known_object);
+        edits.extend(orphans.assign(objects).into_markers());
+
+        Rewrite {
+            known_objects,
+            edits,
+        }
+    }
+}
+
+/// The final objects and markers, ready to be written
+pub(crate) struct Rewrite {
+    known_objects: HashMap<String, KnownObject>,
+    edits: Edits,
+}
+
+impl Rewrite {
+    /// Copy the input to the output with all object definitions and markers added
+    pub fn write(
+        &self,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+        options: &ProcessOptions,
+    ) -> io::Result<usize> {
+        self.edits
+            .write(input, output, &self.known_objects, options)
+    }
+
+    /// The markers with the object definitions rendered, for storing in the cache
+    pub fn rendered_edits(&self, options: &ProcessOptions) -> Edits {
+        let mut edits = self.edits.clone();
+        edits.render_header(&self.known_objects, options);
+        edits
+    }
+}
+
+/// Log the objects excluded for the cancelled tools and those still printed with them
+fn log_cancelled(known_objects: &HashMap<String, KnownObject>, tools: &[u32]) {
+    if tools.is_empty() {
+        return;
+    }
+
+    for known_object in known_objects.values().sorted_by(|a, b| a.name.cmp(&b.name)) {
+        if known_object.printed_only_with(tools) {
+            tracing::info!(
+                "Excluding {}, printed only with cancelled tools",
+                known_object.name
+            );
+        } else if let Some(tool) = known_object
+            .extruders
+            .iter()
+            .find(|tool| tools.contains(tool))
+        {
+            tracing::warn!(
+                "{} is also printed with other tools and isn't excluded, it still uses T{}",
+                known_object.name,
+                tool
+            );
+        }
+    }
+}
+
+/// Identify the slicer from a marker line, returning its name and the matching processor
+pub(crate) fn identify_slicer_marker(line: &str) -> Option<(&'static str, PreProcessorImpl)> {
+    let (slicer, processor): (&'static str, PreProcessorImpl) =
+        if starts_with_marker(line, "; generated by SuperSlicer") {
+            ("SuperSlicer", Slic3r::new().into())
+        } else if starts_with_marker(line, "; generated by PrusaSlicer") {
+            ("PrusaSlicer", Slic3r::new().into())
+        } else if starts_with_marker(line, "; generated by OrcaSlicer") {
+            ("OrcaSlicer", Slic3r::new().into())
+        } else if starts_with_marker(line, "; generated by Slic3r") {
+            ("Slic3r", Slic3r::new().into())
+        } else if starts_with_marker(line, ";Generated with Cura_SteamEngine") {
+            ("Cura", Cura::new().into())
+        } else if starts_with_marker(line, ";Sliced by ideaMaker") {
+            ("ideaMaker", IdeaMaker::new().into())
+        } else if starts_with_marker(line, "M486") {
+            ("M486", M486::new().into())
+        } else {
+            return None;
+        };
+
+    Some((slicer, processor))
+}
+
+/// Comment out M486 commands in files marking their objects with comments as well, so objects
+/// aren't started twice when the printer translates M486 to exclude object commands
+pub(crate) fn strip_m486(line_no: usize, line: &str, options: &ProcessOptions, edits: &mut Edits) {
+    if options.strip_m486 && starts_with_marker(line, "M486") {
+        edits.replace(line_no, Marker::Text(format!("; {line}\n")));
+    }
+}
+
+/// Object markers of each processor, in order of preference
+const OBJECT_MARKERS: [(&str, &str); 4] = [
+    ("; printing object ", "Slic3r"),
+    (";MESH:", "Cura"),
+    (";PRINTING_ID:", "ideaMaker"),
+    ("M486 S", "M486"),
+];
+
+/// Counts the object markers in the body of a file.
+///
+/// Other post-processors like ArcWelder or Klipper Estimator add their own comments to the top of
+/// the file and sometimes drop the banner of the slicer. The processor is then chosen by the
+/// object markers found most often.
+#[derive(Debug, Default)]
+pub(crate) struct ObjectMarkers {
+    counts: [usize; OBJECT_MARKERS.len()],
+}
+
+impl ObjectMarkers {
+    pub fn track(&mut self, line: &str) {
+        // PrusaSlicer also emits M486 between objects, which would outnumber its comments
+        if starts_with_marker(line, "M486 S-1") {
+            return;
+        }
+        if let Some(index) = OBJECT_MARKERS
+            .iter()
+            .position(|(marker, _)| starts_with_marker(line, marker))
+        {
+            self.counts[index] += 1;
+        }
+    }
+
+    /// The processor for the most frequent markers, the earlier one on ties
+    pub fn processor(&self) -> Option<(&'static str, PreProcessorImpl)> {
+        let (index, count) = self
+            .counts
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, count)| **count)?;
+        if *count == 0 {
+            return None;
+        }
+
+        let slicer = OBJECT_MARKERS[index].1;
+        tracing::info!("Identified slicer by {} object markers: {}", count, slicer);
+        let processor: PreProcessorImpl = match index {
+            0 => Slic3r::new().into(),
+            1 => Cura::new().into(),
+            2 => IdeaMaker::new().into(),
+            _ => M486::new().into(),
+        };
+        Some((slicer, processor))
+    }
+}
+
+static SLIC3R_WIDTH_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"^; external perimeters extrusion width = ([0-9.]+)mm"#).unwrap());
+static CURA_WIDTH_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"^;SETTING_3 .*\\nline_width = ([0-9.]+)"#).unwrap());
+
+/// Detect the extrusion width of the outer walls from slicer settings comments
+pub(crate) fn detect_line_width(line: &str) -> Option<f64> {
+    if !line.starts_with(';') {
+        return None;
+    }
+
+    [&SLIC3R_WIDTH_RE, &CURA_WIDTH_RE]
+        .iter()
+        .find_map(|re| re.captures(line))
+        .and_then(|captures| captures.get(1))
+        .and_then(|width| width.as_str().parse::<f64>().ok())
+        .filter(|width| *width > 0.0)
+}
+
+/// Apply the wipe tower handling and drop tiny objects like purge lines from the objects found in a file
+pub(crate) fn filter_objects(
+    known_objects: &mut HashMap<String, KnownObject>,
+    orphans: &OrphanTracker,
+    options: &ProcessOptions,
+) {
+    // Instances skipped on the plate keep their labels but never extrude, they would show up
+    // as objects without an outline
+    known_objects.retain(|_, ko| {
+        let printed = ko.extrusion_length > 0.0;
+        if !printed {
+            tracing::info!("Ignoring object {}, it is never printed", ko.name);
+        }
+        printed
+    });
+
+    known_objects.retain(|_, ko| {
+        let too_small = options.min_object_area.is_some_and(|area| ko.area() < area)
+            || options
+                .min_extrusion_length
+                .is_some_and(|length| ko.extrusion_length < length);
+        if too_small {
+            tracing::info!("Ignoring tiny object {}", ko.name);
+        }
+        !too_small
+    });
+
+    match options.wipe_tower {
+        WipeTowerMode::Keep => {}
+        WipeTowerMode::Exclude => known_objects.retain(|_, ko| !is_wipe_tower(&ko.name)),
+        WipeTowerMode::Object => {
+            if known_objects.values().any(|ko| is_wipe_tower(&ko.name)) {
+                return;
+            }
+            if let Some(wipe_tower) = orphans.wipe_tower(&options.hull) {
+                tracing::info!("Found unlabeled wipe tower");
+                known_objects.insert(WIPE_TOWER_NAME.to_string(), wipe_tower);
+            }
+        }
+    }
+}
+
+/// Shorten names longer than the maximum length, after numbering duplicates so they stay unique
+pub(crate) fn limit_names(
+    known_objects: &mut HashMap<String, KnownObject>,
+    max_length: Option<usize>,
+) {
+    if max_length.is_none() {
+        return;
+    }
+    for known_object in known_objects.values_mut() {
+        known_object.name = KnownObject::clean_id(&known_object.name, max_length);
     }
 }
 
+/// Number objects whose names are the same once sanitized, like `Würfel` and `Wurfel`, so they
+/// aren't merged by Klipper. The first original name in sorted order keeps the name, a warning
+/// is returned for each renamed object.
+pub(crate) fn disambiguate_names(known_objects: &mut HashMap<String, KnownObject>) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut taken: HashSet<String> = known_objects.values().map(|ko| ko.name.clone()).collect();
+    let groups = known_objects
+        .iter()
+        .map(|(key, ko)| (ko.name.clone(), key.clone()))
+        .into_group_map();
+
+    for (name, mut keys) in groups.into_iter().sorted() {
+        if keys.len() < 2 {
+            continue;
+        }
+        keys.sort();
+        let mut number = 1;
+        for key in &keys[1..] {
+            let unique = loop {
+                number += 1;
+                let candidate = format!("{name}_{number}");
+                if taken.insert(candidate.clone()) {
+                    break candidate;
+                }
+            };
+            tracing::warn!(
+                "Objects {} and {} are both named {}, renaming {} to {}",
+                keys[0],
+                key,
+                name,
+                key,
+                unique
+            );
+            warnings.push(Warning::NameCollision {
+                object: key.clone(),
+                name: name.clone(),
+                renamed: unique.clone(),
+            });
+            if let Some(known_object) = known_objects.get_mut(key) {
+                known_object.name = unique;
+            }
+        }
+    }
+
+    warnings
+}
+
+/// The next line of the input. Read errors, e.g. invalid UTF-8, abort collecting the objects
+/// instead of ending the file early, which would write a truncated file.
+pub(crate) fn read_line<R: BufRead>(
+    lines: &mut LineReader<R>,
+) -> Result<Option<(usize, &str)>, PreprocessError> {
+    let line_no = lines.line_no();
+    lines
+        .next_line()
+        .map_err(|err| PreprocessError::ReadLineError(line_no + 1, err.to_string()))
+}
+
 pub(crate) fn maybe_add_point(
     line: &str,
-    known_object: &Option<&mut KnownObject>,
+    known_object: Option<&mut KnownObject>,
+    layer: Option<usize>,
     layer_filter: &LayerFilter,
-) {
+    toolhead: &mut Toolhead,
+) -> Move {
+    // Only moves, tool changes and the extrusion mode commands (M82/M83) affect the toolhead
+    if !matches!(
+        line.trim_start().chars().next(),
+        Some('G' | 'g' | 'M' | 'm' | 'T' | 't')
+    ) {
+        return Move::default();
+    }
+
+    // The toolhead state needs to be tracked for all moves, e.g. arcs start at the previous position
+    let toolhead_move = toolhead.apply_line(line);
+
     if let Some(current_object) = known_object {
-        if layer_filter.contains(current_object.layer as usize)
-            && line.trim().to_lowercase().starts_with('g')
-        {
-            let Command { params, .. } = parse_gcode(line);
-            if let Some(_extrude) = params.get("E").and_then(|v| v.parse::<f64>().ok()) {
-                let x = params.get("X").and_then(|v| v.parse::<f64>().ok());
-                let y = params.get("Y").and_then(|v| v.parse::<f64>().ok());
-                if let (Some(x), Some(y)) = (x, y) {
-                    current_object.hull.add_point(x, y);
-                }
+        if toolhead_move.extruding {
+            current_object.extrusion_length += toolhead_move.length;
+            current_object.extruders.insert(toolhead.tool());
+            if let Some(z) = toolhead.z() {
+                current_object.add_z(z);
             }
         }
+
+        // Prefer the global layer from the slicer comments, objects skipped on a layer would
+        // otherwise shift their own count
+        let layer = layer.unwrap_or(current_object.layer as usize);
+        if toolhead_move.extruding && current_object.collects_layer(layer, layer_filter) {
+            current_object.hull.add_move(&toolhead_move.points);
+        }
     }
+
+    toolhead_move
 }
 
 #[cfg(test)]
 mod tests {
-    use clap::__derive_refs::once_cell;
-    use once_cell::sync::Lazy;
-    use regex::Regex;
-    use std::collections::HashSet;
+    use super::*;
+    use crate::synthetic::{Dialect, SyntheticGcode};
+    use std::collections::{BTreeSet, HashSet};
+    use std::io::Read;
 
     static DEFINITION_RE: Lazy<Regex> =
         Lazy::new(|| Regex::new(r#"^(EXCLUDE_OBJECT_DEFINE).*(NAME=\S+).*$"#).unwrap());
@@ -100,4 +427,287 @@ mod tests {
 
         definitions
     }
+
+    /// Run a processor over a whole file, returning the rewritten G-Code
+    pub(crate) fn process(
+        processor: &impl CancellationPreProcessor,
+        mut input: impl Read,
+        options: &ProcessOptions,
+        report: &mut ProcessReport,
+    ) -> String {
+        let mut gcode = Vec::new();
+        input.read_to_end(&mut gcode).unwrap();
+
+        let rewrite = processor
+            .collect(gcode.as_slice(), options)
+            .unwrap()
+            .finish(options, report);
+        let mut output = Vec::new();
+        rewrite
+            .write(&mut gcode.as_slice(), &mut output, options)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_tool_changes() {
+        let options = ProcessOptions::default();
+        let mut known_object = KnownObject::new("cube", &options.hull);
+        let mut toolhead = Toolhead::default();
+        for line in [
+            "M83",
+            "G1 X0 Y0",
+            "t1",
+            "G1 X10 Y0 E1",
+            "T2",
+            "G1 X10 Y10 E1",
+        ] {
+            maybe_add_point(
+                line,
+                Some(&mut known_object),
+                None,
+                &LayerFilter::all(),
+                &mut toolhead,
+            );
+        }
+        assert_eq!(known_object.extruders, BTreeSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_filter_small_objects() {
+        let options = ProcessOptions {
+            min_object_area: Some(10.0),
+            min_extrusion_length: Some(5.0),
+            ..Default::default()
+        };
+
+        let mut known_objects = HashMap::new();
+        for (name, size, length) in [
+            ("large", 10.0, 40.0),
+            ("purge", 1.0, 40.0),
+            ("dot", 10.0, 1.0),
+        ] {
+            let mut known_object = KnownObject::new(name, &options.hull);
+            for (x, y) in [(0.0, 0.0), (size, 0.0), (size, size), (0.0, size)] {
+                known_object.hull.add_point(x, y);
+            }
+            known_object.extrusion_length = length;
+            known_objects.insert(name.to_string(), known_object);
+        }
+
+        filter_objects(&mut known_objects, &OrphanTracker::default(), &options);
+        assert_eq!(known_objects.keys().collect::<Vec<_>>(), vec!["large"]);
+    }
+
+    #[test]
+    fn test_filter_unprinted_objects() {
+        let gcode = "; generated by OrcaSlicer 2.0.0\nG28\nM83\n;LAYER_CHANGE\n\
+            ; printing object cube id:0 copy 0\nG1 X0 Y0\nG1 X10 Y0 E1\nG1 X10 Y10 E1\n\
+            ; stop printing object cube id:0 copy 0\n\
+            ; printing object skipped id:1 copy 0\nG1 X20 Y20\n\
+            ; stop printing object skipped id:1 copy 0\n";
+        let options = ProcessOptions::default();
+        let mut report = ProcessReport::default();
+        let output = process(&Slic3r::new(), gcode.as_bytes(), &options, &mut report);
+
+        assert_eq!(report.object_names(), ["cube_id_0_copy_0"]);
+        assert!(!output.contains("NAME=skipped"), "{output}");
+        assert!(output.contains("; printing object skipped id:1 copy 0\nG1 X20 Y20\n"));
+    }
+
+    #[test]
+    fn test_disambiguate_names() {
+        let options = ProcessOptions::default();
+        let mut known_objects: HashMap<String, KnownObject> = [
+            "Würfel", "Wurfel", "Wurfel!", "Wurfel_2", "a b", "a_b", "cube",
+        ]
+        .into_iter()
+        .map(|key| (key.to_string(), KnownObject::new(key, &options.hull)))
+        .collect();
+
+        let warnings = disambiguate_names(&mut known_objects);
+        let names: HashMap<&str, &str> = known_objects
+            .iter()
+            .map(|(key, ko)| (key.as_str(), ko.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            HashMap::from([
+                ("Wurfel", "Wurfel"),
+                ("Wurfel!", "Wurfel_3"),
+                ("Würfel", "Wurfel_4"),
+                ("Wurfel_2", "Wurfel_2"),
+                ("a b", "a_b"),
+                ("a_b", "a_b_2"),
+                ("cube", "cube"),
+            ])
+        );
+        assert_eq!(
+            warnings.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "Object Wurfel! is named Wurfel as well, renamed to Wurfel_3",
+                "Object Würfel is named Wurfel as well, renamed to Wurfel_4",
+                "Object a_b is named a_b as well, renamed to a_b_2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_identify_mixed_case() {
+        for (line, slicer) in [
+            ("; generated by PrusaSlicer 2.6.0", "PrusaSlicer"),
+            ("  ;GENERATED BY SuperSlicer 2.5", "SuperSlicer"),
+            (";  Generated by OrcaSlicer 1.6", "OrcaSlicer"),
+            ("; generated with Cura_SteamEngine 5.0.0", "Cura"),
+            ("\t;sliced by IDEAMAKER 4.2.0", "ideaMaker"),
+            ("  m486 T4", "M486"),
+        ] {
+            let identified = identify_slicer_marker(line).map(|(slicer, _)| slicer);
+            assert_eq!(identified, Some(slicer), "{line}");
+        }
+        assert!(identify_slicer_marker("; M486 T4").is_none());
+    }
+
+    #[test]
+    fn test_indented_mixed_case_markers() {
+        for dialect in Dialect::ALL {
+            let replacements: &[(&str, &str)] = match dialect {
+                Dialect::PrusaSlicer => &[
+                    ("; stop printing object ", "\t; STOP printing object "),
+                    ("; printing object ", "  ;Printing Object "),
+                    (";LAYER_CHANGE", " ;layer_change"),
+                ],
+                Dialect::Cura => &[(";MESH:", "  ;mesh:"), (";LAYER:", " ;Layer:")],
+                Dialect::IdeaMaker => &[
+                    (";PRINTING_ID:", "  ;printing_id:"),
+                    (";PRINTING:", "\t;Printing:"),
+                    (";LAYER:", " ;layer:"),
+                ],
+                Dialect::M486 => &[("M486 S", "  m486 S")],
+            };
+            let synthetic = SyntheticGcode::new(dialect).objects(3).layers(4);
+            let gcode = replacements
+                .iter()
+                .fold(synthetic.to_string(), |gcode, (from, to)| {
+                    gcode.replace(&format!("\n{from}"), &format!("\n{to}"))
+                });
+
+            // Identified by the banner, and by the object markers without it. M486 files define
+            // their objects in the banner.
+            let (_, identified) = gcode.lines().find_map(identify_slicer_marker).unwrap();
+            let mut markers = ObjectMarkers::default();
+            let body = match dialect {
+                Dialect::M486 => &gcode,
+                _ => gcode.split_once('\n').unwrap().1,
+            };
+            body.lines().for_each(|line| markers.track(line));
+            let (_, counted) = markers.processor().unwrap();
+
+            for (processor, gcode) in [(identified, gcode.as_str()), (counted, body)] {
+                let mut report = ProcessReport::default();
+                let options = ProcessOptions {
+                    layer_filter: LayerFilter::try_from("2-").unwrap(),
+                    ..Default::default()
+                };
+                let result = process(&processor, gcode.as_bytes(), &options, &mut report);
+
+                assert_eq!(report.objects.len(), 3, "{dialect}");
+                let starts = result
+                    .lines()
+                    .filter(|line| line.starts_with("EXCLUDE_OBJECT_START"))
+                    .count();
+                assert_eq!(starts, 3 * 4, "{dialect}");
+                let ends = result
+                    .lines()
+                    .filter(|line| line.starts_with("EXCLUDE_OBJECT_END"))
+                    .count();
+                assert_eq!(ends, 3 * 4, "{dialect}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_errors() {
+        for dialect in Dialect::ALL {
+            let mut gcode = SyntheticGcode::new(dialect)
+                .objects(2)
+                .layers(2)
+                .to_string()
+                .into_bytes();
+            // Invalid UTF-8 in the comment after the 11th line
+            let position = gcode
+                .iter()
+                .enumerate()
+                .filter(|(_, byte)| **byte == b'\n')
+                .nth(10)
+                .unwrap()
+                .0;
+            gcode.splice(position..position, b" ; \xff".iter().copied());
+
+            let text = String::from_utf8_lossy(&gcode);
+            let (_, processor) = text.lines().find_map(identify_slicer_marker).unwrap();
+            let result = processor.collect(gcode.as_slice(), &ProcessOptions::default());
+            assert!(
+                matches!(result, Err(PreprocessError::ReadLineError(11, _))),
+                "{dialect}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_synthetic_dialects() {
+        for dialect in Dialect::ALL {
+            let synthetic = SyntheticGcode::new(dialect).objects(5).layers(4);
+            let gcode = synthetic.to_string();
+
+            let (_, processor) = gcode
+                .lines()
+                .find_map(identify_slicer_marker)
+                .expect("synthetic G-Code is identified");
+            let mut report = ProcessReport::default();
+            let result = process(
+                &processor,
+                gcode.as_bytes(),
+                &ProcessOptions::default(),
+                &mut report,
+            );
+
+            assert_eq!(report.objects.len(), 5, "{dialect}");
+            let starts = result
+                .lines()
+                .filter(|line| line.starts_with("EXCLUDE_OBJECT_START"))
+                .count();
+            assert_eq!(starts, 5 * 4, "{dialect}");
+            for object in &report.objects {
+                let (x, y) = object.center.unwrap();
+                assert!(
+                    (0..5).any(|i| {
+                        let center = synthetic.center(i);
+                        (center.0 - x).abs() < 0.01 && (center.1 - y).abs() < 0.01
+                    }),
+                    "{dialect}: {}",
+                    object.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_line_width() {
+        assert_eq!(
+            detect_line_width("; external perimeters extrusion width = 0.44mm (3.38mm^3/s)"),
+            Some(0.44)
+        );
+        assert_eq!(
+            detect_line_width(
+                r#";SETTING_3 cubic\\ninfill_sparse_density = 45\\nline_width = 0.4\\nmaterial_flow"#
+            ),
+            Some(0.4)
+        );
+        assert_eq!(
+            detect_line_width("; perimeters extrusion width = 0.48mm"),
+            None
+        );
+        assert_eq!(detect_line_width("G1 X1 Y1"), None);
+    }
 }
@@ -1,10 +1,55 @@
-use crate::gcode::{exclude_object_end, exclude_object_header, exclude_object_start};
+use crate::gcode::strip_marker;
 use crate::hulls::KnownObject;
-use crate::layers::LayerFilter;
-use crate::slicers::{maybe_add_point, CancellationPreProcessor};
-use generator::{done, Gn};
+use crate::layers::LayerTracker;
+use crate::lines::LineReader;
+use crate::motion::Toolhead;
+use crate::orphans::OrphanTracker;
+use crate::preprocess::{PreprocessError, ProcessOptions};
+use crate::slicers::{maybe_add_point, read_line, strip_m486, CancellationPreProcessor, Collected};
+use crate::splice::{Edits, Marker};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Seek};
+use std::io::BufRead;
+
+static COPY_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^.* id:(\d+) copy (\d+)$"#).unwrap());
+
+/// Copies of the same object have identical shapes, compute the outline once for each object
+/// and move it to the position of the other copies.
+fn share_copy_outlines(known_objects: &mut HashMap<String, KnownObject>) {
+    let mut families: HashMap<String, Vec<(u32, String)>> = HashMap::new();
+    for key in known_objects.keys() {
+        if let Some(captures) = COPY_RE.captures(key) {
+            let (Some(id), Some(copy)) = (captures.get(1), captures.get(2)) else {
+                continue;
+            };
+            families
+                .entry(id.as_str().to_string())
+                .or_default()
+                .push((copy.as_str().parse().unwrap_or(u32::MAX), key.clone()));
+        }
+    }
+
+    for mut copies in families.into_values().filter(|copies| copies.len() > 1) {
+        copies.sort();
+
+        let Some(original) = known_objects.get(&copies[0].1) else {
+            continue;
+        };
+        let Some(bounds) = original.hull.bounds() else {
+            continue;
+        };
+        let outline = original.hull.outline();
+
+        for (_, key) in &copies[1..] {
+            if let Some(copy) = known_objects.get_mut(key) {
+                if !copy.hull.reuse_outline(&outline, bounds) {
+                    tracing::debug!("Copy {} differs from {}", key, copies[0].1);
+                }
+            }
+        }
+    }
+}
 
 pub(crate) struct Slic3rProcessor {}
 
@@ -15,81 +60,65 @@ impl Slic3rProcessor {
 }
 
 impl CancellationPreProcessor for Slic3rProcessor {
-    fn process<'a>(
-        &'a self,
-        input: impl Read + Seek + Send + 'a,
-        layer_filter: &'a LayerFilter,
-    ) -> generator::Generator<'a, (), String> {
-        let mut input = BufReader::new(input);
+    fn collect(
+        &self,
+        input: impl BufRead,
+        options: &ProcessOptions,
+    ) -> Result<Collected, PreprocessError> {
         let mut known_objects: HashMap<String, KnownObject> = HashMap::new();
         let mut current_object: Option<&mut KnownObject> = None;
-        for line in input.by_ref().lines() {
-            let line = line.unwrap_or("".to_string());
-            if line.starts_with("; printing object ") {
-                if let Some(object_id) = line.split_once("printing object").map(|(_, o)| o.trim()) {
-                    if !known_objects.contains_key(object_id) {
-                        tracing::info!("Found object {}", object_id);
-                        known_objects.insert(object_id.into(), KnownObject::new(object_id));
-                    }
-
+        let mut toolhead = Toolhead::from_options(options);
+        let mut orphans = OrphanTracker::new(options);
+        let mut layers = LayerTracker::default();
+        let mut edits = Edits::default();
+
+        let mut lines = LineReader::new(input);
+        while let Some((line_no, line)) = read_line(&mut lines)? {
+            let in_body = edits.track(line_no, line);
+            layers.track(line);
+            strip_m486(line_no, line, options, &mut edits);
+            if let Some(object_id) = strip_marker(line, "; printing object ").map(str::trim) {
+                if !known_objects.contains_key(object_id) {
+                    tracing::info!("Found object {}", object_id);
                     known_objects
-                        .entry(object_id.to_string())
-                        .and_modify(|ko| ko.layer += 1);
-                    current_object = known_objects.get_mut(object_id);
-                }
-            }
-
-            if line.starts_with("; stop printing object ") {
-                current_object = None
-            }
-
-            maybe_add_point(&line, &current_object, layer_filter);
-        }
-
-        input.rewind().unwrap();
-
-        Gn::new_scoped(move |mut s| {
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
-
-                if !line.trim().is_empty() && !line.starts_with(';') {
-                    s.yield_from(exclude_object_header(&known_objects));
+                        .insert(object_id.into(), KnownObject::new(object_id, &options.hull));
                 }
 
-                s.yield_with(format!("{}\n", &line));
+                known_objects
+                    .entry(object_id.to_string())
+                    .and_modify(|ko| ko.layer += 1);
+                current_object = known_objects.get_mut(object_id);
 
-                if !line.trim().is_empty() && !line.starts_with(';') {
-                    break;
+                if in_body {
+                    edits.after(line_no, Marker::Start(object_id.into()));
                 }
             }
 
-            for line in input.by_ref().lines() {
-                let line = line.unwrap_or("".to_string());
+            if let Some(object_id) = strip_marker(line, "; stop printing object ") {
+                current_object = None;
 
-                s.yield_with(format!("{}\n", &line));
-
-                if line.starts_with("; printing object ") {
-                    let known_object = line
-                        .split_once("printing object")
-                        .and_then(|(_, oid)| known_objects.get(oid.trim()));
-
-                    if let Some(known_object) = known_object {
-                        s.yield_from(exclude_object_start(&known_object.name));
-                    }
+                if in_body {
+                    edits.after(line_no, Marker::End(object_id.trim().into()));
                 }
+            }
 
-                if line.starts_with("; stop printing object ") {
-                    let known_object = line
-                        .split_once("printing object")
-                        .and_then(|(_, oid)| known_objects.get(oid.trim()));
+            let in_object = current_object.is_some();
+            let toolhead_move = maybe_add_point(
+                line,
+                current_object.as_deref_mut(),
+                layers.layer(),
+                &options.layer_filter,
+                &mut toolhead,
+            );
+            orphans.track(line_no, line, in_object, &toolhead_move);
+        }
 
-                    if let Some(known_object) = known_object {
-                        s.yield_from(exclude_object_end(&known_object.name));
-                    }
-                }
-            }
+        share_copy_outlines(&mut known_objects);
 
-            done!();
+        Ok(Collected {
+            known_objects,
+            orphans,
+            edits,
         })
     }
 }
@@ -97,7 +126,8 @@ impl CancellationPreProcessor for Slic3rProcessor {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::slicers::tests::collect_definitions;
+    use crate::report::ProcessReport;
+    use crate::slicers::tests::{collect_definitions, process};
     use once_cell::sync::Lazy;
     use std::fs::File;
     use std::path::{Path, PathBuf};
@@ -109,9 +139,9 @@ mod tests {
     fn test_superslicer() {
         let processor = Slic3rProcessor::new();
         let input = File::open(GCODE_PATH.join("superslicer.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
 
         let definitions = collect_definitions(&result);
@@ -188,9 +218,9 @@ mod tests {
     fn test_prusaslicer() {
         let processor = Slic3rProcessor::new();
         let input = File::open(GCODE_PATH.join("prusaslicer.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
 
         let definitions = collect_definitions(&result);
@@ -267,9 +297,9 @@ mod tests {
     fn test_slic3r() {
         let processor = Slic3rProcessor::new();
         let input = File::open(GCODE_PATH.join("slic3r.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
 
         let definitions = collect_definitions(&result);
@@ -346,9 +376,9 @@ mod tests {
     fn test_orcaslicer() {
         let processor = Slic3rProcessor::new();
         let input = File::open(GCODE_PATH.join("orcaslicer.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
 
         let definitions = collect_definitions(&result);
@@ -425,9 +455,9 @@ mod tests {
     fn test_issue_1_prusaslicer_point_collection() {
         let processor = Slic3rProcessor::new();
         let input = File::open(GCODE_PATH.join("prusaslicer-issue1.gcode")).unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let result: String = processor.process(input, &layer_filter).collect();
+        let result: String = process(&processor, input, &options, &mut ProcessReport::default());
         let result: Vec<&str> = result.split('\n').collect();
 
         let definitions = collect_definitions(&result);
@@ -475,9 +505,9 @@ mod tests {
                 .join("issue_2_retractions.gcode"),
         )
         .unwrap();
-        let layer_filter = LayerFilter::try_from("*").unwrap();
+        let options = ProcessOptions::default();
 
-        let output: String = processor.process(input, &layer_filter).collect();
+        let output: String = process(&processor, input, &options, &mut ProcessReport::default());
 
         assert!(output.contains("EXCLUDE_OBJECT_DEFINE NAME=Leaf_stl_id_0_copy_0"));
         assert!(output.contains("EXCLUDE_OBJECT_DEFINE NAME=Leaf_stl_id_1_copy_0"));
@@ -0,0 +1,211 @@
+//! Spiral vase prints.
+//!
+//! In vase mode the slicer prints a single object as one continuous spiral. The object markers
+//! of the slicer, if there are any, only cover the solid bottom layers, so the whole print is
+//! defined as one object instead, outlined by its first layer.
+
+use crate::gcode::starts_with_marker;
+use crate::hulls::KnownObject;
+use crate::layers::{LayerFilter, LayerTracker};
+use crate::lines::LineReader;
+use crate::motion::Toolhead;
+use crate::orphans::{OrphanKind, OrphanTracker};
+use crate::preprocess::{PreprocessError, ProcessOptions};
+use crate::slicers::{maybe_add_point, read_line, Collected};
+use crate::splice::{Edits, Marker};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// Name of the object for vase prints without object markers
+pub(crate) const VASE_NAME: &str = "vase";
+
+/// Whether a settings comment enables spiral vase mode
+pub(crate) fn is_spiral_vase(line: &str) -> bool {
+    if !line.starts_with(';') {
+        return false;
+    }
+
+    // PrusaSlicer and its forks, OrcaSlicer and Cura
+    starts_with_marker(line, "; spiral_vase = 1")
+        || starts_with_marker(line, "; spiral_mode = 1")
+        || (starts_with_marker(line, ";SETTING_3 ") && line.contains("magic_spiralize = True"))
+}
+
+/// Collect the whole print as a single object, from the first extrusion after the first layer
+/// change to the last one. Skirts and brims are left out of the outline.
+pub(crate) fn collect(
+    input: impl BufRead,
+    name: &str,
+    options: &ProcessOptions,
+) -> Result<Collected, PreprocessError> {
+    let mut known_object = KnownObject::new(name, &options.hull);
+    let mut toolhead = Toolhead::from_options(options);
+    let mut layers = LayerTracker::default();
+    let mut edits = Edits::default();
+    let first_layer = LayerFilter::first_layer();
+    let mut skirt = false;
+    let mut extrusions: Option<(usize, usize)> = None;
+
+    let mut lines = LineReader::new(input);
+    while let Some((line_no, line)) = read_line(&mut lines)? {
+        let in_body = edits.track(line_no, line);
+        layers.track(line);
+        if let Some(kind) = OrphanKind::from_type_comment(line) {
+            skirt = kind == Some(OrphanKind::SkirtBrim);
+        }
+
+        // The object markers of the slicer are replaced by the single object
+        if in_body && starts_with_marker(line, "M486") {
+            edits.replace(line_no, Marker::Text(format!("; {line}\n")));
+        }
+
+        // Purge lines of the start G-Code and skirts aren't part of the outline
+        let collecting = layers.layer().is_some() && !skirt;
+        let toolhead_move = maybe_add_point(
+            line,
+            collecting.then_some(&mut known_object),
+            layers.layer(),
+            &first_layer,
+            &mut toolhead,
+        );
+        if toolhead_move.extruding && layers.layer().is_some() && in_body {
+            extrusions = Some(extrusions.map_or((line_no, line_no), |(first, _)| (first, line_no)));
+        }
+    }
+
+    if let (Some(first), Some(last)) = (known_object.first_layer, layers.layer()) {
+        known_object.layer = last.saturating_sub(first) as isize;
+    }
+
+    let mut known_objects = HashMap::new();
+    if let Some((first, last)) = extrusions {
+        edits.before(first, Marker::Start(name.to_string()));
+        edits.after(last, Marker::End(name.to_string()));
+        known_objects.insert(name.to_string(), known_object);
+    }
+
+    Ok(Collected {
+        known_objects,
+        orphans: OrphanTracker::default(),
+        edits,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocess::stream;
+    use crate::report::ProcessReport;
+    use std::io::Cursor;
+
+    /// A PrusaSlicer vase: a 10mm square base with object markers, then a spiral growing wider
+    fn vase_gcode() -> String {
+        let mut gcode = String::from(
+            "; generated by PrusaSlicer 2.6.0\nG28\nM83\nG1 Z0.3 F720\nG1 X0 Y-5 E10 ; purge\n",
+        );
+        gcode.push_str(";LAYER_CHANGE\n;Z:0.2\nG1 Z0.2\n;TYPE:Skirt\nG1 X-10 Y-10\n");
+        gcode.push_str("G1 X30 Y-10 E1\nG1 X30 Y30 E1\n");
+        gcode.push_str("; printing object vase.stl id:0 copy 0\n;TYPE:Perimeter\nG1 X0 Y0\n");
+        for (x, y) in [(10, 0), (10, 10), (0, 10), (0, 0)] {
+            gcode.push_str(&format!("G1 X{x} Y{y} E1\n"));
+        }
+        gcode.push_str("; stop printing object vase.stl id:0 copy 0\n");
+        for layer in 1..4 {
+            let size = 10 + layer * 2;
+            let z = 0.2 * (layer + 1) as f64;
+            gcode.push_str(&format!(";LAYER_CHANGE\n;Z:{z:.1}\n"));
+            for (x, y) in [(size, 0), (size, size), (0, size), (0, 0)] {
+                gcode.push_str(&format!("G1 X{x} Y{y} Z{z:.1} E1\n"));
+            }
+        }
+        gcode.push_str("G1 Z20\nM84\n; spiral_vase = 1\n");
+        gcode
+    }
+
+    #[test]
+    fn test_is_spiral_vase() {
+        assert!(is_spiral_vase("; spiral_vase = 1"));
+        assert!(!is_spiral_vase("; spiral_vase = 0"));
+        assert!(is_spiral_vase("; spiral_mode = 1"));
+        assert!(is_spiral_vase(
+            r#";SETTING_3 {"global_quality": "[general]\\nmagic_spiralize = True\\n"#
+        ));
+        assert!(!is_spiral_vase(
+            r#";SETTING_3 \\nmagic_spiralize = False\\n"#
+        ));
+        assert!(!is_spiral_vase("G1 X1 ; spiral_vase = 1"));
+    }
+
+    #[test]
+    fn test_collect() {
+        let gcode = vase_gcode();
+        let options = ProcessOptions::default();
+        let rewrite = collect(gcode.as_bytes(), "vase.stl id:0 copy 0", &options)
+            .unwrap()
+            .finish(&options, &mut ProcessReport::default());
+        let mut output = Vec::new();
+        rewrite
+            .write(&mut gcode.as_bytes(), &mut output, &options)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        // Started before the skirt, ended after the last layer
+        let start = lines
+            .iter()
+            .position(|line| *line == "EXCLUDE_OBJECT_START NAME=vase_stl_id_0_copy_0")
+            .unwrap();
+        assert_eq!(lines[start + 1], "G1 X30 Y-10 E1");
+        let end = lines
+            .iter()
+            .position(|line| *line == "EXCLUDE_OBJECT_END NAME=vase_stl_id_0_copy_0")
+            .unwrap();
+        assert_eq!(lines[end - 1], "G1 X0 Y0 Z0.8 E1");
+        assert_eq!(output.matches("EXCLUDE_OBJECT_START").count(), 1);
+        assert_eq!(output.matches("EXCLUDE_OBJECT_END").count(), 1);
+
+        // Outlined by the first layer, without the skirt
+        let define = lines
+            .iter()
+            .find(|line| line.starts_with("EXCLUDE_OBJECT_DEFINE"))
+            .unwrap();
+        assert!(define.contains("CENTER=5.000,5.000"), "{define}");
+    }
+
+    #[test]
+    fn test_process_vase() {
+        let gcode = vase_gcode();
+        for (gcode, name) in [
+            (gcode.clone(), "vase_stl_id_0_copy_0"),
+            // Without object markers
+            (gcode.replace("printing object", "object"), VASE_NAME),
+        ] {
+            let mut output = Vec::new();
+            let report = stream(
+                &mut Cursor::new(&gcode),
+                &mut output,
+                &ProcessOptions::default(),
+            )
+            .unwrap();
+
+            assert_eq!(report.object_names(), [name]);
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.contains(&format!(
+                "G1 X0 Y0 Z0.8 E1\nEXCLUDE_OBJECT_END NAME={name}\n"
+            )));
+            assert_eq!(output.matches("EXCLUDE_OBJECT_START").count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_collect_without_extrusions() {
+        let options = ProcessOptions::default();
+        let collected = collect(
+            "G28\n;LAYER_CHANGE\nG1 X1\n".as_bytes(),
+            VASE_NAME,
+            &options,
+        )
+        .unwrap();
+        assert!(collected.known_objects.is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+//! Golden files for every G-Code fixture.
+//!
+//! The changes processing makes to each file below `GCode/` are kept as a unified diff in
+//! `GCode/snapshots/`, so any change of the output shows up in review. Run the tests with
+//! `UPDATE_SNAPSHOTS=1` to write new or changed snapshots instead of failing, e.g. after adding a
+//! fixture minimized with `minimize_fixture`.
+
+use crate::diff::unified_diff;
+use crate::gcode::tool_version;
+use crate::preprocess::{self, ProcessOptions};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Larger fixtures take too long in debug builds, they should be minimized first
+const MAX_FIXTURE_SIZE: u64 = 4 * 1024 * 1024;
+
+pub(crate) fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("GCode")
+}
+
+/// All fixtures below the directory, skipping snapshots and ignored outputs
+pub(crate) fn fixtures(dir: &Path) -> Vec<PathBuf> {
+    let mut fixtures = Vec::new();
+    for entry in fs::read_dir(dir).unwrap().map(Result::unwrap) {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if path.is_dir() {
+            if name != "snapshots" {
+                fixtures.extend(self::fixtures(&path));
+            }
+        } else if name.ends_with(".gcode")
+            && !name.ends_with(".out.gcode")
+            && !name.ends_with(".huge.gcode")
+            && entry.metadata().unwrap().len() <= MAX_FIXTURE_SIZE
+        {
+            fixtures.push(path);
+        }
+    }
+
+    fixtures.sort();
+    fixtures
+}
+
+/// Remove what changes between releases: the version in the header
+fn normalize(output: &str) -> String {
+    let version = format!("preprocess_cancellation v{}", tool_version());
+    output
+        .lines()
+        .map(|line| line.replace(&version, "preprocess_cancellation v<version>") + "\n")
+        .collect()
+}
+
+/// The snapshot of a fixture: the diff of the processed file or the error processing failed with
+fn render(fixture: &Path, name: &str) -> String {
+    let input = fs::read(fixture).unwrap();
+    let mut output = Vec::new();
+    match preprocess::stream(
+        &mut Cursor::new(&input),
+        &mut output,
+        &ProcessOptions::default(),
+    ) {
+        Ok(_) => {
+            let output = normalize(&String::from_utf8_lossy(&output));
+            let mut diff = Vec::new();
+            unified_diff(
+                input.as_slice(),
+                output.as_bytes(),
+                (name, &format!("{name} (processed)")),
+                &mut diff,
+            )
+            .unwrap();
+            String::from_utf8(diff).unwrap()
+        }
+        Err(err) => format!("error: {err}\n"),
+    }
+}
+
+/// Compare the actual snapshot with the stored one, returns a description of the difference
+fn check(snapshot: &Path, actual: &str) -> Option<String> {
+    let expected = fs::read_to_string(snapshot).ok();
+    if expected.as_deref() == Some(actual) {
+        return None;
+    }
+
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::create_dir_all(snapshot.parent().unwrap()).unwrap();
+        fs::write(snapshot, actual).unwrap();
+        return None;
+    }
+
+    let Some(expected) = expected else {
+        return Some(format!("{} is missing", snapshot.display()));
+    };
+    let mut diff = Vec::new();
+    unified_diff(
+        expected.as_bytes(),
+        actual.as_bytes(),
+        ("expected", "actual"),
+        &mut diff,
+    )
+    .unwrap();
+    Some(format!(
+        "{} changed:\n{}",
+        snapshot.display(),
+        String::from_utf8_lossy(&diff)
+    ))
+}
+
+#[test]
+fn test_fixture_snapshots() {
+    let dir = fixtures_dir();
+    let failures: Vec<String> = fixtures(&dir)
+        .iter()
+        .filter_map(|fixture| {
+            let name = fixture
+                .strip_prefix(&dir)
+                .unwrap()
+                .to_string_lossy()
+                .replace('\\', "/");
+            let snapshot = dir.join("snapshots").join(format!("{name}.diff"));
+            check(&snapshot, &render(fixture, &name))
+        })
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "{}\nRun with UPDATE_SNAPSHOTS=1 to accept the changes",
+        failures.join("\n")
+    );
+}
+
+/// Processing a processed fixture again leaves it unchanged
+#[test]
+fn test_fixtures_idempotent() {
+    let changed: Vec<String> = fixtures(&fixtures_dir())
+        .iter()
+        .filter_map(|fixture| {
+            let process = |input: &[u8]| {
+                let mut output = Vec::new();
+                preprocess::stream(
+                    &mut Cursor::new(input),
+                    &mut output,
+                    &ProcessOptions::default(),
+                )
+                .ok()
+                .map(|_| output)
+            };
+            let first = process(&fs::read(fixture).unwrap())?;
+            let second = process(&first)?;
+            (first != second).then(|| fixture.display().to_string())
+        })
+        .collect();
+
+    assert!(
+        changed.is_empty(),
+        "Changed by processing again: {changed:?}"
+    );
+}
+
+#[test]
+fn test_normalize() {
+    let output = format!(
+        "; Pre-Processed for Cancel-Object support by preprocess_cancellation v{}\n\
+        EXCLUDE_OBJECT_DEFINE NAME=b\nEXCLUDE_OBJECT_DEFINE NAME=a\nG28\n\
+        EXCLUDE_OBJECT_DEFINE NAME=d\nEXCLUDE_OBJECT_DEFINE NAME=c\n",
+        tool_version()
+    );
+    // The order of the definitions is part of the output
+    assert_eq!(
+        normalize(&output),
+        "; Pre-Processed for Cancel-Object support by preprocess_cancellation v<version>\n\
+        EXCLUDE_OBJECT_DEFINE NAME=b\nEXCLUDE_OBJECT_DEFINE NAME=a\nG28\n\
+        EXCLUDE_OBJECT_DEFINE NAME=d\nEXCLUDE_OBJECT_DEFINE NAME=c\n"
+    );
+}
@@ -0,0 +1,596 @@
+use crate::gcode::{
+    exclude_object_end, exclude_object_header, exclude_object_start, header_marker_version,
+    is_object_progress, is_progress, is_provenance, known_objects_count, object_progress_comment,
+    starts_with_marker, trailer, trailer_version,
+};
+use crate::groups::is_group_line;
+use crate::hulls::KnownObject;
+use crate::lines::LineReader;
+use crate::preprocess::ProcessOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/// Placement of a marker relative to its line of the input
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub(crate) enum Position {
+    Before,
+    /// The line itself is dropped from the output
+    Instead,
+    After,
+}
+
+/// Output injected into the G-Code
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) enum Marker {
+    /// The object definitions
+    Header,
+    /// Start of an object, given by its key until resolved to the object name
+    Start(String),
+    /// End of an object, given by its key until resolved to the object name
+    End(String),
+    /// Literal text including the line break
+    Text(String),
+}
+
+/// Markers to inject into a G-Code file, recorded while collecting the objects.
+///
+/// Markers are kept by line number, so the output is written in a single pass that copies
+/// the input line by line without parsing it again.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Edits {
+    markers: Vec<(usize, Position, Marker)>,
+    /// Line of the first command, the object definitions are placed before it
+    header_line: Option<usize>,
+}
+
+impl Edits {
+    /// Track the position of the header, returns whether the line is part of the body
+    pub fn track(&mut self, line_no: usize, line: &str) -> bool {
+        if self.header_line.is_none() && !line.trim().is_empty() && !line.starts_with(';') {
+            self.header_line = Some(line_no);
+            self.before(line_no, Marker::Header);
+        }
+
+        self.in_body(line_no)
+    }
+
+    /// Markers are only placed after the first command of the file
+    pub fn in_body(&self, line_no: usize) -> bool {
+        self.header_line.is_some_and(|header| line_no > header)
+    }
+
+    pub fn before(&mut self, line_no: usize, marker: Marker) {
+        self.markers.push((line_no, Position::Before, marker));
+    }
+
+    pub fn after(&mut self, line_no: usize, marker: Marker) {
+        self.markers.push((line_no, Position::After, marker));
+    }
+
+    /// Replace a line of the input with a marker
+    pub fn replace(&mut self, line_no: usize, marker: Marker) {
+        self.markers.push((line_no, Position::Instead, marker));
+    }
+
+    /// Place a marker after the last line of the file
+    pub fn at_end(&mut self, marker: Marker) {
+        self.before(usize::MAX, marker);
+    }
+
+    /// Add markers whose objects are already resolved, skipping those before the body
+    pub fn extend(&mut self, markers: impl IntoIterator<Item = (usize, Position, Marker)>) {
+        let markers: Vec<_> = markers
+            .into_iter()
+            .filter(|(line_no, _, _)| self.in_body(*line_no))
+            .collect();
+        self.markers.extend(markers);
+    }
+
+    /// Replace object keys with object names, dropping the markers of unknown objects
+    pub fn resolve(&mut self, known_objects: &HashMap<String, KnownObject>) {
+        self.markers.retain_mut(|(_, _, marker)| match marker {
+            Marker::Start(key) | Marker::End(key) => match known_objects.get(key.as_str()) {
+                Some(known_object) => {
+                    key.clone_from(&known_object.name);
+                    true
+                }
+                None => false,
+            },
+            Marker::Header | Marker::Text(_) => true,
+        });
+    }
+
+    /// Replace the header marker with the object definitions, the edits no longer need the
+    /// objects to be written afterwards
+    pub fn render_header(
+        &mut self,
+        known_objects: &HashMap<String, KnownObject>,
+        options: &ProcessOptions,
+    ) {
+        for (_, _, marker) in self.markers.iter_mut() {
+            if *marker == Marker::Header {
+                *marker = Marker::Text(exclude_object_header(known_objects, options).collect());
+            }
+        }
+    }
+
+    /// Copy the input to the output, injecting all markers, and return the number of lines written
+    /// for them
+    pub fn write(
+        &self,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+        known_objects: &HashMap<String, KnownObject>,
+        options: &ProcessOptions,
+    ) -> io::Result<usize> {
+        let mut markers: Vec<_> = self.markers.iter().collect();
+        markers.sort_by_key(|(line_no, position, _)| (*line_no, *position));
+        // Starts of each object so far and in total, for the object progress comments
+        let mut starts: HashMap<&str, (usize, usize)> = HashMap::new();
+        if options.object_progress {
+            for (_, _, marker) in &markers {
+                if let Marker::Start(name) = marker {
+                    starts.entry(name).or_default().1 += 1;
+                }
+            }
+        }
+
+        let mut line_no = 0;
+        let mut added = 0;
+        let mut text = String::new();
+        for (marker_line, position, marker) in markers {
+            let target = match position {
+                Position::Before | Position::Instead => *marker_line,
+                Position::After => marker_line.saturating_add(1),
+            };
+            if line_no < target {
+                line_no += copy_lines(input, output, target - line_no)?;
+            }
+            // Several markers can replace the same line, it is only skipped once
+            if *position == Position::Instead
+                && line_no == *marker_line
+                && input.skip_until(b'\n')? > 0
+            {
+                line_no += 1;
+            }
+
+            // Generated lines are gathered into a single write
+            text.clear();
+            match marker {
+                Marker::Header => text.extend(exclude_object_header(known_objects, options)),
+                Marker::Start(name) => {
+                    text.extend(exclude_object_start(name));
+                    if let Some((layer, layers)) = starts.get_mut(name.as_str()) {
+                        *layer += 1;
+                        text.push_str(&object_progress_comment(name, *layer, *layers));
+                    }
+                }
+                Marker::End(name) => text.extend(exclude_object_end(name)),
+                Marker::Text(marker_text) => text.push_str(marker_text),
+            }
+            output.write_all(text.as_bytes())?;
+            added += text.matches('\n').count();
+        }
+
+        copy_lines(input, output, usize::MAX)?;
+        if let Some(trailer) = trailer(options).filter(|_| self.header_line.is_some()) {
+            output.write_all(trailer.as_bytes())?;
+            added += 1;
+        }
+
+        Ok(added)
+    }
+}
+
+/// Copy a file processed before to the output without the markers added by this tool.
+///
+/// Drops the header with the object definitions, including the blank lines before it and the
+/// object markers. M486 lines commented out are restored, older versions kept the original
+/// line next to its commented copy. Headers without the marker start with the object count,
+/// or the custom text right before it.
+pub(crate) fn strip_markers(input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut lines = LineReader::untruncated(input);
+    let mut blank_lines: usize = 0;
+    let mut in_header = false;
+    let mut previous = String::new();
+    // A comment after blank lines and the number of them, held back until the next line shows
+    // whether it is the custom text of a header
+    let mut held: Option<(usize, String)> = None;
+
+    while let Some((_, line)) = lines.next_line()? {
+        let header_start = !in_header && known_objects_count(line).is_some();
+        if let Some((held_blank_lines, comment)) = held.take() {
+            if header_start {
+                blank_lines = held_blank_lines;
+            } else {
+                for _ in 0..held_blank_lines {
+                    output.write_all(b"\n")?;
+                }
+                output.write_all(comment.as_bytes())?;
+                output.write_all(b"\n")?;
+                previous = comment;
+            }
+        }
+
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        if trailer_version(line).is_some() {
+            continue;
+        }
+
+        let m486 = line
+            .strip_prefix("; ")
+            .filter(|command| starts_with_marker(command, "M486"));
+        if header_marker_version(line).is_some() || header_start {
+            // The header starts with two blank lines
+            blank_lines = blank_lines.saturating_sub(2);
+            in_header = true;
+        } else if !in_header && blank_lines >= 2 && line.starts_with(';') && m486.is_none() {
+            held = Some((std::mem::take(&mut blank_lines), line.to_string()));
+            continue;
+        }
+        for _ in 0..std::mem::take(&mut blank_lines) {
+            output.write_all(b"\n")?;
+        }
+
+        let injected = in_header
+            && (header_marker_version(line).is_some()
+                || line.ends_with(" known objects")
+                || is_provenance(line)
+                || starts_with_marker(line, "; object NAME=")
+                || starts_with_marker(line, "; extruder ")
+                || starts_with_marker(line, "EXCLUDE_OBJECT NAME=")
+                || is_group_line(line)
+                || starts_with_marker(line, "; object '")
+                || starts_with_marker(line, "; wkt NAME="));
+        in_header = injected || starts_with_marker(line, "EXCLUDE_OBJECT_DEFINE");
+
+        let marker = starts_with_marker(line, "EXCLUDE_OBJECT_DEFINE")
+            || starts_with_marker(line, "EXCLUDE_OBJECT_START")
+            || starts_with_marker(line, "EXCLUDE_OBJECT_END")
+            || is_progress(line)
+            || is_object_progress(line);
+        if let Some(command) = m486 {
+            if command != previous {
+                output.write_all(command.as_bytes())?;
+                output.write_all(b"\n")?;
+                previous.clear();
+                previous.push_str(command);
+            }
+            continue;
+        }
+
+        if !(injected || marker) {
+            output.write_all(line.as_bytes())?;
+            output.write_all(b"\n")?;
+            previous.clear();
+            previous.push_str(line);
+        }
+    }
+
+    if let Some((held_blank_lines, comment)) = held {
+        for _ in 0..held_blank_lines {
+            output.write_all(b"\n")?;
+        }
+        output.write_all(comment.as_bytes())?;
+        output.write_all(b"\n")?;
+    }
+    for _ in 0..blank_lines {
+        output.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Comment out the object commands of other flavors, e.g. left behind by macros of the start
+/// G-Code: all exclude object commands and, with `m486`, the M486 commands. Returns the number
+/// of commands commented out.
+pub(crate) fn normalize_markers(
+    input: impl BufRead,
+    output: &mut impl Write,
+    m486: bool,
+) -> io::Result<usize> {
+    let mut lines = LineReader::untruncated(input);
+    let mut normalized = 0;
+    while let Some((_, line)) = lines.next_line()? {
+        let stray = starts_with_marker(line, "EXCLUDE_OBJECT")
+            || starts_with_marker(line, "DEFINE_OBJECT")
+            || (m486 && starts_with_marker(line, "M486"));
+        if stray {
+            output.write_all(b"; ")?;
+            normalized += 1;
+        }
+        output.write_all(line.as_bytes())?;
+        output.write_all(b"\n")?;
+    }
+
+    Ok(normalized)
+}
+
+/// Copy up to `count` lines and return the number of lines copied.
+///
+/// Runs of complete lines in the read buffer are written at once, only lines with a `\r` or
+/// spanning multiple buffers are copied one by one.
+fn copy_lines(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    count: usize,
+) -> io::Result<usize> {
+    let mut copied = 0;
+    while copied < count {
+        let buffer = input.fill_buf()?;
+        let mut end = 0;
+        let mut lines = 0;
+        for (position, byte) in buffer.iter().enumerate() {
+            match byte {
+                b'\n' => {
+                    end = position + 1;
+                    lines += 1;
+                    if copied + lines == count {
+                        break;
+                    }
+                }
+                b'\r' => break,
+                _ => {}
+            }
+        }
+
+        if lines > 0 {
+            output.write_all(&buffer[..end])?;
+            input.consume(end);
+            copied += lines;
+        } else if copy_line(input, output)? {
+            copied += 1;
+        } else {
+            break;
+        }
+    }
+
+    Ok(copied)
+}
+
+/// Copy a single line, normalizing the line ending to `\n`. Returns false at the end of the input.
+fn copy_line(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<bool> {
+    let mut copied = false;
+    let mut carriage_return = false;
+
+    loop {
+        let buffer = input.fill_buf()?;
+        if buffer.is_empty() {
+            if carriage_return {
+                output.write_all(b"\r")?;
+            }
+            if copied {
+                output.write_all(b"\n")?;
+            }
+            return Ok(copied);
+        }
+
+        // A `\r` at the end of the previous buffer is only dropped when the line ends after it
+        if carriage_return && buffer[0] != b'\n' {
+            output.write_all(b"\r")?;
+        }
+        copied = true;
+
+        match buffer.iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                let content = buffer[..end].strip_suffix(b"\r").unwrap_or(&buffer[..end]);
+                output.write_all(content)?;
+                output.write_all(b"\n")?;
+                input.consume(end + 1);
+                return Ok(true);
+            }
+            None => {
+                let length = buffer.len();
+                carriage_return = buffer.ends_with(b"\r");
+                output.write_all(buffer.strip_suffix(b"\r").unwrap_or(buffer))?;
+                input.consume(length);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hulls::HullOptions;
+    use std::io::{BufReader, Cursor};
+
+    fn known_objects() -> HashMap<String, KnownObject> {
+        let mut known_objects = HashMap::new();
+        known_objects.insert(
+            "object 1".to_string(),
+            KnownObject::new("object 1", &HullOptions::default()),
+        );
+        known_objects
+    }
+
+    #[test]
+    fn test_markers() {
+        let input =
+            "; comment\nG28\n; printing object 1\nG1 X1 E1\n; stop printing object 1\nG1 X2";
+        let mut edits = Edits::default();
+        for (line_no, line) in input.lines().enumerate() {
+            if !edits.track(line_no, line) {
+                continue;
+            }
+            if line.starts_with("; printing") {
+                edits.after(line_no, Marker::Start("object 1".into()));
+            }
+            if line.starts_with("; stop") {
+                edits.after(line_no, Marker::End("object 1".into()));
+                edits.after(line_no, Marker::End("object 2".into()));
+            }
+        }
+        edits.at_end(Marker::Text("; done\n".into()));
+
+        let known_objects = known_objects();
+        edits.resolve(&known_objects);
+
+        let mut output = Vec::new();
+        edits
+            .write(
+                &mut Cursor::new(input),
+                &mut output,
+                &known_objects,
+                &ProcessOptions::default(),
+            )
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("; comment\n\n\n; Pre-Processed for Cancel-Object support"));
+        assert!(output.contains("EXCLUDE_OBJECT_DEFINE NAME=object_1"));
+
+        let body = &output[output.find("G28\n").unwrap()..];
+        assert_eq!(
+            body,
+            "G28\n\
+            ; printing object 1\n\
+            EXCLUDE_OBJECT_START NAME=object_1\n\
+            G1 X1 E1\n\
+            ; stop printing object 1\n\
+            EXCLUDE_OBJECT_END NAME=object_1\n\
+            G1 X2\n\
+            ; done\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_markers() {
+        let gcode = "G28\nEXCLUDE_OBJECT_START NAME=purge\nG1 X1 E1\n  exclude_object_end\n\
+            M486 T2\n; EXCLUDE_OBJECT_START NAME=a\nG1 X2 E1\n";
+        for (m486, normalized, expected) in [
+            (
+                false,
+                2,
+                "G28\n; EXCLUDE_OBJECT_START NAME=purge\nG1 X1 E1\n;   exclude_object_end\n\
+                M486 T2\n; EXCLUDE_OBJECT_START NAME=a\nG1 X2 E1\n",
+            ),
+            (
+                true,
+                3,
+                "G28\n; EXCLUDE_OBJECT_START NAME=purge\nG1 X1 E1\n;   exclude_object_end\n\
+                ; M486 T2\n; EXCLUDE_OBJECT_START NAME=a\nG1 X2 E1\n",
+            ),
+        ] {
+            let mut output = Vec::new();
+            assert_eq!(
+                normalize_markers(gcode.as_bytes(), &mut output, m486).unwrap(),
+                normalized
+            );
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_strip_markers() {
+        let original = "; comment\n\nG28\nM486 S1\nG1 X1 E1\nM486 S-1\nG1 X2\n";
+        let processed = "; comment\n\n\n\n\
+            ; Pre-Processed for Cancel-Object support by preprocess_cancellation v0.1.0\n\
+            ; 1 known objects\n\
+            ; object NAME=1 Z_MIN=0.2 Z_MAX=0.2 LAYERS=1\n\
+            EXCLUDE_OBJECT_DEFINE NAME=1 CENTER=1.000,1.000\n\
+            ; wkt NAME=1 POLYGON EMPTY\n\
+            G28\n\
+            ; M486 S1\n\
+            EXCLUDE_OBJECT_START NAME=1\n\
+            G1 X1 E1\n\
+            ; M486 S-1\n\
+            EXCLUDE_OBJECT_END NAME=1\n\
+            M73 P100 R0 ; progress\n\
+            G1 X2\n";
+
+        let mut output = Vec::new();
+        strip_markers(processed.as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), original);
+
+        // Older versions kept the original M486 lines next to the commented copy
+        let legacy = "; comment\n\nG28\n\
+            M486 S1\n\
+            EXCLUDE_OBJECT_START NAME=1\n\
+            ; M486 S1\n\
+            G1 X1 E1\n\
+            M486 S-1\n\
+            EXCLUDE_OBJECT_END NAME=1\n\
+            ; M486 S-1\n\
+            G1 X2\n";
+        let mut output = Vec::new();
+        strip_markers(legacy.as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), original);
+
+        // Headers without the marker or with custom text, and the trailer marking the file
+        for text in ["", "; cancel objects\n"] {
+            let unmarked = format!(
+                "; comment\n\n\n\n{text}; 1 known objects\n\
+                EXCLUDE_OBJECT_DEFINE NAME=1 CENTER=1.000,1.000\n\
+                G28\n\
+                ; M486 S1\n\
+                EXCLUDE_OBJECT_START NAME=1\n\
+                G1 X1 E1\n\
+                ; M486 S-1\n\
+                EXCLUDE_OBJECT_END NAME=1\n\
+                G1 X2\n\
+                ; Cancel-Object support added by preprocess_cancellation v0.3.0\n"
+            );
+            let mut output = Vec::new();
+            strip_markers(unmarked.as_bytes(), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), original, "{text}");
+        }
+
+        // Markers written by other tools in another case or indented
+        let indented = "; comment\n\n\n\n\
+            ; Pre-Processed for Cancel-Object support by preprocess_cancellation v0.1.0\n\
+            ; 1 known objects\n\
+            ;Object name=1 Z_MIN=0.2 Z_MAX=0.2 LAYERS=1\n\
+            exclude_object_define NAME=1 CENTER=1.000,1.000\n\
+            G28\n\
+            ; M486 S1\n\
+            \x20 Exclude_Object_Start NAME=1\n\
+            G1 X1 E1\n\
+            ; M486 S-1\n\
+            \texclude_object_end NAME=1\n\
+            G1 X2\n";
+        let mut output = Vec::new();
+        strip_markers(indented.as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), original);
+
+        // Comments after blank lines are only dropped before the object count
+        let comments = "; a\n\n\n; b\nG28\n\n\n; c\n\n\n\n; d\n";
+        let mut output = Vec::new();
+        strip_markers(comments.as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), comments);
+    }
+
+    #[test]
+    fn test_copy_lines() {
+        let input = "G1 X1\nG1 X2\r\nG1 X3\nG1 X4\nG1 X5\nG1 X6";
+        for capacity in [1, 4, 16, 1024] {
+            let mut output = Vec::new();
+            let mut input = BufReader::with_capacity(capacity, Cursor::new(input));
+            assert_eq!(copy_lines(&mut input, &mut output, 3).unwrap(), 3);
+            assert_eq!(
+                String::from_utf8(output.clone()).unwrap(),
+                "G1 X1\nG1 X2\nG1 X3\n"
+            );
+
+            assert_eq!(copy_lines(&mut input, &mut output, usize::MAX).unwrap(), 3);
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "G1 X1\nG1 X2\nG1 X3\nG1 X4\nG1 X5\nG1 X6\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_copy_line_endings() {
+        let mut output = Vec::new();
+        let mut input = BufReader::with_capacity(4, Cursor::new("G1 X1\r\nG1 X2\rY\nG1 X3"));
+        while copy_line(&mut input, &mut output).unwrap() {}
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "G1 X1\nG1 X2\rY\nG1 X3\n"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Synthetic multi-object G-Code in the dialects of the supported slicers.
+//!
+//! Used by the benchmarks and tests to measure and check processing of arbitrarily large
+//! plates without shipping large G-Code files.
+
+use std::f64::consts::TAU;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Spacing between the centers of neighbouring objects in mm
+const SPACING: f64 = 40.0;
+/// Radius of the outer perimeter of every object in mm
+const RADIUS: f64 = 15.0;
+/// Distance between perimeters in mm
+const PERIMETER_SPACING: f64 = 0.45;
+const LAYER_HEIGHT: f64 = 0.2;
+
+/// Slicer flavour of the generated G-Code
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dialect {
+    PrusaSlicer,
+    Cura,
+    IdeaMaker,
+    M486,
+}
+
+impl Dialect {
+    pub const ALL: [Dialect; 4] = [
+        Dialect::PrusaSlicer,
+        Dialect::Cura,
+        Dialect::IdeaMaker,
+        Dialect::M486,
+    ];
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Dialect::PrusaSlicer => "prusaslicer",
+            Dialect::Cura => "cura",
+            Dialect::IdeaMaker => "ideamaker",
+            Dialect::M486 => "m486",
+        })
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Dialect::ALL
+            .into_iter()
+            .find(|dialect| dialect.to_string().eq_ignore_ascii_case(value))
+            .ok_or_else(|| format!("Unknown dialect {value}"))
+    }
+}
+
+/// A plate of cylinders arranged on a grid, printed layer by layer
+#[derive(Clone, Debug)]
+pub struct SyntheticGcode {
+    pub dialect: Dialect,
+    pub objects: usize,
+    pub layers: usize,
+    /// Perimeters printed per object and layer
+    pub perimeters: usize,
+    /// Extrusion moves per perimeter
+    pub segments: usize,
+}
+
+impl SyntheticGcode {
+    pub fn new(dialect: Dialect) -> Self {
+        Self {
+            dialect,
+            objects: 4,
+            layers: 20,
+            perimeters: 3,
+            segments: 64,
+        }
+    }
+
+    pub fn objects(mut self, objects: usize) -> Self {
+        self.objects = objects;
+        self
+    }
+
+    pub fn layers(mut self, layers: usize) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    pub fn perimeters(mut self, perimeters: usize) -> Self {
+        self.perimeters = perimeters;
+        self
+    }
+
+    pub fn segments(mut self, segments: usize) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    /// Name of an object as given by the slicer
+    pub fn object_name(&self, object: usize) -> String {
+        match self.dialect {
+            Dialect::PrusaSlicer => format!("cylinder_{object}.stl id:{object} copy 0"),
+            Dialect::Cura => format!("cylinder_{object}.stl"),
+            Dialect::IdeaMaker => format!("cylinder_{object}.stl"),
+            Dialect::M486 => object.to_string(),
+        }
+    }
+
+    /// Center of an object on the bed
+    pub fn center(&self, object: usize) -> (f64, f64) {
+        let columns = (self.objects as f64).sqrt().ceil().max(1.0) as usize;
+        (
+            SPACING * (1 + object % columns) as f64,
+            SPACING * (1 + object / columns) as f64,
+        )
+    }
+
+    /// End points of the extrusion moves of a perimeter of an object, counted from the outside
+    pub fn perimeter(&self, object: usize, perimeter: usize) -> impl Iterator<Item = (f64, f64)> {
+        let (cx, cy) = self.center(object);
+        let segments = self.segments.max(3);
+        let radius = RADIUS - perimeter as f64 * PERIMETER_SPACING;
+        (1..=segments).map(move |segment| {
+            let angle = TAU * segment as f64 / segments as f64;
+            (cx + radius * angle.cos(), cy + radius * angle.sin())
+        })
+    }
+
+    pub fn write(&self, output: &mut impl Write) -> io::Result<()> {
+        self.header(output)?;
+
+        for layer in 0..self.layers {
+            let z = LAYER_HEIGHT * (layer + 1) as f64;
+            self.layer_change(output, layer, z)?;
+
+            for object in 0..self.objects {
+                self.object_start(output, object)?;
+                self.object_moves(output, object)?;
+                self.object_end(output, object)?;
+            }
+        }
+
+        self.footer(output)
+    }
+
+    fn header(&self, output: &mut impl Write) -> io::Result<()> {
+        match self.dialect {
+            Dialect::PrusaSlicer => {
+                writeln!(output, "; generated by PrusaSlicer 2.6.0")?;
+                writeln!(
+                    output,
+                    "; external perimeters extrusion width = {PERIMETER_SPACING:.2}mm"
+                )?;
+            }
+            Dialect::Cura => {
+                writeln!(output, ";FLAVOR:RepRap")?;
+                writeln!(output, ";Generated with Cura_SteamEngine 5.0.0")?;
+            }
+            Dialect::IdeaMaker => writeln!(output, ";Sliced by ideaMaker 4.2.0")?,
+            Dialect::M486 => writeln!(output, "M486 T{}", self.objects)?,
+        }
+
+        writeln!(output, "G28\nG90\nM83")?;
+        if self.dialect == Dialect::Cura {
+            writeln!(output, ";LAYER_COUNT:{}", self.layers)?;
+        }
+
+        Ok(())
+    }
+
+    fn layer_change(&self, output: &mut impl Write, layer: usize, z: f64) -> io::Result<()> {
+        match self.dialect {
+            Dialect::PrusaSlicer | Dialect::M486 => writeln!(output, ";LAYER_CHANGE\n;Z:{z:.1}")?,
+            Dialect::Cura | Dialect::IdeaMaker => writeln!(output, ";LAYER:{layer}")?,
+        }
+        writeln!(output, "G1 Z{z:.3} F720")
+    }
+
+    fn object_start(&self, output: &mut impl Write, object: usize) -> io::Result<()> {
+        let name = self.object_name(object);
+        match self.dialect {
+            Dialect::PrusaSlicer => writeln!(output, "; printing object {name}"),
+            Dialect::Cura => writeln!(output, ";MESH:{name}"),
+            Dialect::IdeaMaker => writeln!(output, ";PRINTING: {name}\n;PRINTING_ID: {object}"),
+            Dialect::M486 => writeln!(output, "M486 S{object}"),
+        }
+    }
+
+    fn object_end(&self, output: &mut impl Write, object: usize) -> io::Result<()> {
+        match self.dialect {
+            Dialect::PrusaSlicer => {
+                writeln!(
+                    output,
+                    "; stop printing object {}",
+                    self.object_name(object)
+                )
+            }
+            Dialect::Cura => writeln!(output, ";MESH:NONMESH"),
+            Dialect::IdeaMaker => writeln!(output, ";PRINTING: NON-OBJECT\n;PRINTING_ID: -1"),
+            Dialect::M486 => writeln!(output, "M486 S-1"),
+        }
+    }
+
+    /// Concentric perimeters around the center of the object
+    fn object_moves(&self, output: &mut impl Write, object: usize) -> io::Result<()> {
+        let (cx, cy) = self.center(object);
+        let extrusion = TAU * RADIUS / self.segments.max(3) as f64 * 0.033;
+
+        for perimeter in 0..self.perimeters {
+            let radius = RADIUS - perimeter as f64 * PERIMETER_SPACING;
+            writeln!(output, "G0 X{:.3} Y{:.3} F9000", cx + radius, cy)?;
+            for (x, y) in self.perimeter(object, perimeter) {
+                writeln!(output, "G1 X{x:.3} Y{y:.3} E{extrusion:.5}")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn footer(&self, output: &mut impl Write) -> io::Result<()> {
+        if self.dialect == Dialect::Cura {
+            writeln!(output, ";TIME_ELAPSED:{}", self.layers * 10)?;
+        }
+        writeln!(output, "M104 S0\nM140 S0\nM84")
+    }
+}
+
+impl fmt::Display for SyntheticGcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut output = Vec::new();
+        self.write(&mut output).map_err(|_err| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dialects() {
+        for dialect in Dialect::ALL {
+            assert_eq!(dialect.to_string().parse::<Dialect>(), Ok(dialect));
+
+            let gcode = SyntheticGcode::new(dialect)
+                .objects(2)
+                .layers(3)
+                .segments(8)
+                .to_string();
+            let moves = gcode
+                .lines()
+                .filter(|line| line.starts_with("G1 X"))
+                .count();
+            assert_eq!(moves, 2 * 3 * 3 * 8, "{dialect}");
+        }
+        assert!("simplify3d".parse::<Dialect>().is_err());
+    }
+
+    #[test]
+    fn test_prusaslicer() {
+        let gcode = SyntheticGcode::new(Dialect::PrusaSlicer)
+            .objects(1)
+            .layers(1)
+            .perimeters(1)
+            .segments(4)
+            .to_string();
+        assert_eq!(
+            gcode,
+            "; generated by PrusaSlicer 2.6.0\n\
+            ; external perimeters extrusion width = 0.45mm\n\
+            G28\nG90\nM83\n\
+            ;LAYER_CHANGE\n;Z:0.2\nG1 Z0.200 F720\n\
+            ; printing object cylinder_0.stl id:0 copy 0\n\
+            G0 X55.000 Y40.000 F9000\n\
+            G1 X40.000 Y55.000 E0.77754\n\
+            G1 X25.000 Y40.000 E0.77754\n\
+            G1 X40.000 Y25.000 E0.77754\n\
+            G1 X55.000 Y40.000 E0.77754\n\
+            ; stop printing object cylinder_0.stl id:0 copy 0\n\
+            M104 S0\nM140 S0\nM84\n"
+        );
+    }
+}
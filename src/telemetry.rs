@@ -0,0 +1,52 @@
+//! Export of the tracing spans to an OpenTelemetry collector.
+//!
+//! Every file is processed in a `file` span with a span for each phase below it, so farms running
+//! the tool on many hosts can collect the time spent on each file and the failures centrally.
+
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Layer exporting the spans to the collector at `endpoint` over OTLP/HTTP, each span is sent
+/// once it is closed
+pub(crate) fn otlp_layer<S>(endpoint: &str) -> Result<impl Layer<S>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            env!("CARGO_PKG_NAME"),
+        )])))
+        .install_simple()?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Flush the spans not exported yet
+pub(crate) fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::Registry;
+
+    #[test]
+    fn test_otlp_layer() {
+        // Nothing is sent before the first span is closed
+        assert!(otlp_layer::<Registry>("http://localhost:4318").is_ok());
+        shutdown();
+    }
+}
@@ -0,0 +1,236 @@
+//! Affine transforms of the object outlines.
+//!
+//! Some setups transform the streamed G-Code in firmware, e.g. IDEX printers in copy or mirror
+//! mode, so the outlines of the slicer don't match what prints. The transforms are applied to
+//! the outlines in the order they were given, after the tool and XY offsets.
+
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Error)]
+pub enum TransformError {
+    #[error(
+        "Invalid transform {0}, expected mirror-x=X, mirror-y=Y, translate=X,Y or rotate=DEG[@X,Y]"
+    )]
+    Invalid(String),
+}
+
+/// A single transform of the outlines
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Transform {
+    /// Mirror across the vertical line at X
+    MirrorX(f64),
+    /// Mirror across the horizontal line at Y
+    MirrorY(f64),
+    /// Move by X,Y
+    Translate(f64, f64),
+    /// Rotate counterclockwise by degrees around a center
+    Rotate { degrees: f64, center: (f64, f64) },
+}
+
+fn parse_pair(value: &str) -> Option<(f64, f64)> {
+    let (x, y) = value.split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+impl FromStr for Transform {
+    type Err = TransformError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || TransformError::Invalid(value.to_string());
+
+        let (kind, args) = value.split_once('=').ok_or_else(invalid)?;
+        let number = |arg: &str| arg.trim().parse::<f64>().map_err(|_err| invalid());
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "mirror-x" => Ok(Self::MirrorX(number(args)?)),
+            "mirror-y" => Ok(Self::MirrorY(number(args)?)),
+            "translate" => {
+                let (x, y) = parse_pair(args).ok_or_else(invalid)?;
+                Ok(Self::Translate(x, y))
+            }
+            "rotate" => {
+                let (degrees, center) = match args.split_once('@') {
+                    Some((degrees, center)) => {
+                        (number(degrees)?, parse_pair(center).ok_or_else(invalid)?)
+                    }
+                    None => (number(args)?, (0.0, 0.0)),
+                };
+                Ok(Self::Rotate { degrees, center })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Transforms combined into a single affine transform, mapping X,Y to
+/// `(a * x + b * y + c, d * x + e * y + f)`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Affine {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+    f: f64,
+}
+
+impl Default for Affine {
+    fn default() -> Self {
+        Self::translate(0.0, 0.0)
+    }
+}
+
+impl Affine {
+    fn translate(x: f64, y: f64) -> Self {
+        Self {
+            a: 1.0,
+            b: 0.0,
+            c: x,
+            d: 0.0,
+            e: 1.0,
+            f: y,
+        }
+    }
+
+    /// The transforms applied in order
+    pub fn new(transforms: &[Transform]) -> Self {
+        transforms
+            .iter()
+            .fold(Self::default(), |affine, transform| {
+                affine.then(Self::from(*transform))
+            })
+    }
+
+    /// This transform followed by another one
+    fn then(self, next: Self) -> Self {
+        Self {
+            a: next.a * self.a + next.b * self.d,
+            b: next.a * self.b + next.b * self.e,
+            c: next.a * self.c + next.b * self.f + next.c,
+            d: next.d * self.a + next.e * self.d,
+            e: next.d * self.b + next.e * self.e,
+            f: next.d * self.c + next.e * self.f + next.f,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        (
+            self.a * x + self.b * y + self.c,
+            self.d * x + self.e * y + self.f,
+        )
+    }
+}
+
+impl From<Transform> for Affine {
+    fn from(transform: Transform) -> Self {
+        match transform {
+            Transform::MirrorX(x) => Self {
+                a: -1.0,
+                c: 2.0 * x,
+                ..Self::default()
+            },
+            Transform::MirrorY(y) => Self {
+                e: -1.0,
+                f: 2.0 * y,
+                ..Self::default()
+            },
+            Transform::Translate(x, y) => Self::translate(x, y),
+            Transform::Rotate {
+                degrees,
+                center: (x, y),
+            } => {
+                let (sin, cos) = degrees.to_radians().sin_cos();
+                let rotate = Self {
+                    a: cos,
+                    b: -sin,
+                    d: sin,
+                    e: cos,
+                    ..Self::default()
+                };
+                Self::translate(-x, -y)
+                    .then(rotate)
+                    .then(Self::translate(x, y))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(transforms: &[&str], point: (f64, f64)) -> (f64, f64) {
+        let transforms: Vec<Transform> = transforms.iter().map(|t| t.parse().unwrap()).collect();
+        let (x, y) = Affine::new(&transforms).apply(point);
+        // Round away the floating point noise of the rotations
+        ((x * 1e9).round() / 1e9, (y * 1e9).round() / 1e9)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "mirror-x=150".parse::<Transform>().unwrap(),
+            Transform::MirrorX(150.0)
+        );
+        assert_eq!(
+            "MIRROR-Y=-5".parse::<Transform>().unwrap(),
+            Transform::MirrorY(-5.0)
+        );
+        assert_eq!(
+            "translate=10, -2.5".parse::<Transform>().unwrap(),
+            Transform::Translate(10.0, -2.5)
+        );
+        assert_eq!(
+            "rotate=90@150,150".parse::<Transform>().unwrap(),
+            Transform::Rotate {
+                degrees: 90.0,
+                center: (150.0, 150.0)
+            }
+        );
+        assert_eq!(
+            "rotate=-45".parse::<Transform>().unwrap(),
+            Transform::Rotate {
+                degrees: -45.0,
+                center: (0.0, 0.0)
+            }
+        );
+        for invalid in [
+            "mirror-x",
+            "mirror-x=a",
+            "translate=1",
+            "rotate=90@1",
+            "scale=2",
+        ] {
+            assert!(invalid.parse::<Transform>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        assert!(Affine::new(&[]).is_identity());
+        assert_eq!(transform(&[], (1.0, 2.0)), (1.0, 2.0));
+        assert_eq!(transform(&["mirror-x=150"], (100.0, 20.0)), (200.0, 20.0));
+        assert_eq!(transform(&["mirror-y=10"], (100.0, 20.0)), (100.0, 0.0));
+        assert_eq!(transform(&["translate=1,-1"], (100.0, 20.0)), (101.0, 19.0));
+        assert_eq!(transform(&["rotate=90"], (10.0, 0.0)), (0.0, 10.0));
+        assert_eq!(transform(&["rotate=90@5,5"], (10.0, 5.0)), (5.0, 10.0));
+
+        // Applied in the order given
+        assert_eq!(
+            transform(&["translate=10,0", "rotate=90"], (0.0, 0.0)),
+            (0.0, 10.0)
+        );
+        assert_eq!(
+            transform(&["rotate=90", "translate=10,0"], (0.0, 0.0)),
+            (10.0, 0.0)
+        );
+        assert_eq!(
+            transform(&["mirror-x=0", "mirror-x=0"], (3.0, 4.0)),
+            (3.0, 4.0)
+        );
+    }
+}
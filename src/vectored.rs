@@ -0,0 +1,172 @@
+use std::io::{self, IoSlice, Write};
+
+/// Number of filled buffers written by a single vectored write
+const MAX_CHUNKS: usize = 16;
+
+/// Output backend gathering the output in several buffers that are written with a single
+/// vectored write, reducing the number of write calls compared to a `BufWriter` of the same
+/// buffer size.
+///
+/// Used instead of `BufWriter` with the `vectored-write` feature, for hosts processing large
+/// amounts of G-Code where the overhead of write calls is measurable.
+pub(crate) struct VectoredWriter<W: Write> {
+    inner: W,
+    /// Filled buffers waiting to be written
+    chunks: Vec<Vec<u8>>,
+    /// Written buffers kept to avoid allocating new ones
+    spare: Vec<Vec<u8>>,
+    current: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<W: Write> VectoredWriter<W> {
+    pub fn with_capacity(chunk_size: usize, inner: W) -> Self {
+        let chunk_size = chunk_size.max(1);
+        Self {
+            inner,
+            chunks: Vec::with_capacity(MAX_CHUNKS),
+            spare: Vec::with_capacity(MAX_CHUNKS),
+            current: Vec::with_capacity(chunk_size),
+            chunk_size,
+        }
+    }
+
+    fn finish_chunk(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        let next = self
+            .spare
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.chunk_size));
+        self.chunks.push(std::mem::replace(&mut self.current, next));
+    }
+
+    fn write_chunks(&mut self) -> io::Result<()> {
+        let mut slices: Vec<IoSlice> = self
+            .chunks
+            .iter()
+            .map(|chunk| IoSlice::new(chunk))
+            .collect();
+        let mut remaining = &mut slices[..];
+        while !remaining.is_empty() {
+            match self.inner.write_vectored(remaining) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => IoSlice::advance_slices(&mut remaining, written),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        for mut chunk in self.chunks.drain(..) {
+            chunk.clear();
+            self.spare.push(chunk);
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for VectoredWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buf = buf;
+        let length = buf.len();
+        while !buf.is_empty() {
+            let available = self.chunk_size - self.current.len();
+            let (head, tail) = buf.split_at(available.min(buf.len()));
+            self.current.extend_from_slice(head);
+            buf = tail;
+
+            if self.current.len() == self.chunk_size {
+                self.finish_chunk();
+                if self.chunks.len() == MAX_CHUNKS {
+                    self.write_chunks()?;
+                }
+            }
+        }
+
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.finish_chunk();
+        self.write_chunks()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for VectoredWriter<W> {
+    fn drop(&mut self) {
+        // Like `BufWriter`, errors on drop are ignored, callers flush explicitly
+        let _result = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the number of write calls, accepting at most `limit` bytes per call
+    #[derive(Default)]
+    struct Recorder {
+        data: Vec<u8>,
+        calls: usize,
+        limit: usize,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.calls += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let length = buf.len().min(self.limit - written);
+                self.data.extend_from_slice(&buf[..length]);
+                written += length;
+            }
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_vectored_writer() {
+        let line = b"G1 X10.5 Y20.25 E0.0125\n";
+        for limit in [7, 1024, usize::MAX] {
+            let mut recorder = Recorder {
+                limit,
+                ..Default::default()
+            };
+            let mut writer = VectoredWriter::with_capacity(64, &mut recorder);
+            for _ in 0..1000 {
+                writer.write_all(line).unwrap();
+            }
+            writer.flush().unwrap();
+            drop(writer);
+
+            assert_eq!(recorder.data, line.repeat(1000), "limit {limit}");
+            if limit == usize::MAX {
+                // 24000 bytes in buffers of 64 bytes, written 16 buffers at a time
+                assert_eq!(recorder.calls, 24);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let mut recorder = Recorder {
+            limit: usize::MAX,
+            ..Default::default()
+        };
+        let mut writer = VectoredWriter::with_capacity(1024, &mut recorder);
+        writer.write_all(b"G28\n").unwrap();
+        drop(writer);
+        assert_eq!(recorder.data, b"G28\n");
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::io::{self, Write};
+
+const START: &[u8] = b"EXCLUDE_OBJECT_START";
+const END: &[u8] = b"EXCLUDE_OBJECT_END";
+/// Common prefix of both markers, lines not starting with it are not kept
+const PREFIX: &[u8] = b"EXCLUDE_OBJECT_";
+
+/// A marker that Klipper would not pair up with the rest of the file
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct MarkerProblem {
+    /// Line of the output, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MarkerProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Checks the object markers in the output while it is written.
+///
+/// Every object has to be started before it is ended, objects are never nested and no object is
+/// left open at the end of the file, so the starts and ends of every object are balanced.
+pub(crate) struct MarkerVerifier<W: Write> {
+    inner: W,
+    /// Lines completed so far
+    line_no: usize,
+    /// Start of the current line while it could still be a marker
+    line: Vec<u8>,
+    skip_line: bool,
+    /// The object started last and the line it was started on
+    open: Option<(String, usize)>,
+    problems: Vec<MarkerProblem>,
+}
+
+impl<W: Write> MarkerVerifier<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            line_no: 0,
+            line: Vec::new(),
+            skip_line: false,
+            open: None,
+            problems: Vec::new(),
+        }
+    }
+
+    /// The output written without verifying it, e.g. for files passed through unchanged
+    pub fn inner(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn scan(&mut self, mut bytes: &[u8]) {
+        while let Some(end) = bytes.iter().position(|byte| *byte == b'\n') {
+            self.extend(&bytes[..end]);
+            self.end_line();
+            bytes = &bytes[end + 1..];
+        }
+        self.extend(bytes);
+    }
+
+    fn extend(&mut self, bytes: &[u8]) {
+        if self.skip_line || bytes.is_empty() {
+            return;
+        }
+        self.line.extend_from_slice(bytes);
+        let length = self.line.len().min(PREFIX.len());
+        if !PREFIX.starts_with(&self.line[..length]) {
+            self.skip_line = true;
+            self.line.clear();
+        }
+    }
+
+    fn end_line(&mut self) {
+        self.line_no += 1;
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.check(&String::from_utf8_lossy(&line));
+            self.line = line;
+            self.line.clear();
+        }
+        self.skip_line = false;
+    }
+
+    fn problem(&mut self, message: String) {
+        self.problems.push(MarkerProblem {
+            line: self.line_no,
+            message,
+        });
+    }
+
+    fn check(&mut self, line: &str) {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default().as_bytes();
+        let name = words
+            .find_map(|word| word.strip_prefix("NAME="))
+            .map(str::to_string);
+
+        if command == START {
+            let Some(name) = name else {
+                return self.problem("EXCLUDE_OBJECT_START without a NAME".into());
+            };
+            if let Some((open, _)) = self.open.take() {
+                self.problem(format!("{name} started while {open} is still printing"));
+            }
+            self.open = Some((name, self.line_no));
+        } else if command == END {
+            match (name, self.open.take()) {
+                (None, Some(_)) => {}
+                (Some(name), Some((open, _))) if name == open => {}
+                (Some(name), Some((open, _))) => {
+                    self.problem(format!("{name} ended while {open} is printing"));
+                }
+                (Some(name), None) => self.problem(format!("{name} ended without being started")),
+                (None, None) => self.problem("EXCLUDE_OBJECT_END without a started object".into()),
+            }
+        }
+    }
+
+    /// The problems found in the output, to be called once everything was written
+    pub fn finish(mut self) -> Vec<MarkerProblem> {
+        if !self.line.is_empty() {
+            self.end_line();
+        }
+        if let Some((open, line)) = self.open.take() {
+            self.problems.push(MarkerProblem {
+                line,
+                message: format!("{open} is never ended"),
+            });
+        }
+
+        self.problems
+    }
+}
+
+impl<W: Write> Write for MarkerVerifier<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.scan(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify(gcode: &str, chunk: usize) -> Vec<MarkerProblem> {
+        let mut output = Vec::new();
+        let mut verifier = MarkerVerifier::new(&mut output);
+        for bytes in gcode.as_bytes().chunks(chunk) {
+            verifier.write_all(bytes).unwrap();
+        }
+        let problems = verifier.finish();
+        assert_eq!(output, gcode.as_bytes());
+        problems
+    }
+
+    #[test]
+    fn test_balanced_markers() {
+        let gcode = "EXCLUDE_OBJECT_DEFINE NAME=a\nEXCLUDE_OBJECT_DEFINE NAME=b\n\
+            EXCLUDE_OBJECT_START NAME=a\nG1 X1 E1\nEXCLUDE_OBJECT_END NAME=a\n\
+            EXCLUDE_OBJECT_START NAME=b\nG1 X2 E1\nEXCLUDE_OBJECT_END\n";
+        for chunk in [1, 5, 1024] {
+            assert_eq!(verify(gcode, chunk), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_unbalanced_markers() {
+        let gcode = "G28\n\
+            EXCLUDE_OBJECT_END NAME=a\n\
+            EXCLUDE_OBJECT_START NAME=a\n\
+            EXCLUDE_OBJECT_START NAME=b\n\
+            EXCLUDE_OBJECT_END NAME=a\n\
+            EXCLUDE_OBJECT_END\n\
+            EXCLUDE_OBJECT_START NAME=c\n\
+            G1 X1 E1";
+        for chunk in [1, 7, 1024] {
+            let problems: Vec<String> = verify(gcode, chunk)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            assert_eq!(
+                problems,
+                vec![
+                    "line 2: a ended without being started",
+                    "line 4: b started while a is still printing",
+                    "line 5: a ended while b is printing",
+                    "line 6: EXCLUDE_OBJECT_END without a started object",
+                    "line 7: c is never ended",
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,530 @@
+//! End-to-end tests of the command line tool: the binary is run on copies of the fixtures in
+//! temporary directories and the written files, exit codes and messages are checked.
+
+use preprocess_cancellation::synthetic::{Dialect, SyntheticGcode};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+/// Exit codes of the failures, see `--help`
+const EXIT_NO_OBJECTS: i32 = 3;
+const EXIT_UNKNOWN_SLICER: i32 = 4;
+const EXIT_IO: i32 = 5;
+const EXIT_INVALID_FILTER: i32 = 6;
+const EXIT_ALREADY_PROCESSED: i32 = 7;
+const EXIT_VALIDATION: i32 = 8;
+const EXIT_PARTIAL_BATCH: i32 = 9;
+const EXIT_HOOK_FAILED: i32 = 10;
+const DEFINE: &str = "EXCLUDE_OBJECT_DEFINE";
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("GCode")
+        .join(name)
+}
+
+/// A temporary directory with copies of the given fixtures
+fn workdir(fixtures: &[&str]) -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for name in fixtures {
+        fs::copy(fixture(name), dir.path().join(name)).unwrap();
+    }
+    dir
+}
+
+fn command(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_preprocess_cancellation"));
+    // Not running as a PrusaSlicer post-processing script, even if the tests are
+    command.current_dir(dir).env_remove("SLIC3R_PP_OUTPUT_NAME");
+    command
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    command(dir).args(args).output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+fn processed(path: &Path) -> bool {
+    fs::read_to_string(path).unwrap().contains(DEFINE)
+}
+
+#[test]
+fn test_in_place() {
+    let dir = workdir(&["slic3r.gcode"]);
+    let output = run(dir.path(), &["slic3r.gcode"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(processed(&dir.path().join("slic3r.gcode")));
+    // Only the processed file is left, the temporary file was moved into place
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_output_dir_created() {
+    let dir = workdir(&["cura.gcode"]);
+    let output = run(dir.path(), &["-O", "out/nested", "cura.gcode"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(processed(&dir.path().join("out/nested/cura.gcode")));
+    assert!(!processed(&dir.path().join("cura.gcode")));
+}
+
+#[test]
+fn test_suffix_placement() {
+    for (placement, expected) in [
+        ("extension", "m486.done.gcode"),
+        ("stem", "m486_done.gcode"),
+        ("replace", "m486.done"),
+    ] {
+        let dir = workdir(&["m486.gcode"]);
+        let output = run(
+            dir.path(),
+            &["-o", "done", "--suffix-placement", placement, "m486.gcode"],
+        );
+
+        assert!(output.status.success(), "{placement}: {}", stderr(&output));
+        assert!(processed(&dir.path().join(expected)), "{placement}");
+        assert!(!processed(&dir.path().join("m486.gcode")), "{placement}");
+    }
+}
+
+#[test]
+fn test_archive_dir() {
+    let dir = workdir(&["cura.gcode"]);
+    let output = run(
+        dir.path(),
+        &["--archive-dir", "raw", "-o", "done", "cura.gcode"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(processed(&dir.path().join("cura.done.gcode")));
+    assert_eq!(
+        fs::read(dir.path().join("raw/cura.gcode")).unwrap(),
+        fs::read(fixture("cura.gcode")).unwrap()
+    );
+    assert!(!dir.path().join("cura.gcode").exists());
+
+    // The archived original is never overwritten
+    fs::copy(fixture("cura.gcode"), dir.path().join("cura.gcode")).unwrap();
+    let output = run(dir.path(), &["--archive-dir", "raw", "cura.gcode"]);
+    assert_eq!(output.status.code(), Some(EXIT_ALREADY_PROCESSED));
+}
+
+#[test]
+fn test_wait_stable() {
+    let dir = workdir(&["cura.gcode"]);
+    let output = run(
+        dir.path(),
+        &["--wait-stable", "0.05", "--retry-delay", "10", "cura.gcode"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(processed(&dir.path().join("cura.gcode")));
+
+    let output = run(dir.path(), &["--wait-stable", "0.05", "missing.gcode"]);
+    assert_eq!(output.status.code(), Some(EXIT_IO));
+    for attempts in ["0", "101"] {
+        let output = run(dir.path(), &["--retry-attempts", attempts, "cura.gcode"]);
+        assert_eq!(output.status.code(), Some(2), "{attempts}");
+    }
+}
+
+#[test]
+fn test_bed_origin() {
+    let dir = workdir(&["cura.gcode"]);
+    let output = run(
+        dir.path(),
+        &[
+            "--bed-origin",
+            "150,150",
+            "--xy-offset",
+            "-0.5,0",
+            "cura.gcode",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let processed = fs::read_to_string(dir.path().join("cura.gcode")).unwrap();
+    assert!(processed.contains("CENTER=-0.500,6.470"), "{processed}");
+
+    // The outlines are checked against the moves with the same offset
+    let output = run(
+        dir.path(),
+        &[
+            "--verify",
+            "--bed-origin",
+            "150,150",
+            "--xy-offset",
+            "-0.5,0",
+            "cura.gcode",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = run(dir.path(), &["--verify", "cura.gcode"]);
+    assert_eq!(output.status.code(), Some(EXIT_VALIDATION));
+}
+
+#[test]
+fn test_transform() {
+    let dir = workdir(&["cura.gcode"]);
+    let args = [
+        "--bed-origin",
+        "150,150",
+        "--transform",
+        "mirror-y=0",
+        "--transform",
+        "rotate=90",
+        "cura.gcode",
+    ];
+    let output = run(dir.path(), &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let processed = fs::read_to_string(dir.path().join("cura.gcode")).unwrap();
+    assert!(processed.contains("CENTER=6.470,"), "{processed}");
+
+    let output = run(dir.path(), &[&["--verify"], &args[..]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = run(dir.path(), &["--transform", "scale=2", "cura.gcode"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Invalid transform scale=2"));
+}
+
+#[test]
+fn test_multiple_files() {
+    let files = ["slic3r.gcode", "cura.gcode", "ideamaker.gcode"];
+    let dir = workdir(&files);
+    let output = run(dir.path(), &files);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    for file in files {
+        assert!(processed(&dir.path().join(file)), "{file}");
+    }
+}
+
+#[test]
+fn test_failure_stops_batch() {
+    let dir = workdir(&["slic3r.gcode"]);
+    fs::write(dir.path().join("unknown.gcode"), "G28\nG1 X1 Y1 E1\n").unwrap();
+    let output = run(dir.path(), &["unknown.gcode", "slic3r.gcode"]);
+
+    assert_eq!(output.status.code(), Some(EXIT_UNKNOWN_SLICER));
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains("Error processing file unknown.gcode"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("could not be identified"), "{stderr}");
+    // The failed file is left alone and the files after it aren't processed
+    assert_eq!(
+        fs::read_to_string(dir.path().join("unknown.gcode")).unwrap(),
+        "G28\nG1 X1 Y1 E1\n"
+    );
+    assert!(!processed(&dir.path().join("slic3r.gcode")));
+
+    // Failing after a processed file tells the caller that some files were written
+    let output = run(dir.path(), &["slic3r.gcode", "unknown.gcode"]);
+    assert_eq!(output.status.code(), Some(EXIT_PARTIAL_BATCH));
+    assert!(processed(&dir.path().join("slic3r.gcode")));
+}
+
+#[test]
+fn test_failing_post_hook() {
+    let files = ["slic3r.gcode", "cura.gcode"];
+    let dir = workdir(&files);
+    let output = run(dir.path(), &["--post-hook", "exit 1", "slic3r.gcode"]);
+
+    assert_eq!(output.status.code(), Some(EXIT_HOOK_FAILED));
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains("Error processing file slic3r.gcode"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("Error: Error:"), "{stderr}");
+    assert!(processed(&dir.path().join("slic3r.gcode")));
+
+    // The summary lists the file like any other failure
+    let output = run(
+        dir.path(),
+        &["--report", "--post-hook", "exit 1", "slic3r.gcode"],
+    );
+    assert_eq!(output.status.code(), Some(EXIT_HOOK_FAILED));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("failed: Post-processing hook exited with"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("1 files, 1 failed"), "{stdout}");
+
+    // Failing for a later file of the batch
+    #[cfg(unix)]
+    {
+        let hook = r#"test "$PREPROCESS_CANCELLATION_INPUT" != cura.gcode"#;
+        let output = run(dir.path(), &[&["--post-hook", hook], &files[..]].concat());
+        assert_eq!(output.status.code(), Some(EXIT_PARTIAL_BATCH));
+    }
+}
+
+#[test]
+fn test_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(dir.path(), &["missing.gcode"]);
+
+    assert_eq!(output.status.code(), Some(EXIT_IO));
+    assert!(stderr(&output).contains("Error reading/writing file missing.gcode"));
+}
+
+#[test]
+fn test_fail_if_processed() {
+    let dir = workdir(&["cura.gcode"]);
+    let output = run(dir.path(), &["--fail-if-processed", "cura.gcode"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = run(dir.path(), &["cura.gcode"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = run(dir.path(), &["--fail-if-processed", "cura.gcode"]);
+    assert_eq!(output.status.code(), Some(EXIT_ALREADY_PROCESSED));
+    assert!(stderr(&output).contains("The file was processed already"));
+}
+
+#[test]
+fn test_no_objects() {
+    let dir = tempfile::tempdir().unwrap();
+    let gcode = "; generated by PrusaSlicer 2.6.0\nG28\nG1 X1 Y1 E1\n";
+    fs::write(dir.path().join("plate.gcode"), gcode).unwrap();
+
+    let output = run(dir.path(), &["plate.gcode"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("No objects found"));
+
+    let output = run(dir.path(), &["--require-objects", "plate.gcode"]);
+    assert_eq!(output.status.code(), Some(EXIT_NO_OBJECTS));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("plate.gcode")).unwrap(),
+        gcode
+    );
+}
+
+#[test]
+fn test_json_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let gcode = "; generated by PrusaSlicer 2.6.0\nG28\nG1 X1 Y1 E1\n";
+    fs::write(dir.path().join("plate.gcode"), gcode).unwrap();
+
+    let error = |args: &[&str]| {
+        let output = run(dir.path(), &[&["--json-errors"], args].concat());
+        assert!(!output.status.success());
+        let stderr = stderr(&output);
+        let last = stderr.lines().last().unwrap();
+        serde_json::from_str::<serde_json::Value>(last).unwrap()
+    };
+
+    let errors = [
+        error(&["--require-objects", "plate.gcode"]),
+        error(&["missing.gcode"]),
+    ];
+    assert_eq!(errors[0]["code"], "no_objects");
+    assert_eq!(errors[0]["file"], "plate.gcode");
+    assert_eq!(errors[0]["line"], serde_json::Value::Null);
+    assert!(errors[0]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("No objects found in the PrusaSlicer file"));
+    assert_eq!(errors[1]["code"], "io_error");
+    assert_eq!(errors[1]["file"], "missing.gcode");
+
+    // Only printed when asked for
+    let output = run(dir.path(), &["missing.gcode"]);
+    assert!(!stderr(&output).contains(r#""code""#));
+}
+
+#[test]
+fn test_invalid_arguments() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(dir.path(), &["--layers", "*/0", "plate.gcode"]);
+    assert_eq!(output.status.code(), Some(EXIT_INVALID_FILTER));
+
+    let output = run(dir.path(), &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("<GCODE>"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_exit_status_help() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(dir.path(), &["--help"]);
+    assert!(output.status.success());
+    let help = String::from_utf8_lossy(&output.stdout);
+    assert!(help.contains("Exit status:"), "{help}");
+    assert!(
+        help.contains(&format!("{EXIT_PARTIAL_BATCH}  A file failed after")),
+        "{help}"
+    );
+}
+
+#[test]
+fn test_stdio() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut child = command(dir.path())
+        .arg("--stdio")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&fs::read(fixture("cura.gcode")).unwrap())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).contains(DEFINE));
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+/// Every dialect of the synthetic plates of the benchmarks is processed by the binary
+#[test]
+fn test_synthetic_stdio() {
+    let dir = tempfile::tempdir().unwrap();
+    for dialect in Dialect::ALL {
+        let mut child = command(dir.path())
+            .arg("--stdio")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let gcode = SyntheticGcode::new(dialect).objects(4).layers(5);
+        gcode.write(&mut child.stdin.take().unwrap()).unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert!(output.status.success(), "{dialect}: {}", stderr(&output));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(stdout.matches(DEFINE).count(), 4, "{dialect}");
+    }
+}
+
+#[test]
+fn test_prusaslicer_post_processing() {
+    let dir = workdir(&["prusaslicer.gcode"]);
+    let output = command(dir.path())
+        .env("SLIC3R_PP_OUTPUT_NAME", "/home/me/plate.gcode")
+        .args(["-o", "done", "prusaslicer.gcode"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    // PrusaSlicer expects the file in place and the new name next to it
+    assert!(processed(&dir.path().join("prusaslicer.gcode")));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("prusaslicer.gcode.output_name")).unwrap(),
+        "plate.done.gcode"
+    );
+}
+
+#[test]
+fn test_container_plates() {
+    let dir = tempfile::tempdir().unwrap();
+    let gcode = fs::read(fixture("orcaslicer.gcode")).unwrap();
+    let mut archive =
+        zip::ZipWriter::new(fs::File::create(dir.path().join("cube.gcode.3mf")).unwrap());
+    for name in ["Metadata/plate_1.gcode", "Metadata/plate_2.gcode"] {
+        archive
+            .start_file(name, zip::write::FileOptions::default())
+            .unwrap();
+        archive.write_all(&gcode).unwrap();
+    }
+    archive.finish().unwrap();
+
+    let output = run(dir.path(), &["cube.gcode.3mf"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(processed(&dir.path().join("cube_plate1.gcode")));
+    assert!(processed(&dir.path().join("cube_plate2.gcode")));
+
+    let output = run(
+        dir.path(),
+        &[
+            "-O",
+            "out",
+            "--output-template",
+            "{plate}/{name}",
+            "cube.gcode.3mf",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(processed(&dir.path().join("out/2/cube.gcode")));
+}
+
+/// The stress test tool generates and processes a plate
+#[cfg(unix)]
+#[test]
+fn test_stress_test() {
+    let output = Command::new(env!("CARGO_BIN_EXE_stress_test"))
+        .args(["--objects", "2", "--layers", "3", "--dialect", "cura"])
+        .env_remove("SLIC3R_PP_OUTPUT_NAME")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("cura, 2 objects, 3 layers"));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Processed in"));
+}
+
+/// The daemon reports readiness, processes the queue and stops on SIGTERM
+#[cfg(target_os = "linux")]
+#[test]
+fn test_daemon() {
+    use std::os::unix::net::UnixDatagram;
+    use std::time::{Duration, Instant};
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket_path = dir.path().join("notify.sock");
+    let socket = UnixDatagram::bind(&socket_path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+    let receive = || {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).ok()?;
+        Some(String::from_utf8_lossy(&buf[..len]).to_string())
+    };
+
+    let mut child = command(dir.path())
+        .args(["--daemon", "queue", "--poll-interval", "0.05"])
+        .env("NOTIFY_SOCKET", &socket_path)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    assert_eq!(receive().as_deref(), Some("READY=1"));
+
+    let queue = dir.path().join("queue");
+    fs::copy(fixture("slic3r.gcode"), queue.join("incoming/slic3r.gcode")).unwrap();
+    fs::write(queue.join("incoming/broken.3mf"), "G28\n").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while fs::read_dir(queue.join("incoming")).unwrap().count() > 0 {
+        assert!(Instant::now() < deadline, "The queue was not processed");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(processed(&queue.join("processed/slic3r.gcode")));
+    assert!(queue.join("failed/broken.3mf").exists());
+    assert!(fs::read_to_string(queue.join("failed/broken.3mf.error"))
+        .unwrap()
+        .contains("broken.3mf"));
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let states: Vec<String> = std::iter::from_fn(receive).collect();
+    assert!(states.contains(&"STATUS=1 processed, 1 failed".to_string()));
+    assert_eq!(states.last().unwrap(), "STOPPING=1");
+}